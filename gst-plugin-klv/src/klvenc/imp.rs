@@ -0,0 +1,268 @@
+//! Encodes ST 0601 local sets given as JSON Lines into KLV, one packet per line.
+//!
+//! Lines have the field names of `UasDatalinkLocalSet`, e.g.
+//! `{"platform_heading": 12.5, "sensor_latitude": 59.43}`. A packet gets the PTS of the buffer
+//! its line ends in. Input without PTS, e.g. from `filesrc`, is timed by the Precision Time
+//! Stamps of the lines relative to the first one.
+
+use gst::{glib, prelude::*, subclass::prelude::*};
+use gstreamer as gst;
+use gstreamer_klv_test::klv::st0601;
+use std::{
+    mem,
+    sync::{LazyLock, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static CAT: LazyLock<gst::DebugCategory> = LazyLock::new(|| {
+    gst::DebugCategory::new("klvenc", gst::DebugColorFlags::empty(), Some("KLV encoder"))
+});
+
+#[derive(Debug, Clone, Default)]
+struct Settings {
+    /// Mission ID of lines which have none.
+    mission_id: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Input after the last complete line.
+    pending: Vec<u8>,
+    /// PTS of the last buffer, for the line at EOS.
+    last_pts: Option<gst::ClockTime>,
+    /// Precision Time Stamp of the first line, for input without PTS.
+    first_time_stamp: Option<u64>,
+}
+
+pub struct KlvEnc {
+    sinkpad: gst::Pad,
+    srcpad: gst::Pad,
+    settings: Mutex<Settings>,
+    state: Mutex<State>,
+}
+
+impl KlvEnc {
+    fn sink_chain(
+        &self,
+        _pad: &gst::Pad,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let map = buffer.map_readable().map_err(|_| {
+            gst::element_imp_error!(self, gst::CoreError::Failed, ["Failed to map buffer"]);
+            gst::FlowError::Error
+        })?;
+        let lines = {
+            let mut state = self.state.lock().unwrap();
+            state.pending.extend_from_slice(map.as_slice());
+            state.last_pts = buffer.pts();
+            let Some(end) = state.pending.iter().rposition(|&b| b == b'\n') else {
+                return Ok(gst::FlowSuccess::Ok);
+            };
+            let rest = state.pending.split_off(end + 1);
+            mem::replace(&mut state.pending, rest)
+        };
+        for line in lines.split(|&b| b == b'\n') {
+            self.encode_line(line, buffer.pts())?;
+        }
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    fn encode_line(
+        &self,
+        line: &[u8],
+        pts: Option<gst::ClockTime>,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        if line.trim_ascii().is_empty() {
+            return Ok(gst::FlowSuccess::Ok);
+        }
+        let mut set: st0601::UasDatalinkLocalSet = match serde_json::from_slice(line) {
+            Ok(set) => set,
+            Err(err) => {
+                gst::element_imp_warning!(
+                    self,
+                    gst::StreamError::Decode,
+                    ["Invalid metadata line: {}", err]
+                );
+                return Ok(gst::FlowSuccess::Ok);
+            }
+        };
+        let time_stamp = *set.precision_time_stamp.get_or_insert_with(|| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            now.as_micros() as u64
+        });
+        set.ls_version.get_or_insert(st0601::LS_VERSION);
+        if set.mission_id.is_none() {
+            set.mission_id = self.settings.lock().unwrap().mission_id.clone();
+        }
+        let pts = pts.or_else(|| {
+            let mut state = self.state.lock().unwrap();
+            let first = *state.first_time_stamp.get_or_insert(time_stamp);
+            Some(gst::ClockTime::from_useconds(
+                time_stamp.saturating_sub(first),
+            ))
+        });
+
+        let data = set.encode().encode();
+        gst::trace!(CAT, imp: self, "encoded {} bytes at {:?}", data.len(), pts);
+        let mut buffer = gst::Buffer::from_mut_slice(data);
+        buffer.get_mut().unwrap().set_pts(pts);
+        self.srcpad.push(buffer)
+    }
+
+    fn sink_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        match event.view() {
+            EventView::Caps(_) => {
+                let caps = self.srcpad.pad_template_caps();
+                self.srcpad.push_event(gst::event::Caps::new(&caps))
+            }
+            // KLV is timed, segments in bytes from e.g. `filesrc` are replaced.
+            EventView::Segment(segment) if segment.segment().format() != gst::Format::Time => {
+                let segment = gst::FormattedSegment::<gst::ClockTime>::new();
+                self.srcpad.push_event(gst::event::Segment::new(&segment))
+            }
+            EventView::Eos(_) => {
+                // Last line doesn't have to end with a newline, it ends in the last buffer.
+                let (line, pts) = {
+                    let mut state = self.state.lock().unwrap();
+                    (mem::take(&mut state.pending), state.last_pts)
+                };
+                let _ = self.encode_line(&line, pts);
+                self.srcpad.push_event(event)
+            }
+            EventView::FlushStop(_) => {
+                {
+                    let mut state = self.state.lock().unwrap();
+                    state.pending.clear();
+                    state.last_pts = None;
+                }
+                self.srcpad.push_event(event)
+            }
+            _ => gst::Pad::event_default(pad, Some(&*self.obj()), event),
+        }
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for KlvEnc {
+    const NAME: &'static str = "GstKlvEnc";
+    type Type = super::KlvEnc;
+    type ParentType = gst::Element;
+
+    fn with_class(klass: &Self::Class) -> Self {
+        let templ = klass.pad_template("sink").unwrap();
+        let sinkpad = gst::Pad::builder_from_template(&templ)
+            .chain_function(|pad, parent, buffer| {
+                KlvEnc::catch_panic_pad_function(
+                    parent,
+                    || Err(gst::FlowError::Error),
+                    |enc| enc.sink_chain(pad, buffer),
+                )
+            })
+            .event_function(|pad, parent, event| {
+                KlvEnc::catch_panic_pad_function(parent, || false, |enc| enc.sink_event(pad, event))
+            })
+            .build();
+        let templ = klass.pad_template("src").unwrap();
+        let srcpad = gst::Pad::builder_from_template(&templ).build();
+
+        KlvEnc {
+            sinkpad,
+            srcpad,
+            settings: Mutex::new(Settings::default()),
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl ObjectImpl for KlvEnc {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: LazyLock<Vec<glib::ParamSpec>> = LazyLock::new(|| {
+            vec![glib::ParamSpecString::builder("mission-id")
+                .nick("Mission ID")
+                .blurb("Mission ID of the local sets which have none")
+                .mutable_playing()
+                .build()]
+        });
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "mission-id" => {
+                self.settings.lock().unwrap().mission_id = value.get().expect("type checked");
+            }
+            name => unreachable!("unknown property {name}"),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "mission-id" => self.settings.lock().unwrap().mission_id.to_value(),
+            name => unreachable!("unknown property {name}"),
+        }
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        let obj = self.obj();
+        obj.add_pad(&self.sinkpad).unwrap();
+        obj.add_pad(&self.srcpad).unwrap();
+    }
+}
+
+impl GstObjectImpl for KlvEnc {}
+
+impl ElementImpl for KlvEnc {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: LazyLock<gst::subclass::ElementMetadata> = LazyLock::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "KLV encoder",
+                "Encoder/Metadata",
+                "Encodes ST 0601 local sets given as JSON Lines into KLV",
+                "Andres Vahter <andres@vahter.me>",
+            )
+        });
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: LazyLock<Vec<gst::PadTemplate>> = LazyLock::new(|| {
+            let sink_caps = gst::Caps::new_empty_simple("application/json");
+            let src_caps = gst::Caps::builder("meta/x-klv")
+                .field("parsed", true)
+                .build();
+            vec![
+                gst::PadTemplate::new(
+                    "sink",
+                    gst::PadDirection::Sink,
+                    gst::PadPresence::Always,
+                    &sink_caps,
+                )
+                .unwrap(),
+                gst::PadTemplate::new(
+                    "src",
+                    gst::PadDirection::Src,
+                    gst::PadPresence::Always,
+                    &src_caps,
+                )
+                .unwrap(),
+            ]
+        });
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        let ret = self.parent_change_state(transition)?;
+        if transition == gst::StateChange::PausedToReady {
+            *self.state.lock().unwrap() = State::default();
+        }
+        Ok(ret)
+    }
+}
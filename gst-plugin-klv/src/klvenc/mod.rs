@@ -0,0 +1,17 @@
+use gst::{glib, prelude::*};
+use gstreamer as gst;
+
+mod imp;
+
+glib::wrapper! {
+    pub struct KlvEnc(ObjectSubclass<imp::KlvEnc>) @extends gst::Element, gst::Object;
+}
+
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        "klvenc",
+        gst::Rank::None,
+        KlvEnc::static_type(),
+    )
+}
@@ -0,0 +1,184 @@
+//! Draws the KLV attached to every frame over it, the overlay of the receiver as an element.
+//!
+//! Bin around `overlaycomposition`, frames need `KlvMeta` from `klvsync` or from SEI. Frame
+//! and KLV time stamps, security banner and VMTI targets are drawn.
+
+use gst::{glib, prelude::*, subclass::prelude::*};
+use gstreamer as gst;
+use gstreamer_klv_test::overlay;
+use std::sync::{LazyLock, Mutex};
+
+static CAT: LazyLock<gst::DebugCategory> = LazyLock::new(|| {
+    gst::DebugCategory::new(
+        "klvoverlay",
+        gst::DebugColorFlags::empty(),
+        Some("KLV overlay"),
+    )
+});
+
+const DEFAULT_FONT: &str = "monospace 26";
+
+pub struct KlvOverlay {
+    sinkpad: gst::GhostPad,
+    srcpad: gst::GhostPad,
+    font: Mutex<String>,
+    /// Created when going to READY for the first time, with the font set by then.
+    overlay: Mutex<Option<gst::Element>>,
+}
+
+impl KlvOverlay {
+    fn build_overlay(&self) -> Result<(), gst::StateChangeError> {
+        let mut overlay = self.overlay.lock().unwrap();
+        if overlay.is_some() {
+            return Ok(());
+        }
+        let composition = gst::ElementFactory::make("overlaycomposition")
+            .build()
+            .map_err(|err| {
+                gst::element_imp_error!(
+                    self,
+                    gst::CoreError::MissingPlugin,
+                    ["No overlaycomposition: {}", err]
+                );
+                gst::StateChangeError
+            })?;
+        let font = self.font.lock().unwrap().clone();
+        gst::debug!(CAT, imp: self, "drawing with font {}", font);
+        overlay::connect_overlay(&composition, &font);
+
+        let obj = self.obj();
+        obj.add(&composition).map_err(|_| gst::StateChangeError)?;
+        let targets = self
+            .sinkpad
+            .set_target(composition.static_pad("sink").as_ref())
+            .and_then(|_| {
+                self.srcpad
+                    .set_target(composition.static_pad("src").as_ref())
+            });
+        if let Err(err) = targets {
+            gst::element_imp_error!(
+                self,
+                gst::CoreError::Negotiation,
+                ["Failed to link overlaycomposition: {}", err]
+            );
+            return Err(gst::StateChangeError);
+        }
+        *overlay = Some(composition);
+        Ok(())
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for KlvOverlay {
+    const NAME: &'static str = "GstKlvOverlay";
+    type Type = super::KlvOverlay;
+    type ParentType = gst::Bin;
+
+    fn with_class(klass: &Self::Class) -> Self {
+        let templ = klass.pad_template("sink").unwrap();
+        let sinkpad = gst::GhostPad::builder_from_template(&templ).build();
+        let templ = klass.pad_template("src").unwrap();
+        let srcpad = gst::GhostPad::builder_from_template(&templ).build();
+
+        KlvOverlay {
+            sinkpad,
+            srcpad,
+            font: Mutex::new(String::from(DEFAULT_FONT)),
+            overlay: Mutex::new(None),
+        }
+    }
+}
+
+impl ObjectImpl for KlvOverlay {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: LazyLock<Vec<glib::ParamSpec>> = LazyLock::new(|| {
+            vec![glib::ParamSpecString::builder("font")
+                .nick("Font")
+                .blurb("Pango font description, used from the first time the element is READY")
+                .default_value(Some(DEFAULT_FONT))
+                .build()]
+        });
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        match pspec.name() {
+            "font" => {
+                let font = value.get::<Option<String>>().expect("type checked");
+                *self.font.lock().unwrap() = font.unwrap_or_else(|| String::from(DEFAULT_FONT));
+                if self.overlay.lock().unwrap().is_some() {
+                    gst::warning!(CAT, imp: self, "font is changed only for the next instance");
+                }
+            }
+            name => unreachable!("unknown property {name}"),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        match pspec.name() {
+            "font" => self.font.lock().unwrap().to_value(),
+            name => unreachable!("unknown property {name}"),
+        }
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        let obj = self.obj();
+        obj.add_pad(&self.sinkpad).unwrap();
+        obj.add_pad(&self.srcpad).unwrap();
+    }
+}
+
+impl GstObjectImpl for KlvOverlay {}
+
+impl ElementImpl for KlvOverlay {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: LazyLock<gst::subclass::ElementMetadata> = LazyLock::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "KLV overlay",
+                "Filter/Editor/Video",
+                "Draws time stamps, security banner and targets from the KLV of every frame",
+                "Andres Vahter <andres@vahter.me>",
+            )
+        });
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: LazyLock<Vec<gst::PadTemplate>> = LazyLock::new(|| {
+            // Same as `overlaycomposition`, which draws on any memory the sink can handle.
+            let caps = gst::Caps::builder_full()
+                .structure_with_any_features(gst::Structure::new_empty("video/x-raw"))
+                .build();
+            vec![
+                gst::PadTemplate::new(
+                    "sink",
+                    gst::PadDirection::Sink,
+                    gst::PadPresence::Always,
+                    &caps,
+                )
+                .unwrap(),
+                gst::PadTemplate::new(
+                    "src",
+                    gst::PadDirection::Src,
+                    gst::PadPresence::Always,
+                    &caps,
+                )
+                .unwrap(),
+            ]
+        });
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        if transition == gst::StateChange::NullToReady {
+            self.build_overlay()?;
+        }
+        self.parent_change_state(transition)
+    }
+}
+
+impl BinImpl for KlvOverlay {}
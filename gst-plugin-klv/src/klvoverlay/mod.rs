@@ -0,0 +1,17 @@
+use gst::{glib, prelude::*};
+use gstreamer as gst;
+
+mod imp;
+
+glib::wrapper! {
+    pub struct KlvOverlay(ObjectSubclass<imp::KlvOverlay>) @extends gst::Bin, gst::Element, gst::Object, @implements gst::ChildProxy;
+}
+
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        "klvoverlay",
+        gst::Rank::None,
+        KlvOverlay::static_type(),
+    )
+}
@@ -0,0 +1,214 @@
+//! Validates KLV and frames it into one packet per buffer, `meta/x-klv, parsed=true`.
+//!
+//! Input can be split anywhere, e.g. PES payloads after `tsdemux`, and can be wrapped in
+//! ST 1402 metadata AU cells. Packets with bad framing or checksum are dropped and reported
+//! with the `klv-corrupted` element message.
+
+use gst::{glib, prelude::*, subclass::prelude::*};
+use gstreamer as gst;
+use gstreamer_klv_test::{
+    klv::{self, KlvStreamParser},
+    mpegts,
+};
+use std::sync::{LazyLock, Mutex};
+
+static CAT: LazyLock<gst::DebugCategory> = LazyLock::new(|| {
+    gst::DebugCategory::new(
+        "klvparse",
+        gst::DebugColorFlags::empty(),
+        Some("KLV parser"),
+    )
+});
+
+#[derive(Default)]
+struct State {
+    parser: KlvStreamParser,
+    /// Corrupted packets so far.
+    corrupted: u64,
+    /// Next pushed buffer continues after dropped data.
+    discont: bool,
+}
+
+pub struct KlvParse {
+    sinkpad: gst::Pad,
+    srcpad: gst::Pad,
+    state: Mutex<State>,
+}
+
+impl KlvParse {
+    fn sink_chain(
+        &self,
+        _pad: &gst::Pad,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let map = buffer.map_readable().map_err(|_| {
+            gst::element_imp_error!(self, gst::CoreError::Failed, ["Failed to map buffer"]);
+            gst::FlowError::Error
+        })?;
+        let mut state = self.state.lock().unwrap();
+        if buffer.flags().contains(gst::BufferFlags::DISCONT) {
+            if state.parser.pending_bytes() > 0 {
+                gst::warning!(
+                    CAT,
+                    imp: self,
+                    "drop {} bytes of incomplete KLV on discontinuity",
+                    state.parser.pending_bytes()
+                );
+                state.parser.reset();
+            }
+            state.discont = true;
+        }
+
+        let data = mpegts::unwrap_metadata_au(map.as_slice());
+        let mut packets = Vec::new();
+        let mut corrupted = Vec::new();
+        for (pts, packet) in state.parser.push_raw(&data, buffer.pts()) {
+            match packet {
+                // Bytes as received, the checksum covers their length form.
+                Ok((_, raw)) => {
+                    let mut packet_buffer = gst::Buffer::from_mut_slice(raw);
+                    {
+                        let packet_buffer = packet_buffer.get_mut().unwrap();
+                        packet_buffer.set_pts(pts);
+                        if state.discont {
+                            packet_buffer.set_flags(gst::BufferFlags::DISCONT);
+                            state.discont = false;
+                        }
+                    }
+                    packets.push(packet_buffer);
+                }
+                Err(err) => {
+                    state.corrupted += 1;
+                    state.discont = true;
+                    corrupted.push((pts, err, state.corrupted));
+                }
+            }
+        }
+        drop(state);
+
+        for (pts, err, count) in corrupted {
+            gst::warning!(CAT, imp: self, "corrupted KLV at {:?}: {}", pts, err);
+            klv::post_corrupted(&*self.obj(), pts, &err, count);
+        }
+        for packet in packets {
+            self.srcpad.push(packet)?;
+        }
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    fn sink_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        match event.view() {
+            EventView::Caps(_) => {
+                let caps = self.srcpad.pad_template_caps();
+                self.srcpad.push_event(gst::event::Caps::new(&caps))
+            }
+            EventView::FlushStop(_) => {
+                let mut state = self.state.lock().unwrap();
+                state.parser.reset();
+                state.discont = true;
+                drop(state);
+                self.srcpad.push_event(event)
+            }
+            _ => gst::Pad::event_default(pad, Some(&*self.obj()), event),
+        }
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for KlvParse {
+    const NAME: &'static str = "GstKlvParse";
+    type Type = super::KlvParse;
+    type ParentType = gst::Element;
+
+    fn with_class(klass: &Self::Class) -> Self {
+        let templ = klass.pad_template("sink").unwrap();
+        let sinkpad = gst::Pad::builder_from_template(&templ)
+            .chain_function(|pad, parent, buffer| {
+                KlvParse::catch_panic_pad_function(
+                    parent,
+                    || Err(gst::FlowError::Error),
+                    |parse| parse.sink_chain(pad, buffer),
+                )
+            })
+            .event_function(|pad, parent, event| {
+                KlvParse::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |parse| parse.sink_event(pad, event),
+                )
+            })
+            .build();
+        let templ = klass.pad_template("src").unwrap();
+        let srcpad = gst::Pad::builder_from_template(&templ).build();
+
+        KlvParse {
+            sinkpad,
+            srcpad,
+            state: Mutex::new(State::default()),
+        }
+    }
+}
+
+impl ObjectImpl for KlvParse {
+    fn constructed(&self) {
+        self.parent_constructed();
+        let obj = self.obj();
+        obj.add_pad(&self.sinkpad).unwrap();
+        obj.add_pad(&self.srcpad).unwrap();
+    }
+}
+
+impl GstObjectImpl for KlvParse {}
+
+impl ElementImpl for KlvParse {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: LazyLock<gst::subclass::ElementMetadata> = LazyLock::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "KLV parser",
+                "Codec/Parser/Metadata",
+                "Validates KLV and frames it into one packet per buffer",
+                "Andres Vahter <andres@vahter.me>",
+            )
+        });
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: LazyLock<Vec<gst::PadTemplate>> = LazyLock::new(|| {
+            let sink_caps = gst::Caps::new_empty_simple("meta/x-klv");
+            let src_caps = gst::Caps::builder("meta/x-klv")
+                .field("parsed", true)
+                .build();
+            vec![
+                gst::PadTemplate::new(
+                    "sink",
+                    gst::PadDirection::Sink,
+                    gst::PadPresence::Always,
+                    &sink_caps,
+                )
+                .unwrap(),
+                gst::PadTemplate::new(
+                    "src",
+                    gst::PadDirection::Src,
+                    gst::PadPresence::Always,
+                    &src_caps,
+                )
+                .unwrap(),
+            ]
+        });
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        let ret = self.parent_change_state(transition)?;
+        if transition == gst::StateChange::PausedToReady {
+            *self.state.lock().unwrap() = State::default();
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,17 @@
+use gst::{glib, prelude::*};
+use gstreamer as gst;
+
+mod imp;
+
+glib::wrapper! {
+    pub struct KlvParse(ObjectSubclass<imp::KlvParse>) @extends gst::Element, gst::Object;
+}
+
+pub fn register(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    gst::Element::register(
+        Some(plugin),
+        "klvparse",
+        gst::Rank::None,
+        KlvParse::static_type(),
+    )
+}
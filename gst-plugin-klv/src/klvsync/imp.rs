@@ -0,0 +1,437 @@
+//! Attaches KLV to the video frame with the same PTS as `KlvMeta`, the way the receiver does
+//! after its decoder.
+//!
+//! Video goes from `video` to `src` unchanged otherwise, KLV from `klv` only feeds the matcher.
+//! A frame waits until KLV has reached its PTS, by buffers or gaps, or KLV has ended, so both
+//! pads need their own streaming thread, e.g. a queue in front of each after the demuxer.
+//! KLV which doesn't come within `timeout`, e.g. a stream which has none but still links the
+//! `klv` pad, releases the frame without it, and the frames after it until KLV comes again.
+//! Frames without KLV are reported with the `klv-unmatched` element message, frames which
+//! already have KLV, e.g. from SEI, are left as they are.
+
+use gst::{glib, prelude::*, subclass::prelude::*};
+use gstreamer as gst;
+use gstreamer_klv_test::klv::{self, matcher, meta::KlvMeta, KlvMatcher};
+use std::{
+    sync::{Condvar, LazyLock, Mutex},
+    time::Duration,
+};
+
+static CAT: LazyLock<gst::DebugCategory> = LazyLock::new(|| {
+    gst::DebugCategory::new(
+        "klvsync",
+        gst::DebugColorFlags::empty(),
+        Some("KLV to video frame sync"),
+    )
+});
+
+/// Longest a frame waits for its KLV by default, long enough for a muxer's interleave.
+const DEFAULT_TIMEOUT: gst::ClockTime = gst::ClockTime::from_seconds(1);
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    tolerance: gst::ClockTime,
+    max_age: gst::ClockTime,
+    timeout: gst::ClockTime,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            tolerance: matcher::DEFAULT_TOLERANCE,
+            max_age: matcher::DEFAULT_MAX_AGE,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    matcher: KlvMatcher,
+    /// PTS up to which the KLV stream has arrived.
+    klv_position: Option<gst::ClockTime>,
+    klv_eos: bool,
+    /// A frame waited `timeout` in vain, frames don't wait until KLV comes again.
+    klv_stalled: bool,
+    /// Video is flushing or stopping, frames waiting for KLV give up.
+    flushing: bool,
+    /// Longest a frame waits for KLV, from the settings.
+    timeout: Duration,
+}
+
+impl State {
+    fn klv_reached(&mut self, pts: gst::ClockTime) {
+        self.klv_position = Some(self.klv_position.map_or(pts, |position| position.max(pts)));
+        self.klv_stalled = false;
+    }
+
+    /// Whether all KLV which can match the frame at `pts` has arrived or won't come anymore.
+    /// KLV comes in PTS order, so that is once it is within the tolerance of the frame.
+    fn klv_ready(&self, pts: gst::ClockTime) -> bool {
+        self.klv_eos
+            || self
+                .klv_position
+                .is_some_and(|position| position.saturating_add(self.matcher.tolerance()) >= pts)
+    }
+}
+
+pub struct KlvSync {
+    video_sinkpad: gst::Pad,
+    klv_sinkpad: gst::Pad,
+    srcpad: gst::Pad,
+    settings: Mutex<Settings>,
+    state: Mutex<State>,
+    /// Signaled when KLV arrives or ends and on flushing.
+    klv_cond: Condvar,
+}
+
+impl KlvSync {
+    fn video_chain(
+        &self,
+        _pad: &gst::Pad,
+        mut buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let Some(pts) = buffer.pts() else {
+            return self.srcpad.push(buffer);
+        };
+        if buffer.meta::<KlvMeta>().is_some() {
+            return self.srcpad.push(buffer);
+        }
+
+        let state = self.state.lock().unwrap();
+        let timeout = state.timeout;
+        let (mut state, wait) = self
+            .klv_cond
+            .wait_timeout_while(state, timeout, |state| {
+                !state.flushing && !state.klv_stalled && !state.klv_ready(pts)
+            })
+            .unwrap();
+        if state.flushing {
+            return Err(gst::FlowError::Flushing);
+        }
+        if wait.timed_out() {
+            gst::info!(CAT, imp: self, "KLV hasn't reached frame {} in {:?}", pts, timeout);
+            state.klv_stalled = true;
+        }
+        if let Some(klv_match) = state.matcher.find(pts) {
+            drop(state);
+            KlvMeta::add(buffer.make_mut(), klv_match);
+        } else {
+            let count = state.matcher.unmatched_frames();
+            drop(state);
+            gst::debug!(CAT, imp: self, "no KLV for frame {}", pts);
+            let s = gst::Structure::builder(klv::UNMATCHED_MESSAGE)
+                .field("pts", pts)
+                .field("count", count)
+                .build();
+            let obj = self.obj();
+            let _ = obj.post_message(gst::message::Element::builder(s).src(&*obj).build());
+        }
+        self.srcpad.push(buffer)
+    }
+
+    fn klv_chain(
+        &self,
+        _pad: &gst::Pad,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let Some(pts) = buffer.pts() else {
+            gst::warning!(CAT, imp: self, "dropping KLV without PTS");
+            return Ok(gst::FlowSuccess::Ok);
+        };
+        let map = buffer.map_readable().map_err(|_| {
+            gst::element_imp_error!(self, gst::CoreError::Failed, ["Failed to map buffer"]);
+            gst::FlowError::Error
+        })?;
+        let mut state = self.state.lock().unwrap();
+        // Framed by `klvparse` or a muxer, anything corrupted was reported there already.
+        for packet in klv::decode_buffer(map.as_slice()).into_iter().flatten() {
+            state.matcher.insert(pts, packet);
+        }
+        state.klv_reached(pts);
+        self.klv_cond.notify_all();
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    fn video_event(&self, _pad: &gst::Pad, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        match event.view() {
+            EventView::FlushStart(_) => {
+                self.state.lock().unwrap().flushing = true;
+                self.klv_cond.notify_all();
+            }
+            // Flushing seek can take PTS back, KLV of the old position must not match new frames.
+            EventView::FlushStop(_) => {
+                let mut state = self.state.lock().unwrap();
+                state.flushing = false;
+                state.matcher.clear();
+            }
+            _ => (),
+        }
+        self.srcpad.push_event(event)
+    }
+
+    /// KLV stream ends in the matcher, none of its events go further. They only tell how far
+    /// the stream has got.
+    fn klv_event(&self, _pad: &gst::Pad, event: gst::Event) -> bool {
+        use gst::EventView;
+
+        gst::log!(CAT, imp: self, "KLV event {:?}", event.type_());
+        let mut state = self.state.lock().unwrap();
+        match event.view() {
+            EventView::Eos(_) => state.klv_eos = true,
+            // Sparse KLV, e.g. from `tsdemux`, has gaps where there is none.
+            EventView::Gap(gap) => {
+                let (pts, duration) = gap.get();
+                state.klv_reached(pts.saturating_add(duration.unwrap_or(gst::ClockTime::ZERO)));
+            }
+            EventView::FlushStop(_) => {
+                state.matcher.clear();
+                state.klv_position = None;
+                state.klv_eos = false;
+                state.klv_stalled = false;
+            }
+            _ => return true,
+        }
+        self.klv_cond.notify_all();
+        true
+    }
+
+    fn klv_query(&self, pad: &gst::Pad, query: &mut gst::QueryRef) -> bool {
+        match query.view_mut() {
+            gst::QueryViewMut::Caps(q) => {
+                let caps = pad.pad_template_caps();
+                let caps = match q.filter() {
+                    Some(filter) => {
+                        filter.intersect_with_mode(&caps, gst::CapsIntersectMode::First)
+                    }
+                    None => caps,
+                };
+                q.set_result(&caps);
+                true
+            }
+            gst::QueryViewMut::AcceptCaps(q) => {
+                let accepted = q.caps().can_intersect(&pad.pad_template_caps());
+                q.set_result(accepted);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for KlvSync {
+    const NAME: &'static str = "GstKlvSync";
+    type Type = super::KlvSync;
+    type ParentType = gst::Element;
+
+    fn with_class(klass: &Self::Class) -> Self {
+        let templ = klass.pad_template("video").unwrap();
+        let video_sinkpad = gst::Pad::builder_from_template(&templ)
+            .chain_function(|pad, parent, buffer| {
+                KlvSync::catch_panic_pad_function(
+                    parent,
+                    || Err(gst::FlowError::Error),
+                    |sync| sync.video_chain(pad, buffer),
+                )
+            })
+            .event_function(|pad, parent, event| {
+                KlvSync::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |sync| sync.video_event(pad, event),
+                )
+            })
+            .query_function(|_pad, parent, query| {
+                KlvSync::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |sync| sync.srcpad.peer_query(query),
+                )
+            })
+            .build();
+
+        let templ = klass.pad_template("klv").unwrap();
+        let klv_sinkpad = gst::Pad::builder_from_template(&templ)
+            .chain_function(|pad, parent, buffer| {
+                KlvSync::catch_panic_pad_function(
+                    parent,
+                    || Err(gst::FlowError::Error),
+                    |sync| sync.klv_chain(pad, buffer),
+                )
+            })
+            .event_function(|pad, parent, event| {
+                KlvSync::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |sync| sync.klv_event(pad, event),
+                )
+            })
+            .query_function(|pad, parent, query| {
+                KlvSync::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |sync| sync.klv_query(pad, query),
+                )
+            })
+            .build();
+
+        // Video goes straight through, upstream events and queries only go to video.
+        let templ = klass.pad_template("src").unwrap();
+        let srcpad = gst::Pad::builder_from_template(&templ)
+            .event_function(|_pad, parent, event| {
+                KlvSync::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |sync| sync.video_sinkpad.push_event(event),
+                )
+            })
+            .query_function(|_pad, parent, query| {
+                KlvSync::catch_panic_pad_function(
+                    parent,
+                    || false,
+                    |sync| sync.video_sinkpad.peer_query(query),
+                )
+            })
+            .build();
+
+        KlvSync {
+            video_sinkpad,
+            klv_sinkpad,
+            srcpad,
+            settings: Mutex::new(Settings::default()),
+            state: Mutex::new(State::default()),
+            klv_cond: Condvar::new(),
+        }
+    }
+}
+
+impl ObjectImpl for KlvSync {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: LazyLock<Vec<glib::ParamSpec>> = LazyLock::new(|| {
+            vec![
+                glib::ParamSpecUInt64::builder("tolerance")
+                    .nick("Tolerance")
+                    .blurb("KLV within this many nanoseconds of the frame PTS belongs to it")
+                    .default_value(matcher::DEFAULT_TOLERANCE.nseconds())
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt64::builder("max-age")
+                    .nick("Maximum age")
+                    .blurb("KLV this many nanoseconds older than the newest frame is dropped")
+                    .default_value(matcher::DEFAULT_MAX_AGE.nseconds())
+                    .mutable_ready()
+                    .build(),
+                glib::ParamSpecUInt64::builder("timeout")
+                    .nick("Timeout")
+                    .blurb("Frame goes on without KLV after waiting this many nanoseconds for it")
+                    .default_value(DEFAULT_TIMEOUT.nseconds())
+                    .mutable_ready()
+                    .build(),
+            ]
+        });
+        PROPERTIES.as_ref()
+    }
+
+    fn set_property(&self, _id: usize, value: &glib::Value, pspec: &glib::ParamSpec) {
+        let mut settings = self.settings.lock().unwrap();
+        let nanos = gst::ClockTime::from_nseconds(value.get().expect("type checked"));
+        match pspec.name() {
+            "tolerance" => settings.tolerance = nanos,
+            "max-age" => settings.max_age = nanos,
+            "timeout" => settings.timeout = nanos,
+            name => unreachable!("unknown property {name}"),
+        }
+    }
+
+    fn property(&self, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        let settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "tolerance" => settings.tolerance.nseconds().to_value(),
+            "max-age" => settings.max_age.nseconds().to_value(),
+            "timeout" => settings.timeout.nseconds().to_value(),
+            name => unreachable!("unknown property {name}"),
+        }
+    }
+
+    fn constructed(&self) {
+        self.parent_constructed();
+        let obj = self.obj();
+        obj.add_pad(&self.video_sinkpad).unwrap();
+        obj.add_pad(&self.klv_sinkpad).unwrap();
+        obj.add_pad(&self.srcpad).unwrap();
+    }
+}
+
+impl GstObjectImpl for KlvSync {}
+
+impl ElementImpl for KlvSync {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: LazyLock<gst::subclass::ElementMetadata> = LazyLock::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "KLV sync",
+                "Filter/Metadata/Video",
+                "Attaches KLV to the video frame with the same PTS",
+                "Andres Vahter <andres@vahter.me>",
+            )
+        });
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: LazyLock<Vec<gst::PadTemplate>> = LazyLock::new(|| {
+            let klv_caps = gst::Caps::builder("meta/x-klv")
+                .field("parsed", true)
+                .build();
+            vec![
+                gst::PadTemplate::new(
+                    "video",
+                    gst::PadDirection::Sink,
+                    gst::PadPresence::Always,
+                    &gst::Caps::new_any(),
+                )
+                .unwrap(),
+                gst::PadTemplate::new(
+                    "klv",
+                    gst::PadDirection::Sink,
+                    gst::PadPresence::Always,
+                    &klv_caps,
+                )
+                .unwrap(),
+                gst::PadTemplate::new(
+                    "src",
+                    gst::PadDirection::Src,
+                    gst::PadPresence::Always,
+                    &gst::Caps::new_any(),
+                )
+                .unwrap(),
+            ]
+        });
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn change_state(
+        &self,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        match transition {
+            gst::StateChange::ReadyToPaused => {
+                let settings = *self.settings.lock().unwrap();
+                *self.state.lock().unwrap() = State {
+                    matcher: KlvMatcher::new(settings.tolerance, settings.max_age),
+                    timeout: Duration::from_nanos(settings.timeout.nseconds()),
+                    ..State::default()
+                };
+            }
+            // Pads can't be deactivated while a frame waits for KLV.
+            gst::StateChange::PausedToReady => {
+                self.state.lock().unwrap().flushing = true;
+                self.klv_cond.notify_all();
+            }
+            _ => (),
+        }
+        self.parent_change_state(transition)
+    }
+}
@@ -0,0 +1,36 @@
+//! `klv` GStreamer plugin, the KLV handling of `gstreamer-klv-test` as elements:
+//!
+//! * `klvenc` encodes ST 0601 local sets given as JSON into KLV,
+//! * `klvparse` validates KLV and frames it into one packet per buffer,
+//! * `klvsync` attaches KLV to the video frame with the same PTS,
+//! * `klvoverlay` draws the KLV attached to every frame over it.
+//!
+//! Build with `cargo build --release -p gst-plugin-klv` and point `GST_PLUGIN_PATH` to
+//! `target/release`.
+
+use gst::glib;
+use gstreamer as gst;
+
+mod klvenc;
+mod klvoverlay;
+mod klvparse;
+mod klvsync;
+
+fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
+    klvenc::register(plugin)?;
+    klvparse::register(plugin)?;
+    klvsync::register(plugin)?;
+    klvoverlay::register(plugin)?;
+    Ok(())
+}
+
+gst::plugin_define!(
+    klv,
+    env!("CARGO_PKG_DESCRIPTION"),
+    plugin_init,
+    env!("CARGO_PKG_VERSION"),
+    "unknown",
+    env!("CARGO_PKG_NAME"),
+    env!("CARGO_PKG_NAME"),
+    env!("CARGO_PKG_REPOSITORY")
+);
@@ -0,0 +1,162 @@
+//! Elements of the plugin driven by `gst_check::Harness`, skipped where GStreamer is missing.
+
+use gst::prelude::*;
+use gst_check::Harness;
+use gstreamer as gst;
+use gstreamer_check as gst_check;
+use gstreamer_klv_test::klv::{self, meta::KlvMeta, st0601, KlvPacket};
+use std::sync::Once;
+
+fn init() -> bool {
+    static REGISTER: Once = Once::new();
+    if let Err(err) = gst::init() {
+        eprintln!("skipped, GStreamer not available: {err}");
+        return false;
+    }
+    REGISTER.call_once(|| gstklv::plugin_register_static().expect("klv plugin registers"));
+    true
+}
+
+fn ms(ms: u64) -> gst::ClockTime {
+    gst::ClockTime::from_mseconds(ms)
+}
+
+fn buffer(data: Vec<u8>, pts: gst::ClockTime) -> gst::Buffer {
+    let mut buffer = gst::Buffer::from_mut_slice(data);
+    buffer.get_mut().unwrap().set_pts(pts);
+    buffer
+}
+
+fn heading_packet(heading: f64) -> KlvPacket {
+    st0601::UasDatalinkLocalSet {
+        precision_time_stamp: Some(1_224_807_209_913_000),
+        platform_heading: Some(heading),
+        ..Default::default()
+    }
+    .encode()
+}
+
+fn decode(buffer: &gst::Buffer) -> st0601::UasDatalinkLocalSet {
+    let map = buffer.map_readable().unwrap();
+    let packets = klv::decode_buffer(map.as_slice());
+    assert_eq!(packets.len(), 1, "one packet per buffer");
+    st0601::UasDatalinkLocalSet::decode(packets[0].as_ref().unwrap()).unwrap()
+}
+
+#[test]
+fn klvenc_klvparse_round_trip() {
+    if !init() {
+        return;
+    }
+    let mut h = Harness::new_parse("klvenc mission-id=TEST ! klvparse");
+    h.set_src_caps_str("application/json");
+
+    let lines = [
+        (
+            r#"{"platform_heading": 12.5, "precision_time_stamp": 1224807209913000}"#,
+            0,
+        ),
+        (r#"{"platform_heading": 90.0, "mission_id": "OWN"}"#, 40),
+    ];
+    for (line, pts) in lines {
+        h.push(buffer(format!("{line}\n").into_bytes(), ms(pts)))
+            .unwrap();
+    }
+
+    let first = h.pull().unwrap();
+    assert_eq!(first.pts(), Some(ms(0)));
+    let set = decode(&first);
+    assert_eq!(
+        set.platform_heading.map(|h| (h * 10.0).round()),
+        Some(125.0)
+    );
+    assert_eq!(set.precision_time_stamp, Some(1_224_807_209_913_000));
+    assert_eq!(set.mission_id.as_deref(), Some("TEST"));
+
+    let second = h.pull().unwrap();
+    assert_eq!(second.pts(), Some(ms(40)));
+    let set = decode(&second);
+    assert_eq!(set.platform_heading.map(f64::round), Some(90.0));
+    assert_eq!(set.mission_id.as_deref(), Some("OWN"));
+    assert!(set.precision_time_stamp.is_some());
+}
+
+/// `klvsync` with a harness on each sink pad and a bus for its messages.
+fn klvsync() -> Option<(Harness, Harness, gst::Bus)> {
+    if !init() {
+        return None;
+    }
+    let sync = gst::ElementFactory::make("klvsync")
+        // Frames without KLV don't hold the test up for long.
+        .property("timeout", ms(50).nseconds())
+        .build()
+        .unwrap();
+    let bus = gst::Bus::new();
+    sync.set_bus(Some(&bus));
+
+    let mut video = Harness::with_element(&sync, Some("video"), Some("src"));
+    video.set_src_caps_str("video/x-raw");
+    let mut klv = Harness::with_element(&sync, Some("klv"), None);
+    klv.set_src_caps_str("meta/x-klv, parsed=true");
+    video.play();
+    Some((video, klv, bus))
+}
+
+/// PTS and count of the next `klv-unmatched` message.
+fn unmatched(bus: &gst::Bus) -> Option<(gst::ClockTime, u64)> {
+    let msg = bus.pop_filtered(&[gst::MessageType::Element])?;
+    let s = msg.structure()?;
+    assert_eq!(s.name(), klv::UNMATCHED_MESSAGE);
+    Some((s.get("pts").unwrap(), s.get("count").unwrap()))
+}
+
+#[test]
+fn klvsync_attaches_klv_at_equal_pts() {
+    let Some((mut video, mut klv, bus)) = klvsync() else {
+        return;
+    };
+    let packet = heading_packet(12.5);
+    klv.push(buffer(packet.encode(), ms(40))).unwrap();
+    video.push(buffer(vec![0; 16], ms(40))).unwrap();
+
+    let frame = video.pull().unwrap();
+    let meta = frame.meta::<KlvMeta>().expect("KLV attached to the frame");
+    assert_eq!(meta.klv_pts(), ms(40));
+    assert_eq!(meta.packets(), [packet]);
+    assert_eq!(unmatched(&bus), None);
+}
+
+#[test]
+fn klvsync_posts_unmatched_and_times_out() {
+    let Some((mut video, mut klv, bus)) = klvsync() else {
+        return;
+    };
+    klv.push(buffer(heading_packet(12.5).encode(), ms(40)))
+        .unwrap();
+    video.push(buffer(vec![0; 16], ms(40))).unwrap();
+    assert!(video.pull().unwrap().meta::<KlvMeta>().is_some());
+
+    // KLV never reaches this frame, it goes on without it after the timeout.
+    video.push(buffer(vec![0; 16], ms(1000))).unwrap();
+    assert!(video.pull().unwrap().meta::<KlvMeta>().is_none());
+    assert_eq!(unmatched(&bus), Some((ms(1000), 1)));
+}
+
+#[test]
+fn klvsync_flush_clears_matcher() {
+    let Some((mut video, mut klv, bus)) = klvsync() else {
+        return;
+    };
+    klv.push(buffer(heading_packet(12.5).encode(), ms(2000)))
+        .unwrap();
+
+    // Flushing seek, KLV of the old position must not match frames after it.
+    assert!(video.push_event(gst::event::FlushStart::new()));
+    assert!(video.push_event(gst::event::FlushStop::new(true)));
+    let segment = gst::FormattedSegment::<gst::ClockTime>::new();
+    assert!(video.push_event(gst::event::Segment::new(&segment)));
+
+    video.push(buffer(vec![0; 16], ms(2000))).unwrap();
+    assert!(video.pull().unwrap().meta::<KlvMeta>().is_none());
+    assert_eq!(unmatched(&bus), Some((ms(2000), 1)));
+}
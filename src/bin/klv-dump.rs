@@ -0,0 +1,44 @@
+//! Lists every KLV packet of a recorded MPEG-TS file, no display needed.
+
+use clap::Parser;
+use gstreamer_klv_test::{dump, init_logger, klv::sei::SeiUuid};
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "List KLV packets of an MPEG-TS file")]
+struct Args {
+    /// MPEG-TS file, e.g. a recorded segment.
+    file: PathBuf,
+    #[arg(short, long, value_enum, default_value_t)]
+    format: dump::Format,
+    /// UUID of H.264 SEI messages with KLV, the one of this project by default.
+    #[arg(long, default_value_t)]
+    sei_uuid: SeiUuid,
+    /// Log level, logs go to stderr.
+    #[arg(long, default_value = "warn")]
+    log_level: log::LevelFilter,
+}
+
+fn main() {
+    let args = Args::parse();
+    init_logger(args.log_level);
+
+    let records = match dump::scan(&args.file, args.sei_uuid) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Error! {e}");
+            std::process::exit(1);
+        }
+    };
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    if let Err(e) = dump::write(&mut out, &records, args.format).and_then(|()| out.flush()) {
+        // Closed pipe, e.g. output went through `head`.
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("Error! {e}");
+            std::process::exit(1);
+        }
+    }
+}
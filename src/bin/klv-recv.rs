@@ -0,0 +1,34 @@
+//! `udpsrc` → `tsdemux` or `rtpbin` → decoder → overlay → video sink, KLV to `appsink`.
+
+use gstreamer_klv_test::{
+    builder::{KlvPipelineBuilder, Role},
+    config, init_logger, pipeline, run,
+};
+use log::*;
+
+fn main() {
+    let config = match config::PipelineConfig::from_args_for(Role::Receiver) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error! {e}");
+            std::process::exit(2);
+        }
+    };
+    init_logger(config.log_level);
+
+    info!("start");
+    // Video window needs the macOS run loop, the sender has no window.
+    let ok = run::run(move || {
+        let pipeline = KlvPipelineBuilder::new(config).role(Role::Receiver).build();
+        match pipeline.and_then(pipeline::main_loop) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Error! {e}");
+                false
+            }
+        }
+    });
+    if !ok {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,25 @@
+//! Camera → encoder → KLV → `mpegtsmux` or RTP payloaders → `udpsink`.
+
+use gstreamer_klv_test::{
+    builder::{KlvPipelineBuilder, Role},
+    config, init_logger, pipeline,
+};
+use log::*;
+
+fn main() {
+    let config = match config::PipelineConfig::from_args_for(Role::Sender) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error! {e}");
+            std::process::exit(2);
+        }
+    };
+    init_logger(config.log_level);
+
+    info!("start");
+    let pipeline = KlvPipelineBuilder::new(config).role(Role::Sender).build();
+    if let Err(e) = pipeline.and_then(pipeline::main_loop) {
+        eprintln!("Error! {e}");
+        std::process::exit(1);
+    }
+}
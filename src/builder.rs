@@ -0,0 +1,123 @@
+//! `KlvPipelineBuilder`, the way to embed the sender, the receiver or both in other programs.
+//!
+//! Everything not given to the builder comes from `PipelineConfig`, so the binaries build their
+//! pipelines with nothing but the config.
+
+use crate::{
+    check::SyncCheck,
+    config::{self, Transport},
+    klv::KlvPacket,
+    pipeline,
+    producer::KlvProducer,
+    receiver, rtp, sender,
+};
+use anyhow::Error;
+use gstreamer as gst;
+use std::sync::{Arc, Mutex};
+
+/// Called with PTS and KLV of every frame reaching the video sink, no packets if the frame
+/// has no KLV.
+pub type KlvConsumerFn = dyn Fn(gst::ClockTime, &[KlvPacket]) + Send + Sync;
+
+/// Which side of the stream the pipeline is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Role {
+    /// Sender and receiver in one pipeline, without network in between.
+    #[default]
+    Loopback,
+    /// Sends to `config.network`.
+    Sender,
+    /// Receives from `config.network` and shows the video.
+    Receiver,
+}
+
+/// Elements and callbacks used instead of the configured ones, any of them can be left out.
+#[derive(Clone, Default)]
+pub struct PipelineParts {
+    /// Gives raw video, replaces `config.source`.
+    pub source: Option<gst::Element>,
+    /// Takes raw video and gives H.264, replaces `x264enc`.
+    pub encoder: Option<gst::Element>,
+    /// Takes raw video with the overlay, replaces `config.sink`.
+    pub sink: Option<gst::Element>,
+    /// Replaces the producer of `config.klv_producer`.
+    pub klv_producer: Option<Arc<Mutex<dyn KlvProducer>>>,
+    /// Gets the KLV of every shown frame, in addition to the overlay.
+    pub klv_consumer: Option<Arc<KlvConsumerFn>>,
+}
+
+/// Builds a sending, receiving or loopback pipeline from a config and optional parts.
+pub struct KlvPipelineBuilder {
+    config: config::PipelineConfig,
+    role: Role,
+    parts: PipelineParts,
+    check: Option<Arc<SyncCheck>>,
+}
+
+impl KlvPipelineBuilder {
+    pub fn new(config: config::PipelineConfig) -> Self {
+        KlvPipelineBuilder {
+            config,
+            role: Role::default(),
+            parts: PipelineParts::default(),
+            check: None,
+        }
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Overrides `config.network.transport`, not used in loopback.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.config.network.transport = transport;
+        self
+    }
+
+    pub fn source(mut self, source: gst::Element) -> Self {
+        self.parts.source = Some(source);
+        self
+    }
+
+    pub fn encoder(mut self, encoder: gst::Element) -> Self {
+        self.parts.encoder = Some(encoder);
+        self
+    }
+
+    pub fn sink(mut self, sink: gst::Element) -> Self {
+        self.parts.sink = Some(sink);
+        self
+    }
+
+    /// Any `KlvProducer`, also a closure taking `&producer::Frame`.
+    pub fn klv_producer(mut self, producer: impl KlvProducer + 'static) -> Self {
+        self.parts.klv_producer = Some(Arc::new(Mutex::new(producer)));
+        self
+    }
+
+    pub fn klv_consumer(
+        mut self,
+        consumer: impl Fn(gst::ClockTime, &[KlvPacket]) + Send + Sync + 'static,
+    ) -> Self {
+        self.parts.klv_consumer = Some(Arc::new(consumer));
+        self
+    }
+
+    /// Records sent and received frames into `check`, only in loopback.
+    pub fn sync_check(mut self, check: Arc<SyncCheck>) -> Self {
+        self.check = Some(check);
+        self
+    }
+
+    pub fn build(self) -> Result<gst::Pipeline, Error> {
+        let (config, parts) = (&self.config, &self.parts);
+        match (self.role, config.network.transport) {
+            (Role::Loopback, _) => pipeline::loopback(config, parts, self.check),
+            (Role::Sender, Transport::Ts) => sender::udp_pipeline(config, parts),
+            (Role::Sender, Transport::Rtp) => rtp::sender_pipeline(config, parts),
+            (Role::Receiver, Transport::Ts) => receiver::udp_pipeline(config, parts),
+            (Role::Receiver, Transport::Rtp) => rtp::receiver_pipeline(config, parts),
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Headless end-to-end check that every frame after `tsdemux` carries the KLV generated for it.
+//!
+//! The sender side records KLV for every frame in the order frames are produced, the
+//! receiving side records the `KlvMeta` of every frame reaching the video sink. Frames are
+//! not reordered by the pipeline, so n-th received frame has to carry n-th sent KLV.
+//! If KLV has its own stream, the PMT after the muxer has to signal it as configured.
+
+use crate::{
+    config::{Framerate, KlvSignaling},
+    klv::meta::KlvMeta,
+    mpegts::Pmt,
+};
+use gstreamer as gst;
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Frame durations either side may fall behind before it counts as stalled. Tighter than the
+/// 233 ms encoder stall at 30 fps this check was made for, slow machines can set a longer gap.
+const MAX_GAP_FRAMES: u32 = 3;
+
+/// Longest allowed wall clock time between two frames on either side at `framerate`.
+pub fn default_max_frame_gap(framerate: Framerate) -> Duration {
+    let frame =
+        Duration::from_secs_f64(f64::from(framerate.denominator) / f64::from(framerate.numerator));
+    frame * MAX_GAP_FRAMES
+}
+
+/// PTS goes through 90 kHz MPEG-TS timestamps, so frame distances can be off by rounding.
+const PTS_TOLERANCE: gst::ClockTime = gst::ClockTime::from_mseconds(1);
+
+#[derive(Debug)]
+struct Frame {
+    pts: Option<gst::ClockTime>,
+    /// Encoded KLV packets, `None` if the frame had no KLV.
+    klv: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+struct Side {
+    frames: Vec<Frame>,
+    last_at: Option<Instant>,
+    max_gap: Duration,
+}
+
+impl Side {
+    fn push(&mut self, frame: Frame) {
+        let now = Instant::now();
+        if let Some(last_at) = self.last_at {
+            self.max_gap = self.max_gap.max(now - last_at);
+        }
+        self.last_at = Some(now);
+        self.frames.push(frame);
+    }
+}
+
+#[derive(Debug)]
+pub struct SyncCheck {
+    frames: u32,
+    max_frame_gap: Duration,
+    klv_signaling: Option<KlvSignaling>,
+    sent: Mutex<Side>,
+    received: Mutex<Side>,
+    pmt: Mutex<Option<Pmt>>,
+}
+
+impl SyncCheck {
+    /// Check which sends `frames` video frames at `framerate`.
+    pub fn new(frames: u32, framerate: Framerate) -> Self {
+        SyncCheck {
+            frames,
+            max_frame_gap: default_max_frame_gap(framerate),
+            klv_signaling: None,
+            sent: Mutex::default(),
+            received: Mutex::default(),
+            pmt: Mutex::default(),
+        }
+    }
+
+    /// Longest allowed time between two frames, derived from the framerate if not set.
+    pub fn max_frame_gap(mut self, max_frame_gap: Option<Duration>) -> Self {
+        if let Some(max_frame_gap) = max_frame_gap {
+            self.max_frame_gap = max_frame_gap;
+        }
+        self
+    }
+
+    /// Signaling the KLV stream must have in the PMT, not checked if not set.
+    pub fn klv_signaling(mut self, klv_signaling: Option<KlvSignaling>) -> Self {
+        self.klv_signaling = klv_signaling;
+        self
+    }
+
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// True if PMTs have to be given to `pmt`.
+    pub fn checks_pmt(&self) -> bool {
+        self.klv_signaling.is_some()
+    }
+
+    /// Records a PMT found in the muxed stream.
+    pub fn pmt(&self, pmt: Pmt) {
+        *self.pmt.lock().unwrap() = Some(pmt);
+    }
+
+    /// Records KLV sent along with a source frame.
+    pub fn sent(&self, pts: Option<gst::ClockTime>, klv: &[u8]) {
+        self.sent.lock().unwrap().push(Frame {
+            pts,
+            klv: Some(klv.to_vec()),
+        });
+    }
+
+    /// Records a frame which reached the video sink.
+    pub fn received(&self, buffer: &gst::BufferRef) {
+        let klv = buffer.meta::<KlvMeta>().map(|meta| {
+            let mut klv = Vec::new();
+            for packet in meta.packets() {
+                packet.encode_into(&mut klv);
+            }
+            klv
+        });
+        self.received.lock().unwrap().push(Frame {
+            pts: buffer.pts(),
+            klv,
+        });
+    }
+
+    pub fn report(&self) -> SyncReport {
+        let sent = self.sent.lock().unwrap();
+        let received = self.received.lock().unwrap();
+
+        let mut report = SyncReport {
+            frames_expected: self.frames as usize,
+            frames_sent: sent.frames.len(),
+            frames_received: received.frames.len(),
+            missing_klv: Vec::new(),
+            wrong_klv: Vec::new(),
+            wrong_pts: Vec::new(),
+            max_sent_gap: sent.max_gap,
+            max_received_gap: received.max_gap,
+            max_frame_gap: self.max_frame_gap,
+            klv_signaling: self.klv_signaling,
+            pmt: self.pmt.lock().unwrap().clone(),
+        };
+
+        // Demuxer is free to shift timestamps, but distance from the first frame must stay.
+        let first_sent = sent.frames.first().and_then(|f| f.pts);
+        let first_received = received.frames.first().and_then(|f| f.pts);
+        for (nr, (tx, rx)) in sent.frames.iter().zip(&received.frames).enumerate() {
+            match &rx.klv {
+                None => report.missing_klv.push(nr),
+                Some(klv) if Some(klv) != tx.klv.as_ref() => report.wrong_klv.push(nr),
+                Some(_) => (),
+            }
+            let tx_offset = tx
+                .pts
+                .zip(first_sent)
+                .and_then(|(pts, first)| pts.checked_sub(first));
+            let rx_offset = rx
+                .pts
+                .zip(first_received)
+                .and_then(|(pts, first)| pts.checked_sub(first));
+            let drift = tx_offset
+                .zip(rx_offset)
+                .map(|(tx, rx)| tx.nseconds().abs_diff(rx.nseconds()));
+            match drift {
+                Some(diff) if diff <= PTS_TOLERANCE.nseconds() => (),
+                _ => report.wrong_pts.push(nr),
+            }
+        }
+        report
+    }
+}
+
+/// Outcome of a `SyncCheck`, frames are identified by their sequence number.
+#[derive(Debug, Clone)]
+pub struct SyncReport {
+    pub frames_expected: usize,
+    pub frames_sent: usize,
+    pub frames_received: usize,
+    pub missing_klv: Vec<usize>,
+    pub wrong_klv: Vec<usize>,
+    pub wrong_pts: Vec<usize>,
+    pub max_sent_gap: Duration,
+    pub max_received_gap: Duration,
+    pub max_frame_gap: Duration,
+    /// Expected signaling of the KLV stream.
+    pub klv_signaling: Option<KlvSignaling>,
+    /// Last PMT received.
+    pub pmt: Option<Pmt>,
+}
+
+impl SyncReport {
+    /// Human readable list of everything that went wrong, empty if the check passed.
+    pub fn failures(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        if self.frames_sent != self.frames_expected {
+            failures.push(format!(
+                "sent {} frames instead of {}",
+                self.frames_sent, self.frames_expected
+            ));
+        }
+        if self.frames_received != self.frames_sent {
+            failures.push(format!(
+                "received {} frames but sent {}",
+                self.frames_received, self.frames_sent
+            ));
+        }
+        let frame_lists = [
+            ("without KLV", &self.missing_klv),
+            ("with KLV of some other frame", &self.wrong_klv),
+            ("with shifted PTS", &self.wrong_pts),
+        ];
+        for (what, frames) in frame_lists {
+            if !frames.is_empty() {
+                failures.push(format!("{} frames {what}: {frames:?}", frames.len()));
+            }
+        }
+        let gaps = [
+            ("sender", self.max_sent_gap),
+            ("receiver", self.max_received_gap),
+        ];
+        for (side, gap) in gaps {
+            if gap > self.max_frame_gap {
+                failures.push(format!(
+                    "{side} stalled for {gap:?}, allowed {:?}",
+                    self.max_frame_gap
+                ));
+            }
+        }
+        if let Some(expected) = self.klv_signaling {
+            match &self.pmt {
+                None => failures.push(String::from("no PMT received")),
+                Some(pmt) => match pmt.klv_stream() {
+                    Some((_, signaling)) if signaling == expected => (),
+                    Some((pid, signaling)) => failures.push(format!(
+                        "KLV on PID {pid} signaled as {signaling:?} instead of {expected:?}"
+                    )),
+                    None => {
+                        let types: Vec<String> = pmt
+                            .streams
+                            .iter()
+                            .map(|s| format!("PID {} type {:#04x}", s.pid, s.stream_type))
+                            .collect();
+                        failures.push(format!(
+                            "no stream signaled as KLV in PMT: {}",
+                            types.join(", ")
+                        ))
+                    }
+                },
+            }
+        }
+        failures
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.failures().is_empty()
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "frames: sent {}/{}, received {}",
+            self.frames_sent, self.frames_expected, self.frames_received
+        )?;
+        writeln!(
+            f,
+            "longest gap between frames: sender {:?}, receiver {:?}",
+            self.max_sent_gap, self.max_received_gap
+        )?;
+        if let Some((pid, signaling)) = self.pmt.as_ref().and_then(Pmt::klv_stream) {
+            writeln!(f, "KLV stream: PID {pid}, {signaling:?} signaling")?;
+        }
+        let failures = self.failures();
+        if failures.is_empty() {
+            write!(f, "OK, every frame has its own KLV")
+        } else {
+            write!(f, "FAILED")?;
+            for failure in failures {
+                write!(f, "\n  {failure}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framerate(numerator: i32, denominator: i32) -> Framerate {
+        Framerate {
+            numerator,
+            denominator,
+        }
+    }
+
+    #[test]
+    fn max_frame_gap_from_framerate() {
+        let gap = default_max_frame_gap(framerate(30, 1));
+        assert!(gap > Duration::from_millis(99) && gap < Duration::from_millis(101));
+        assert_eq!(
+            default_max_frame_gap(framerate(1, 2)),
+            Duration::from_secs(6)
+        );
+        assert_eq!(
+            default_max_frame_gap(framerate(25, 1)),
+            Duration::from_millis(120)
+        );
+    }
+
+    #[test]
+    fn max_frame_gap_override() {
+        let check = SyncCheck::new(10, framerate(30, 1));
+        assert_eq!(check.report().max_frame_gap, check.max_frame_gap);
+        let check = check.max_frame_gap(Some(Duration::from_secs(2)));
+        assert_eq!(check.max_frame_gap, Duration::from_secs(2));
+        let check = check.max_frame_gap(None);
+        assert_eq!(check.max_frame_gap, Duration::from_secs(2));
+    }
+
+    fn report(sent_gap: Duration, received_gap: Duration) -> SyncReport {
+        SyncReport {
+            frames_expected: 0,
+            frames_sent: 0,
+            frames_received: 0,
+            missing_klv: Vec::new(),
+            wrong_klv: Vec::new(),
+            wrong_pts: Vec::new(),
+            max_sent_gap: sent_gap,
+            max_received_gap: received_gap,
+            max_frame_gap: default_max_frame_gap(framerate(30, 1)),
+            klv_signaling: None,
+            pmt: None,
+        }
+    }
+
+    #[test]
+    fn stall() {
+        let frame = Duration::from_millis(33);
+        assert!(report(frame, frame * 3).is_ok());
+        // Encoder hiccup like in log.txt, 233 ms every 5th frame.
+        let failures = report(frame, Duration::from_millis(233)).failures();
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].starts_with("receiver stalled"), "{failures:?}");
+    }
+}
@@ -0,0 +1,1052 @@
+//! Command line and TOML configuration of the pipeline.
+//!
+//! Settings are taken from built-in defaults, then from the optional config file and then from
+//! the command line, every step overriding the previous one. The result is validated into
+//! `PipelineConfig` before any element is created.
+
+use crate::{
+    builder::Role,
+    klv::sei::SeiUuid,
+    media::{VideoSink, VideoSource},
+};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use derive_more::{Display, Error};
+use serde::Deserialize;
+use std::{
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+const DEFAULT_SEGMENT_DURATION: Duration = Duration::from_secs(60);
+const MEGABYTE: u64 = 1_000_000;
+
+/// Options only the sender uses, by their `Cli` field.
+const SENDER_OPTIONS: &[&str] = &[
+    "source",
+    "width",
+    "height",
+    "tune",
+    "speed_preset",
+    "bitrate",
+    "klv_profile",
+    "klv_signaling",
+    "klv_producer",
+    "klv_replay",
+    "telemetry",
+    "telemetry_offset",
+    "telemetry_interpolation",
+    "flight_route",
+    "flight_seed",
+    "ttl",
+];
+
+/// Options only the receiver uses.
+const RECEIVER_OPTIONS: &[&str] = &["sink", "sink_sync", "no_overlay", "font"];
+
+/// Options only the loopback binary has, it runs both sides in one pipeline.
+const LOOPBACK_OPTIONS: &[&str] = &["play", "sync_check", "max_frame_gap"];
+
+/// x264 `tune` flags, several can be combined with `+`.
+const X264_TUNES: &[&str] = &[
+    "film",
+    "animation",
+    "grain",
+    "stillimage",
+    "psnr",
+    "ssim",
+    "fastdecode",
+    "zerolatency",
+];
+
+const X264_SPEED_PRESETS: &[&str] = &[
+    "ultrafast",
+    "superfast",
+    "veryfast",
+    "faster",
+    "fast",
+    "medium",
+    "slow",
+    "slower",
+    "veryslow",
+    "placebo",
+];
+
+#[derive(Debug, Display, Error)]
+pub enum ConfigError {
+    #[display(fmt = "failed to read config {}: {source}", "path.display()")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[display(fmt = "failed to parse config {}: {source}", "path.display()")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[display(fmt = "invalid {field}: {reason}")]
+    Invalid { field: &'static str, reason: String },
+    #[display(fmt = "--{option} is not used by the {side}")]
+    Unused { option: String, side: &'static str },
+}
+
+impl ConfigError {
+    fn invalid(field: &'static str, reason: impl fmt::Display) -> Self {
+        ConfigError::Invalid {
+            field,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Which local sets are sent with every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KlvProfile {
+    /// ST 0601 only.
+    Minimal,
+    /// ST 0601 with ST 0102 security metadata.
+    Security,
+    /// ST 0601 with ST 0102 security metadata and ST 0903 moving targets.
+    #[default]
+    Full,
+}
+
+/// What gives the KLV sent with every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KlvProducerKind {
+    /// ST 0601 with heading turning a degree per frame.
+    #[default]
+    Counter,
+    /// Same ST 0601 with every frame, only the time stamp changes.
+    Static,
+    /// Simulated aircraft along the route of `flight`.
+    Flight,
+    /// KLV packets of `klv_replay`, one per frame.
+    Replay,
+    /// Records of the `telemetry` log at the PTS of every frame.
+    Telemetry,
+}
+
+/// How the telemetry producer gets a record between two logged ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TelemetryInterpolation {
+    /// Record nearest to the frame.
+    #[default]
+    Nearest,
+    /// Every value on a line between the records, angles the shorter way around.
+    Linear,
+    /// Linear, but attitude and sensor pointing by spherical interpolation of the rotation.
+    Slerp,
+}
+
+/// Route of the simulated flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlightRoute {
+    /// Circle around the center with the sensor pointed at it.
+    #[default]
+    Orbit,
+    /// From waypoint to waypoint and back to the first, sensor on the next waypoint.
+    Waypoints,
+    /// Seeded random walk within the radius from the center, sensor on the center.
+    Random,
+}
+
+/// How KLV travels along with the video.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KlvCarriage {
+    /// Own stream next to the video, matched to frames by PTS at the receiver.
+    #[default]
+    Stream,
+    /// H.264 SEI user data in the access unit of its frame, marked with a UUID both sides have
+    /// to agree on. Not a MISB carriage, other systems don't look for it.
+    Sei,
+}
+
+/// How the KLV stream is signaled in the MPEG-TS program map table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KlvSignaling {
+    /// Asynchronous KLV, stream type 0x06 with a `KLVA` registration descriptor.
+    #[default]
+    Async,
+    /// Synchronous KLV, stream type 0x15 with metadata descriptors (ISO 13818-1, ST 1402).
+    Sync,
+}
+
+/// How video and KLV travel between `klv-send` and `klv-recv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// MPEG-TS with KLV in its own stream, on `port`.
+    #[default]
+    Ts,
+    /// RTP H.264 and RFC 6597 KLV with RTCP, on `port` up to `port + 3`.
+    Rtp,
+}
+
+/// Frame rate as `numerator/denominator`, e.g. `30/1` or `30000/1001`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Framerate {
+    pub numerator: i32,
+    pub denominator: i32,
+}
+
+impl FromStr for Framerate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (num, den) = s.split_once('/').unwrap_or((s, "1"));
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<i32>()
+                .ok()
+                .filter(|v| *v > 0)
+                .ok_or_else(|| format!("\"{s}\" is not a positive fraction like 30/1"))
+        };
+        Ok(Framerate {
+            numerator: parse(num)?,
+            denominator: parse(den)?,
+        })
+    }
+}
+
+impl fmt::Display for Framerate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Video with KLV metadata through MPEG-TS")]
+pub struct Cli {
+    /// TOML config file, command line options override it.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// auto, avf, v4l2[:<device>], test or file:<path>.
+    #[arg(long, env = "KLV_VIDEO_SOURCE")]
+    pub source: Option<String>,
+    /// auto, osx, xv, fake or file:<path>.
+    #[arg(long, env = "KLV_VIDEO_SINK")]
+    pub sink: Option<String>,
+    /// Frame width in pixels.
+    #[arg(long)]
+    pub width: Option<u32>,
+    /// Frame height in pixels.
+    #[arg(long)]
+    pub height: Option<u32>,
+    /// Frame rate, e.g. 30/1.
+    #[arg(long)]
+    pub framerate: Option<String>,
+    /// x264 tune, e.g. zerolatency.
+    #[arg(long)]
+    pub tune: Option<String>,
+    /// x264 speed preset, e.g. ultrafast.
+    #[arg(long)]
+    pub speed_preset: Option<String>,
+    /// Encoder bitrate in kbit/s.
+    #[arg(long)]
+    pub bitrate: Option<u32>,
+    /// Let video sink synchronize frames to the clock.
+    #[arg(long)]
+    pub sink_sync: Option<bool>,
+    #[arg(long, value_enum)]
+    pub klv_profile: Option<KlvProfile>,
+    #[arg(long, value_enum)]
+    pub klv_carriage: Option<KlvCarriage>,
+    /// UUID of the H.264 SEI messages with KLV, e.g. 8d2f5a1e-3c4b-4f6a-9e21-7b0c58d346a1.
+    #[arg(long, value_name = "UUID")]
+    pub klv_sei_uuid: Option<String>,
+    /// What gives the KLV of every frame.
+    #[arg(long, value_enum)]
+    pub klv_producer: Option<KlvProducerKind>,
+    /// File of KLV packets for the replay producer.
+    #[arg(long, value_name = "FILE")]
+    pub klv_replay: Option<PathBuf>,
+    /// Telemetry log for the telemetry producer, CSV or JSON Lines.
+    #[arg(long, value_name = "FILE")]
+    pub telemetry: Option<PathBuf>,
+    /// Seconds into the telemetry log of PTS 0, negative if the video starts before it.
+    #[arg(long, value_name = "SECONDS", allow_negative_numbers = true)]
+    pub telemetry_offset: Option<f64>,
+    /// How telemetry between two records is interpolated.
+    #[arg(long, value_enum)]
+    pub telemetry_interpolation: Option<TelemetryInterpolation>,
+    /// Route of the flight producer.
+    #[arg(long, value_enum)]
+    pub flight_route: Option<FlightRoute>,
+    /// Seed of the random flight, the same seed gives the same flight.
+    #[arg(long)]
+    pub flight_seed: Option<u64>,
+    /// MPEG-TS signaling of the KLV stream.
+    #[arg(long, value_enum)]
+    pub klv_signaling: Option<KlvSignaling>,
+    /// Don't draw KLV over the video.
+    #[arg(long)]
+    pub no_overlay: bool,
+    /// Overlay font, e.g. "monospace 26".
+    #[arg(long)]
+    pub font: Option<String>,
+    /// off, error, warn, info, debug or trace. RUST_LOG takes precedence.
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Address klv-send sends to and klv-recv listens on, unicast or multicast.
+    #[arg(long, env = "KLV_HOST")]
+    pub host: Option<String>,
+    /// UDP port of the stream, RTP takes this and the next 3 ports.
+    #[arg(long, env = "KLV_PORT")]
+    pub port: Option<u16>,
+    #[arg(long, value_enum, env = "KLV_TRANSPORT")]
+    pub transport: Option<Transport>,
+    /// Network interface for multicast, e.g. eth0.
+    #[arg(long)]
+    pub multicast_iface: Option<String>,
+    /// Time to live of sent packets, hops a multicast stream can cross.
+    #[arg(long)]
+    pub ttl: Option<u8>,
+    /// Record muxed MPEG-TS into segment files in this directory.
+    #[arg(long, value_name = "DIR")]
+    pub record: Option<PathBuf>,
+    /// Start a new segment after this many seconds, 60 by default.
+    #[arg(long, value_name = "SECONDS", conflicts_with = "segment_megabytes")]
+    pub segment_seconds: Option<u64>,
+    /// Start a new segment after this many megabytes instead of after some time.
+    #[arg(long, value_name = "MB")]
+    pub segment_megabytes: Option<u64>,
+    /// Delete the oldest segments when all of them take more megabytes than this.
+    #[arg(long, value_name = "MB")]
+    pub max_record_megabytes: Option<u64>,
+    /// Play recorded MPEG-TS file instead of the camera, commands are read from stdin.
+    #[arg(long, value_name = "FILE", conflicts_with = "sync_check")]
+    pub play: Option<PathBuf>,
+    /// Run headless sync check with this many frames and exit.
+    #[arg(long, env = "KLV_SYNC_CHECK", value_name = "FRAMES")]
+    pub sync_check: Option<u32>,
+    /// Longest time between two frames before the sync check fails, 3 frames by default.
+    #[arg(long, value_name = "MS", requires = "sync_check")]
+    pub max_frame_gap: Option<u64>,
+}
+
+/// Config file layout, everything is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub log_level: Option<String>,
+    pub video: VideoSection,
+    pub encoder: EncoderSection,
+    pub klv: KlvSection,
+    pub flight: FlightSection,
+    pub telemetry: TelemetrySection,
+    pub overlay: OverlaySection,
+    pub network: NetworkSection,
+    pub record: RecordSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoSection {
+    pub source: Option<String>,
+    pub sink: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub framerate: Option<String>,
+    pub sink_sync: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncoderSection {
+    pub tune: Option<String>,
+    pub speed_preset: Option<String>,
+    pub bitrate: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KlvSection {
+    pub profile: Option<KlvProfile>,
+    pub carriage: Option<KlvCarriage>,
+    pub sei_uuid: Option<String>,
+    pub signaling: Option<KlvSignaling>,
+    pub producer: Option<KlvProducerKind>,
+    pub replay: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlightSection {
+    pub route: Option<FlightRoute>,
+    pub center: Option<(f64, f64)>,
+    pub radius: Option<f64>,
+    pub altitude: Option<f64>,
+    pub speed: Option<f64>,
+    pub waypoints: Option<Vec<(f64, f64, f64)>>,
+    pub seed: Option<u64>,
+    pub horizontal_fov: Option<f64>,
+    pub ground_elevation: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetrySection {
+    pub file: Option<PathBuf>,
+    pub offset: Option<f64>,
+    pub interpolation: Option<TelemetryInterpolation>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverlaySection {
+    pub enabled: Option<bool>,
+    pub font: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkSection {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub transport: Option<Transport>,
+    pub multicast_iface: Option<String>,
+    pub ttl: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordSection {
+    pub dir: Option<PathBuf>,
+    pub segment_seconds: Option<u64>,
+    pub segment_megabytes: Option<u64>,
+    pub max_megabytes: Option<u64>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncoderConfig {
+    pub tune: String,
+    pub speed_preset: Option<String>,
+    /// kbit/s, encoder default if not set.
+    pub bitrate: Option<u32>,
+}
+
+/// Simulated flight of the flight producer. Angles are in degrees, distances in meters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlightConfig {
+    pub route: FlightRoute,
+    /// Latitude and longitude of the orbit center and of the area the random walk stays in.
+    pub center: (f64, f64),
+    /// Orbit radius and how far the random walk goes from the center.
+    pub radius: f64,
+    /// Above ground, the random walk climbs and descends around it.
+    pub altitude: f64,
+    /// Meters per second.
+    pub speed: f64,
+    /// Latitude, longitude and altitude above ground of every waypoint.
+    pub waypoints: Vec<(f64, f64, f64)>,
+    pub seed: u64,
+    /// Vertical field of view follows from it and the frame size.
+    pub horizontal_fov: f64,
+    /// Height of the flat ground above the ellipsoid.
+    pub ground_elevation: f64,
+}
+
+impl FlightConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let (lat, lon) = self.center;
+        if !(-89.0..=89.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(ConfigError::invalid(
+                "flight center",
+                "is not a valid position",
+            ));
+        }
+        for (field, v) in [
+            ("flight radius", self.radius),
+            ("flight altitude", self.altitude),
+            ("flight speed", self.speed),
+        ] {
+            if !v.is_finite() || v <= 0.0 {
+                return Err(ConfigError::invalid(field, "has to be positive"));
+            }
+        }
+        if !(self.horizontal_fov > 0.0 && self.horizontal_fov < 180.0) {
+            return Err(ConfigError::invalid(
+                "flight horizontal fov",
+                "has to be between 0 and 180 degrees",
+            ));
+        }
+        if self.route == FlightRoute::Waypoints {
+            if self.waypoints.len() < 2 {
+                return Err(ConfigError::invalid(
+                    "flight waypoints",
+                    "waypoints route needs at least 2",
+                ));
+            }
+            if self
+                .waypoints
+                .iter()
+                .any(|&(_, _, altitude)| !altitude.is_finite() || altitude <= 0.0)
+            {
+                return Err(ConfigError::invalid(
+                    "flight waypoints",
+                    "altitude has to be positive",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Recorded telemetry of the telemetry producer.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TelemetryConfig {
+    /// CSV or JSON Lines log.
+    pub file: Option<PathBuf>,
+    /// Seconds from the first record of the log to PTS 0.
+    pub offset: f64,
+    pub interpolation: TelemetryInterpolation,
+}
+
+/// UDP transport between `klv-send` and `klv-recv`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkConfig {
+    pub host: String,
+    pub port: u16,
+    pub transport: Transport,
+    pub multicast_iface: Option<String>,
+    /// System default if not set.
+    pub ttl: Option<u8>,
+}
+
+impl NetworkConfig {
+    pub fn is_multicast(&self) -> bool {
+        self.host
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_multicast())
+    }
+}
+
+/// When a new recording segment is started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Duration(Duration),
+    /// Bytes.
+    Size(u64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordConfig {
+    pub dir: PathBuf,
+    pub segment: Segment,
+    /// Oldest segments are deleted to keep the directory under it, no limit if not set.
+    pub max_total_bytes: Option<u64>,
+}
+
+/// Validated settings the pipeline is built from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineConfig {
+    pub source: VideoSource,
+    pub sink: VideoSink,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: Framerate,
+    pub sink_sync: bool,
+    pub encoder: EncoderConfig,
+    pub klv_profile: KlvProfile,
+    pub klv_carriage: KlvCarriage,
+    /// Marks SEI messages with KLV if it is carried in SEI.
+    pub klv_sei_uuid: SeiUuid,
+    pub klv_signaling: KlvSignaling,
+    pub klv_producer: KlvProducerKind,
+    /// File the replay producer reads.
+    pub klv_replay: Option<PathBuf>,
+    pub flight: FlightConfig,
+    pub telemetry: TelemetryConfig,
+    pub overlay: bool,
+    pub font: String,
+    pub log_level: log::LevelFilter,
+    pub network: NetworkConfig,
+    /// Recording of muxed stream, nothing is recorded if not set.
+    pub record: Option<RecordConfig>,
+    /// File played instead of live video.
+    pub play: Option<PathBuf>,
+    /// Frames of the headless sync check, normal run if not set.
+    pub sync_check: Option<u32>,
+    /// Stall limit of the sync check, derived from the framerate if not set.
+    pub max_frame_gap: Option<Duration>,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            source: VideoSource::default(),
+            sink: VideoSink::default(),
+            width: 1920,
+            height: 1080,
+            framerate: Framerate {
+                numerator: 30,
+                denominator: 1,
+            },
+            sink_sync: false,
+            encoder: EncoderConfig {
+                tune: String::from("zerolatency"),
+                speed_preset: None,
+                bitrate: None,
+            },
+            klv_profile: KlvProfile::default(),
+            klv_carriage: KlvCarriage::default(),
+            klv_sei_uuid: SeiUuid::default(),
+            klv_signaling: KlvSignaling::default(),
+            klv_producer: KlvProducerKind::default(),
+            klv_replay: None,
+            flight: FlightConfig {
+                route: FlightRoute::default(),
+                center: (59.4370, 24.7536),
+                radius: 1500.0,
+                altitude: 1000.0,
+                speed: 40.0,
+                waypoints: Vec::new(),
+                seed: 1,
+                horizontal_fov: 30.0,
+                ground_elevation: 0.0,
+            },
+            telemetry: TelemetryConfig::default(),
+            overlay: true,
+            font: String::from("monospace 26"),
+            log_level: log::LevelFilter::Info,
+            network: NetworkConfig {
+                host: String::from("127.0.0.1"),
+                port: 5000,
+                transport: Transport::default(),
+                multicast_iface: None,
+                ttl: None,
+            },
+            record: None,
+            play: None,
+            sync_check: None,
+            max_frame_gap: None,
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Parses command line and the config file it points to.
+    pub fn from_args() -> Result<Self, ConfigError> {
+        Self::from_cli(Cli::parse())
+    }
+
+    /// Like `from_args` for the binary of one side of a network stream. Options which only
+    /// the other side or the loopback binary use are an error instead of being ignored.
+    pub fn from_args_for(role: Role) -> Result<Self, ConfigError> {
+        let matches = Cli::command().get_matches();
+        check_options(&matches, role)?;
+        Self::from_cli(Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+    }
+
+    fn from_cli(cli: Cli) -> Result<Self, ConfigError> {
+        let file = match &cli.config {
+            Some(path) => FileConfig::load(path)?,
+            None => FileConfig::default(),
+        };
+        Self::new(file, cli)
+    }
+
+    /// Merges defaults, config file and command line and validates the result.
+    pub fn new(file: FileConfig, cli: Cli) -> Result<Self, ConfigError> {
+        let default = PipelineConfig::default();
+        let source = match cli.source.or(file.video.source) {
+            Some(v) => v.parse().map_err(|e| ConfigError::invalid("source", e))?,
+            None => default.source,
+        };
+        let sink = match cli.sink.or(file.video.sink) {
+            Some(v) => v.parse().map_err(|e| ConfigError::invalid("sink", e))?,
+            None => default.sink,
+        };
+        let framerate = match cli.framerate.or(file.video.framerate) {
+            Some(v) => v
+                .parse()
+                .map_err(|e| ConfigError::invalid("framerate", e))?,
+            None => default.framerate,
+        };
+        let klv_sei_uuid = match cli.klv_sei_uuid.or(file.klv.sei_uuid) {
+            Some(v) => v
+                .parse()
+                .map_err(|e| ConfigError::invalid("KLV SEI UUID", e))?,
+            None => default.klv_sei_uuid,
+        };
+        let log_level = match cli.log_level.or(file.log_level) {
+            Some(v) => v
+                .parse()
+                .map_err(|e| ConfigError::invalid("log level", e))?,
+            None => default.log_level,
+        };
+
+        // Command line segment option replaces both of the file.
+        let (segment_seconds, segment_megabytes) =
+            if cli.segment_seconds.is_some() || cli.segment_megabytes.is_some() {
+                (cli.segment_seconds, cli.segment_megabytes)
+            } else {
+                (file.record.segment_seconds, file.record.segment_megabytes)
+            };
+        let segment = match (segment_seconds, segment_megabytes) {
+            (None, None) => Segment::Duration(DEFAULT_SEGMENT_DURATION),
+            (Some(secs), None) => Segment::Duration(Duration::from_secs(secs)),
+            (None, Some(mb)) => Segment::Size(mb.saturating_mul(MEGABYTE)),
+            (Some(_), Some(_)) => {
+                return Err(ConfigError::invalid(
+                    "segment",
+                    "give either seconds or megabytes, not both",
+                ))
+            }
+        };
+        let record = cli.record.or(file.record.dir).map(|dir| RecordConfig {
+            dir,
+            segment,
+            max_total_bytes: cli
+                .max_record_megabytes
+                .or(file.record.max_megabytes)
+                .map(|mb| mb.saturating_mul(MEGABYTE)),
+        });
+
+        let config = PipelineConfig {
+            source,
+            sink,
+            width: cli.width.or(file.video.width).unwrap_or(default.width),
+            height: cli.height.or(file.video.height).unwrap_or(default.height),
+            framerate,
+            sink_sync: cli
+                .sink_sync
+                .or(file.video.sink_sync)
+                .unwrap_or(default.sink_sync),
+            encoder: EncoderConfig {
+                tune: cli
+                    .tune
+                    .or(file.encoder.tune)
+                    .unwrap_or(default.encoder.tune),
+                speed_preset: cli.speed_preset.or(file.encoder.speed_preset),
+                bitrate: cli.bitrate.or(file.encoder.bitrate),
+            },
+            klv_profile: cli
+                .klv_profile
+                .or(file.klv.profile)
+                .unwrap_or(default.klv_profile),
+            klv_carriage: cli
+                .klv_carriage
+                .or(file.klv.carriage)
+                .unwrap_or(default.klv_carriage),
+            klv_sei_uuid,
+            klv_signaling: cli
+                .klv_signaling
+                .or(file.klv.signaling)
+                .unwrap_or(default.klv_signaling),
+            klv_producer: cli
+                .klv_producer
+                .or(file.klv.producer)
+                .unwrap_or(default.klv_producer),
+            klv_replay: cli.klv_replay.or(file.klv.replay),
+            flight: FlightConfig {
+                route: cli
+                    .flight_route
+                    .or(file.flight.route)
+                    .unwrap_or(default.flight.route),
+                center: file.flight.center.unwrap_or(default.flight.center),
+                radius: file.flight.radius.unwrap_or(default.flight.radius),
+                altitude: file.flight.altitude.unwrap_or(default.flight.altitude),
+                speed: file.flight.speed.unwrap_or(default.flight.speed),
+                waypoints: file.flight.waypoints.unwrap_or(default.flight.waypoints),
+                seed: cli
+                    .flight_seed
+                    .or(file.flight.seed)
+                    .unwrap_or(default.flight.seed),
+                horizontal_fov: file
+                    .flight
+                    .horizontal_fov
+                    .unwrap_or(default.flight.horizontal_fov),
+                ground_elevation: file
+                    .flight
+                    .ground_elevation
+                    .unwrap_or(default.flight.ground_elevation),
+            },
+            telemetry: TelemetryConfig {
+                file: cli.telemetry.or(file.telemetry.file),
+                offset: cli
+                    .telemetry_offset
+                    .or(file.telemetry.offset)
+                    .unwrap_or(default.telemetry.offset),
+                interpolation: cli
+                    .telemetry_interpolation
+                    .or(file.telemetry.interpolation)
+                    .unwrap_or(default.telemetry.interpolation),
+            },
+            overlay: !cli.no_overlay && file.overlay.enabled.unwrap_or(default.overlay),
+            font: cli.font.or(file.overlay.font).unwrap_or(default.font),
+            log_level,
+            network: NetworkConfig {
+                host: cli
+                    .host
+                    .or(file.network.host)
+                    .unwrap_or(default.network.host),
+                port: cli
+                    .port
+                    .or(file.network.port)
+                    .unwrap_or(default.network.port),
+                transport: cli
+                    .transport
+                    .or(file.network.transport)
+                    .unwrap_or(default.network.transport),
+                multicast_iface: cli.multicast_iface.or(file.network.multicast_iface),
+                ttl: cli.ttl.or(file.network.ttl),
+            },
+            record,
+            play: cli.play,
+            sync_check: cli.sync_check,
+            max_frame_gap: cli.max_frame_gap.map(Duration::from_millis),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        // x264 and most raw formats need even dimensions for chroma subsampling.
+        for (field, v) in [("width", self.width), ("height", self.height)] {
+            if v == 0 {
+                return Err(ConfigError::invalid(field, "has to be positive"));
+            }
+            if v % 2 == 1 {
+                return Err(ConfigError::invalid(field, format!("{v} is not even")));
+            }
+        }
+        for tune in self.encoder.tune.split('+') {
+            if !X264_TUNES.contains(&tune) {
+                let reason = format!("\"{tune}\" is not one of {}", X264_TUNES.join(", "));
+                return Err(ConfigError::invalid("tune", reason));
+            }
+        }
+        if let Some(preset) = &self.encoder.speed_preset {
+            if !X264_SPEED_PRESETS.contains(&preset.as_str()) {
+                let reason = format!(
+                    "\"{preset}\" is not one of {}",
+                    X264_SPEED_PRESETS.join(", ")
+                );
+                return Err(ConfigError::invalid("speed preset", reason));
+            }
+        }
+        if self.encoder.bitrate == Some(0) {
+            return Err(ConfigError::invalid("bitrate", "has to be positive"));
+        }
+        if self.klv_producer == KlvProducerKind::Replay && self.klv_replay.is_none() {
+            return Err(ConfigError::invalid(
+                "KLV replay",
+                "replay producer needs a file",
+            ));
+        }
+        if self.klv_producer == KlvProducerKind::Telemetry && self.telemetry.file.is_none() {
+            return Err(ConfigError::invalid(
+                "telemetry",
+                "telemetry producer needs a log file",
+            ));
+        }
+        if !self.telemetry.offset.is_finite() {
+            return Err(ConfigError::invalid("telemetry offset", "is not a number"));
+        }
+        self.flight.validate()?;
+        if self.font.trim().is_empty() {
+            return Err(ConfigError::invalid("font", "is empty"));
+        }
+        if self.network.host.trim().is_empty() {
+            return Err(ConfigError::invalid("host", "is empty"));
+        }
+        if self.network.port == 0 {
+            return Err(ConfigError::invalid("port", "has to be positive"));
+        }
+        if self.network.transport == Transport::Rtp && self.network.port > u16::MAX - 3 {
+            return Err(ConfigError::invalid("port", "RTP needs 4 ports from it"));
+        }
+        if let Some(record) = &self.record {
+            match record.segment {
+                Segment::Duration(duration) if duration.is_zero() => {
+                    return Err(ConfigError::invalid(
+                        "segment seconds",
+                        "has to be positive",
+                    ));
+                }
+                Segment::Size(0) => {
+                    return Err(ConfigError::invalid(
+                        "segment megabytes",
+                        "has to be positive",
+                    ));
+                }
+                _ => (),
+            }
+            if record.max_total_bytes == Some(0) {
+                return Err(ConfigError::invalid(
+                    "max record megabytes",
+                    "has to be positive",
+                ));
+            }
+        }
+        if self.sync_check == Some(0) {
+            return Err(ConfigError::invalid(
+                "sync check",
+                "needs at least one frame",
+            ));
+        }
+        if self.max_frame_gap.is_some_and(|gap| gap.is_zero()) {
+            return Err(ConfigError::invalid("max frame gap", "has to be positive"));
+        }
+        Ok(())
+    }
+}
+
+/// Fails on the first option given on the command line or in the environment which `role`
+/// doesn't use. Config file settings are not checked, one file can serve both sides.
+fn check_options(matches: &ArgMatches, role: Role) -> Result<(), ConfigError> {
+    let (side, unused): (_, &[&[&str]]) = match role {
+        Role::Loopback => return Ok(()),
+        Role::Sender => ("sender", &[RECEIVER_OPTIONS, LOOPBACK_OPTIONS]),
+        Role::Receiver => ("receiver", &[SENDER_OPTIONS, LOOPBACK_OPTIONS]),
+    };
+    for &id in unused.iter().copied().flatten() {
+        if matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            return Err(ConfigError::Unused {
+                option: id.replace('_', "-"),
+                side,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `Cli` without its `KLV_*` environment variables, so that the shell running the tests
+    /// can't change their outcome.
+    fn command() -> clap::Command {
+        Cli::command().mut_args(|arg| arg.env(None))
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        let matches = command().get_matches_from(["gstreamer-klv-test"].iter().chain(args));
+        Cli::from_arg_matches(&matches).unwrap()
+    }
+
+    fn config(args: &[&str]) -> Result<PipelineConfig, ConfigError> {
+        PipelineConfig::new(FileConfig::default(), cli(args))
+    }
+
+    fn error(args: &[&str]) -> String {
+        config(args).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = config(&["--source", "test", "--sink", "fake"]).unwrap();
+        assert_eq!(config.width, 1920);
+        assert_eq!(config.framerate.to_string(), "30/1");
+    }
+
+    #[test]
+    fn frame_size() {
+        assert_eq!(
+            error(&["--width", "0"]),
+            "invalid width: has to be positive"
+        );
+        assert_eq!(
+            error(&["--height", "0"]),
+            "invalid height: has to be positive"
+        );
+        assert_eq!(error(&["--width", "641"]), "invalid width: 641 is not even");
+    }
+
+    fn check(role: Role, args: &[&str]) -> Result<(), String> {
+        let args = ["klv"].iter().chain(args);
+        let matches = command().try_get_matches_from(args).unwrap();
+        check_options(&matches, role).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn options_of_other_side() {
+        let common = [
+            "--host",
+            "239.0.0.1",
+            "--port",
+            "5000",
+            "--framerate",
+            "25/1",
+        ];
+        assert_eq!(check(Role::Sender, &common), Ok(()));
+        assert_eq!(check(Role::Receiver, &common), Ok(()));
+        assert_eq!(check(Role::Sender, &["--source", "test"]), Ok(()));
+        assert_eq!(check(Role::Receiver, &["--no-overlay"]), Ok(()));
+        assert_eq!(
+            check(Role::Sender, &["--no-overlay"]),
+            Err(String::from("--no-overlay is not used by the sender"))
+        );
+        assert_eq!(
+            check(Role::Receiver, &["--klv-producer", "flight"]),
+            Err(String::from("--klv-producer is not used by the receiver"))
+        );
+        for role in [Role::Sender, Role::Receiver] {
+            assert_eq!(
+                check(role, &["--play", "a.ts"]).unwrap_err(),
+                format!(
+                    "--play is not used by the {}",
+                    format!("{role:?}").to_lowercase()
+                )
+            );
+        }
+        assert_eq!(
+            check(Role::Loopback, &["--play", "a.ts", "--ttl", "4"]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn option_lists_are_options() {
+        let command = Cli::command();
+        for id in [SENDER_OPTIONS, RECEIVER_OPTIONS, LOOPBACK_OPTIONS].concat() {
+            assert!(
+                command.get_arguments().any(|arg| arg.get_id() == id),
+                "{id}"
+            );
+        }
+    }
+
+    #[test]
+    fn sei_uuid() {
+        assert_eq!(config(&[]).unwrap().klv_sei_uuid, SeiUuid::PRIVATE);
+        let uuid = "00112233-4455-6677-8899-aabbccddeeff";
+        let config = config(&["--klv-sei-uuid", uuid]).unwrap();
+        assert_eq!(config.klv_sei_uuid.to_string(), uuid);
+        assert!(error(&["--klv-sei-uuid", "0011"]).starts_with("invalid KLV SEI UUID"));
+    }
+
+    #[test]
+    fn file_and_command_line() {
+        let file: FileConfig = toml::from_str("[video]\nwidth = 640\nheight = 480\n").unwrap();
+        let config = PipelineConfig::new(file, cli(&["--height", "360"])).unwrap();
+        assert_eq!((config.width, config.height), (640, 360));
+    }
+}
@@ -0,0 +1,460 @@
+//! Listing of every KLV packet in a recorded MPEG-TS file, for `klv-dump`.
+//!
+//! KLV is taken from metadata streams and from H.264 SEI, packets of all streams are listed
+//! together in PTS order. ST 0601 local sets are decoded into named fields with units, any
+//! other packet is listed with its key and length only.
+
+use crate::{
+    klv::{self, sei::SeiUuid, st0601, KlvPacket, KlvStreamParser},
+    mpegts,
+};
+use anyhow::{bail, Error};
+use clap::ValueEnum;
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use log::*;
+use serde::Serialize;
+use std::{
+    io::{self, Write},
+    mem,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Output format of `klv-dump`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Packet per line with its fields indented below it.
+    #[default]
+    Table,
+    /// JSON object per packet.
+    Jsonl,
+    /// Row per field, packets without fields take one row.
+    Csv,
+}
+
+/// One KLV packet of the file.
+#[derive(Debug, Clone, Serialize)]
+pub struct Record {
+    /// PID of the elementary stream, the video PID for KLV from SEI.
+    pub pid: Option<u16>,
+    /// Nanoseconds.
+    pub pts: Option<u64>,
+    /// Dotted hex, empty if the packet could not be framed.
+    pub key: String,
+    /// Length of the value in bytes.
+    pub length: usize,
+    pub fields: Vec<st0601::Field>,
+    pub error: Option<String>,
+}
+
+impl Record {
+    fn new(
+        pid: Option<u16>,
+        pts: Option<gst::ClockTime>,
+        packet: Result<KlvPacket, klv::KlvError>,
+    ) -> Self {
+        let mut record = Record {
+            pid,
+            pts: pts.map(gst::ClockTime::nseconds),
+            key: String::new(),
+            length: 0,
+            fields: Vec::new(),
+            error: None,
+        };
+        match packet {
+            Ok(packet) => {
+                record.key = packet.key.to_string();
+                record.length = packet.value.len();
+                if packet.key == st0601::UAS_DATALINK_LS_KEY {
+                    match st0601::UasDatalinkLocalSet::decode(&packet) {
+                        Ok(set) => record.fields = set.fields(),
+                        Err(err) => record.error = Some(err.to_string()),
+                    }
+                }
+            }
+            Err(err) => record.error = Some(err.to_string()),
+        }
+        record
+    }
+}
+
+/// Demuxes the whole file and returns its KLV packets sorted by PTS. KLV in H.264 SEI is taken
+/// from messages with `sei_uuid`.
+pub fn scan(path: &Path, sei_uuid: SeiUuid) -> Result<Vec<Record>, Error> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", path.to_string_lossy().as_ref())
+        .build()?;
+    let tsdemux = gst::ElementFactory::make("tsdemux").build()?;
+    pipeline.add_many(&[&filesrc, &tsdemux])?;
+    filesrc.link(&tsdemux)?;
+
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let pipeline_weak = pipeline.downgrade();
+    let pad_records = Arc::clone(&records);
+    tsdemux.connect_pad_added(move |_, src_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        if let Err(err) = add_stream(&pipeline, src_pad, &pad_records, sei_uuid) {
+            warn!("failed to dump stream {}: {err}", src_pad.name());
+        }
+    });
+
+    pipeline.set_state(gst::State::Playing)?;
+    let bus = pipeline
+        .bus()
+        .expect("Pipeline without bus. Shouldn't happen!");
+    let msg = bus.timed_pop_filtered(
+        gst::ClockTime::NONE,
+        &[gst::MessageType::Eos, gst::MessageType::Error],
+    );
+    pipeline.set_state(gst::State::Null)?;
+    if let Some(gst::MessageView::Error(err)) = msg.as_ref().map(|msg| msg.view()) {
+        bail!("failed to read {}: {}", path.display(), err.error());
+    }
+
+    let mut records = mem::take(&mut *records.lock().unwrap());
+    // Stable, packets with the same PTS stay in the order they were in the stream.
+    records.sort_by_key(|record: &Record| record.pts);
+    Ok(records)
+}
+
+/// Links a `tsdemux` pad to an `appsink` which collects its KLV, streams without KLV go to a
+/// `fakesink` so that they don't stop the demuxer.
+fn add_stream(
+    pipeline: &gst::Pipeline,
+    src_pad: &gst::Pad,
+    records: &Arc<Mutex<Vec<Record>>>,
+    sei_uuid: SeiUuid,
+) -> Result<(), Error> {
+    let name = src_pad.name();
+    // Pads are named `<type>_<program>_<pid>` with PID in hex.
+    let pid = name
+        .rsplit('_')
+        .next()
+        .and_then(|pid| u16::from_str_radix(pid, 16).ok());
+    let caps = src_pad
+        .current_caps()
+        .unwrap_or_else(|| src_pad.query_caps(None));
+    let structure = caps.structure(0).map(|s| s.name().to_string());
+    info!("dump stream {name}, pid {pid:?}, {caps}");
+
+    let queue = gst::ElementFactory::make("queue").build()?;
+    let appsink = gst_app::AppSink::builder().sync(false).build();
+    let mut elements = vec![queue];
+    let records = Arc::clone(records);
+    match structure.as_deref() {
+        Some("meta/x-klv") => {
+            let mut parser = KlvStreamParser::new();
+            appsink.set_callbacks(
+                gst_app::AppSinkCallbacks::builder()
+                    .new_sample(move |appsink| {
+                        let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                        if let Some(buffer) = sample.buffer() {
+                            let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                            let data = mpegts::unwrap_metadata_au(map.as_slice());
+                            let mut records = records.lock().unwrap();
+                            for (pts, packet) in parser.push(&data, buffer.pts()) {
+                                records.push(Record::new(pid, pts, packet));
+                            }
+                        }
+                        Ok(gst::FlowSuccess::Ok)
+                    })
+                    .build(),
+            );
+            elements.push(appsink.upcast());
+        }
+        Some("video/x-h264") => {
+            elements.push(gst::ElementFactory::make("h264parse").build()?);
+            elements.push(
+                gst::ElementFactory::make("capsfilter")
+                    .property(
+                        "caps",
+                        gst::Caps::builder("video/x-h264")
+                            .field("stream-format", "byte-stream")
+                            .field("alignment", "au")
+                            .build(),
+                    )
+                    .build()?,
+            );
+            appsink.set_callbacks(
+                gst_app::AppSinkCallbacks::builder()
+                    .new_sample(move |appsink| {
+                        let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                        if let Some(buffer) = sample.buffer() {
+                            let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                            let data = klv::sei::extract_klv(map.as_slice(), sei_uuid);
+                            let mut records = records.lock().unwrap();
+                            for packet in klv::decode_buffer(&data) {
+                                records.push(Record::new(pid, buffer.pts(), packet));
+                            }
+                        }
+                        Ok(gst::FlowSuccess::Ok)
+                    })
+                    .build(),
+            );
+            elements.push(appsink.upcast());
+        }
+        _ => {
+            let fakesink = gst::ElementFactory::make("fakesink")
+                .property("sync", false)
+                .build()?;
+            elements.push(fakesink);
+        }
+    }
+
+    let elements: Vec<&gst::Element> = elements.iter().collect();
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+    src_pad.link(&elements[0].static_pad("sink").unwrap())?;
+    for element in elements {
+        element.sync_state_with_parent()?;
+    }
+    Ok(())
+}
+
+/// Writes `records` to `out` in `format`.
+pub fn write(out: &mut impl Write, records: &[Record], format: Format) -> io::Result<()> {
+    match format {
+        Format::Table => write_table(out, records),
+        Format::Jsonl => {
+            for record in records {
+                serde_json::to_writer(&mut *out, record)?;
+                writeln!(out)?;
+            }
+            Ok(())
+        }
+        Format::Csv => write_csv(out, records),
+    }
+}
+
+fn write_table(out: &mut impl Write, records: &[Record]) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:>6}  {:>16}  {:<47}  {:>6}",
+        "PID", "PTS", "KEY", "LENGTH"
+    )?;
+    for record in records {
+        let pid = record
+            .pid
+            .map_or("-".to_string(), |pid| format!("0x{pid:04x}"));
+        let pts = record.pts.map_or("-".to_string(), |pts| {
+            gst::ClockTime::from_nseconds(pts).to_string()
+        });
+        writeln!(
+            out,
+            "{pid:>6}  {pts:>16}  {:<47}  {:>6}",
+            record.key, record.length
+        )?;
+        for field in &record.fields {
+            writeln!(
+                out,
+                "    {:>3}  {:<32}  {} {}",
+                field.tag, field.name, field.value, field.unit
+            )?;
+        }
+        if let Some(error) = &record.error {
+            writeln!(out, "    error: {error}")?;
+        }
+    }
+    Ok(())
+}
+
+fn write_csv(out: &mut impl Write, records: &[Record]) -> io::Result<()> {
+    writeln!(out, "pid,pts,key,length,tag,name,value,unit,error")?;
+    for record in records {
+        let pid = record.pid.map_or(String::new(), |pid| pid.to_string());
+        let pts = record.pts.map_or(String::new(), |pts| pts.to_string());
+        let error = csv_escape(record.error.as_deref().unwrap_or_default());
+        let packet = format!("{pid},{pts},{},{}", record.key, record.length);
+        if record.fields.is_empty() {
+            writeln!(out, "{packet},,,,,{error}")?;
+        }
+        for field in &record.fields {
+            writeln!(
+                out,
+                "{packet},{},{},{},{},{error}",
+                field.tag,
+                csv_escape(field.name),
+                csv_escape(&field.value),
+                field.unit
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Quotes `value` if it has characters with a meaning in CSV.
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "06.0E.2B.34.02.0B.01.01.0E.01.03.01.01.00.00.00";
+
+    /// ST 0601 packet with fields, one with CSV characters, and a packet which could not be
+    /// framed.
+    fn records() -> Vec<Record> {
+        vec![
+            Record {
+                pid: Some(0x44),
+                pts: Some(1_000_000_000),
+                key: KEY.into(),
+                length: 20,
+                fields: vec![
+                    st0601::Field {
+                        tag: 2,
+                        name: "Precision Time Stamp",
+                        value: "1224807209913000".into(),
+                        unit: "us",
+                    },
+                    st0601::Field {
+                        tag: 3,
+                        name: "Mission ID",
+                        value: "A,\"B\"".into(),
+                        unit: "",
+                    },
+                ],
+                error: None,
+            },
+            Record {
+                pid: None,
+                pts: None,
+                key: String::new(),
+                length: 0,
+                fields: Vec::new(),
+                error: Some("not enough data, need 3 more bytes".into()),
+            },
+        ]
+    }
+
+    fn output(format: Format) -> String {
+        let mut out = Vec::new();
+        write(&mut out, &records(), format).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn table() {
+        assert_eq!(
+            output(Format::Table).lines().collect::<Vec<_>>(),
+            [
+                "   PID               PTS  KEY                                              LENGTH",
+                "0x0044  0:00:01.000000000  06.0E.2B.34.02.0B.01.01.0E.01.03.01.01.00.00.00      20",
+                "      2  Precision Time Stamp              1224807209913000 us",
+                "      3  Mission ID                        A,\"B\" ",
+                "     -                 -                                                        0",
+                "    error: not enough data, need 3 more bytes",
+            ]
+        );
+    }
+
+    #[test]
+    fn jsonl() {
+        let output = output(Format::Jsonl);
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                serde_json::json!({
+                    "pid": 0x44,
+                    "pts": 1_000_000_000,
+                    "key": KEY,
+                    "length": 20,
+                    "fields": [
+                        {
+                            "tag": 2,
+                            "name": "Precision Time Stamp",
+                            "value": "1224807209913000",
+                            "unit": "us",
+                        },
+                        {"tag": 3, "name": "Mission ID", "value": "A,\"B\"", "unit": ""},
+                    ],
+                    "error": null,
+                }),
+                serde_json::json!({
+                    "pid": null,
+                    "pts": null,
+                    "key": "",
+                    "length": 0,
+                    "fields": [],
+                    "error": "not enough data, need 3 more bytes",
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(
+            output(Format::Csv).lines().collect::<Vec<_>>(),
+            [
+                "pid,pts,key,length,tag,name,value,unit,error",
+                &format!("68,1000000000,{KEY},20,2,Precision Time Stamp,1224807209913000,us,"),
+                &format!("68,1000000000,{KEY},20,3,Mission ID,\"A,\"\"B\"\"\",,"),
+                ",,,0,,,,,\"not enough data, need 3 more bytes\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_escapes() {
+        assert_eq!(csv_escape("plain text"), "plain text");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_escape("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_escape(""), "");
+    }
+
+    #[test]
+    fn records_of_packets() {
+        let set = st0601::UasDatalinkLocalSet {
+            precision_time_stamp: Some(1_224_807_209_913_000),
+            mission_id: Some("MISSION01".into()),
+            ..Default::default()
+        };
+        let packet = set.encode();
+        let pts = Some(gst::ClockTime::from_mseconds(40));
+        let record = Record::new(Some(0x44), pts, Ok(packet.clone()));
+        assert_eq!(record.key, KEY);
+        assert_eq!(
+            (record.pts, record.length),
+            (Some(40_000_000), packet.value.len())
+        );
+        assert_eq!(record.fields, set.fields());
+        assert_eq!(record.error, None);
+
+        // Other keys are only listed.
+        let other = KlvPacket::new(klv::st0102::SECURITY_LS_KEY, vec![1, 2, 3]);
+        let record = Record::new(None, None, Ok(other));
+        assert_eq!(record.length, 3);
+        assert!(record.fields.is_empty() && record.error.is_none());
+
+        let corrupt = KlvPacket::new(st0601::UAS_DATALINK_LS_KEY, vec![2, 8, 0]);
+        let record = Record::new(None, None, Ok(corrupt));
+        assert!(record.fields.is_empty());
+        assert!(record.error.is_some());
+
+        let error = klv::KlvError::Incomplete { needed: 3 };
+        let record = Record::new(None, None, Err(error));
+        assert_eq!((record.key.as_str(), record.length), ("", 0));
+        assert_eq!(
+            record.error.as_deref(),
+            Some("not enough data, need 3 more bytes")
+        );
+    }
+}
@@ -1,17 +1,120 @@
+use crate::mpegts;
 use anyhow::Result;
+use codec::Checksum;
 use gst::{element_error, prelude::*, Caps};
 use gstreamer as gst;
 use gstreamer_app as gst_app;
+use std::sync::{Arc, Mutex};
 
-pub fn klv_sink() -> Result<gst::Element> {
+mod codec;
+pub mod imapb;
+pub mod matcher;
+pub mod meta;
+pub mod parser;
+pub mod sei;
+pub mod st0102;
+pub mod st0601;
+pub mod st0903;
+
+pub use codec::{KlvError, KlvKey, KlvPacket};
+pub use matcher::KlvMatcher;
+pub use parser::KlvStreamParser;
+
+/// Name of the element message `klv_sink` posts on the bus for every corrupted KLV packet.
+/// It has `error` (string), `count` (u64, corrupted packets so far) and `pts` fields.
+pub const CORRUPTED_MESSAGE: &str = "klv-corrupted";
+
+/// Name of the element message posted for every video frame without matching KLV.
+/// It has `pts` and `count` (u64, unmatched frames so far) fields.
+pub const UNMATCHED_MESSAGE: &str = "klv-unmatched";
+
+/// Keys of local sets which end with a checksum item and how they compute it.
+const CHECKSUMMED_KEYS: [(KlvKey, Checksum); 2] = [
+    (st0601::UAS_DATALINK_LS_KEY, Checksum::RunningSum),
+    (st0903::VMTI_LS_KEY, Checksum::Crc16Ccitt),
+];
+
+/// Returns `packet` if it has no checksum or if the checksum over `raw` is valid.
+fn check_packet(packet: KlvPacket, raw: &[u8]) -> Result<KlvPacket, KlvError> {
+    if let Some((_, checksum)) = CHECKSUMMED_KEYS.iter().find(|(key, _)| *key == packet.key) {
+        codec::verify_checksum(raw, *checksum)?;
+    }
+    Ok(packet)
+}
+
+/// Decodes all KLV packets in a buffer.
+/// ST 0601 and standalone ST 0903 packets are only returned if their checksum is valid,
+/// so nobody has to trust corrupted bytes.
+/// Framing errors end decoding since the rest of the buffer is garbage.
+pub fn decode_buffer(mut data: &[u8]) -> Vec<Result<KlvPacket, KlvError>> {
+    let mut packets = Vec::new();
+    while !data.is_empty() {
+        match KlvPacket::decode(data) {
+            Ok((packet, used)) => {
+                packets.push(check_packet(packet, &data[..used]));
+                data = &data[used..];
+            }
+            Err(err) => {
+                packets.push(Err(err));
+                break;
+            }
+        }
+    }
+    packets
+}
+
+/// First valid ST 0601 local set among packets, if there is any.
+pub fn find_uas_local_set(packets: &[KlvPacket]) -> Option<st0601::UasDatalinkLocalSet> {
+    packets
+        .iter()
+        .filter(|packet| packet.key == st0601::UAS_DATALINK_LS_KEY)
+        .find_map(|packet| st0601::UasDatalinkLocalSet::decode(packet).ok())
+}
+
+/// First valid VMTI local set among packets, either standalone or nested in ST 0601.
+pub fn find_vmti_local_set(packets: &[KlvPacket]) -> Option<st0903::VmtiLocalSet> {
+    packets.iter().find_map(|packet| match packet.key {
+        st0903::VMTI_LS_KEY => st0903::VmtiLocalSet::decode(packet).ok(),
+        st0601::UAS_DATALINK_LS_KEY => st0601::UasDatalinkLocalSet::decode(packet)
+            .ok()
+            .and_then(|set| set.vmti),
+        _ => None,
+    })
+}
+
+/// Posts `CORRUPTED_MESSAGE` from `src`.
+pub fn post_corrupted(
+    src: &impl IsA<gst::Element>,
+    pts: Option<gst::ClockTime>,
+    err: &KlvError,
+    count: u64,
+) {
+    let mut s = gst::Structure::builder(CORRUPTED_MESSAGE)
+        .field("error", err.to_string())
+        .field("count", count)
+        .build();
+    if let Some(pts) = pts {
+        s.set("pts", pts);
+    }
+    let msg = gst::message::Element::builder(s).src(src).build();
+    if src.post_message(msg).is_err() {
+        log::warn!("failed to post {CORRUPTED_MESSAGE} message");
+    }
+}
+
+/// Appsink which parses received KLV and hands valid packets with PTS to `matcher`.
+pub fn klv_sink(matcher: Arc<Mutex<KlvMatcher>>) -> Result<gst::Element> {
     let appsink = gst_app::AppSink::builder()
         .caps(&Caps::builder("meta/x-klv").field("parsed", true).build())
         .build();
 
+    let mut corrupted = 0;
+    // PES packets after `tsdemux` don't have to line up with KLV packets.
+    let mut parser = KlvStreamParser::new();
     appsink.set_callbacks(
         gst_app::AppSinkCallbacks::builder()
             // Add a handler to the "new-sample" signal.
-            .new_sample(|appsink| {
+            .new_sample(move |appsink| {
                 // Pull the sample in question out of the appsink's buffer.
                 let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
                 let buffer = sample.buffer().ok_or_else(|| {
@@ -23,9 +126,46 @@ pub fn klv_sink() -> Result<gst::Element> {
                     gst::FlowError::Error
                 })?;
 
+                if buffer.flags().contains(gst::BufferFlags::DISCONT) && parser.pending_bytes() > 0
+                {
+                    log::warn!(
+                        "drop {} bytes of incomplete klv on discontinuity",
+                        parser.pending_bytes()
+                    );
+                    parser.reset();
+                }
+
                 if buffer.size() > 0 {
                     let mr = buffer.map_readable().unwrap();
-                    log::info!("receive klv {:?}", mr.as_slice());
+                    let data = mpegts::unwrap_metadata_au(mr.as_slice());
+                    for (pts, packet) in parser.push(&data, buffer.pts()) {
+                        if let (Some(pts), Ok(packet)) = (pts, &packet) {
+                            matcher.lock().unwrap().insert(pts, packet.clone());
+                        }
+                        let err = match packet {
+                            Ok(packet) if packet.key == st0601::UAS_DATALINK_LS_KEY => {
+                                match st0601::UasDatalinkLocalSet::decode(&packet) {
+                                    Ok(set) => {
+                                        log::info!("receive klv {pts:?} {set:?}");
+                                        continue;
+                                    }
+                                    Err(err) => err,
+                                }
+                            }
+                            Ok(packet) => {
+                                log::info!(
+                                    "receive klv {pts:?} key {} len {}",
+                                    packet.key,
+                                    packet.value.len()
+                                );
+                                continue;
+                            }
+                            Err(err) => err,
+                        };
+                        corrupted += 1;
+                        log::warn!("receive corrupted klv {pts:?}: {err}");
+                        post_corrupted(appsink, pts, &err, corrupted);
+                    }
                 }
                 Ok(gst::FlowSuccess::Ok)
             })
@@ -41,3 +181,56 @@ pub fn klv_test_src() -> Result<gst::Element> {
         .build();
     Ok(appsrc.upcast::<gst::Element>())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use st0601::tests::EXAMPLE_PACKET;
+
+    #[test]
+    fn valid_st0601_packet() {
+        let packets = decode_buffer(&EXAMPLE_PACKET);
+        assert_eq!(packets.len(), 1);
+        let packet = packets[0].as_ref().unwrap();
+        let set = find_uas_local_set(std::slice::from_ref(packet)).unwrap();
+        assert_eq!(set.mission_id.as_deref(), Some("MISSION01"));
+    }
+
+    #[test]
+    fn flipped_byte() {
+        // Every single byte of the value changes the running sum.
+        for i in KlvKey::LEN + 2..EXAMPLE_PACKET.len() - 4 {
+            let mut data = EXAMPLE_PACKET;
+            data[i] ^= 0x10;
+            assert!(
+                matches!(
+                    decode_buffer(&data)[..],
+                    [Err(KlvError::ChecksumMismatch { .. })]
+                ),
+                "byte {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn vmti_checksum() {
+        let vmti = st0903::VmtiLocalSet {
+            version: Some(st0903::LS_VERSION),
+            ..Default::default()
+        };
+        let mut data = vmti.encode().encode();
+        assert!(matches!(decode_buffer(&data)[..], [Ok(_)]));
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(matches!(
+            decode_buffer(&data)[..],
+            [Err(KlvError::ChecksumMismatch { .. })]
+        ));
+    }
+
+    #[test]
+    fn unchecked_key() {
+        let security = st0102::SecurityLocalSet::default().encode();
+        assert_eq!(decode_buffer(&security.encode()), [Ok(security)]);
+    }
+}
@@ -0,0 +1,594 @@
+//! SMPTE 336M Key-Length-Value coding.
+//!
+//! A KLV packet is a 16 byte Universal Label key, a BER encoded length and the value bytes.
+//! This module only deals with that outer framing, the meaning of the value is up to the
+//! local set implementations next to it.
+
+use derive_more::{Display, Error};
+use std::fmt;
+
+/// First four bytes shared by every SMPTE Universal Label.
+pub const UL_PREFIX: [u8; 4] = [0x06, 0x0E, 0x2B, 0x34];
+
+/// Longest BER long form length we accept, `usize` has to be able to hold it.
+const MAX_LENGTH_OCTETS: usize = 8;
+
+/// Longest BER-OID we accept, 10 septets are enough for any `u64`.
+const MAX_OID_OCTETS: usize = 10;
+
+#[derive(Debug, Display, Error, Clone, PartialEq, Eq)]
+pub enum KlvError {
+    #[display(fmt = "not enough data, need {needed} more bytes")]
+    Incomplete { needed: usize },
+    #[display(fmt = "indefinite BER length is not allowed in KLV")]
+    IndefiniteLength,
+    #[display(fmt = "BER length with {octets} octets is not supported")]
+    LengthTooLong { octets: usize },
+    #[display(fmt = "BER-OID longer than {MAX_OID_OCTETS} octets is not supported")]
+    OidTooLong,
+    #[display(fmt = "unexpected key {key}")]
+    UnexpectedKey { key: KlvKey },
+    #[display(fmt = "tag {tag} has invalid length {len}")]
+    InvalidLength { tag: u64, len: usize },
+    #[display(fmt = "expected {expected} bytes, got {len}")]
+    UnexpectedSize { expected: usize, len: usize },
+    #[display(fmt = "reserved ST 1201 special value")]
+    ReservedSpecialValue,
+    #[display(fmt = "tag {tag} has invalid value")]
+    InvalidValue { tag: u64 },
+    #[display(fmt = "mandatory tag {tag} is missing")]
+    MissingItem { tag: u64 },
+    #[display(fmt = "checksum is missing or not the last item")]
+    MissingChecksum,
+    #[display(
+        fmt = "checksum mismatch, packet has {expected:#06x} but data gives {computed:#06x}"
+    )]
+    ChecksumMismatch { expected: u16, computed: u16 },
+    #[display(fmt = "skipped {skipped} bytes without a known key")]
+    Garbage { skipped: usize },
+    #[display(fmt = "packet length {len} is over the limit of {max} bytes")]
+    PacketTooLong { len: usize, max: usize },
+}
+
+/// 16 byte SMPTE Universal Label used as KLV key.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KlvKey(pub [u8; 16]);
+
+impl KlvKey {
+    pub const LEN: usize = 16;
+
+    pub const fn new(bytes: [u8; 16]) -> Self {
+        KlvKey(bytes)
+    }
+
+    /// Reads key from the start of `data`.
+    pub fn from_slice(data: &[u8]) -> Result<Self, KlvError> {
+        if data.len() < Self::LEN {
+            return Err(KlvError::Incomplete {
+                needed: Self::LEN - data.len(),
+            });
+        }
+        let mut key = [0; Self::LEN];
+        key.copy_from_slice(&data[..Self::LEN]);
+        Ok(KlvKey(key))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// True if key starts with the SMPTE UL prefix `06 0E 2B 34`.
+    pub fn is_universal_label(&self) -> bool {
+        self.0.starts_with(&UL_PREFIX)
+    }
+}
+
+impl fmt::Display for KlvKey {
+    // Keys are usually written in dotted hex form, e.g. `06.0E.2B.34.02.0B.01.01...`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for KlvKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KlvKey({self})")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KlvPacket {
+    pub key: KlvKey,
+    pub value: Vec<u8>,
+}
+
+impl KlvPacket {
+    pub fn new(key: KlvKey, value: Vec<u8>) -> Self {
+        KlvPacket { key, value }
+    }
+
+    /// Size of the packet on the wire: key, BER length and value.
+    pub fn encoded_len(&self) -> usize {
+        KlvKey::LEN + ber_length_size(self.value.len()) + self.value.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.encoded_len());
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.key.0);
+        encode_ber_length(self.value.len(), out);
+        out.extend_from_slice(&self.value);
+    }
+
+    /// Decodes one packet from the start of `data`.
+    /// Returns the packet and the number of bytes it took, trailing data is left alone.
+    pub fn decode(data: &[u8]) -> Result<(Self, usize), KlvError> {
+        let key = KlvKey::from_slice(data)?;
+        let (len, len_size) = decode_ber_length(&data[KlvKey::LEN..])?;
+        let start = KlvKey::LEN + len_size;
+        let available = data.len() - start;
+        if available < len {
+            return Err(KlvError::Incomplete {
+                needed: len - available,
+            });
+        }
+        let value = data[start..start + len].to_vec();
+        Ok((KlvPacket { key, value }, start + len))
+    }
+
+    /// Decodes back to back packets which must fill `data` completely.
+    pub fn decode_all(mut data: &[u8]) -> Result<Vec<Self>, KlvError> {
+        let mut packets = Vec::new();
+        while !data.is_empty() {
+            let (packet, used) = Self::decode(data)?;
+            packets.push(packet);
+            data = &data[used..];
+        }
+        Ok(packets)
+    }
+}
+
+/// Number of bytes `encode_ber_length` uses for `len`.
+pub fn ber_length_size(len: usize) -> usize {
+    if len < 0x80 {
+        1
+    } else {
+        1 + (usize::BITS - len.leading_zeros()).div_ceil(8) as usize
+    }
+}
+
+/// Writes `len` in BER short form if it fits into 7 bits, otherwise in the shortest long form.
+pub fn encode_ber_length(len: usize, out: &mut Vec<u8>) {
+    if len < 0x80 {
+        out.push(len as u8);
+        return;
+    }
+    let octets = ber_length_size(len) - 1;
+    out.push(0x80 | octets as u8);
+    out.extend_from_slice(&len.to_be_bytes()[std::mem::size_of::<usize>() - octets..]);
+}
+
+/// Reads BER length from the start of `data`.
+/// Returns the length and the number of bytes the length field itself took.
+pub fn decode_ber_length(data: &[u8]) -> Result<(usize, usize), KlvError> {
+    let first = *data.first().ok_or(KlvError::Incomplete { needed: 1 })?;
+    if first & 0x80 == 0 {
+        return Ok((first as usize, 1));
+    }
+
+    let octets = (first & 0x7F) as usize;
+    if octets == 0 {
+        return Err(KlvError::IndefiniteLength);
+    }
+    if octets > MAX_LENGTH_OCTETS {
+        return Err(KlvError::LengthTooLong { octets });
+    }
+    if data.len() < 1 + octets {
+        return Err(KlvError::Incomplete {
+            needed: 1 + octets - data.len(),
+        });
+    }
+    let len = data[1..=octets]
+        .iter()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    Ok((len, 1 + octets))
+}
+
+/// Writes `value` as BER-OID: big endian groups of 7 bits, high bit set on all but the last byte.
+pub fn encode_ber_oid(value: u64, out: &mut Vec<u8>) {
+    let septets = ((u64::BITS - value.leading_zeros()).div_ceil(7)).max(1);
+    for i in (0..septets).rev() {
+        let septet = ((value >> (7 * i)) & 0x7F) as u8;
+        out.push(if i > 0 { septet | 0x80 } else { septet });
+    }
+}
+
+/// Reads BER-OID from the start of `data`.
+/// Returns the value and the number of bytes it took.
+pub fn decode_ber_oid(data: &[u8]) -> Result<(u64, usize), KlvError> {
+    let mut value = 0u64;
+    for (i, b) in data.iter().enumerate() {
+        if i == MAX_OID_OCTETS {
+            return Err(KlvError::OidTooLong);
+        }
+        value = (value << 7) | (b & 0x7F) as u64;
+        if b & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(KlvError::Incomplete { needed: 1 })
+}
+
+/// Splits local set value into tag and value pairs.
+/// Tags are BER-OID encoded and lengths BER encoded as in MISB ST 0601, ST 0102 and ST 0903.
+pub fn parse_local_set(mut data: &[u8]) -> Result<Vec<(u64, &[u8])>, KlvError> {
+    let mut items = Vec::new();
+    while !data.is_empty() {
+        let (tag, tag_size) = decode_ber_oid(data)?;
+        let (len, len_size) = decode_ber_length(&data[tag_size..])?;
+        let start = tag_size + len_size;
+        let available = data.len() - start;
+        if available < len {
+            return Err(KlvError::Incomplete {
+                needed: len - available,
+            });
+        }
+        items.push((tag, &data[start..start + len]));
+        data = &data[start + len..];
+    }
+    Ok(items)
+}
+
+/// Appends one local set item.
+pub fn write_local_set_item(tag: u64, value: &[u8], out: &mut Vec<u8>) {
+    encode_ber_oid(tag, out);
+    encode_ber_length(value.len(), out);
+    out.extend_from_slice(value);
+}
+
+/// Decodes single byte item.
+pub fn decode_u8(value: &[u8], tag: u64) -> Result<u8, KlvError> {
+    match value {
+        [v] => Ok(*v),
+        _ => Err(KlvError::InvalidLength {
+            tag,
+            len: value.len(),
+        }),
+    }
+}
+
+/// Decodes ISO 646 string item, anything outside of it is replaced instead of failing.
+pub fn decode_string(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).into_owned()
+}
+
+/// Checksum tag shared by MISB local sets which carry one, e.g. ST 0601 and ST 0903.
+pub const CHECKSUM_TAG: u64 = 1;
+
+/// Tag, length and the two checksum bytes which end every checksummed packet.
+const CHECKSUM_ITEM_LEN: usize = 4;
+
+/// How a local set computes its checksum item, the standards differ in that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// 16 bit running sum of MISB ST 0601.
+    RunningSum,
+    /// CRC-16-CCITT of MISB ST 0903.
+    Crc16Ccitt,
+}
+
+impl Checksum {
+    pub fn compute(self, data: &[u8]) -> u16 {
+        match self {
+            Checksum::RunningSum => running_sum16(data),
+            Checksum::Crc16Ccitt => crc16_ccitt(data),
+        }
+    }
+}
+
+/// Builds packet from local set items and appends the checksum item as the last one.
+/// Checksum covers everything from the key up to and including its own tag and length.
+pub fn encode_with_checksum(key: KlvKey, mut value: Vec<u8>, checksum: Checksum) -> KlvPacket {
+    value.extend_from_slice(&[CHECKSUM_TAG as u8, 2]);
+    let mut covered = key.0.to_vec();
+    // Length already has to include the two checksum bytes which are appended below.
+    encode_ber_length(value.len() + 2, &mut covered);
+    covered.extend_from_slice(&value);
+    value.extend_from_slice(&checksum.compute(&covered).to_be_bytes());
+    KlvPacket::new(key, value)
+}
+
+/// Verifies the checksum of one complete encoded packet, `raw` has to start with the key.
+///
+/// Checksum is calculated over the bytes as they were received, so this works even if
+/// the sender used a longer BER length form than we would.
+pub fn verify_checksum(raw: &[u8], checksum: Checksum) -> Result<(), KlvError> {
+    if raw.len() < KlvKey::LEN + 1 + CHECKSUM_ITEM_LEN {
+        return Err(KlvError::MissingChecksum);
+    }
+    match raw[raw.len() - CHECKSUM_ITEM_LEN..] {
+        [0x01, 0x02, hi, lo] => {
+            let expected = u16::from_be_bytes([hi, lo]);
+            let computed = checksum.compute(&raw[..raw.len() - 2]);
+            if expected == computed {
+                Ok(())
+            } else {
+                Err(KlvError::ChecksumMismatch { expected, computed })
+            }
+        }
+        _ => Err(KlvError::MissingChecksum),
+    }
+}
+
+/// Running sum of ST 0601, bytes at even offsets are added to the high byte and bytes at odd
+/// offsets to the low byte.
+pub fn running_sum16(data: &[u8]) -> u16 {
+    data.iter().enumerate().fold(0u16, |sum, (i, b)| {
+        let b = *b as u16;
+        sum.wrapping_add(if i % 2 == 0 { b << 8 } else { b })
+    })
+}
+
+/// CRC-16-CCITT (polynomial 0x1021, initial value 0xFFFF) used by the ST 0903 checksum.
+pub fn crc16_ccitt(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |mut crc, b| {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: KlvKey = KlvKey::new([
+        0x06, 0x0E, 0x2B, 0x34, 0x02, 0x0B, 0x01, 0x01, 0x0E, 0x01, 0x03, 0x01, 0x01, 0x00, 0x00,
+        0x00,
+    ]);
+
+    /// Precision time stamp, LS version 17 and the ST 0601 checksum item.
+    const ITEMS: [u8; 17] = [
+        0x02, 0x08, 0x00, 0x04, 0x59, 0xF4, 0xA6, 0xAA, 0x4A, 0xA8, 0x41, 0x01, 0x11, 0x01, 0x02,
+        0xAC, 0xEF,
+    ];
+
+    fn ber_length(len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_ber_length(len, &mut out);
+        out
+    }
+
+    #[test]
+    fn ber_length_round_trip() {
+        for (len, encoded) in [
+            (0, vec![0x00]),
+            (0x7F, vec![0x7F]),
+            (0x80, vec![0x81, 0x80]),
+            (0xFF, vec![0x81, 0xFF]),
+            (0x100, vec![0x82, 0x01, 0x00]),
+            (0x01_0000, vec![0x83, 0x01, 0x00, 0x00]),
+        ] {
+            assert_eq!(ber_length(len), encoded);
+            assert_eq!(ber_length_size(len), encoded.len());
+            assert_eq!(decode_ber_length(&encoded), Ok((len, encoded.len())));
+        }
+    }
+
+    #[test]
+    fn ber_length_long_form_for_short_value() {
+        assert_eq!(decode_ber_length(&[0x81, 0x10, 0xAA]), Ok((0x10, 2)));
+        assert_eq!(decode_ber_length(&[0x84, 0, 0, 0, 0x10]), Ok((0x10, 5)));
+    }
+
+    #[test]
+    fn ber_length_errors() {
+        assert_eq!(
+            decode_ber_length(&[]),
+            Err(KlvError::Incomplete { needed: 1 })
+        );
+        assert_eq!(
+            decode_ber_length(&[0x82, 0x01]),
+            Err(KlvError::Incomplete { needed: 1 })
+        );
+        assert_eq!(decode_ber_length(&[0x80]), Err(KlvError::IndefiniteLength));
+        assert_eq!(
+            decode_ber_length(&[0x89, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+            Err(KlvError::LengthTooLong { octets: 9 })
+        );
+    }
+
+    #[test]
+    fn ber_oid_round_trip() {
+        for (value, encoded) in [
+            (0, vec![0x00]),
+            (1, vec![0x01]),
+            (0x7F, vec![0x7F]),
+            (0x80, vec![0x81, 0x00]),
+            (144, vec![0x81, 0x10]),
+            (0x3FFF, vec![0xFF, 0x7F]),
+            (0x4000, vec![0x81, 0x80, 0x00]),
+            (
+                u64::MAX,
+                vec![0x81, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F],
+            ),
+        ] {
+            let mut out = Vec::new();
+            encode_ber_oid(value, &mut out);
+            assert_eq!(out, encoded);
+            assert_eq!(decode_ber_oid(&encoded), Ok((value, encoded.len())));
+        }
+    }
+
+    #[test]
+    fn ber_oid_errors() {
+        assert_eq!(
+            decode_ber_oid(&[0x81, 0x80]),
+            Err(KlvError::Incomplete { needed: 1 })
+        );
+        assert_eq!(decode_ber_oid(&[0x80; 11]), Err(KlvError::OidTooLong));
+    }
+
+    #[test]
+    fn local_set_round_trip() {
+        let long = vec![0x55; 200];
+        let mut value = Vec::new();
+        write_local_set_item(2, &[1, 2, 3], &mut value);
+        write_local_set_item(144, &long, &mut value);
+        write_local_set_item(0x4000, &[], &mut value);
+        assert_eq!(
+            parse_local_set(&value),
+            Ok(vec![
+                (2, &[1, 2, 3][..]),
+                (144, &long[..]),
+                (0x4000, &[][..])
+            ])
+        );
+    }
+
+    #[test]
+    fn local_set_truncated() {
+        assert_eq!(
+            parse_local_set(&[0x02, 0x08, 0x00, 0x01]),
+            Err(KlvError::Incomplete { needed: 6 })
+        );
+        assert_eq!(
+            parse_local_set(&[0x81]),
+            Err(KlvError::Incomplete { needed: 1 })
+        );
+        assert_eq!(
+            parse_local_set(&[0x02]),
+            Err(KlvError::Incomplete { needed: 1 })
+        );
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        for len in [0, 1, 0x7F, 0x80, 300] {
+            let packet = KlvPacket::new(KEY, vec![0xA5; len]);
+            let mut data = packet.encode();
+            assert_eq!(data.len(), packet.encoded_len());
+            data.extend_from_slice(&[0xEE; 3]);
+            assert_eq!(
+                KlvPacket::decode(&data),
+                Ok((packet.clone(), data.len() - 3))
+            );
+        }
+    }
+
+    #[test]
+    fn packet_truncated() {
+        let data = KlvPacket::new(KEY, vec![0xA5; 300]).encode();
+        for end in 0..data.len() {
+            assert!(
+                matches!(
+                    KlvPacket::decode(&data[..end]),
+                    Err(KlvError::Incomplete { .. })
+                ),
+                "{end} bytes"
+            );
+        }
+        assert!(KlvPacket::decode_all(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        // CRC-16/CCITT-FALSE check value.
+        assert_eq!(crc16_ccitt(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn running_sum() {
+        assert_eq!(running_sum16(&[]), 0);
+        assert_eq!(running_sum16(&[0x12, 0x34, 0x56]), 0x6834);
+        // Overflow wraps around.
+        assert_eq!(running_sum16(&[0xFF, 0xFF, 0x00, 0x01]), 0x0000);
+    }
+
+    #[test]
+    fn checksum_known_packet() {
+        let packet = encode_with_checksum(KEY, ITEMS[..13].to_vec(), Checksum::RunningSum);
+        assert_eq!(packet.value, ITEMS);
+        let raw = packet.encode();
+        assert_eq!(raw[KlvKey::LEN], 17);
+        assert_eq!(verify_checksum(&raw, Checksum::RunningSum), Ok(()));
+        assert_eq!(
+            verify_checksum(&raw, Checksum::Crc16Ccitt),
+            Err(KlvError::ChecksumMismatch {
+                expected: 0xACEF,
+                computed: 0x6D50
+            })
+        );
+    }
+
+    #[test]
+    fn crc16_packet() {
+        let packet = encode_with_checksum(KEY, ITEMS[..13].to_vec(), Checksum::Crc16Ccitt);
+        assert_eq!(packet.value[15..], [0x6D, 0x50]);
+        assert_eq!(
+            verify_checksum(&packet.encode(), Checksum::Crc16Ccitt),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn checksum_covers_received_length_form() {
+        // Same items with `81 11` as length, the checksum changes with it.
+        let mut raw = KEY.0.to_vec();
+        raw.extend_from_slice(&[0x81, 0x11]);
+        raw.extend_from_slice(&ITEMS[..15]);
+        raw.extend_from_slice(&[0x68, 0xB5]);
+        assert_eq!(verify_checksum(&raw, Checksum::RunningSum), Ok(()));
+
+        let (packet, used) = KlvPacket::decode(&raw).unwrap();
+        assert_eq!(used, raw.len());
+        // Encoding again uses the short form, which the checksum doesn't cover.
+        assert_eq!(
+            verify_checksum(&packet.encode(), Checksum::RunningSum),
+            Err(KlvError::ChecksumMismatch {
+                expected: 0x68B5,
+                computed: 0xACEF
+            })
+        );
+    }
+
+    #[test]
+    fn checksum_errors() {
+        let mut raw =
+            encode_with_checksum(KEY, ITEMS[..13].to_vec(), Checksum::RunningSum).encode();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        assert_eq!(
+            verify_checksum(&raw, Checksum::RunningSum),
+            Err(KlvError::ChecksumMismatch {
+                expected: 0xACEE,
+                computed: 0xACEF
+            })
+        );
+        assert_eq!(
+            verify_checksum(&raw[..KlvKey::LEN + 2], Checksum::RunningSum),
+            Err(KlvError::MissingChecksum)
+        );
+        let raw = KlvPacket::new(KEY, ITEMS[..13].to_vec()).encode();
+        assert_eq!(
+            verify_checksum(&raw, Checksum::RunningSum),
+            Err(KlvError::MissingChecksum)
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! MISB ST 1201 floating point to integer mapping, IMAPB variant.
+//!
+//! IMAPB maps `a..=b` to an unsigned integer of `len` bytes using a power of two scale so that
+//! the mapping is exact for values which fit the precision. The most significant bit of the
+//! integer is never used by normal values, it marks special values such as infinity and NaN.
+
+use super::codec::KlvError;
+
+/// Special value bit patterns, these are the five most significant bits of the first byte.
+const POSITIVE_INFINITY: u8 = 0b11001;
+const NEGATIVE_INFINITY: u8 = 0b11101;
+const POSITIVE_QUIET_NAN: u8 = 0b11010;
+const NEGATIVE_QUIET_NAN: u8 = 0b11110;
+const POSITIVE_SIGNALING_NAN: u8 = 0b11011;
+const NEGATIVE_SIGNALING_NAN: u8 = 0b11111;
+
+/// Forward and reverse IMAPB(a, b, L) mapping.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Imapb {
+    min: f64,
+    max: f64,
+    len: usize,
+    /// Forward scale `sF`, integer steps per unit.
+    scale: f64,
+    /// Reverse scale `sR`, units per integer step.
+    precision: f64,
+    /// Offset which makes zero exactly representable when the range spans zero.
+    z_offset: f64,
+}
+
+impl Imapb {
+    /// Mapping of `min..=max` to `len` bytes, `len` has to be between 1 and 8.
+    pub fn new(min: f64, max: f64, len: usize) -> Self {
+        assert!(min < max, "IMAPB range {min}..{max} is empty");
+        assert!((1..=8).contains(&len), "IMAPB length {len} not in 1..=8");
+
+        let b_pow = (max - min).log2().ceil() as i32;
+        let d_pow = 8 * len as i32 - 1;
+        let scale = 2f64.powi(d_pow - b_pow);
+        let precision = 2f64.powi(b_pow - d_pow);
+        let z_offset = if min < 0.0 && max > 0.0 {
+            scale * min - (scale * min).floor()
+        } else {
+            0.0
+        };
+
+        Imapb {
+            min,
+            max,
+            len,
+            scale,
+            precision,
+            z_offset,
+        }
+    }
+
+    /// Number of bytes in the encoded value.
+    pub fn byte_len(&self) -> usize {
+        self.len
+    }
+
+    /// Smallest difference between two encoded values.
+    pub fn precision(&self) -> f64 {
+        self.precision
+    }
+
+    /// Maps `value` to an integer.
+    /// NaN keeps its sign, infinities and values outside the range are sent as infinity.
+    pub fn encode_int(&self, value: f64) -> u64 {
+        let special = if value.is_nan() {
+            Some(if value.is_sign_negative() {
+                NEGATIVE_QUIET_NAN
+            } else {
+                POSITIVE_QUIET_NAN
+            })
+        } else if value > self.max {
+            Some(POSITIVE_INFINITY)
+        } else if value < self.min {
+            Some(NEGATIVE_INFINITY)
+        } else {
+            None
+        };
+        if let Some(bits) = special {
+            return (bits as u64) << (8 * self.len - 5);
+        }
+
+        let y = (self.scale * (value - self.min) + self.z_offset).floor() as u64;
+        // Ranges which are exact powers of two would put `max` on the special value bit.
+        y.min(self.max_normal())
+    }
+
+    pub fn encode(&self, value: f64) -> Vec<u8> {
+        self.encode_int(value).to_be_bytes()[8 - self.len..].to_vec()
+    }
+
+    /// Maps integer back to a value, special values are decoded to infinity or NaN.
+    pub fn decode_int(&self, y: u64) -> Result<f64, KlvError> {
+        if y > self.max_normal() {
+            let bits = (y >> (8 * self.len - 5)) as u8;
+            return match bits {
+                POSITIVE_INFINITY => Ok(f64::INFINITY),
+                NEGATIVE_INFINITY => Ok(f64::NEG_INFINITY),
+                POSITIVE_QUIET_NAN | POSITIVE_SIGNALING_NAN => Ok(f64::NAN),
+                NEGATIVE_QUIET_NAN | NEGATIVE_SIGNALING_NAN => Ok(-f64::NAN),
+                _ => Err(KlvError::ReservedSpecialValue),
+            };
+        }
+        Ok(self.precision * (y as f64 - self.z_offset) + self.min)
+    }
+
+    pub fn decode(&self, data: &[u8]) -> Result<f64, KlvError> {
+        if data.len() != self.len {
+            return Err(KlvError::UnexpectedSize {
+                expected: self.len,
+                len: data.len(),
+            });
+        }
+        self.decode_int(data.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    fn max_normal(&self) -> u64 {
+        (1 << (8 * self.len - 1)) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ST 0601 Target Width Extended, tag 96.
+    fn target_width() -> Imapb {
+        Imapb::new(0.0, 1_500_000.0, 3)
+    }
+
+    /// ST 0601 Density Altitude Extended, tag 103.
+    fn density_altitude() -> Imapb {
+        Imapb::new(-900.0, 40_000.0, 3)
+    }
+
+    #[test]
+    fn forward() {
+        assert_eq!(target_width().encode(13898.5463), [0x00, 0xD9, 0x2A]);
+        assert_eq!(density_altitude().encode(23456.24), [0x2F, 0x92, 0x1E]);
+        assert_eq!(density_altitude().encode(-900.0), [0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn reverse() {
+        assert_eq!(target_width().decode(&[0x00, 0xD9, 0x2A]), Ok(13898.5));
+        assert_eq!(
+            density_altitude().decode(&[0x2F, 0x92, 0x1E]),
+            Ok(23456.234375)
+        );
+        assert_eq!(target_width().precision(), 0.25);
+        assert_eq!(density_altitude().precision(), 1.0 / 128.0);
+    }
+
+    #[test]
+    fn round_trip_within_precision() {
+        let imapb = Imapb::new(-10.0, 10.0, 2);
+        for value in [-10.0, -3.3, 0.0, 0.001, 7.25, 10.0] {
+            let decoded = imapb.decode(&imapb.encode(value)).unwrap();
+            assert!((decoded - value).abs() <= imapb.precision(), "{value}");
+        }
+    }
+
+    #[test]
+    fn zero_is_exact_with_offset() {
+        let imapb = Imapb::new(-0.1, 1.0, 2);
+        let decoded = imapb.decode(&imapb.encode(0.0)).unwrap();
+        assert!(decoded.abs() < 1e-12, "{decoded}");
+    }
+
+    #[test]
+    fn max_of_power_of_two_range() {
+        let imapb = Imapb::new(0.0, 256.0, 2);
+        assert_eq!(imapb.encode(256.0), [0x7F, 0xFF]);
+    }
+
+    #[test]
+    fn special_values() {
+        let imapb = density_altitude();
+        assert_eq!(imapb.encode(f64::INFINITY), [0xC8, 0x00, 0x00]);
+        assert_eq!(imapb.encode(f64::NEG_INFINITY), [0xE8, 0x00, 0x00]);
+        assert_eq!(imapb.encode(f64::NAN), [0xD0, 0x00, 0x00]);
+        assert_eq!(imapb.encode(-f64::NAN), [0xF0, 0x00, 0x00]);
+
+        assert_eq!(imapb.decode(&[0xC8, 0x00, 0x00]), Ok(f64::INFINITY));
+        assert_eq!(imapb.decode(&[0xE8, 0x00, 0x00]), Ok(f64::NEG_INFINITY));
+        for nan in [[0xD0, 0, 0], [0xD8, 0, 0]] {
+            let value = imapb.decode(&nan).unwrap();
+            assert!(value.is_nan() && value.is_sign_positive());
+        }
+        for nan in [[0xF0, 0, 0], [0xF8, 0, 0]] {
+            let value = imapb.decode(&nan).unwrap();
+            assert!(value.is_nan() && value.is_sign_negative());
+        }
+    }
+
+    #[test]
+    fn out_of_range() {
+        let imapb = density_altitude();
+        assert_eq!(imapb.encode(40_000.1), [0xC8, 0x00, 0x00]);
+        assert_eq!(imapb.encode(-900.1), [0xE8, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn reserved_and_wrong_size() {
+        let imapb = density_altitude();
+        assert_eq!(
+            imapb.decode(&[0x80, 0x00, 0x00]),
+            Err(KlvError::ReservedSpecialValue)
+        );
+        assert_eq!(
+            imapb.decode(&[0x00, 0x00]),
+            Err(KlvError::UnexpectedSize {
+                expected: 3,
+                len: 2
+            })
+        );
+    }
+}
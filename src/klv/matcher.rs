@@ -0,0 +1,209 @@
+//! Matches received KLV to video frames by PTS.
+//!
+//! KLV and video travel through different branches after `tsdemux`, so the KLV which
+//! arrived last is not necessarily the one for the frame being drawn. Packets are kept in
+//! PTS order until the frame with the same PTS shows up or until they get too old.
+
+use super::KlvPacket;
+use gstreamer as gst;
+use std::collections::BTreeMap;
+
+/// PTS goes through 90 kHz MPEG-TS timestamps, so exact match can be off by rounding.
+pub const DEFAULT_TOLERANCE: gst::ClockTime = gst::ClockTime::from_mseconds(1);
+
+/// Packets this much older than the newest frame or packet are dropped.
+pub const DEFAULT_MAX_AGE: gst::ClockTime = gst::ClockTime::from_seconds(2);
+
+/// KLV packets matched to a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KlvMatch {
+    /// PTS of the KLV, equal to frame PTS unless matched within tolerance.
+    pub pts: gst::ClockTime,
+    pub packets: Vec<KlvPacket>,
+}
+
+#[derive(Debug)]
+pub struct KlvMatcher {
+    packets: BTreeMap<gst::ClockTime, Vec<KlvPacket>>,
+    tolerance: gst::ClockTime,
+    max_age: gst::ClockTime,
+    /// Newest PTS seen on either stream, everything older than `max_age` from it is expired.
+    newest: Option<gst::ClockTime>,
+    matched: u64,
+    unmatched: u64,
+}
+
+impl Default for KlvMatcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_TOLERANCE, DEFAULT_MAX_AGE)
+    }
+}
+
+impl KlvMatcher {
+    pub fn new(tolerance: gst::ClockTime, max_age: gst::ClockTime) -> Self {
+        KlvMatcher {
+            packets: BTreeMap::new(),
+            tolerance,
+            max_age,
+            newest: None,
+            matched: 0,
+            unmatched: 0,
+        }
+    }
+
+    pub fn tolerance(&self) -> gst::ClockTime {
+        self.tolerance
+    }
+
+    /// Frames which had KLV within tolerance.
+    pub fn matched_frames(&self) -> u64 {
+        self.matched
+    }
+
+    /// Frames without any KLV within tolerance.
+    pub fn unmatched_frames(&self) -> u64 {
+        self.unmatched
+    }
+
+    /// Number of PTS values waiting for their frame.
+    pub fn pending(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Stores packet received with `pts`, packets with the same PTS are kept together.
+    pub fn insert(&mut self, pts: gst::ClockTime, packet: KlvPacket) {
+        self.packets.entry(pts).or_default().push(packet);
+        self.expire(pts);
+    }
+
+    /// Returns KLV for a frame, exact PTS match or the nearest one within tolerance.
+    /// Matched packets are left in place since the same frame can be drawn again.
+    pub fn find(&mut self, frame_pts: gst::ClockTime) -> Option<KlvMatch> {
+        self.expire(frame_pts);
+
+        let from = frame_pts.saturating_sub(self.tolerance);
+        let to = frame_pts.saturating_add(self.tolerance);
+        let nearest = self
+            .packets
+            .range(from..=to)
+            .min_by_key(|(pts, _)| pts.nseconds().abs_diff(frame_pts.nseconds()));
+        match nearest {
+            Some((pts, packets)) => {
+                self.matched += 1;
+                Some(KlvMatch {
+                    pts: *pts,
+                    packets: packets.clone(),
+                })
+            }
+            None => {
+                self.unmatched += 1;
+                None
+            }
+        }
+    }
+
+    /// Forgets everything, used on flushing seeks where PTS can jump back.
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.newest = None;
+    }
+
+    fn expire(&mut self, pts: gst::ClockTime) {
+        let newest = self.newest.map_or(pts, |newest| newest.max(pts));
+        self.newest = Some(newest);
+        let oldest = newest.saturating_sub(self.max_age);
+        self.packets = self.packets.split_off(&oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::klv::KlvKey;
+
+    fn packet(n: u8) -> KlvPacket {
+        KlvPacket::new(KlvKey::new([n; 16]), vec![n])
+    }
+
+    fn ms(ms: u64) -> gst::ClockTime {
+        gst::ClockTime::from_mseconds(ms)
+    }
+
+    fn us(us: u64) -> gst::ClockTime {
+        gst::ClockTime::from_useconds(us)
+    }
+
+    #[test]
+    fn exact_match() {
+        let mut matcher = KlvMatcher::default();
+        matcher.insert(ms(40), packet(1));
+        matcher.insert(ms(40), packet(2));
+        matcher.insert(ms(80), packet(3));
+        let found = matcher.find(ms(40)).unwrap();
+        assert_eq!(found.pts, ms(40));
+        assert_eq!(found.packets, [packet(1), packet(2)]);
+        // Matched packets stay for the same frame drawn again.
+        assert_eq!(matcher.find(ms(40)).unwrap().packets.len(), 2);
+        assert_eq!(matcher.pending(), 2);
+    }
+
+    #[test]
+    fn within_tolerance() {
+        let mut matcher = KlvMatcher::default();
+        // 90 kHz rounding of 40 ms.
+        matcher.insert(us(40_011), packet(1));
+        let found = matcher.find(ms(40)).unwrap();
+        assert_eq!(found.pts, us(40_011));
+        assert_eq!(matcher.find(us(38_999)), None);
+        assert_eq!(matcher.find(us(41_012)), None);
+        assert!(matcher.find(us(39_011)).is_some());
+        assert!(matcher.find(us(41_011)).is_some());
+    }
+
+    #[test]
+    fn nearest_of_two() {
+        let mut matcher = KlvMatcher::default();
+        matcher.insert(us(39_200), packet(1));
+        matcher.insert(us(40_300), packet(2));
+        assert_eq!(matcher.find(ms(40)).unwrap().packets, [packet(2)]);
+        assert_eq!(matcher.find(us(39_700)).unwrap().packets, [packet(1)]);
+    }
+
+    #[test]
+    fn expiry() {
+        let mut matcher = KlvMatcher::new(ms(1), ms(500));
+        matcher.insert(ms(0), packet(1));
+        matcher.insert(ms(400), packet(2));
+        assert_eq!(matcher.pending(), 2);
+        // A newer frame expires what is older than `max_age` from it.
+        assert_eq!(matcher.find(ms(600)), None);
+        assert_eq!(matcher.pending(), 1);
+        assert!(matcher.find(ms(400)).is_some());
+        // So does newer KLV, the newest PTS of both counts.
+        matcher.insert(ms(1000), packet(3));
+        assert_eq!(matcher.pending(), 1);
+        assert_eq!(matcher.find(ms(400)), None);
+    }
+
+    #[test]
+    fn frame_counts() {
+        let mut matcher = KlvMatcher::default();
+        matcher.insert(ms(40), packet(1));
+        for frame in [0, 40, 80, 40, 120] {
+            matcher.find(ms(frame));
+        }
+        assert_eq!(matcher.matched_frames(), 2);
+        assert_eq!(matcher.unmatched_frames(), 3);
+    }
+
+    #[test]
+    fn clear() {
+        let mut matcher = KlvMatcher::default();
+        matcher.insert(ms(10_000), packet(1));
+        matcher.clear();
+        assert_eq!(matcher.pending(), 0);
+        // PTS back at the start after a seek is not expired by the old position.
+        matcher.insert(ms(0), packet(2));
+        assert_eq!(matcher.find(ms(0)).unwrap().packets, [packet(2)]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Custom `GstMeta` which carries the KLV matched to a video frame on the frame buffer itself.
+//!
+//! Once attached after the decoder, every element and probe downstream can read the KLV of
+//! the exact frame it is handling. The meta has no tags, so it is copied along with the
+//! buffer by converters, queues and tees.
+
+use super::{matcher::KlvMatch, KlvPacket};
+use gst::prelude::*;
+use gstreamer as gst;
+use std::{fmt, mem};
+
+#[repr(transparent)]
+pub struct KlvMeta(imp::KlvMeta);
+
+// SAFETY: Meta only holds owned Rust data which is Send and Sync.
+unsafe impl Send for KlvMeta {}
+unsafe impl Sync for KlvMeta {}
+
+impl KlvMeta {
+    /// Attaches matched KLV to the buffer.
+    pub fn add(
+        buffer: &mut gst::BufferRef,
+        klv: KlvMatch,
+    ) -> gst::MetaRefMut<Self, gst::meta::Standalone> {
+        unsafe {
+            // Ownership of the params moves to the meta in `klv_meta_init`.
+            let mut params = mem::ManuallyDrop::new(imp::KlvMetaParams { klv });
+            let meta = gst::ffi::gst_buffer_add_meta(
+                buffer.as_mut_ptr(),
+                imp::klv_meta_get_info(),
+                &mut *params as *mut imp::KlvMetaParams as gst::glib::ffi::gpointer,
+            ) as *mut imp::KlvMeta;
+            Self::from_mut_ptr(buffer, meta)
+        }
+    }
+
+    /// PTS the KLV was sent with, equal to frame PTS unless matched within tolerance.
+    pub fn klv_pts(&self) -> gst::ClockTime {
+        self.0.klv.pts
+    }
+
+    pub fn packets(&self) -> &[KlvPacket] {
+        &self.0.klv.packets
+    }
+}
+
+// SAFETY: `KlvMeta` is a transparent wrapper of the registered meta struct.
+unsafe impl MetaAPI for KlvMeta {
+    type GstType = imp::KlvMeta;
+
+    fn meta_api() -> gst::glib::Type {
+        imp::klv_meta_api_get_type()
+    }
+}
+
+impl fmt::Debug for KlvMeta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KlvMeta")
+            .field("klv_pts", &self.klv_pts())
+            .field("packets", &self.packets())
+            .finish()
+    }
+}
+
+mod imp {
+    use super::KlvMatch;
+    use gst::glib::{self, translate::*};
+    use gstreamer as gst;
+    use std::{mem, ptr, sync::OnceLock};
+
+    pub(super) struct KlvMetaParams {
+        pub klv: KlvMatch,
+    }
+
+    #[repr(C)]
+    pub struct KlvMeta {
+        parent: gst::ffi::GstMeta,
+        pub(super) klv: KlvMatch,
+    }
+
+    pub(super) fn klv_meta_api_get_type() -> glib::Type {
+        static TYPE: OnceLock<glib::Type> = OnceLock::new();
+        *TYPE.get_or_init(|| unsafe {
+            let t = from_glib(gst::ffi::gst_meta_api_type_register(
+                b"GstKlvMetaAPI\0".as_ptr() as *const _,
+                // No tags, the meta stays valid whatever happens to the video.
+                [ptr::null::<std::os::raw::c_char>()].as_ptr() as *mut *const _,
+            ));
+            assert_ne!(t, glib::Type::INVALID);
+            t
+        })
+    }
+
+    unsafe extern "C" fn klv_meta_init(
+        meta: *mut gst::ffi::GstMeta,
+        params: glib::ffi::gpointer,
+        _buffer: *mut gst::ffi::GstBuffer,
+    ) -> glib::ffi::gboolean {
+        assert!(!params.is_null());
+        let meta = &mut *(meta as *mut KlvMeta);
+        let params = ptr::read(params as *const KlvMetaParams);
+        ptr::write(&mut meta.klv, params.klv);
+        true.into_glib()
+    }
+
+    unsafe extern "C" fn klv_meta_free(
+        meta: *mut gst::ffi::GstMeta,
+        _buffer: *mut gst::ffi::GstBuffer,
+    ) {
+        let meta = &mut *(meta as *mut KlvMeta);
+        ptr::drop_in_place(&mut meta.klv);
+    }
+
+    unsafe extern "C" fn klv_meta_transform(
+        dest: *mut gst::ffi::GstBuffer,
+        meta: *mut gst::ffi::GstMeta,
+        _buffer: *mut gst::ffi::GstBuffer,
+        _type_: glib::ffi::GQuark,
+        _data: glib::ffi::gpointer,
+    ) -> glib::ffi::gboolean {
+        let meta = &*(meta as *mut KlvMeta);
+        super::KlvMeta::add(gst::BufferRef::from_mut_ptr(dest), meta.klv.clone());
+        true.into_glib()
+    }
+
+    pub(super) fn klv_meta_get_info() -> *const gst::ffi::GstMetaInfo {
+        struct MetaInfo(ptr::NonNull<gst::ffi::GstMetaInfo>);
+        // SAFETY: Registered meta info is never freed or changed.
+        unsafe impl Send for MetaInfo {}
+        unsafe impl Sync for MetaInfo {}
+
+        static META_INFO: OnceLock<MetaInfo> = OnceLock::new();
+        META_INFO
+            .get_or_init(|| unsafe {
+                MetaInfo(
+                    ptr::NonNull::new(gst::ffi::gst_meta_register(
+                        klv_meta_api_get_type().into_glib(),
+                        b"GstKlvMeta\0".as_ptr() as *const _,
+                        mem::size_of::<KlvMeta>(),
+                        Some(klv_meta_init),
+                        Some(klv_meta_free),
+                        Some(klv_meta_transform),
+                    ) as *mut gst::ffi::GstMetaInfo)
+                    .expect("Failed to register KLV meta"),
+                )
+            })
+            .0
+            .as_ptr()
+    }
+}
@@ -0,0 +1,354 @@
+//! Stateful KLV parser for streams where buffer boundaries have nothing to do with packets.
+//!
+//! After `tsdemux` one KLV packet can be split over several PES buffers and one buffer can
+//! hold several packets. The parser keeps whatever it has not used yet and continues with it
+//! when the next buffer arrives. After garbage it skips ahead to the next known key.
+
+use super::codec::{decode_ber_length, KlvError, KlvKey, KlvPacket, UL_PREFIX};
+use super::{check_packet, st0102, st0601, st0903};
+use gstreamer as gst;
+use std::collections::VecDeque;
+
+/// Packets longer than this are taken as a corrupted length instead of waiting for the data.
+pub const DEFAULT_MAX_PACKET_LEN: usize = 64 * 1024;
+
+/// Packet with the bytes it was decoded from.
+pub type RawPacket = (KlvPacket, Vec<u8>);
+
+pub struct KlvStreamParser {
+    buf: Vec<u8>,
+    /// Start offset in `buf` and PTS of every input buffer which still has unused bytes.
+    timestamps: VecDeque<(usize, Option<gst::ClockTime>)>,
+    /// Keys which are searched for when the parser has lost the packet boundaries.
+    keys: Vec<KlvKey>,
+    max_packet_len: usize,
+    /// True if `buf` is known to start at a packet boundary.
+    synced: bool,
+    /// True if the bytes being skipped belong to an error which was already reported.
+    reported: bool,
+    skipped: u64,
+}
+
+impl Default for KlvStreamParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KlvStreamParser {
+    /// Parser which resynchronizes on the local set keys this crate knows about.
+    pub fn new() -> Self {
+        Self::with_keys(vec![
+            st0601::UAS_DATALINK_LS_KEY,
+            st0903::VMTI_LS_KEY,
+            st0102::SECURITY_LS_KEY,
+        ])
+    }
+
+    pub fn with_keys(keys: Vec<KlvKey>) -> Self {
+        KlvStreamParser {
+            buf: Vec::new(),
+            timestamps: VecDeque::new(),
+            keys,
+            max_packet_len: DEFAULT_MAX_PACKET_LEN,
+            synced: false,
+            reported: false,
+            skipped: 0,
+        }
+    }
+
+    pub fn max_packet_len(mut self, max_packet_len: usize) -> Self {
+        self.max_packet_len = max_packet_len;
+        self
+    }
+
+    /// Bytes thrown away so far while looking for a known key.
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Bytes waiting for the rest of a packet.
+    pub fn pending_bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Forgets buffered data, used on discontinuities and flushes where the next buffer
+    /// can't continue the previous one.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.timestamps.clear();
+        self.synced = false;
+        self.reported = false;
+    }
+
+    /// Feeds the next buffer and returns every packet it completed.
+    /// Each packet gets the PTS of the buffer where it started.
+    /// Packets with a known checksummed key are only returned if the checksum is valid.
+    pub fn push(
+        &mut self,
+        data: &[u8],
+        pts: Option<gst::ClockTime>,
+    ) -> Vec<(Option<gst::ClockTime>, Result<KlvPacket, KlvError>)> {
+        self.push_raw(data, pts)
+            .into_iter()
+            .map(|(pts, packet)| (pts, packet.map(|(packet, _)| packet)))
+            .collect()
+    }
+
+    /// Like `push`, but every packet comes with its bytes as they were received. Passing those
+    /// on keeps the checksum valid, encoding the packet again may change the BER length form
+    /// the checksum was computed over.
+    pub fn push_raw(
+        &mut self,
+        data: &[u8],
+        pts: Option<gst::ClockTime>,
+    ) -> Vec<(Option<gst::ClockTime>, Result<RawPacket, KlvError>)> {
+        if data.is_empty() {
+            return Vec::new();
+        }
+        self.timestamps.push_back((self.buf.len(), pts));
+        self.buf.extend_from_slice(data);
+
+        let mut packets = Vec::new();
+        loop {
+            // Anything following a packet has to be another universal label.
+            let prefix = &self.buf[..self.buf.len().min(UL_PREFIX.len())];
+            if !UL_PREFIX.starts_with(prefix) {
+                self.synced = false;
+            }
+            if !self.synced {
+                let skipped = self.resync();
+                if skipped > 0 && !self.reported {
+                    packets.push((self.front_pts(), Err(KlvError::Garbage { skipped })));
+                }
+                if !self.synced {
+                    break;
+                }
+            }
+
+            match self.next_packet() {
+                Ok(Some((packet, used))) => {
+                    let raw = self.buf[..used].to_vec();
+                    let packet = check_packet(packet, &raw).map(|packet| (packet, raw));
+                    let ok = packet.is_ok();
+                    packets.push((self.front_pts(), packet));
+                    self.consume(used);
+                    // Bad checksum with the next packet right after it means the length
+                    // was fine, otherwise the loop head notices lost sync.
+                    self.reported = !ok;
+                }
+                // Wait for the next buffer.
+                Ok(None) => break,
+                Err(err) => {
+                    // Length can't be trusted, look for the next key after this one.
+                    packets.push((self.front_pts(), Err(err)));
+                    self.synced = false;
+                    self.reported = true;
+                    self.consume(1);
+                    self.skipped += 1;
+                }
+            }
+        }
+        packets
+    }
+
+    /// Decodes packet at the start of the buffer and returns it with its size.
+    /// Returns `None` if the packet is not complete yet and an error if the packet
+    /// boundaries are lost.
+    fn next_packet(&self) -> Result<Option<(KlvPacket, usize)>, KlvError> {
+        if self.buf.len() < KlvKey::LEN {
+            return Ok(None);
+        }
+        let too_long = |len| KlvError::PacketTooLong {
+            len,
+            max: self.max_packet_len,
+        };
+        let len = match decode_ber_length(&self.buf[KlvKey::LEN..]) {
+            Ok((len, len_size)) => len.checked_add(len_size).ok_or(too_long(len))?,
+            Err(KlvError::Incomplete { .. }) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if len > self.max_packet_len {
+            return Err(too_long(len));
+        }
+        if self.buf.len() - KlvKey::LEN < len {
+            return Ok(None);
+        }
+
+        KlvPacket::decode(&self.buf).map(Some)
+    }
+
+    /// Skips to the first known key, returns number of bytes skipped.
+    fn resync(&mut self) -> usize {
+        let found = (0..self.buf.len()).find(|&i| {
+            let rest = &self.buf[i..];
+            self.keys.iter().any(|key| {
+                let key = key.as_bytes();
+                // Partial key at the end of the buffer may still become a match.
+                (rest.len() < KlvKey::LEN && key.starts_with(rest)) || rest.starts_with(key)
+            })
+        });
+        let skip = found.unwrap_or(self.buf.len());
+        if self.buf.len() - skip >= KlvKey::LEN {
+            self.synced = true;
+        }
+        self.consume(skip);
+        self.skipped += skip as u64;
+        skip
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.buf.drain(..n);
+        while self.timestamps.get(1).is_some_and(|(start, _)| *start <= n) {
+            self.timestamps.pop_front();
+        }
+        for (start, _) in self.timestamps.iter_mut() {
+            *start = start.saturating_sub(n);
+        }
+        if self.buf.is_empty() {
+            self.timestamps.clear();
+        }
+    }
+
+    /// PTS of the buffer the first unused byte came in.
+    fn front_pts(&self) -> Option<gst::ClockTime> {
+        self.timestamps.front().and_then(|(_, pts)| *pts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::klv::codec::{encode_with_checksum, Checksum};
+
+    fn stream() -> (Vec<KlvPacket>, Vec<u8>) {
+        let packets = vec![
+            encode_with_checksum(
+                st0601::UAS_DATALINK_LS_KEY,
+                vec![0x02, 0x08, 0x00, 0x04, 0x59, 0xF4, 0xA6, 0xAA, 0x4A, 0xA8],
+                Checksum::RunningSum,
+            ),
+            encode_with_checksum(
+                st0903::VMTI_LS_KEY,
+                vec![0x04, 0x01, 0x06],
+                Checksum::Crc16Ccitt,
+            ),
+            // Long enough for a long form length.
+            encode_with_checksum(
+                st0601::UAS_DATALINK_LS_KEY,
+                [0x03, 0x80].repeat(65),
+                Checksum::RunningSum,
+            ),
+        ];
+        let data = packets.iter().flat_map(KlvPacket::encode).collect();
+        (packets, data)
+    }
+
+    fn ok(packets: Vec<(Option<gst::ClockTime>, Result<KlvPacket, KlvError>)>) -> Vec<KlvPacket> {
+        packets
+            .into_iter()
+            .map(|(_, packet)| packet.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn every_split_point() {
+        let (expected, data) = stream();
+        for split in 0..=data.len() {
+            let mut parser = KlvStreamParser::new();
+            let first = gst::ClockTime::from_mseconds(40);
+            let second = gst::ClockTime::from_mseconds(80);
+            let mut packets = parser.push(&data[..split], Some(first));
+            packets.extend(parser.push(&data[split..], Some(second)));
+            let pts = packets.first().and_then(|(pts, _)| *pts);
+            assert_eq!(pts, Some(if split == 0 { second } else { first }));
+            assert_eq!(ok(packets), expected, "split at {split}");
+            assert_eq!(parser.pending_bytes(), 0);
+            assert_eq!(parser.skipped_bytes(), 0);
+        }
+    }
+
+    #[test]
+    fn byte_by_byte() {
+        let (expected, data) = stream();
+        let mut parser = KlvStreamParser::new();
+        let packets = data.chunks(1).flat_map(|b| parser.push(b, None)).collect();
+        assert_eq!(ok(packets), expected);
+    }
+
+    #[test]
+    fn garbage_prefix() {
+        let (expected, data) = stream();
+        let mut garbage = vec![0x00, 0x06, 0x0E, 0x2B, 0xFF, 0x06];
+        garbage.extend_from_slice(&data);
+        let mut parser = KlvStreamParser::new();
+        let mut packets = parser.push(&garbage, None).into_iter();
+        assert_eq!(
+            packets.next().unwrap().1,
+            Err(KlvError::Garbage { skipped: 6 })
+        );
+        assert_eq!(ok(packets.collect()), expected);
+        assert_eq!(parser.skipped_bytes(), 6);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let (expected, mut data) = stream();
+        // Last byte of the first checksum.
+        data[30] ^= 0xFF;
+        let mut parser = KlvStreamParser::new();
+        let mut packets = parser.push(&data, None).into_iter();
+        assert!(matches!(
+            packets.next().unwrap().1,
+            Err(KlvError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(ok(packets.collect()), expected[1..]);
+    }
+
+    #[test]
+    fn raw_bytes_of_long_form_length() {
+        // Value of 17 bytes with a long form length, which the checksum covers.
+        let mut raw = st0601::UAS_DATALINK_LS_KEY.as_bytes().to_vec();
+        raw.extend_from_slice(&[0x81, 0x11]);
+        raw.extend_from_slice(&[0x02, 0x08, 0x00, 0x04, 0x59, 0xF4, 0xA6, 0xAA, 0x4A, 0xA8]);
+        raw.extend_from_slice(&[0x41, 0x01, 0x11, 0x01, 0x02, 0x68, 0xB5]);
+        let mut parser = KlvStreamParser::new();
+        let mut packets = parser.push_raw(&raw, None);
+        assert_eq!(packets.len(), 1);
+        let (packet, bytes) = packets.remove(0).1.unwrap();
+        assert_eq!(bytes, raw);
+        // Encoded again it gets a short form length and the checksum no longer matches.
+        assert_ne!(packet.encode(), raw);
+    }
+
+    #[test]
+    fn overflowing_length() {
+        let (expected, data) = stream();
+        let mut bad = st0601::UAS_DATALINK_LS_KEY.as_bytes().to_vec();
+        bad.push(0x88);
+        bad.extend_from_slice(&[0xFF; 8]);
+        bad.extend_from_slice(&data);
+        let mut parser = KlvStreamParser::new();
+        let mut packets = parser.push(&bad, None).into_iter();
+        assert_eq!(
+            packets.next().unwrap().1,
+            Err(KlvError::PacketTooLong {
+                len: usize::MAX,
+                max: DEFAULT_MAX_PACKET_LEN
+            })
+        );
+        assert_eq!(ok(packets.collect()), expected);
+    }
+
+    #[test]
+    fn too_long() {
+        let (_, data) = stream();
+        let mut parser = KlvStreamParser::new().max_packet_len(100);
+        let packets = parser.push(&data, None);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(
+            packets[2].1,
+            Err(KlvError::PacketTooLong { len: 136, max: 100 })
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! KLV in H.264 SEI user data unregistered messages.
+//!
+//! KLV of a frame goes into a SEI NAL unit of the frame's own access unit, so nothing on the
+//! way can separate the two and no PTS matching is needed at the receiver. Access units are
+//! handled in byte-stream format with start codes.
+//!
+//! KLV in SEI is not a MISB carriage. ST 0604 puts only time stamps into SEI and KLV goes in a
+//! stream of its own, so there is no standard UUID for KLV messages. Sender and receiver have to
+//! agree on `SeiUuid`, other systems only find the KLV if they are configured with the same one.
+//! What ST 0604 does define, the precision time stamp of the frame, goes into the same SEI NAL
+//! unit as a message of its own, see `encode_sei`.
+
+use std::{fmt, str::FromStr};
+
+/// NAL unit type of supplemental enhancement information.
+const NAL_TYPE_SEI: u8 = 6;
+
+/// SEI payload type of user data unregistered.
+const USER_DATA_UNREGISTERED: usize = 5;
+
+/// ST 0604 time stamp status: bit 7 set as the clock isn't locked to a reference, no
+/// discontinuity (bit 6), forward (bit 5), reserved bits 4-0 set.
+const TIME_STAMP_STATUS: u8 = 0x9f;
+
+/// Marks user data unregistered messages which carry KLV, messages with any other UUID such as
+/// encoder version strings are skipped. Written as a UUID, e.g.
+/// `8d2f5a1e-3c4b-4f6a-9e21-7b0c58d346a1`, dashes are optional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeiUuid(pub [u8; 16]);
+
+impl SeiUuid {
+    /// Random UUID of this project, used unless another one is configured.
+    pub const PRIVATE: SeiUuid = SeiUuid([
+        0x8d, 0x2f, 0x5a, 0x1e, 0x3c, 0x4b, 0x4f, 0x6a, 0x9e, 0x21, 0x7b, 0x0c, 0x58, 0xd3, 0x46,
+        0xa1,
+    ]);
+
+    /// ST 0604 precision time stamp messages, "MISPmicrosectime" in ASCII.
+    pub const ST0604_TIME_STAMP: SeiUuid = SeiUuid(*b"MISPmicrosectime");
+}
+
+impl Default for SeiUuid {
+    fn default() -> Self {
+        SeiUuid::PRIVATE
+    }
+}
+
+impl FromStr for SeiUuid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: Vec<u8> = s.bytes().filter(|b| *b != b'-').collect();
+        let invalid = || format!("\"{s}\" is not a UUID of 32 hex digits");
+        if digits.len() != 32 {
+            return Err(invalid());
+        }
+        let mut uuid = [0; 16];
+        for (byte, pair) in uuid.iter_mut().zip(digits.chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+        }
+        Ok(SeiUuid(uuid))
+    }
+}
+
+impl fmt::Display for SeiUuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// SEI NAL unit without start code, with one user data unregistered message holding `klv` and
+/// with `time_stamp`, microseconds since 1970, in an ST 0604 message in front of it.
+pub fn encode_sei(klv: &[u8], uuid: SeiUuid, time_stamp: Option<u64>) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(klv.len() + 64);
+    if let Some(time_stamp) = time_stamp {
+        write_user_data(
+            &mut rbsp,
+            SeiUuid::ST0604_TIME_STAMP,
+            &encode_time_stamp(time_stamp),
+        );
+    }
+    write_user_data(&mut rbsp, uuid, klv);
+    // rbsp_trailing_bits: stop bit and alignment zeros.
+    rbsp.push(0x80);
+
+    let mut nal = Vec::with_capacity(rbsp.len() + rbsp.len() / 64 + 1);
+    nal.push(NAL_TYPE_SEI);
+    let mut zeros = 0;
+    for byte in rbsp {
+        // Two zero bytes followed by 0-3 would look like a start code, escape them with 3.
+        if zeros >= 2 && byte <= 3 {
+            nal.push(3);
+            zeros = 0;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        nal.push(byte);
+    }
+    nal
+}
+
+/// KLV from every user data unregistered message with `uuid` in a SEI NAL unit,
+/// empty for other NAL units. Truncated messages end decoding.
+pub fn decode_sei(nal: &[u8], uuid: SeiUuid) -> Vec<Vec<u8>> {
+    user_data(nal)
+        .into_iter()
+        .filter_map(|payload| payload.strip_prefix(&uuid.0).map(<[u8]>::to_vec))
+        .collect()
+}
+
+/// ST 0604 precision time stamp of a SEI NAL unit, microseconds since 1970.
+pub fn decode_time_stamp(nal: &[u8]) -> Option<u64> {
+    decode_sei(nal, SeiUuid::ST0604_TIME_STAMP)
+        .iter()
+        .find_map(|message| parse_time_stamp(message))
+}
+
+/// Payloads of all user data unregistered messages in a SEI NAL unit, UUID included.
+fn user_data(nal: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages = Vec::new();
+    if nal.first().map(|header| header & 0x1f) != Some(NAL_TYPE_SEI) {
+        return messages;
+    }
+
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &byte in &nal[1..] {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+
+    let mut data = rbsp.as_slice();
+    // Whatever is left after the messages is `rbsp_trailing_bits`.
+    while data.len() > 1 {
+        let (Some(payload_type), Some(size)) = (read_ff_coded(&mut data), read_ff_coded(&mut data))
+        else {
+            break;
+        };
+        if size > data.len() {
+            break;
+        }
+        let (payload, rest) = data.split_at(size);
+        data = rest;
+        if payload_type == USER_DATA_UNREGISTERED {
+            messages.push(payload.to_vec());
+        }
+    }
+    messages
+}
+
+/// ST 0604 time stamp message after the UUID: status and the 8 time stamp bytes, with 0xff after
+/// every pair of them so that the time stamp can't look like a start code.
+fn encode_time_stamp(time_stamp: u64) -> Vec<u8> {
+    let mut message = vec![TIME_STAMP_STATUS];
+    for (i, pair) in time_stamp.to_be_bytes().chunks(2).enumerate() {
+        if i > 0 {
+            message.push(0xff);
+        }
+        message.extend_from_slice(pair);
+    }
+    message
+}
+
+fn parse_time_stamp(message: &[u8]) -> Option<u64> {
+    let [_status, t0, t1, 0xff, t2, t3, 0xff, t4, t5, 0xff, t6, t7] = *message else {
+        return None;
+    };
+    Some(u64::from_be_bytes([t0, t1, t2, t3, t4, t5, t6, t7]))
+}
+
+/// NAL units of a byte-stream buffer, without start codes.
+pub fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let starts = start_codes(data);
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, &(_, begin))| {
+            let end = starts.get(i + 1).map_or(data.len(), |&(next, _)| next);
+            // Zero bytes in front of the next start code don't belong to the NAL unit.
+            let mut nal = &data[begin..end];
+            while let [rest @ .., 0] = nal {
+                nal = rest;
+            }
+            nal
+        })
+        .collect()
+}
+
+/// Access unit with `sei` NAL unit inserted in front of the first slice, where SEI has to be.
+pub fn insert_sei(access_unit: &[u8], sei: &[u8]) -> Vec<u8> {
+    let at = start_codes(access_unit)
+        .into_iter()
+        .find(|&(_, nal)| {
+            access_unit
+                .get(nal)
+                .is_some_and(|header| matches!(header & 0x1f, 1..=5))
+        })
+        .map_or(access_unit.len(), |(start, _)| start);
+
+    let mut out = Vec::with_capacity(access_unit.len() + sei.len() + 4);
+    out.extend_from_slice(&access_unit[..at]);
+    out.extend_from_slice(&[0, 0, 0, 1]);
+    out.extend_from_slice(sei);
+    out.extend_from_slice(&access_unit[at..]);
+    out
+}
+
+/// KLV packets from all SEI NAL units of an access unit with `uuid`, concatenated.
+pub fn extract_klv(access_unit: &[u8], uuid: SeiUuid) -> Vec<u8> {
+    nal_units(access_unit)
+        .into_iter()
+        .flat_map(|nal| decode_sei(nal, uuid))
+        .flatten()
+        .collect()
+}
+
+/// ST 0604 precision time stamp of an access unit, microseconds since 1970.
+pub fn extract_time_stamp(access_unit: &[u8]) -> Option<u64> {
+    nal_units(access_unit)
+        .into_iter()
+        .find_map(decode_time_stamp)
+}
+
+/// Positions of start codes and of the NAL units following them.
+/// A 4 byte start code begins at its leading zero byte.
+fn start_codes(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            let start = if i > 0 && data[i - 1] == 0 { i - 1 } else { i };
+            starts.push((start, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+}
+
+/// User data unregistered message of `uuid` followed by `data`.
+fn write_user_data(out: &mut Vec<u8>, uuid: SeiUuid, data: &[u8]) {
+    write_ff_coded(out, USER_DATA_UNREGISTERED);
+    write_ff_coded(out, uuid.0.len() + data.len());
+    out.extend_from_slice(&uuid.0);
+    out.extend_from_slice(data);
+}
+
+/// SEI payload type and size are coded as a run of 0xff bytes and the remainder.
+fn write_ff_coded(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0xff {
+        out.push(0xff);
+        value -= 0xff;
+    }
+    out.push(value as u8);
+}
+
+fn read_ff_coded(data: &mut &[u8]) -> Option<usize> {
+    let mut value = 0;
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value += usize::from(byte);
+        if byte != 0xff {
+            return Some(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SPS, PPS and IDR slice headers of an access unit, payloads cut short.
+    const ACCESS_UNIT: [u8; 20] = [
+        0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1E, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80, 0, 0, 1, 0x65, 0x88,
+    ];
+
+    #[test]
+    fn uuid_text() {
+        let text = "8d2f5a1e-3c4b-4f6a-9e21-7b0c58d346a1";
+        assert_eq!(text.parse(), Ok(SeiUuid::PRIVATE));
+        assert_eq!(SeiUuid::PRIVATE.to_string(), text);
+        assert_eq!(
+            text.replace('-', "").to_uppercase().parse(),
+            Ok(SeiUuid::PRIVATE)
+        );
+        assert!("8d2f5a1e".parse::<SeiUuid>().is_err());
+        assert!("8d2f5a1e-3c4b-4f6a-9e21-7b0c58d346ax"
+            .parse::<SeiUuid>()
+            .is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        // Zeros which need emulation prevention and a size over 255.
+        let klv: Vec<u8> = (0..300).map(|i| if i % 3 == 2 { 1 } else { 0 }).collect();
+        let uuid = SeiUuid([7; 16]);
+        let access_unit = insert_sei(&ACCESS_UNIT, &encode_sei(&klv, uuid, None));
+
+        let nals = nal_units(&access_unit);
+        assert_eq!(nals.len(), 4);
+        // SEI goes after the parameter sets, right in front of the slice.
+        assert_eq!(nals[2][0], NAL_TYPE_SEI);
+        assert_eq!(nals[3], &ACCESS_UNIT[18..]);
+        assert!(!nals[2].windows(3).any(|w| w == [0, 0, 1]));
+
+        assert_eq!(extract_klv(&access_unit, uuid), klv);
+        assert!(extract_klv(&access_unit, SeiUuid::PRIVATE).is_empty());
+        assert!(extract_klv(&ACCESS_UNIT, uuid).is_empty());
+        assert_eq!(extract_time_stamp(&access_unit), None);
+    }
+
+    #[test]
+    fn time_stamp() {
+        // Time stamp of the ST 0601 example, 2008-10-24T00:13:29.913Z.
+        let time_stamp = 1_224_807_209_913_000;
+        let sei = encode_sei(&[1, 2, 3], SeiUuid::PRIVATE, Some(time_stamp));
+        assert_eq!(
+            sei[..32],
+            [
+                NAL_TYPE_SEI,
+                5,
+                28,
+                b'M',
+                b'I',
+                b'S',
+                b'P',
+                b'm',
+                b'i',
+                b'c',
+                b'r',
+                b'o',
+                b's',
+                b'e',
+                b'c',
+                b't',
+                b'i',
+                b'm',
+                b'e',
+                0x9f,
+                0x00,
+                0x04,
+                0xff,
+                0x59,
+                0xf4,
+                0xff,
+                0xa6,
+                0xaa,
+                0xff,
+                0x4a,
+                0xa8,
+                5,
+            ]
+        );
+
+        let access_unit = insert_sei(&ACCESS_UNIT, &sei);
+        assert_eq!(extract_time_stamp(&access_unit), Some(time_stamp));
+        assert_eq!(extract_klv(&access_unit, SeiUuid::PRIVATE), [1, 2, 3]);
+        // Time stamp message is no KLV of its own.
+        assert!(extract_klv(&access_unit, SeiUuid([7; 16])).is_empty());
+    }
+
+    #[test]
+    fn truncated_message() {
+        let mut sei = encode_sei(&[1, 2, 3], SeiUuid::PRIVATE, None);
+        sei.truncate(sei.len() - 3);
+        assert!(decode_sei(&sei, SeiUuid::PRIVATE).is_empty());
+    }
+}
@@ -0,0 +1,348 @@
+//! MISB ST 0102 Security Metadata Local Set.
+//!
+//! Usually carried inside ST 0601 tag 48, where it is a plain local set without key and
+//! length, but it can also be sent as a standalone KLV packet.
+
+use super::codec::{
+    decode_string, decode_u8, parse_local_set, write_local_set_item, KlvError, KlvKey, KlvPacket,
+};
+use std::fmt;
+
+pub const SECURITY_LS_KEY: KlvKey = KlvKey::new([
+    0x06, 0x0E, 0x2B, 0x34, 0x02, 0x03, 0x01, 0x01, 0x0E, 0x01, 0x03, 0x03, 0x02, 0x00, 0x00, 0x00,
+]);
+
+/// ST 0102 revision we follow, sent in tag 22.
+pub const LS_VERSION: u16 = 12;
+
+/// Country coding method for ISO 3166 three letter codes such as `USA`.
+pub const ISO_3166_THREE_LETTER: u8 = 0x02;
+
+pub mod tag {
+    pub const SECURITY_CLASSIFICATION: u64 = 1;
+    pub const CLASSIFYING_COUNTRY_CODING_METHOD: u64 = 2;
+    pub const CLASSIFYING_COUNTRY: u64 = 3;
+    pub const SCI_SHI_INFORMATION: u64 = 4;
+    pub const CAVEATS: u64 = 5;
+    pub const RELEASING_INSTRUCTIONS: u64 = 6;
+    pub const OBJECT_COUNTRY_CODING_METHOD: u64 = 12;
+    pub const OBJECT_COUNTRY_CODES: u64 = 13;
+    pub const VERSION: u64 = 22;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Classification {
+    #[default]
+    Unclassified,
+    Restricted,
+    Confidential,
+    Secret,
+    TopSecret,
+}
+
+impl Classification {
+    fn to_u8(self) -> u8 {
+        match self {
+            Classification::Unclassified => 1,
+            Classification::Restricted => 2,
+            Classification::Confidential => 3,
+            Classification::Secret => 4,
+            Classification::TopSecret => 5,
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Classification::Unclassified),
+            2 => Some(Classification::Restricted),
+            3 => Some(Classification::Confidential),
+            4 => Some(Classification::Secret),
+            5 => Some(Classification::TopSecret),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Classification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Classification::Unclassified => "UNCLASSIFIED",
+            Classification::Restricted => "RESTRICTED",
+            Classification::Confidential => "CONFIDENTIAL",
+            Classification::Secret => "SECRET",
+            Classification::TopSecret => "TOP SECRET",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SecurityLocalSet {
+    pub classification: Classification,
+    pub classifying_country_coding_method: Option<u8>,
+    /// Country codes are prefixed with `//`, e.g. `//USA`.
+    pub classifying_country: Option<String>,
+    pub sci_shi_information: Option<String>,
+    pub caveats: Option<String>,
+    pub releasing_instructions: Option<String>,
+    pub object_country_coding_method: Option<u8>,
+    /// Countries shown in the imagery, separated with `;`.
+    pub object_country_codes: Option<String>,
+    pub version: Option<u16>,
+    pub unknown: Vec<(u64, Vec<u8>)>,
+}
+
+impl SecurityLocalSet {
+    /// Marking shown on the classification banner, e.g. `SECRET//USA//REL TO USA, GBR`.
+    pub fn banner(&self) -> String {
+        let mut banner = self.classification.to_string();
+        let country = self
+            .classifying_country
+            .as_deref()
+            .map(|c| c.trim_start_matches('/'));
+        let parts = [
+            country,
+            self.sci_shi_information.as_deref(),
+            self.caveats.as_deref(),
+            self.releasing_instructions.as_deref(),
+        ];
+        for part in parts.into_iter().flatten().filter(|p| !p.is_empty()) {
+            banner.push_str("//");
+            banner.push_str(part);
+        }
+        banner
+    }
+
+    /// Encodes as standalone KLV packet.
+    pub fn encode(&self) -> KlvPacket {
+        KlvPacket::new(SECURITY_LS_KEY, self.encode_value())
+    }
+
+    pub fn decode(packet: &KlvPacket) -> Result<Self, KlvError> {
+        if packet.key != SECURITY_LS_KEY {
+            return Err(KlvError::UnexpectedKey { key: packet.key });
+        }
+        Self::decode_value(&packet.value)
+    }
+
+    /// Encodes local set items only, this is what goes into ST 0601 tag 48.
+    pub fn encode_value(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_local_set_item(
+            tag::SECURITY_CLASSIFICATION,
+            &[self.classification.to_u8()],
+            &mut out,
+        );
+        if let Some(v) = self.classifying_country_coding_method {
+            write_local_set_item(tag::CLASSIFYING_COUNTRY_CODING_METHOD, &[v], &mut out);
+        }
+        let strings = [
+            (tag::CLASSIFYING_COUNTRY, &self.classifying_country),
+            (tag::SCI_SHI_INFORMATION, &self.sci_shi_information),
+            (tag::CAVEATS, &self.caveats),
+            (tag::RELEASING_INSTRUCTIONS, &self.releasing_instructions),
+        ];
+        for (tag, v) in strings {
+            if let Some(v) = v {
+                write_local_set_item(tag, v.as_bytes(), &mut out);
+            }
+        }
+        if let Some(v) = self.object_country_coding_method {
+            write_local_set_item(tag::OBJECT_COUNTRY_CODING_METHOD, &[v], &mut out);
+        }
+        if let Some(v) = &self.object_country_codes {
+            // Object country codes are the only UTF-16 item in the set.
+            let utf16: Vec<u8> = v.encode_utf16().flat_map(u16::to_be_bytes).collect();
+            write_local_set_item(tag::OBJECT_COUNTRY_CODES, &utf16, &mut out);
+        }
+        if let Some(v) = self.version {
+            write_local_set_item(tag::VERSION, &v.to_be_bytes(), &mut out);
+        }
+        for (tag, v) in &self.unknown {
+            write_local_set_item(*tag, v, &mut out);
+        }
+        out
+    }
+
+    pub fn decode_value(data: &[u8]) -> Result<Self, KlvError> {
+        let mut set = SecurityLocalSet::default();
+        let mut classification = None;
+        for (tag, value) in parse_local_set(data)? {
+            let invalid = || KlvError::InvalidLength {
+                tag,
+                len: value.len(),
+            };
+            match tag {
+                tag::SECURITY_CLASSIFICATION => {
+                    let v = decode_u8(value, tag)?;
+                    classification =
+                        Some(Classification::from_u8(v).ok_or(KlvError::InvalidValue { tag })?);
+                }
+                tag::CLASSIFYING_COUNTRY_CODING_METHOD => {
+                    set.classifying_country_coding_method = Some(decode_u8(value, tag)?)
+                }
+                tag::CLASSIFYING_COUNTRY => set.classifying_country = Some(decode_string(value)),
+                tag::SCI_SHI_INFORMATION => set.sci_shi_information = Some(decode_string(value)),
+                tag::CAVEATS => set.caveats = Some(decode_string(value)),
+                tag::RELEASING_INSTRUCTIONS => {
+                    set.releasing_instructions = Some(decode_string(value))
+                }
+                tag::OBJECT_COUNTRY_CODING_METHOD => {
+                    set.object_country_coding_method = Some(decode_u8(value, tag)?)
+                }
+                tag::OBJECT_COUNTRY_CODES => {
+                    set.object_country_codes = Some(decode_utf16(value).ok_or_else(invalid)?)
+                }
+                tag::VERSION => {
+                    let bytes = value.try_into().map_err(|_| invalid())?;
+                    set.version = Some(u16::from_be_bytes(bytes));
+                }
+                _ => set.unknown.push((tag, value.to_vec())),
+            }
+        }
+        // Without classification nothing else in the set can be trusted.
+        set.classification = classification.ok_or(KlvError::MissingItem {
+            tag: tag::SECURITY_CLASSIFICATION,
+        })?;
+        Ok(set)
+    }
+}
+
+fn decode_utf16(value: &[u8]) -> Option<String> {
+    let chunks = value.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let units: Vec<u16> = chunks.map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+    String::from_utf16(&units).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn security() -> SecurityLocalSet {
+        SecurityLocalSet {
+            classification: Classification::Secret,
+            classifying_country_coding_method: Some(ISO_3166_THREE_LETTER),
+            classifying_country: Some("//USA".into()),
+            sci_shi_information: Some("SI".into()),
+            caveats: Some("FOUO".into()),
+            releasing_instructions: Some("REL TO USA, GBR".into()),
+            object_country_coding_method: Some(ISO_3166_THREE_LETTER),
+            object_country_codes: Some("AFG;IRQ".into()),
+            version: Some(LS_VERSION),
+            unknown: vec![(7, b"2030".to_vec())],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let set = security();
+        let packet = set.encode();
+        assert_eq!(packet.key, SECURITY_LS_KEY);
+        assert_eq!(SecurityLocalSet::decode(&packet), Ok(set));
+    }
+
+    #[test]
+    fn encoded_items() {
+        let value = security().encode_value();
+        let items = parse_local_set(&value).unwrap();
+        assert_eq!(items[0], (tag::SECURITY_CLASSIFICATION, &[0x04][..]));
+        assert_eq!(
+            items[1],
+            (tag::CLASSIFYING_COUNTRY_CODING_METHOD, &[0x02][..])
+        );
+        assert_eq!(items[2], (tag::CLASSIFYING_COUNTRY, &b"//USA"[..]));
+        // Object country codes are UTF-16.
+        assert!(items.contains(&(
+            tag::OBJECT_COUNTRY_CODES,
+            &[0, b'A', 0, b'F', 0, b'G', 0, b';', 0, b'I', 0, b'R', 0, b'Q'][..]
+        )));
+        assert!(items.contains(&(tag::VERSION, &[0x00, 0x0C][..])));
+    }
+
+    #[test]
+    fn classification_values() {
+        for (classification, value) in [
+            (Classification::Unclassified, 0x01),
+            (Classification::Restricted, 0x02),
+            (Classification::Confidential, 0x03),
+            (Classification::Secret, 0x04),
+            (Classification::TopSecret, 0x05),
+        ] {
+            let set = SecurityLocalSet {
+                classification,
+                ..Default::default()
+            };
+            assert_eq!(set.encode_value(), [0x01, 0x01, value]);
+            assert_eq!(
+                SecurityLocalSet::decode_value(&[0x01, 0x01, value]),
+                Ok(set)
+            );
+        }
+        for value in [0x00, 0x06, 0xFF] {
+            assert_eq!(
+                SecurityLocalSet::decode_value(&[0x01, 0x01, value]),
+                Err(KlvError::InvalidValue { tag: 1 })
+            );
+        }
+        assert_eq!(
+            SecurityLocalSet::decode_value(&[0x03, 0x03, b'U', b'S', b'A']),
+            Err(KlvError::MissingItem { tag: 1 })
+        );
+    }
+
+    #[test]
+    fn country_coding_methods() {
+        // ISO 3166 two letter, three letter and numeric, FIPS 10-4 two letter.
+        for method in [0x01, ISO_3166_THREE_LETTER, 0x05, 0x03] {
+            let set = SecurityLocalSet {
+                classifying_country_coding_method: Some(method),
+                object_country_coding_method: Some(method),
+                ..Default::default()
+            };
+            let value = set.encode_value();
+            assert_eq!(
+                value,
+                [0x01, 0x01, 0x01, 0x02, 0x01, method, 0x0C, 0x01, method]
+            );
+            assert_eq!(SecurityLocalSet::decode_value(&value), Ok(set));
+        }
+        assert_eq!(
+            SecurityLocalSet::decode_value(&[0x01, 0x01, 0x01, 0x02, 0x02, 0x00, 0x02]),
+            Err(KlvError::InvalidLength { tag: 2, len: 2 })
+        );
+    }
+
+    #[test]
+    fn odd_utf16_length() {
+        assert_eq!(
+            SecurityLocalSet::decode_value(&[0x01, 0x01, 0x01, 0x0D, 0x03, 0x00, b'A', 0x00]),
+            Err(KlvError::InvalidLength { tag: 13, len: 3 })
+        );
+    }
+
+    #[test]
+    fn banner() {
+        assert_eq!(
+            security().banner(),
+            "SECRET//USA//SI//FOUO//REL TO USA, GBR"
+        );
+        assert_eq!(SecurityLocalSet::default().banner(), "UNCLASSIFIED");
+        let set = SecurityLocalSet {
+            classification: Classification::TopSecret,
+            classifying_country: Some("//GBR".into()),
+            caveats: Some(String::new()),
+            ..Default::default()
+        };
+        assert_eq!(set.banner(), "TOP SECRET//GBR");
+        let set = SecurityLocalSet {
+            classification: Classification::Restricted,
+            releasing_instructions: Some("REL TO NATO".into()),
+            ..Default::default()
+        };
+        assert_eq!(set.banner(), "RESTRICTED//REL TO NATO");
+        assert_eq!(Classification::Confidential.to_string(), "CONFIDENTIAL");
+        assert!(Classification::TopSecret > Classification::Secret);
+    }
+}
@@ -0,0 +1,1031 @@
+//! MISB ST 0601 UAS Datalink Local Set.
+//!
+//! Only the subset of tags we produce and display is typed, everything else is kept as raw
+//! bytes in `unknown` so that decoding and encoding again does not lose anything.
+
+use super::codec::{
+    decode_string, decode_u8, encode_with_checksum, parse_local_set, write_local_set_item,
+    Checksum, KlvError, KlvKey, KlvPacket,
+};
+use super::imapb::Imapb;
+use super::st0102::SecurityLocalSet;
+use super::st0903::VmtiLocalSet;
+use serde::{Deserialize, Serialize};
+
+pub const UAS_DATALINK_LS_KEY: KlvKey = KlvKey::new([
+    0x06, 0x0E, 0x2B, 0x34, 0x02, 0x0B, 0x01, 0x01, 0x0E, 0x01, 0x03, 0x01, 0x01, 0x00, 0x00, 0x00,
+]);
+
+/// ST 0601 revision we follow, sent in tag 65.
+pub const LS_VERSION: u8 = 17;
+
+pub mod tag {
+    pub const CHECKSUM: u64 = 1;
+    pub const PRECISION_TIME_STAMP: u64 = 2;
+    pub const MISSION_ID: u64 = 3;
+    pub const PLATFORM_TAIL_NUMBER: u64 = 4;
+    pub const PLATFORM_HEADING: u64 = 5;
+    pub const PLATFORM_PITCH: u64 = 6;
+    pub const PLATFORM_ROLL: u64 = 7;
+    pub const PLATFORM_TRUE_AIRSPEED: u64 = 8;
+    pub const PLATFORM_INDICATED_AIRSPEED: u64 = 9;
+    pub const PLATFORM_DESIGNATION: u64 = 10;
+    pub const IMAGE_SOURCE_SENSOR: u64 = 11;
+    pub const IMAGE_COORDINATE_SYSTEM: u64 = 12;
+    pub const SENSOR_LATITUDE: u64 = 13;
+    pub const SENSOR_LONGITUDE: u64 = 14;
+    pub const SENSOR_TRUE_ALTITUDE: u64 = 15;
+    pub const SENSOR_HORIZONTAL_FOV: u64 = 16;
+    pub const SENSOR_VERTICAL_FOV: u64 = 17;
+    pub const SENSOR_RELATIVE_AZIMUTH: u64 = 18;
+    pub const SENSOR_RELATIVE_ELEVATION: u64 = 19;
+    pub const SENSOR_RELATIVE_ROLL: u64 = 20;
+    pub const SLANT_RANGE: u64 = 21;
+    pub const TARGET_WIDTH: u64 = 22;
+    pub const FRAME_CENTER_LATITUDE: u64 = 23;
+    pub const FRAME_CENTER_LONGITUDE: u64 = 24;
+    pub const FRAME_CENTER_ELEVATION: u64 = 25;
+    /// Tags 26..=33 alternate latitude and longitude of the four corner offsets.
+    pub const OFFSET_CORNER_LATITUDE_POINT_1: u64 = 26;
+    pub const OFFSET_CORNER_LONGITUDE_POINT_4: u64 = 33;
+    pub const SECURITY_LOCAL_SET: u64 = 48;
+    pub const PLATFORM_GROUND_SPEED: u64 = 56;
+    pub const UAS_LS_VERSION_NUMBER: u64 = 65;
+    pub const VMTI_LOCAL_SET: u64 = 74;
+    pub const TARGET_WIDTH_EXTENDED: u64 = 96;
+}
+
+/// One item of a local set in human readable form, see `UasDatalinkLocalSet::fields`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Field {
+    pub tag: u64,
+    /// Item name from the standard.
+    pub name: &'static str,
+    pub value: String,
+    /// Unit of `value`, empty if it has none.
+    pub unit: &'static str,
+}
+
+/// Decoded UAS Datalink Local Set.
+/// Angles are in degrees, distances and altitudes in meters and speeds in meters per second.
+///
+/// Deserializes from an object with the names of the fields, nested sets and unknown tags
+/// can't be given that way.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UasDatalinkLocalSet {
+    /// Microseconds since 1970-01-01 UTC.
+    pub precision_time_stamp: Option<u64>,
+    pub mission_id: Option<String>,
+    pub platform_tail_number: Option<String>,
+    pub platform_heading: Option<f64>,
+    pub platform_pitch: Option<f64>,
+    pub platform_roll: Option<f64>,
+    pub platform_true_airspeed: Option<u8>,
+    pub platform_indicated_airspeed: Option<u8>,
+    pub platform_designation: Option<String>,
+    pub image_source_sensor: Option<String>,
+    pub image_coordinate_system: Option<String>,
+    pub sensor_latitude: Option<f64>,
+    pub sensor_longitude: Option<f64>,
+    pub sensor_true_altitude: Option<f64>,
+    pub sensor_horizontal_fov: Option<f64>,
+    pub sensor_vertical_fov: Option<f64>,
+    pub sensor_relative_azimuth: Option<f64>,
+    pub sensor_relative_elevation: Option<f64>,
+    pub sensor_relative_roll: Option<f64>,
+    pub slant_range: Option<f64>,
+    pub target_width: Option<f64>,
+    pub frame_center_latitude: Option<f64>,
+    pub frame_center_longitude: Option<f64>,
+    pub frame_center_elevation: Option<f64>,
+    /// Corner latitudes relative to the frame center, points 1 to 4.
+    pub offset_corner_latitude: [Option<f64>; 4],
+    /// Corner longitudes relative to the frame center, points 1 to 4.
+    pub offset_corner_longitude: [Option<f64>; 4],
+    /// MISB ST 0102 security metadata, nested as tag 48.
+    #[serde(skip)]
+    pub security: Option<SecurityLocalSet>,
+    pub platform_ground_speed: Option<u8>,
+    pub ls_version: Option<u8>,
+    /// MISB ST 0903 moving target indicator data, nested as tag 74.
+    #[serde(skip)]
+    pub vmti: Option<VmtiLocalSet>,
+    /// Same as `target_width` but up to 1500 km, IMAPB encoded.
+    pub target_width_extended: Option<f64>,
+    /// Tags this implementation does not know about, in the order they were received.
+    #[serde(skip)]
+    pub unknown: Vec<(u64, Vec<u8>)>,
+}
+
+impl UasDatalinkLocalSet {
+    /// Encodes the set as KLV packet and appends the mandatory checksum as the last item.
+    pub fn encode(&self) -> KlvPacket {
+        encode_with_checksum(
+            UAS_DATALINK_LS_KEY,
+            self.encode_items(),
+            Checksum::RunningSum,
+        )
+    }
+
+    pub fn decode(packet: &KlvPacket) -> Result<Self, KlvError> {
+        if packet.key != UAS_DATALINK_LS_KEY {
+            return Err(KlvError::UnexpectedKey { key: packet.key });
+        }
+
+        let mut set = UasDatalinkLocalSet::default();
+        for (tag, value) in parse_local_set(&packet.value)? {
+            set.decode_item(tag, value)?;
+        }
+        Ok(set)
+    }
+
+    /// Items of the set in tag order with their ST 0601 names, for display.
+    /// Nested sets are summarized and unknown tags are shown as hex.
+    pub fn fields(&self) -> Vec<Field> {
+        let mut fields = Vec::new();
+        let mut push = |tag, name, value: String, unit| {
+            fields.push(Field {
+                tag,
+                name,
+                value,
+                unit,
+            })
+        };
+
+        if let Some(v) = self.precision_time_stamp {
+            push(
+                tag::PRECISION_TIME_STAMP,
+                "Precision Time Stamp",
+                v.to_string(),
+                "us",
+            );
+        }
+        let strings = [
+            (tag::MISSION_ID, "Mission ID", &self.mission_id),
+            (
+                tag::PLATFORM_TAIL_NUMBER,
+                "Platform Tail Number",
+                &self.platform_tail_number,
+            ),
+        ];
+        for (tag, name, v) in strings {
+            if let Some(v) = v {
+                push(tag, name, v.clone(), "");
+            }
+        }
+        let angles = [
+            (
+                tag::PLATFORM_HEADING,
+                "Platform Heading Angle",
+                self.platform_heading,
+            ),
+            (
+                tag::PLATFORM_PITCH,
+                "Platform Pitch Angle",
+                self.platform_pitch,
+            ),
+            (
+                tag::PLATFORM_ROLL,
+                "Platform Roll Angle",
+                self.platform_roll,
+            ),
+        ];
+        for (tag, name, v) in angles {
+            if let Some(v) = v {
+                push(tag, name, v.to_string(), "deg");
+            }
+        }
+        let speeds = [
+            (
+                tag::PLATFORM_TRUE_AIRSPEED,
+                "Platform True Airspeed",
+                self.platform_true_airspeed,
+            ),
+            (
+                tag::PLATFORM_INDICATED_AIRSPEED,
+                "Platform Indicated Airspeed",
+                self.platform_indicated_airspeed,
+            ),
+        ];
+        for (tag, name, v) in speeds {
+            if let Some(v) = v {
+                push(tag, name, v.to_string(), "m/s");
+            }
+        }
+        let strings = [
+            (
+                tag::PLATFORM_DESIGNATION,
+                "Platform Designation",
+                &self.platform_designation,
+            ),
+            (
+                tag::IMAGE_SOURCE_SENSOR,
+                "Image Source Sensor",
+                &self.image_source_sensor,
+            ),
+            (
+                tag::IMAGE_COORDINATE_SYSTEM,
+                "Image Coordinate System",
+                &self.image_coordinate_system,
+            ),
+        ];
+        for (tag, name, v) in strings {
+            if let Some(v) = v {
+                push(tag, name, v.clone(), "");
+            }
+        }
+        let mapped = [
+            (
+                tag::SENSOR_LATITUDE,
+                "Sensor Latitude",
+                self.sensor_latitude,
+                "deg",
+            ),
+            (
+                tag::SENSOR_LONGITUDE,
+                "Sensor Longitude",
+                self.sensor_longitude,
+                "deg",
+            ),
+            (
+                tag::SENSOR_TRUE_ALTITUDE,
+                "Sensor True Altitude",
+                self.sensor_true_altitude,
+                "m",
+            ),
+            (
+                tag::SENSOR_HORIZONTAL_FOV,
+                "Sensor Horizontal Field of View",
+                self.sensor_horizontal_fov,
+                "deg",
+            ),
+            (
+                tag::SENSOR_VERTICAL_FOV,
+                "Sensor Vertical Field of View",
+                self.sensor_vertical_fov,
+                "deg",
+            ),
+            (
+                tag::SENSOR_RELATIVE_AZIMUTH,
+                "Sensor Relative Azimuth Angle",
+                self.sensor_relative_azimuth,
+                "deg",
+            ),
+            (
+                tag::SENSOR_RELATIVE_ELEVATION,
+                "Sensor Relative Elevation Angle",
+                self.sensor_relative_elevation,
+                "deg",
+            ),
+            (
+                tag::SENSOR_RELATIVE_ROLL,
+                "Sensor Relative Roll Angle",
+                self.sensor_relative_roll,
+                "deg",
+            ),
+            (tag::SLANT_RANGE, "Slant Range", self.slant_range, "m"),
+            (tag::TARGET_WIDTH, "Target Width", self.target_width, "m"),
+            (
+                tag::FRAME_CENTER_LATITUDE,
+                "Frame Center Latitude",
+                self.frame_center_latitude,
+                "deg",
+            ),
+            (
+                tag::FRAME_CENTER_LONGITUDE,
+                "Frame Center Longitude",
+                self.frame_center_longitude,
+                "deg",
+            ),
+            (
+                tag::FRAME_CENTER_ELEVATION,
+                "Frame Center Elevation",
+                self.frame_center_elevation,
+                "m",
+            ),
+        ];
+        for (tag, name, v, unit) in mapped {
+            if let Some(v) = v {
+                push(tag, name, v.to_string(), unit);
+            }
+        }
+        const CORNER_NAMES: [&str; 8] = [
+            "Offset Corner Latitude Point 1",
+            "Offset Corner Longitude Point 1",
+            "Offset Corner Latitude Point 2",
+            "Offset Corner Longitude Point 2",
+            "Offset Corner Latitude Point 3",
+            "Offset Corner Longitude Point 3",
+            "Offset Corner Latitude Point 4",
+            "Offset Corner Longitude Point 4",
+        ];
+        for i in 0..4 {
+            let corner = [
+                self.offset_corner_latitude[i],
+                self.offset_corner_longitude[i],
+            ];
+            for (j, v) in corner.into_iter().enumerate() {
+                if let Some(v) = v {
+                    let tag = tag::OFFSET_CORNER_LATITUDE_POINT_1 + (2 * i + j) as u64;
+                    push(tag, CORNER_NAMES[2 * i + j], v.to_string(), "deg");
+                }
+            }
+        }
+        if let Some(v) = &self.security {
+            push(
+                tag::SECURITY_LOCAL_SET,
+                "Security Local Set",
+                v.banner(),
+                "",
+            );
+        }
+        if let Some(v) = self.platform_ground_speed {
+            push(
+                tag::PLATFORM_GROUND_SPEED,
+                "Platform Ground Speed",
+                v.to_string(),
+                "m/s",
+            );
+        }
+        if let Some(v) = self.ls_version {
+            push(
+                tag::UAS_LS_VERSION_NUMBER,
+                "UAS Datalink LS Version Number",
+                v.to_string(),
+                "",
+            );
+        }
+        if let Some(v) = &self.vmti {
+            let value = format!("{} targets", v.targets.len());
+            push(tag::VMTI_LOCAL_SET, "VMTI Local Set", value, "");
+        }
+        if let Some(v) = self.target_width_extended {
+            push(
+                tag::TARGET_WIDTH_EXTENDED,
+                "Target Width Extended",
+                v.to_string(),
+                "m",
+            );
+        }
+        for (tag, v) in &self.unknown {
+            let value = v.iter().map(|b| format!("{b:02X}")).collect();
+            push(*tag, "Unknown", value, "");
+        }
+        fields
+    }
+
+    fn encode_items(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        // Precision time stamp is expected to be the first item.
+        if let Some(v) = self.precision_time_stamp {
+            write_local_set_item(tag::PRECISION_TIME_STAMP, &v.to_be_bytes(), &mut out);
+        }
+        let strings = [
+            (tag::MISSION_ID, &self.mission_id),
+            (tag::PLATFORM_TAIL_NUMBER, &self.platform_tail_number),
+            (tag::PLATFORM_DESIGNATION, &self.platform_designation),
+            (tag::IMAGE_SOURCE_SENSOR, &self.image_source_sensor),
+            (tag::IMAGE_COORDINATE_SYSTEM, &self.image_coordinate_system),
+        ];
+        for (tag, v) in strings {
+            if let Some(v) = v {
+                write_local_set_item(tag, v.as_bytes(), &mut out);
+            }
+        }
+        let bytes = [
+            (tag::PLATFORM_TRUE_AIRSPEED, self.platform_true_airspeed),
+            (
+                tag::PLATFORM_INDICATED_AIRSPEED,
+                self.platform_indicated_airspeed,
+            ),
+            (tag::PLATFORM_GROUND_SPEED, self.platform_ground_speed),
+        ];
+        for (tag, v) in bytes {
+            if let Some(v) = v {
+                write_local_set_item(tag, &[v], &mut out);
+            }
+        }
+        let mapped = [
+            (
+                tag::PLATFORM_HEADING,
+                self.platform_heading,
+                Mapping::HEADING,
+            ),
+            (tag::PLATFORM_PITCH, self.platform_pitch, Mapping::PITCH),
+            (tag::PLATFORM_ROLL, self.platform_roll, Mapping::ROLL),
+            (
+                tag::SENSOR_LATITUDE,
+                self.sensor_latitude,
+                Mapping::LATITUDE,
+            ),
+            (
+                tag::SENSOR_LONGITUDE,
+                self.sensor_longitude,
+                Mapping::LONGITUDE,
+            ),
+            (
+                tag::SENSOR_TRUE_ALTITUDE,
+                self.sensor_true_altitude,
+                Mapping::ALTITUDE,
+            ),
+            (
+                tag::SENSOR_HORIZONTAL_FOV,
+                self.sensor_horizontal_fov,
+                Mapping::FOV,
+            ),
+            (
+                tag::SENSOR_VERTICAL_FOV,
+                self.sensor_vertical_fov,
+                Mapping::FOV,
+            ),
+            (
+                tag::SENSOR_RELATIVE_AZIMUTH,
+                self.sensor_relative_azimuth,
+                Mapping::ANGLE,
+            ),
+            (
+                tag::SENSOR_RELATIVE_ELEVATION,
+                self.sensor_relative_elevation,
+                Mapping::SIGNED_ANGLE,
+            ),
+            (
+                tag::SENSOR_RELATIVE_ROLL,
+                self.sensor_relative_roll,
+                Mapping::ANGLE,
+            ),
+            (tag::SLANT_RANGE, self.slant_range, Mapping::SLANT_RANGE),
+            (tag::TARGET_WIDTH, self.target_width, Mapping::TARGET_WIDTH),
+            (
+                tag::FRAME_CENTER_LATITUDE,
+                self.frame_center_latitude,
+                Mapping::LATITUDE,
+            ),
+            (
+                tag::FRAME_CENTER_LONGITUDE,
+                self.frame_center_longitude,
+                Mapping::LONGITUDE,
+            ),
+            (
+                tag::FRAME_CENTER_ELEVATION,
+                self.frame_center_elevation,
+                Mapping::ALTITUDE,
+            ),
+        ];
+        for (tag, v, mapping) in mapped {
+            if let Some(v) = v {
+                write_local_set_item(tag, &mapping.encode(v), &mut out);
+            }
+        }
+        for i in 0..4 {
+            let tag = tag::OFFSET_CORNER_LATITUDE_POINT_1 + 2 * i as u64;
+            if let Some(v) = self.offset_corner_latitude[i] {
+                write_local_set_item(tag, &Mapping::CORNER_OFFSET.encode(v), &mut out);
+            }
+            if let Some(v) = self.offset_corner_longitude[i] {
+                write_local_set_item(tag + 1, &Mapping::CORNER_OFFSET.encode(v), &mut out);
+            }
+        }
+        if let Some(v) = self.ls_version {
+            write_local_set_item(tag::UAS_LS_VERSION_NUMBER, &[v], &mut out);
+        }
+        if let Some(v) = &self.security {
+            write_local_set_item(tag::SECURITY_LOCAL_SET, &v.encode_value(), &mut out);
+        }
+        if let Some(v) = &self.vmti {
+            write_local_set_item(tag::VMTI_LOCAL_SET, &v.encode_value(), &mut out);
+        }
+        if let Some(v) = self.target_width_extended {
+            write_local_set_item(
+                tag::TARGET_WIDTH_EXTENDED,
+                &target_width_extended().encode(v),
+                &mut out,
+            );
+        }
+        for (tag, v) in &self.unknown {
+            write_local_set_item(*tag, v, &mut out);
+        }
+        out
+    }
+
+    fn decode_item(&mut self, tag: u64, value: &[u8]) -> Result<(), KlvError> {
+        match tag {
+            // Checksum is not part of the data, the whole packet is needed to verify it.
+            tag::CHECKSUM => {}
+            tag::PRECISION_TIME_STAMP => {
+                let bytes = value.try_into().map_err(|_| KlvError::InvalidLength {
+                    tag,
+                    len: value.len(),
+                })?;
+                self.precision_time_stamp = Some(u64::from_be_bytes(bytes));
+            }
+            tag::MISSION_ID => self.mission_id = Some(decode_string(value)),
+            tag::PLATFORM_TAIL_NUMBER => self.platform_tail_number = Some(decode_string(value)),
+            tag::PLATFORM_HEADING => self.platform_heading = Mapping::HEADING.decode(value, tag)?,
+            tag::PLATFORM_PITCH => self.platform_pitch = Mapping::PITCH.decode(value, tag)?,
+            tag::PLATFORM_ROLL => self.platform_roll = Mapping::ROLL.decode(value, tag)?,
+            tag::PLATFORM_TRUE_AIRSPEED => {
+                self.platform_true_airspeed = Some(decode_u8(value, tag)?)
+            }
+            tag::PLATFORM_INDICATED_AIRSPEED => {
+                self.platform_indicated_airspeed = Some(decode_u8(value, tag)?)
+            }
+            tag::PLATFORM_DESIGNATION => self.platform_designation = Some(decode_string(value)),
+            tag::IMAGE_SOURCE_SENSOR => self.image_source_sensor = Some(decode_string(value)),
+            tag::IMAGE_COORDINATE_SYSTEM => {
+                self.image_coordinate_system = Some(decode_string(value))
+            }
+            tag::SENSOR_LATITUDE => self.sensor_latitude = Mapping::LATITUDE.decode(value, tag)?,
+            tag::SENSOR_LONGITUDE => {
+                self.sensor_longitude = Mapping::LONGITUDE.decode(value, tag)?
+            }
+            tag::SENSOR_TRUE_ALTITUDE => {
+                self.sensor_true_altitude = Mapping::ALTITUDE.decode(value, tag)?
+            }
+            tag::SENSOR_HORIZONTAL_FOV => {
+                self.sensor_horizontal_fov = Mapping::FOV.decode(value, tag)?
+            }
+            tag::SENSOR_VERTICAL_FOV => {
+                self.sensor_vertical_fov = Mapping::FOV.decode(value, tag)?
+            }
+            tag::SENSOR_RELATIVE_AZIMUTH => {
+                self.sensor_relative_azimuth = Mapping::ANGLE.decode(value, tag)?
+            }
+            tag::SENSOR_RELATIVE_ELEVATION => {
+                self.sensor_relative_elevation = Mapping::SIGNED_ANGLE.decode(value, tag)?
+            }
+            tag::SENSOR_RELATIVE_ROLL => {
+                self.sensor_relative_roll = Mapping::ANGLE.decode(value, tag)?
+            }
+            tag::SLANT_RANGE => self.slant_range = Mapping::SLANT_RANGE.decode(value, tag)?,
+            tag::TARGET_WIDTH => self.target_width = Mapping::TARGET_WIDTH.decode(value, tag)?,
+            tag::FRAME_CENTER_LATITUDE => {
+                self.frame_center_latitude = Mapping::LATITUDE.decode(value, tag)?
+            }
+            tag::FRAME_CENTER_LONGITUDE => {
+                self.frame_center_longitude = Mapping::LONGITUDE.decode(value, tag)?
+            }
+            tag::FRAME_CENTER_ELEVATION => {
+                self.frame_center_elevation = Mapping::ALTITUDE.decode(value, tag)?
+            }
+            tag::OFFSET_CORNER_LATITUDE_POINT_1..=tag::OFFSET_CORNER_LONGITUDE_POINT_4 => {
+                let offset = (tag - tag::OFFSET_CORNER_LATITUDE_POINT_1) as usize;
+                let v = Mapping::CORNER_OFFSET.decode(value, tag)?;
+                match offset % 2 {
+                    0 => self.offset_corner_latitude[offset / 2] = v,
+                    _ => self.offset_corner_longitude[offset / 2] = v,
+                }
+            }
+            tag::SECURITY_LOCAL_SET => self.security = Some(SecurityLocalSet::decode_value(value)?),
+            tag::PLATFORM_GROUND_SPEED => self.platform_ground_speed = Some(decode_u8(value, tag)?),
+            tag::UAS_LS_VERSION_NUMBER => self.ls_version = Some(decode_u8(value, tag)?),
+            tag::VMTI_LOCAL_SET => self.vmti = Some(VmtiLocalSet::decode_value(value)?),
+            tag::TARGET_WIDTH_EXTENDED => {
+                self.target_width_extended = Some(target_width_extended().decode(value)?)
+            }
+            _ => self.unknown.push((tag, value.to_vec())),
+        }
+        Ok(())
+    }
+}
+
+fn target_width_extended() -> Imapb {
+    Imapb::new(0.0, 1_500_000.0, 3)
+}
+
+/// Linear mapping between a floating point range and a fixed size integer.
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    min: f64,
+    max: f64,
+    bytes: usize,
+    signed: bool,
+}
+
+impl Mapping {
+    const HEADING: Mapping = Mapping::unsigned(0.0, 360.0, 2);
+    const PITCH: Mapping = Mapping::signed(20.0, 2);
+    const ROLL: Mapping = Mapping::signed(50.0, 2);
+    const LATITUDE: Mapping = Mapping::signed(90.0, 4);
+    const LONGITUDE: Mapping = Mapping::signed(180.0, 4);
+    const ALTITUDE: Mapping = Mapping::unsigned(-900.0, 19000.0, 2);
+    const FOV: Mapping = Mapping::unsigned(0.0, 180.0, 2);
+    const ANGLE: Mapping = Mapping::unsigned(0.0, 360.0, 4);
+    const SIGNED_ANGLE: Mapping = Mapping::signed(180.0, 4);
+    const SLANT_RANGE: Mapping = Mapping::unsigned(0.0, 5_000_000.0, 4);
+    const TARGET_WIDTH: Mapping = Mapping::unsigned(0.0, 10_000.0, 2);
+    const CORNER_OFFSET: Mapping = Mapping::signed(0.075, 2);
+
+    const fn unsigned(min: f64, max: f64, bytes: usize) -> Self {
+        Mapping {
+            min,
+            max,
+            bytes,
+            signed: false,
+        }
+    }
+
+    const fn signed(max: f64, bytes: usize) -> Self {
+        Mapping {
+            min: -max,
+            max,
+            bytes,
+            signed: true,
+        }
+    }
+
+    fn encode(&self, value: f64) -> Vec<u8> {
+        if self.signed {
+            map_signed(value, self.max, self.bytes)
+        } else {
+            map_unsigned(value, self.min, self.max, self.bytes)
+        }
+    }
+
+    fn decode(&self, value: &[u8], tag: u64) -> Result<Option<f64>, KlvError> {
+        if self.signed {
+            unmap_signed(value, self.max, self.bytes, tag)
+        } else {
+            unmap_unsigned(value, self.min, self.max, self.bytes)
+                .map(Some)
+                .ok_or(KlvError::InvalidLength {
+                    tag,
+                    len: value.len(),
+                })
+        }
+    }
+}
+
+/// Maps `value` from `min..=max` to `0..=2^(8 * bytes) - 1`, values outside the range are clamped.
+fn map_unsigned(value: f64, min: f64, max: f64, bytes: usize) -> Vec<u8> {
+    let int_max = (u64::MAX >> (64 - 8 * bytes)) as f64;
+    let mapped = ((value.clamp(min, max) - min) * int_max / (max - min)).round() as u64;
+    mapped.to_be_bytes()[8 - bytes..].to_vec()
+}
+
+fn unmap_unsigned(value: &[u8], min: f64, max: f64, bytes: usize) -> Option<f64> {
+    if value.len() != bytes {
+        return None;
+    }
+    let int_max = (u64::MAX >> (64 - 8 * bytes)) as f64;
+    let raw = value.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    Some(min + raw as f64 * (max - min) / int_max)
+}
+
+/// Maps `value` from `-max..=max` to `-(2^(8 * bytes - 1) - 1)..=2^(8 * bytes - 1) - 1`.
+/// Out of range and NaN values are sent as the reserved "error" value `0x80..00`.
+fn map_signed(value: f64, max: f64, bytes: usize) -> Vec<u8> {
+    let int_max = (i64::MAX >> (64 - 8 * bytes)) as f64;
+    let mapped = if value.is_nan() || value.abs() > max {
+        i64::MIN >> (64 - 8 * bytes)
+    } else {
+        (value * int_max / max).round() as i64
+    };
+    mapped.to_be_bytes()[8 - bytes..].to_vec()
+}
+
+/// Inverse of `map_signed`, the reserved error value is decoded as `None`.
+fn unmap_signed(value: &[u8], max: f64, bytes: usize, tag: u64) -> Result<Option<f64>, KlvError> {
+    if value.len() != bytes {
+        return Err(KlvError::InvalidLength {
+            tag,
+            len: value.len(),
+        });
+    }
+    let int_max = (i64::MAX >> (64 - 8 * bytes)) as f64;
+    // Sign extend by shifting the big endian value to the top of i64 and back.
+    let raw = value.iter().fold(0i64, |acc, b| (acc << 8) | *b as i64) << (64 - 8 * bytes)
+        >> (64 - 8 * bytes);
+    if raw == i64::MIN >> (64 - 8 * bytes) {
+        return Ok(None);
+    }
+    Ok(Some(raw as f64 * max / int_max))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::klv::codec::{verify_checksum, Checksum};
+    use crate::klv::st0102::{Classification, ISO_3166_THREE_LETTER};
+    use crate::klv::st0903::VTarget;
+
+    /// Items 2 to 25 of the example in ST 0601 with the running sum checksum.
+    pub(crate) const EXAMPLE_PACKET: [u8; 156] = [
+        0x06, 0x0E, 0x2B, 0x34, 0x02, 0x0B, 0x01, 0x01, 0x0E, 0x01, 0x03, 0x01, 0x01, 0x00, 0x00,
+        0x00, 0x81, 0x8A, 0x02, 0x08, 0x00, 0x04, 0x59, 0xF4, 0xA6, 0xAA, 0x4A, 0xA8, 0x03, 0x09,
+        0x4D, 0x49, 0x53, 0x53, 0x49, 0x4F, 0x4E, 0x30, 0x31, 0x04, 0x06, 0x41, 0x46, 0x2D, 0x31,
+        0x30, 0x31, 0x05, 0x02, 0x71, 0xC2, 0x06, 0x02, 0xFD, 0x3D, 0x07, 0x02, 0x08, 0xB8, 0x08,
+        0x01, 0x93, 0x09, 0x01, 0x9F, 0x0A, 0x05, 0x4D, 0x51, 0x31, 0x2D, 0x42, 0x0B, 0x02, 0x45,
+        0x4F, 0x0C, 0x06, 0x57, 0x47, 0x53, 0x2D, 0x38, 0x34, 0x0D, 0x04, 0x55, 0x95, 0xB6, 0x6D,
+        0x0E, 0x04, 0x5B, 0x53, 0x60, 0xC4, 0x0F, 0x02, 0xC2, 0x21, 0x10, 0x02, 0xCD, 0x9C, 0x11,
+        0x02, 0xD9, 0x17, 0x12, 0x04, 0x72, 0x4A, 0x0A, 0x20, 0x13, 0x04, 0x87, 0xF8, 0x4B, 0x86,
+        0x14, 0x04, 0x7D, 0xC5, 0x5E, 0xCE, 0x15, 0x04, 0x03, 0x83, 0x09, 0x26, 0x16, 0x02, 0x12,
+        0x81, 0x17, 0x04, 0xF1, 0x01, 0xA2, 0x29, 0x18, 0x04, 0x14, 0xBC, 0x08, 0x2B, 0x19, 0x02,
+        0x34, 0xF3, 0x01, 0x02, 0xB4, 0xA1,
+    ];
+
+    /// Values of `EXAMPLE_PACKET` as given in the standard, slant range and sensor relative roll
+    /// with the digits of their encoded value.
+    fn example_set() -> UasDatalinkLocalSet {
+        UasDatalinkLocalSet {
+            precision_time_stamp: Some(1_224_807_209_913_000),
+            mission_id: Some("MISSION01".into()),
+            platform_tail_number: Some("AF-101".into()),
+            platform_heading: Some(159.9744),
+            platform_pitch: Some(-0.4315251),
+            platform_roll: Some(3.405814),
+            platform_true_airspeed: Some(147),
+            platform_indicated_airspeed: Some(159),
+            platform_designation: Some("MQ1-B".into()),
+            image_source_sensor: Some("EO".into()),
+            image_coordinate_system: Some("WGS-84".into()),
+            sensor_latitude: Some(60.17682296),
+            sensor_longitude: Some(128.42675904),
+            sensor_true_altitude: Some(14190.72),
+            sensor_horizontal_fov: Some(144.5713),
+            sensor_vertical_fov: Some(152.6436),
+            sensor_relative_azimuth: Some(160.71921147),
+            sensor_relative_elevation: Some(-168.79232483),
+            sensor_relative_roll: Some(176.8654376),
+            slant_range: Some(68590.983),
+            target_width: Some(722.8199),
+            frame_center_latitude: Some(-10.54238863),
+            frame_center_longitude: Some(29.15789012),
+            frame_center_elevation: Some(3216.037),
+            ..Default::default()
+        }
+    }
+
+    /// Every typed item set.
+    fn full_set() -> UasDatalinkLocalSet {
+        UasDatalinkLocalSet {
+            offset_corner_latitude: [Some(-0.0376), Some(0.0125), Some(0.0), Some(-0.075)],
+            offset_corner_longitude: [Some(0.0459), Some(-0.0212), Some(0.075), Some(0.001)],
+            security: Some(SecurityLocalSet {
+                classification: Classification::Secret,
+                classifying_country_coding_method: Some(ISO_3166_THREE_LETTER),
+                classifying_country: Some("//USA".into()),
+                releasing_instructions: Some("REL TO USA, GBR".into()),
+                version: Some(12),
+                ..Default::default()
+            }),
+            platform_ground_speed: Some(140),
+            ls_version: Some(LS_VERSION),
+            vmti: Some(VmtiLocalSet {
+                version: Some(6),
+                frame_width: Some(1280),
+                frame_height: Some(720),
+                targets: vec![VTarget {
+                    id: 1,
+                    centroid: Some(460_801),
+                    confidence: Some(80),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            target_width_extended: Some(13898.5463),
+            ..example_set()
+        }
+    }
+
+    /// Value of one unit of the encoded integer.
+    fn step(mapping: Mapping) -> f64 {
+        if mapping.signed {
+            mapping.max / (i64::MAX >> (64 - 8 * mapping.bytes)) as f64
+        } else {
+            (mapping.max - mapping.min) / (u64::MAX >> (64 - 8 * mapping.bytes)) as f64
+        }
+    }
+
+    macro_rules! mapped_fields {
+        ($($field:ident: $step:expr),+ $(,)?) => {
+            /// Mapped values of `set` with the precision they are sent with.
+            fn mapped(set: &UasDatalinkLocalSet) -> Vec<(&'static str, Option<f64>, f64)> {
+                let mut values = vec![$((stringify!($field), set.$field, $step)),+];
+                for i in 0..4 {
+                    let step = step(Mapping::CORNER_OFFSET);
+                    values.push(("offset_corner_latitude", set.offset_corner_latitude[i], step));
+                    values.push(("offset_corner_longitude", set.offset_corner_longitude[i], step));
+                }
+                values
+            }
+
+            fn without_mapped(mut set: UasDatalinkLocalSet) -> UasDatalinkLocalSet {
+                $(set.$field = None;)+
+                set.offset_corner_latitude = [None; 4];
+                set.offset_corner_longitude = [None; 4];
+                set
+            }
+        };
+    }
+
+    mapped_fields! {
+        platform_heading: step(Mapping::HEADING),
+        platform_pitch: step(Mapping::PITCH),
+        platform_roll: step(Mapping::ROLL),
+        sensor_latitude: step(Mapping::LATITUDE),
+        sensor_longitude: step(Mapping::LONGITUDE),
+        sensor_true_altitude: step(Mapping::ALTITUDE),
+        sensor_horizontal_fov: step(Mapping::FOV),
+        sensor_vertical_fov: step(Mapping::FOV),
+        sensor_relative_azimuth: step(Mapping::ANGLE),
+        sensor_relative_elevation: step(Mapping::SIGNED_ANGLE),
+        sensor_relative_roll: step(Mapping::ANGLE),
+        slant_range: step(Mapping::SLANT_RANGE),
+        target_width: step(Mapping::TARGET_WIDTH),
+        frame_center_latitude: step(Mapping::LATITUDE),
+        frame_center_longitude: step(Mapping::LONGITUDE),
+        frame_center_elevation: step(Mapping::ALTITUDE),
+        // IMAPB of 3 bytes up to 1500 km has 2^-3 m precision.
+        target_width_extended: 0.125,
+    }
+
+    /// Mapped values are equal within their precision and everything else exactly.
+    fn assert_same(actual: &UasDatalinkLocalSet, expected: &UasDatalinkLocalSet) {
+        for ((name, a, step), (_, e, _)) in mapped(actual).into_iter().zip(mapped(expected)) {
+            match (a, e) {
+                (Some(a), Some(e)) => assert!((a - e).abs() <= step, "{name}: {a} is not {e}"),
+                _ => assert_eq!(a, e, "{name}"),
+            }
+        }
+        assert_eq!(
+            without_mapped(actual.clone()),
+            without_mapped(expected.clone())
+        );
+    }
+
+    fn round_trip(set: &UasDatalinkLocalSet) -> UasDatalinkLocalSet {
+        UasDatalinkLocalSet::decode(&set.encode()).unwrap()
+    }
+
+    fn tags(packet: &KlvPacket) -> Vec<u64> {
+        let mut tags: Vec<u64> = parse_local_set(&packet.value)
+            .unwrap()
+            .into_iter()
+            .map(|(tag, _)| tag)
+            .collect();
+        tags.sort_unstable();
+        tags
+    }
+
+    #[test]
+    fn example_packet() {
+        assert_eq!(
+            verify_checksum(&EXAMPLE_PACKET, Checksum::RunningSum),
+            Ok(())
+        );
+        let (packet, used) = KlvPacket::decode(&EXAMPLE_PACKET).unwrap();
+        assert_eq!(used, EXAMPLE_PACKET.len());
+        assert_same(
+            &UasDatalinkLocalSet::decode(&packet).unwrap(),
+            &example_set(),
+        );
+    }
+
+    #[test]
+    fn round_trip_every_tag() {
+        let mut set = full_set();
+        set.unknown = vec![(59, b"CALLSIGN".to_vec())];
+        let packet = set.encode();
+        let mut expected: Vec<u64> = (1..=33).collect();
+        expected.extend([48, 56, 59, 65, 74, 96]);
+        assert_eq!(tags(&packet), expected);
+        // Checksum is the last item.
+        assert_eq!(packet.value[packet.value.len() - 4..][..2], [0x01, 0x02]);
+
+        let decoded = round_trip(&set);
+        assert_same(&decoded, &set);
+        // Decoded values are encoded to the same integers again.
+        assert_eq!(round_trip(&decoded), decoded);
+    }
+
+    #[test]
+    fn imapb_target_width_extended() {
+        let set = UasDatalinkLocalSet {
+            target_width_extended: Some(13898.5463),
+            ..Default::default()
+        };
+        let packet = set.encode();
+        assert_eq!(packet.value[..5], [0x60, 0x03, 0x00, 0xD9, 0x2A]);
+        assert_eq!(round_trip(&set).target_width_extended, Some(13898.5));
+    }
+
+    #[test]
+    fn out_of_range_and_nan() {
+        let set = UasDatalinkLocalSet {
+            platform_heading: Some(400.0),
+            platform_pitch: Some(25.0),
+            platform_roll: Some(f64::NAN),
+            sensor_latitude: Some(-91.0),
+            sensor_true_altitude: Some(-1000.0),
+            sensor_horizontal_fov: Some(f64::NAN),
+            ..Default::default()
+        };
+        let packet = set.encode();
+        let items = parse_local_set(&packet.value).unwrap();
+        // Signed mappings send the reserved error value.
+        assert!(items.contains(&(tag::PLATFORM_PITCH, &[0x80, 0x00][..])));
+        assert!(items.contains(&(tag::PLATFORM_ROLL, &[0x80, 0x00][..])));
+        assert!(items.contains(&(tag::SENSOR_LATITUDE, &[0x80, 0x00, 0x00, 0x00][..])));
+
+        let decoded = round_trip(&set);
+        assert_eq!(decoded.platform_pitch, None);
+        assert_eq!(decoded.platform_roll, None);
+        assert_eq!(decoded.sensor_latitude, None);
+        // Unsigned mappings are clamped, NaN to the minimum.
+        assert_eq!(decoded.platform_heading, Some(360.0));
+        assert_eq!(decoded.sensor_true_altitude, Some(-900.0));
+        assert_eq!(decoded.sensor_horizontal_fov, Some(0.0));
+    }
+
+    #[test]
+    fn invalid_length() {
+        let mut value = Vec::new();
+        write_local_set_item(tag::PLATFORM_HEADING, &[0x71, 0xC2, 0x00], &mut value);
+        let packet = encode_with_checksum(UAS_DATALINK_LS_KEY, value, Checksum::RunningSum);
+        assert_eq!(
+            UasDatalinkLocalSet::decode(&packet),
+            Err(KlvError::InvalidLength { tag: 5, len: 3 })
+        );
+    }
+
+    #[test]
+    fn unknown_tags_kept() {
+        let mut value = Vec::new();
+        write_local_set_item(tag::PRECISION_TIME_STAMP, &[0; 8], &mut value);
+        write_local_set_item(59, b"CALLSIGN", &mut value);
+        write_local_set_item(tag::PLATFORM_HEADING, &[0x71, 0xC2], &mut value);
+        write_local_set_item(200, &[1, 2, 3], &mut value);
+        let packet = encode_with_checksum(UAS_DATALINK_LS_KEY, value, Checksum::RunningSum);
+
+        let set = UasDatalinkLocalSet::decode(&packet).unwrap();
+        assert_eq!(
+            set.unknown,
+            vec![(59, b"CALLSIGN".to_vec()), (200, vec![1, 2, 3])]
+        );
+        // Encoded after the typed items in the order they came.
+        let packet = set.encode();
+        let items = parse_local_set(&packet.value).unwrap();
+        let n = items.len();
+        assert_eq!(
+            items[n - 3..n - 1],
+            [(59, &b"CALLSIGN"[..]), (200, &[1, 2, 3][..])]
+        );
+        assert_eq!(round_trip(&set), set);
+    }
+
+    #[test]
+    fn nested_sets() {
+        let set = full_set();
+        let packet = set.encode();
+        let items = parse_local_set(&packet.value).unwrap();
+        let item = |tag| items.iter().find(|(t, _)| *t == tag).unwrap().1;
+        // Nested sets are items only, without key, length or checksum.
+        let security = set.security.as_ref().unwrap();
+        assert_eq!(item(tag::SECURITY_LOCAL_SET), security.encode_value());
+        let vmti = set.vmti.as_ref().unwrap();
+        assert_eq!(item(tag::VMTI_LOCAL_SET), vmti.encode_value());
+
+        let decoded = round_trip(&set);
+        assert_eq!(decoded.security.as_ref(), Some(security));
+        assert_eq!(decoded.vmti.as_ref(), Some(vmti));
+    }
+
+    #[test]
+    fn fields() {
+        let set = UasDatalinkLocalSet {
+            precision_time_stamp: Some(1_224_807_209_913_000),
+            mission_id: Some("MISSION01".into()),
+            platform_heading: Some(12.5),
+            platform_true_airspeed: Some(147),
+            sensor_true_altitude: Some(1500.0),
+            offset_corner_longitude: [None, Some(-0.0212), None, None],
+            security: full_set().security,
+            vmti: full_set().vmti,
+            target_width_extended: Some(13898.5),
+            unknown: vec![(59, vec![0xAB, 0x01])],
+            ..Default::default()
+        };
+        let fields: Vec<_> = set
+            .fields()
+            .into_iter()
+            .map(|field| (field.tag, field.name, field.value, field.unit))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                (2, "Precision Time Stamp", "1224807209913000", "us"),
+                (3, "Mission ID", "MISSION01", ""),
+                (5, "Platform Heading Angle", "12.5", "deg"),
+                (8, "Platform True Airspeed", "147", "m/s"),
+                (15, "Sensor True Altitude", "1500", "m"),
+                (29, "Offset Corner Longitude Point 2", "-0.0212", "deg"),
+                (48, "Security Local Set", "SECRET//USA//REL TO USA, GBR", ""),
+                (74, "VMTI Local Set", "1 targets", ""),
+                (96, "Target Width Extended", "13898.5", "m"),
+                (59, "Unknown", "AB01", ""),
+            ]
+            .map(|(tag, name, value, unit)| (tag, name, value.to_owned(), unit))
+        );
+    }
+}
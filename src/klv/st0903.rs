@@ -0,0 +1,372 @@
+//! MISB ST 0903 Video Moving Target Indicator (VMTI) Local Set.
+//!
+//! Sent either as a standalone packet with its own key and checksum or nested in ST 0601
+//! tag 74, where it is a plain local set without key and checksum.
+
+use super::codec::{
+    decode_ber_length, decode_ber_oid, decode_string, decode_u8, encode_ber_length, encode_ber_oid,
+    encode_with_checksum, parse_local_set, write_local_set_item, Checksum, KlvError, KlvKey,
+    KlvPacket,
+};
+use super::imapb::Imapb;
+
+pub const VMTI_LS_KEY: KlvKey = KlvKey::new([
+    0x06, 0x0E, 0x2B, 0x34, 0x02, 0x0B, 0x01, 0x01, 0x0E, 0x01, 0x03, 0x03, 0x06, 0x00, 0x00, 0x00,
+]);
+
+/// ST 0903 revision we follow, sent in tag 4.
+pub const LS_VERSION: u16 = 6;
+
+pub mod tag {
+    pub const CHECKSUM: u64 = 1;
+    pub const PRECISION_TIME_STAMP: u64 = 2;
+    pub const SYSTEM_NAME: u64 = 3;
+    pub const LS_VERSION: u64 = 4;
+    pub const TOTAL_TARGETS_DETECTED: u64 = 5;
+    pub const REPORTED_TARGETS: u64 = 6;
+    pub const FRAME_NUMBER: u64 = 7;
+    pub const FRAME_WIDTH: u64 = 8;
+    pub const FRAME_HEIGHT: u64 = 9;
+    pub const SOURCE_SENSOR: u64 = 10;
+    pub const HORIZONTAL_FOV: u64 = 11;
+    pub const VERTICAL_FOV: u64 = 12;
+    pub const VTARGET_SERIES: u64 = 101;
+}
+
+/// Tags inside of a VTarget pack.
+pub mod target_tag {
+    pub const CENTROID: u64 = 1;
+    pub const BOUNDARY_TOP_LEFT: u64 = 2;
+    pub const BOUNDARY_BOTTOM_RIGHT: u64 = 3;
+    pub const PRIORITY: u64 = 4;
+    pub const CONFIDENCE_LEVEL: u64 = 5;
+}
+
+/// ST 0903 pixel number of zero based `column` and `row`.
+/// Pixel numbers start from 1 at the top left corner and run row by row.
+pub fn pixel_number(column: u32, row: u32, frame_width: u32) -> u64 {
+    u64::from(row) * u64::from(frame_width) + u64::from(column) + 1
+}
+
+/// Zero based `(column, row)` of a pixel number, inverse of `pixel_number`.
+pub fn pixel_position(pixel: u64, frame_width: u32) -> (u32, u32) {
+    let index = pixel.saturating_sub(1);
+    let width = u64::from(frame_width.max(1));
+    ((index % width) as u32, (index / width) as u32)
+}
+
+/// One detected target, a VTarget pack.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VTarget {
+    pub id: u64,
+    /// Pixel number of the target centroid.
+    pub centroid: Option<u64>,
+    /// Pixel number of the top left corner of the bounding box.
+    pub boundary_top_left: Option<u64>,
+    /// Pixel number of the bottom right corner of the bounding box.
+    pub boundary_bottom_right: Option<u64>,
+    /// 1 is the highest priority.
+    pub priority: Option<u8>,
+    /// Detection confidence in percent.
+    pub confidence: Option<u8>,
+    pub unknown: Vec<(u64, Vec<u8>)>,
+}
+
+impl VTarget {
+    /// Bounding box as zero based `(x, y, width, height)` in pixels of a frame `frame_width` wide.
+    pub fn bounding_box(&self, frame_width: u32) -> Option<(u32, u32, u32, u32)> {
+        let (left, top) = pixel_position(self.boundary_top_left?, frame_width);
+        let (right, bottom) = pixel_position(self.boundary_bottom_right?, frame_width);
+        Some((
+            left.min(right),
+            top.min(bottom),
+            left.abs_diff(right) + 1,
+            top.abs_diff(bottom) + 1,
+        ))
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        encode_ber_oid(self.id, out);
+        let uints = [
+            (target_tag::CENTROID, self.centroid),
+            (target_tag::BOUNDARY_TOP_LEFT, self.boundary_top_left),
+            (
+                target_tag::BOUNDARY_BOTTOM_RIGHT,
+                self.boundary_bottom_right,
+            ),
+        ];
+        for (tag, v) in uints {
+            if let Some(v) = v {
+                write_local_set_item(tag, &encode_uint(v), out);
+            }
+        }
+        if let Some(v) = self.priority {
+            write_local_set_item(target_tag::PRIORITY, &[v], out);
+        }
+        if let Some(v) = self.confidence {
+            write_local_set_item(target_tag::CONFIDENCE_LEVEL, &[v], out);
+        }
+        for (tag, v) in &self.unknown {
+            write_local_set_item(*tag, v, out);
+        }
+    }
+
+    fn decode(data: &[u8]) -> Result<Self, KlvError> {
+        let (id, id_size) = decode_ber_oid(data)?;
+        let mut target = VTarget {
+            id,
+            ..Default::default()
+        };
+        for (tag, value) in parse_local_set(&data[id_size..])? {
+            match tag {
+                target_tag::CENTROID => target.centroid = Some(decode_uint(value, tag)?),
+                target_tag::BOUNDARY_TOP_LEFT => {
+                    target.boundary_top_left = Some(decode_uint(value, tag)?)
+                }
+                target_tag::BOUNDARY_BOTTOM_RIGHT => {
+                    target.boundary_bottom_right = Some(decode_uint(value, tag)?)
+                }
+                target_tag::PRIORITY => target.priority = Some(decode_u8(value, tag)?),
+                target_tag::CONFIDENCE_LEVEL => target.confidence = Some(decode_u8(value, tag)?),
+                _ => target.unknown.push((tag, value.to_vec())),
+            }
+        }
+        Ok(target)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VmtiLocalSet {
+    /// Microseconds since 1970-01-01 UTC.
+    pub precision_time_stamp: Option<u64>,
+    pub system_name: Option<String>,
+    pub version: Option<u16>,
+    pub total_targets_detected: Option<u64>,
+    pub reported_targets: Option<u64>,
+    pub frame_number: Option<u64>,
+    pub frame_width: Option<u32>,
+    pub frame_height: Option<u32>,
+    pub source_sensor: Option<String>,
+    /// Degrees.
+    pub horizontal_fov: Option<f64>,
+    /// Degrees.
+    pub vertical_fov: Option<f64>,
+    pub targets: Vec<VTarget>,
+    pub unknown: Vec<(u64, Vec<u8>)>,
+}
+
+impl VmtiLocalSet {
+    /// Encodes as standalone KLV packet with checksum.
+    pub fn encode(&self) -> KlvPacket {
+        encode_with_checksum(VMTI_LS_KEY, self.encode_value(), Checksum::Crc16Ccitt)
+    }
+
+    pub fn decode(packet: &KlvPacket) -> Result<Self, KlvError> {
+        if packet.key != VMTI_LS_KEY {
+            return Err(KlvError::UnexpectedKey { key: packet.key });
+        }
+        Self::decode_value(&packet.value)
+    }
+
+    /// Encodes local set items only, this is what goes into ST 0601 tag 74.
+    pub fn encode_value(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if let Some(v) = self.precision_time_stamp {
+            write_local_set_item(tag::PRECISION_TIME_STAMP, &v.to_be_bytes(), &mut out);
+        }
+        if let Some(v) = &self.system_name {
+            write_local_set_item(tag::SYSTEM_NAME, v.as_bytes(), &mut out);
+        }
+        let uints = [
+            (tag::LS_VERSION, self.version.map(u64::from)),
+            (tag::TOTAL_TARGETS_DETECTED, self.total_targets_detected),
+            (tag::REPORTED_TARGETS, self.reported_targets),
+            (tag::FRAME_NUMBER, self.frame_number),
+            (tag::FRAME_WIDTH, self.frame_width.map(u64::from)),
+            (tag::FRAME_HEIGHT, self.frame_height.map(u64::from)),
+        ];
+        for (tag, v) in uints {
+            if let Some(v) = v {
+                write_local_set_item(tag, &encode_uint(v), &mut out);
+            }
+        }
+        if let Some(v) = &self.source_sensor {
+            write_local_set_item(tag::SOURCE_SENSOR, v.as_bytes(), &mut out);
+        }
+        if let Some(v) = self.horizontal_fov {
+            write_local_set_item(tag::HORIZONTAL_FOV, &fov().encode(v), &mut out);
+        }
+        if let Some(v) = self.vertical_fov {
+            write_local_set_item(tag::VERTICAL_FOV, &fov().encode(v), &mut out);
+        }
+        if !self.targets.is_empty() {
+            // Series is a list of packs, each prefixed with its BER length.
+            let mut series = Vec::new();
+            for target in &self.targets {
+                let mut pack = Vec::new();
+                target.encode(&mut pack);
+                encode_ber_length(pack.len(), &mut series);
+                series.extend_from_slice(&pack);
+            }
+            write_local_set_item(tag::VTARGET_SERIES, &series, &mut out);
+        }
+        for (tag, v) in &self.unknown {
+            write_local_set_item(*tag, v, &mut out);
+        }
+        out
+    }
+
+    pub fn decode_value(data: &[u8]) -> Result<Self, KlvError> {
+        let mut set = VmtiLocalSet::default();
+        for (tag, value) in parse_local_set(data)? {
+            let invalid = || KlvError::InvalidLength {
+                tag,
+                len: value.len(),
+            };
+            match tag {
+                tag::CHECKSUM => {}
+                tag::PRECISION_TIME_STAMP => {
+                    let bytes = value.try_into().map_err(|_| invalid())?;
+                    set.precision_time_stamp = Some(u64::from_be_bytes(bytes));
+                }
+                tag::SYSTEM_NAME => set.system_name = Some(decode_string(value)),
+                tag::LS_VERSION => {
+                    set.version = Some(decode_uint(value, tag)?.try_into().map_err(|_| invalid())?)
+                }
+                tag::TOTAL_TARGETS_DETECTED => {
+                    set.total_targets_detected = Some(decode_uint(value, tag)?)
+                }
+                tag::REPORTED_TARGETS => set.reported_targets = Some(decode_uint(value, tag)?),
+                tag::FRAME_NUMBER => set.frame_number = Some(decode_uint(value, tag)?),
+                tag::FRAME_WIDTH => {
+                    set.frame_width =
+                        Some(decode_uint(value, tag)?.try_into().map_err(|_| invalid())?)
+                }
+                tag::FRAME_HEIGHT => {
+                    set.frame_height =
+                        Some(decode_uint(value, tag)?.try_into().map_err(|_| invalid())?)
+                }
+                tag::SOURCE_SENSOR => set.source_sensor = Some(decode_string(value)),
+                tag::HORIZONTAL_FOV => set.horizontal_fov = Some(fov().decode(value)?),
+                tag::VERTICAL_FOV => set.vertical_fov = Some(fov().decode(value)?),
+                tag::VTARGET_SERIES => {
+                    let mut series = value;
+                    while !series.is_empty() {
+                        let (len, len_size) = decode_ber_length(series).map_err(|_| invalid())?;
+                        let end = len_size.checked_add(len).ok_or_else(invalid)?;
+                        let pack = series.get(len_size..end).ok_or_else(invalid)?;
+                        set.targets.push(VTarget::decode(pack)?);
+                        series = &series[end..];
+                    }
+                }
+                _ => set.unknown.push((tag, value.to_vec())),
+            }
+        }
+        Ok(set)
+    }
+}
+
+fn fov() -> Imapb {
+    Imapb::new(0.0, 180.0, 2)
+}
+
+/// Unsigned integer in as few bytes as needed, but at least one.
+fn encode_uint(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = (value.leading_zeros() / 8).min(7) as usize;
+    bytes[skip..].to_vec()
+}
+
+fn decode_uint(value: &[u8], tag: u64) -> Result<u64, KlvError> {
+    if value.is_empty() || value.len() > 8 {
+        return Err(KlvError::InvalidLength {
+            tag,
+            len: value.len(),
+        });
+    }
+    Ok(value.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vmti() -> VmtiLocalSet {
+        VmtiLocalSet {
+            precision_time_stamp: Some(1_234_567_890_123_456),
+            version: Some(LS_VERSION),
+            frame_width: Some(1280),
+            frame_height: Some(720),
+            targets: vec![
+                VTarget {
+                    id: 1,
+                    centroid: Some(pixel_number(640, 360, 1280)),
+                    boundary_top_left: Some(pixel_number(600, 330, 1280)),
+                    boundary_bottom_right: Some(pixel_number(680, 390, 1280)),
+                    confidence: Some(80),
+                    ..Default::default()
+                },
+                VTarget {
+                    id: 200,
+                    priority: Some(1),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let set = vmti();
+        let packet = set.encode();
+        assert_eq!(VmtiLocalSet::decode(&packet), Ok(set.clone()));
+        assert_eq!(VmtiLocalSet::decode_value(&set.encode_value()), Ok(set));
+    }
+
+    #[test]
+    fn bounding_box() {
+        let target = &vmti().targets[0];
+        assert_eq!(target.bounding_box(1280), Some((600, 330, 81, 61)));
+        assert_eq!(pixel_position(pixel_number(5, 7, 1280), 1280), (5, 7));
+    }
+
+    fn series(value: &[u8]) -> Result<VmtiLocalSet, KlvError> {
+        let mut data = Vec::new();
+        write_local_set_item(tag::VTARGET_SERIES, value, &mut data);
+        VmtiLocalSet::decode_value(&data)
+    }
+
+    #[test]
+    fn truncated_series() {
+        // Pack of 5 bytes with only 2 of them.
+        assert_eq!(
+            series(&[0x05, 0x01, 0x01]),
+            Err(KlvError::InvalidLength {
+                tag: tag::VTARGET_SERIES,
+                len: 3
+            })
+        );
+        // Long form length without its octets.
+        assert_eq!(
+            series(&[0x82, 0x01]),
+            Err(KlvError::InvalidLength {
+                tag: tag::VTARGET_SERIES,
+                len: 2
+            })
+        );
+    }
+
+    #[test]
+    fn oversized_series() {
+        let mut value = vec![0x88];
+        value.extend_from_slice(&[0xFF; 8]);
+        assert_eq!(
+            series(&value),
+            Err(KlvError::InvalidLength {
+                tag: tag::VTARGET_SERIES,
+                len: 9
+            })
+        );
+    }
+}
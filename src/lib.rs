@@ -0,0 +1,30 @@
+//! Video with MISB KLV metadata through MPEG-TS, shared by `gstreamer-klv-test`, which sends
+//! and shows video in one pipeline, and by `klv-send` and `klv-recv`, which do the same over
+//! UDP, either as MPEG-TS or as RTP.
+//!
+//! Other programs build the same pipelines with [`builder::KlvPipelineBuilder`], replacing
+//! source, encoder, video sink and the KLV sent and received with their own.
+
+pub mod builder;
+pub mod check;
+pub mod config;
+pub mod dump;
+pub mod klv;
+pub mod media;
+pub mod mpegts;
+pub mod overlay;
+pub mod pipeline;
+pub mod playback;
+pub mod producer;
+pub mod receiver;
+pub mod recorder;
+pub mod rtp;
+pub mod run;
+pub mod sender;
+
+/// Logs at `level` unless `RUST_LOG` says otherwise.
+pub fn init_logger(level: log::LevelFilter) {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level.as_str()))
+        .format_timestamp_millis()
+        .init();
+}
@@ -0,0 +1,241 @@
+//! Video source and sink selection, so that the same pipeline runs on macOS, on Linux and
+//! on machines without camera or display.
+
+use anyhow::Error;
+use derive_more::{Display, Error};
+use gst::prelude::*;
+use gstreamer as gst;
+use std::{fmt, path::PathBuf, str::FromStr};
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "unknown video {kind} \"{value}\", expected one of: {expected}")]
+pub struct ParseMediaError {
+    kind: &'static str,
+    value: String,
+    expected: &'static str,
+}
+
+/// Where raw video frames come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoSource {
+    /// Whatever camera `autovideosrc` finds.
+    Auto,
+    /// macOS camera.
+    Avf,
+    /// Linux camera, with optional device such as `/dev/video0`.
+    V4l2(Option<String>),
+    /// Generated test pattern, for machines without camera.
+    Test,
+    /// Any file `decodebin` can decode.
+    File(PathBuf),
+}
+
+/// Where video with overlay goes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoSink {
+    Auto,
+    Osx,
+    Xv,
+    /// Drops frames, for machines without display.
+    Fake,
+    /// H.264 in Matroska.
+    File(PathBuf),
+}
+
+impl Default for VideoSource {
+    fn default() -> Self {
+        if cfg!(target_os = "macos") {
+            VideoSource::Avf
+        } else {
+            VideoSource::Auto
+        }
+    }
+}
+
+impl Default for VideoSink {
+    fn default() -> Self {
+        if cfg!(target_os = "macos") {
+            VideoSink::Osx
+        } else {
+            VideoSink::Auto
+        }
+    }
+}
+
+impl FromStr for VideoSource {
+    type Err = ParseMediaError;
+
+    /// Parses `auto`, `avf`, `v4l2`, `v4l2:<device>`, `test` or `file:<path>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match split_arg(s) {
+            ("auto", None) => Ok(VideoSource::Auto),
+            ("avf", None) => Ok(VideoSource::Avf),
+            ("v4l2", device) => Ok(VideoSource::V4l2(device.map(String::from))),
+            ("test", None) => Ok(VideoSource::Test),
+            ("file", Some(path)) => Ok(VideoSource::File(path.into())),
+            _ => Err(ParseMediaError {
+                kind: "source",
+                value: s.to_string(),
+                expected: "auto, avf, v4l2[:<device>], test, file:<path>",
+            }),
+        }
+    }
+}
+
+impl FromStr for VideoSink {
+    type Err = ParseMediaError;
+
+    /// Parses `auto`, `osx`, `xv`, `fake` or `file:<path>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match split_arg(s) {
+            ("auto", None) => Ok(VideoSink::Auto),
+            ("osx", None) => Ok(VideoSink::Osx),
+            ("xv", None) => Ok(VideoSink::Xv),
+            ("fake", None) => Ok(VideoSink::Fake),
+            ("file", Some(path)) => Ok(VideoSink::File(path.into())),
+            _ => Err(ParseMediaError {
+                kind: "sink",
+                value: s.to_string(),
+                expected: "auto, osx, xv, fake, file:<path>",
+            }),
+        }
+    }
+}
+
+impl fmt::Display for VideoSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VideoSource::Auto => write!(f, "auto"),
+            VideoSource::Avf => write!(f, "avf"),
+            VideoSource::V4l2(None) => write!(f, "v4l2"),
+            VideoSource::V4l2(Some(device)) => write!(f, "v4l2:{device}"),
+            VideoSource::Test => write!(f, "test"),
+            VideoSource::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+impl fmt::Display for VideoSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VideoSink::Auto => write!(f, "auto"),
+            VideoSink::Osx => write!(f, "osx"),
+            VideoSink::Xv => write!(f, "xv"),
+            VideoSink::Fake => write!(f, "fake"),
+            VideoSink::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+impl VideoSource {
+    /// Element with a `src` pad which gives raw video.
+    pub fn build(&self) -> Result<gst::Element, Error> {
+        let src = match self {
+            VideoSource::Auto => gst::ElementFactory::make("autovideosrc").build()?,
+            VideoSource::Avf => gst::ElementFactory::make("avfvideosrc").build()?,
+            VideoSource::V4l2(device) => {
+                // Cameras often give MJPEG or some YUV format the encoder does not take.
+                let bin = gst::parse_bin_from_description(
+                    "v4l2src name=camera ! decodebin ! videoconvert",
+                    true,
+                )?;
+                if let Some(device) = device {
+                    bin.by_name("camera")
+                        .expect("v4l2src is in the bin")
+                        .set_property("device", device);
+                }
+                bin.upcast()
+            }
+            VideoSource::Test => gst::ElementFactory::make("videotestsrc")
+                // Behave like a camera, otherwise frames are produced as fast as possible.
+                .property("is-live", true)
+                .build()?,
+            VideoSource::File(path) => {
+                let bin = gst::Bin::new();
+                let filesrc = gst::ElementFactory::make("filesrc")
+                    .property("location", path.to_string_lossy().as_ref())
+                    .build()?;
+                let decodebin = gst::ElementFactory::make("decodebin").build()?;
+                let convert = gst::ElementFactory::make("videoconvert").build()?;
+                bin.add_many(&[&filesrc, &decodebin, &convert])?;
+                filesrc.link(&decodebin)?;
+                let src_pad = convert
+                    .static_pad("src")
+                    .expect("videoconvert has a src pad");
+                bin.add_pad(&gst::GhostPad::with_target(&src_pad)?)?;
+
+                let bin_weak = bin.downgrade();
+                decodebin.connect_pad_added(move |_, src_pad| {
+                    let Some(bin) = bin_weak.upgrade() else {
+                        return;
+                    };
+                    if let Err(err) = link_decoded(&bin, src_pad, &convert) {
+                        log::warn!("failed to link decoded stream {}: {err}", src_pad.name());
+                    }
+                });
+                bin.upcast()
+            }
+        };
+        Ok(src)
+    }
+}
+
+impl VideoSink {
+    /// Element with a `sink` pad which takes raw video.
+    /// With `sync` off frames are shown as soon as they are ready.
+    pub fn build(&self, sync: bool) -> Result<gst::Element, Error> {
+        let sink = match self {
+            VideoSink::Auto => gst::ElementFactory::make("autovideosink").build()?,
+            VideoSink::Osx => gst::ElementFactory::make("osxvideosink").build()?,
+            VideoSink::Xv => gst::ElementFactory::make("xvimagesink").build()?,
+            VideoSink::Fake => gst::ElementFactory::make("fakesink").build()?,
+            VideoSink::File(path) => {
+                let bin = gst::parse_bin_from_description(
+                    "videoconvert ! x264enc tune=zerolatency ! h264parse ! matroskamux ! filesink name=file",
+                    true,
+                )?;
+                let filesink = bin.by_name("file").expect("filesink is in the bin");
+                filesink.set_property("location", path.to_string_lossy().as_ref());
+                filesink.set_property("sync", sync);
+                return Ok(bin.upcast());
+            }
+        };
+        if sink.has_property("sync", None) {
+            sink.set_property("sync", sync);
+        }
+        Ok(sink)
+    }
+}
+
+/// Links the first raw video stream of `decodebin` to `convert`, other streams such as audio go
+/// to a `fakesink` so that they don't stop decoding with not-linked errors.
+fn link_decoded(bin: &gst::Bin, src_pad: &gst::Pad, convert: &gst::Element) -> Result<(), Error> {
+    let caps = src_pad
+        .current_caps()
+        .unwrap_or_else(|| src_pad.query_caps(None));
+    let is_video = caps
+        .structure(0)
+        .is_some_and(|s| s.name().starts_with("video/"));
+    let sink_pad = convert
+        .static_pad("sink")
+        .expect("videoconvert has a sink pad");
+    if is_video && !sink_pad.is_linked() {
+        src_pad.link(&sink_pad)?;
+        return Ok(());
+    }
+    let fakesink = gst::ElementFactory::make("fakesink")
+        .property("sync", false)
+        .build()?;
+    bin.add(&fakesink)?;
+    src_pad.link(&fakesink.static_pad("sink").unwrap())?;
+    fakesink.sync_state_with_parent()?;
+    Ok(())
+}
+
+/// Splits `name:arg` into its parts.
+fn split_arg(s: &str) -> (&str, Option<&str>) {
+    match s.split_once(':') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (s, None),
+    }
+}
@@ -0,0 +1,613 @@
+//! MPEG-TS signaling of the KLV stream in the program map table (ISO 13818-1, MISB ST 1402).
+//!
+//! `mpegtsmux` decides on its own how a `meta/x-klv` stream is described in the PMT, so the
+//! sender rewrites the KLV entry of every PMT to the configured signaling and the sync check
+//! parses PMTs after the muxer to verify it. Sync KLV is also carried differently: PES
+//! packets have the metadata stream id and KLV is wrapped in metadata access unit cells.
+//!
+//! Only PSI sections which fit in one TS packet are handled, which a program with a few
+//! streams always does.
+
+use crate::{config::KlvSignaling, klv::KlvKey};
+use log::*;
+use std::{borrow::Cow, ops::Range};
+
+pub const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+
+const TABLE_ID_PAT: u8 = 0x00;
+const TABLE_ID_PMT: u8 = 0x02;
+/// Size of a section header up to `section_length` and of the trailing CRC.
+const SECTION_HEADER_SIZE: usize = 3;
+const CRC_SIZE: usize = 4;
+
+pub const STREAM_TYPE_PRIVATE_DATA: u8 = 0x06;
+pub const STREAM_TYPE_METADATA: u8 = 0x15;
+
+const REGISTRATION_DESCRIPTOR: u8 = 0x05;
+const METADATA_DESCRIPTOR: u8 = 0x26;
+const METADATA_STD_DESCRIPTOR: u8 = 0x27;
+/// Format identifier of SMPTE 336M KLV.
+const KLVA: [u8; 4] = *b"KLVA";
+/// Format fields with this value are followed by a 32 bit format identifier.
+const APPLICATION_FORMAT_IDENTIFIER: u16 = 0xffff;
+const FORMAT_IDENTIFIER: u8 = 0xff;
+
+const STREAM_ID_PRIVATE_1: u8 = 0xbd;
+const STREAM_ID_METADATA: u8 = 0xfc;
+
+/// Leak rates of the metadata decoder model in units of 400 bit/s, 1 Mbit/s.
+const METADATA_LEAK_RATE: u32 = 2500;
+/// Metadata decoder buffer in units of 1024 bytes.
+const METADATA_BUFFER_SIZE: u32 = 64;
+
+/// Metadata AU cell header: service id, sequence number, flags and data length.
+const CELL_HEADER_SIZE: usize = 5;
+/// `cell_fragment_indication` values, in the top bits of the cell flags.
+const CELL_COMPLETE: u8 = 0b11;
+const CELL_FIRST: u8 = 0b01;
+const CELL_MIDDLE: u8 = 0b00;
+const CELL_LAST: u8 = 0b10;
+const CELL_RANDOM_ACCESS: u8 = 0x10;
+const CELL_RESERVED: u8 = 0x0f;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Descriptor {
+    pub tag: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElementaryStream {
+    pub stream_type: u8,
+    pub pid: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl ElementaryStream {
+    /// How the stream is signaled as KLV, `None` if it is not KLV or not signaled as such.
+    pub fn klv_signaling(&self) -> Option<KlvSignaling> {
+        let descriptor = |tag| self.descriptors.iter().find(|d| d.tag == tag);
+        match self.stream_type {
+            STREAM_TYPE_PRIVATE_DATA => descriptor(REGISTRATION_DESCRIPTOR)
+                .filter(|d| d.data.starts_with(&KLVA))
+                .map(|_| KlvSignaling::Async),
+            STREAM_TYPE_METADATA => descriptor(METADATA_DESCRIPTOR)
+                .filter(|d| metadata_format_identifier(&d.data) == Some(KLVA))
+                .map(|_| KlvSignaling::Sync),
+            _ => None,
+        }
+    }
+
+    /// Replaces stream type and KLV descriptors with the ones of `signaling`, other
+    /// descriptors are kept.
+    pub fn set_klv_signaling(&mut self, signaling: KlvSignaling) {
+        self.descriptors.retain(|d| match d.tag {
+            METADATA_DESCRIPTOR | METADATA_STD_DESCRIPTOR => false,
+            REGISTRATION_DESCRIPTOR => !d.data.starts_with(&KLVA),
+            _ => true,
+        });
+        match signaling {
+            KlvSignaling::Async => {
+                self.stream_type = STREAM_TYPE_PRIVATE_DATA;
+                self.descriptors.push(Descriptor {
+                    tag: REGISTRATION_DESCRIPTOR,
+                    data: KLVA.to_vec(),
+                });
+            }
+            KlvSignaling::Sync => {
+                self.stream_type = STREAM_TYPE_METADATA;
+                let mut metadata = Vec::new();
+                metadata.extend_from_slice(&APPLICATION_FORMAT_IDENTIFIER.to_be_bytes());
+                metadata.extend_from_slice(&KLVA);
+                metadata.push(FORMAT_IDENTIFIER);
+                metadata.extend_from_slice(&KLVA);
+                // metadata_service_id, then no decoder config and no DSM-CC.
+                metadata.extend_from_slice(&[0x00, 0x0f]);
+                let mut std = Vec::new();
+                for v in [METADATA_LEAK_RATE, METADATA_BUFFER_SIZE, METADATA_LEAK_RATE] {
+                    // 2 reserved bits and 22 bits of value.
+                    std.extend_from_slice(&(0x00c0_0000 | v).to_be_bytes()[1..]);
+                }
+                self.descriptors.push(Descriptor {
+                    tag: METADATA_DESCRIPTOR,
+                    data: metadata,
+                });
+                self.descriptors.push(Descriptor {
+                    tag: METADATA_STD_DESCRIPTOR,
+                    data: std,
+                });
+            }
+        }
+    }
+}
+
+/// `metadata_format_identifier` of a metadata descriptor, if the format is given by one.
+fn metadata_format_identifier(data: &[u8]) -> Option<[u8; 4]> {
+    let application_format = u16::from_be_bytes(data.get(..2)?.try_into().ok()?);
+    let mut at = 2;
+    if application_format == APPLICATION_FORMAT_IDENTIFIER {
+        at += 4;
+    }
+    if *data.get(at)? != FORMAT_IDENTIFIER {
+        return None;
+    }
+    data.get(at + 1..at + 5)?.try_into().ok()
+}
+
+/// Program map table section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pmt {
+    pub program_number: u16,
+    pub version: u8,
+    pub pcr_pid: u16,
+    pub program_info: Vec<Descriptor>,
+    pub streams: Vec<ElementaryStream>,
+}
+
+impl Pmt {
+    /// Parses a whole PMT section, `None` if it is something else, truncated or its CRC is
+    /// wrong.
+    pub fn parse(section: &[u8]) -> Option<Self> {
+        if section.first() != Some(&TABLE_ID_PMT) || !section_crc_ok(section) {
+            return None;
+        }
+        let data = &section[..section.len() - CRC_SIZE];
+        let mut rest = data;
+        let header = take(&mut rest, 12)?;
+        let pcr_pid = u16::from_be_bytes([header[8] & 0x1f, header[9]]);
+        let program_info_len = usize::from(u16::from_be_bytes([header[10] & 0x0f, header[11]]));
+        let program_info = parse_descriptors(take(&mut rest, program_info_len)?)?;
+
+        let mut streams = Vec::new();
+        while !rest.is_empty() {
+            let header = take(&mut rest, 5)?;
+            let es_info_len = usize::from(u16::from_be_bytes([header[3] & 0x0f, header[4]]));
+            streams.push(ElementaryStream {
+                stream_type: header[0],
+                pid: u16::from_be_bytes([header[1] & 0x1f, header[2]]),
+                descriptors: parse_descriptors(take(&mut rest, es_info_len)?)?,
+            });
+        }
+        Some(Pmt {
+            program_number: u16::from_be_bytes([header[3], header[4]]),
+            version: (header[5] >> 1) & 0x1f,
+            pcr_pid,
+            program_info,
+            streams,
+        })
+    }
+
+    /// Whole section including CRC.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&self.program_number.to_be_bytes());
+        // Reserved bits, version and current_next_indicator, then section numbers.
+        body.extend_from_slice(&[0xc1 | (self.version & 0x1f) << 1, 0, 0]);
+        body.extend_from_slice(&(0xe000 | self.pcr_pid).to_be_bytes());
+        write_descriptors(&self.program_info, &mut body);
+        for stream in &self.streams {
+            body.push(stream.stream_type);
+            body.extend_from_slice(&(0xe000 | stream.pid).to_be_bytes());
+            write_descriptors(&stream.descriptors, &mut body);
+        }
+
+        let section_len = (body.len() + CRC_SIZE) as u16;
+        let mut section = vec![TABLE_ID_PMT];
+        // section_syntax_indicator, '0' and reserved bits.
+        section.extend_from_slice(&(0xb000 | section_len).to_be_bytes());
+        section.extend_from_slice(&body);
+        let crc = crc32_mpeg2(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+        section
+    }
+
+    /// PID and signaling of the first stream signaled as KLV.
+    pub fn klv_stream(&self) -> Option<(u16, KlvSignaling)> {
+        self.streams
+            .iter()
+            .find_map(|stream| Some((stream.pid, stream.klv_signaling()?)))
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if len > data.len() {
+        return None;
+    }
+    let (head, rest) = data.split_at(len);
+    *data = rest;
+    Some(head)
+}
+
+fn parse_descriptors(mut data: &[u8]) -> Option<Vec<Descriptor>> {
+    let mut descriptors = Vec::new();
+    while !data.is_empty() {
+        let header = take(&mut data, 2)?;
+        descriptors.push(Descriptor {
+            tag: header[0],
+            data: take(&mut data, usize::from(header[1]))?.to_vec(),
+        });
+    }
+    Some(descriptors)
+}
+
+/// Descriptor loop with its 12 bit length in front.
+fn write_descriptors(descriptors: &[Descriptor], out: &mut Vec<u8>) {
+    let len: usize = descriptors.iter().map(|d| 2 + d.data.len()).sum();
+    out.extend_from_slice(&(0xf000 | len as u16).to_be_bytes());
+    for d in descriptors {
+        out.push(d.tag);
+        out.push(d.data.len() as u8);
+        out.extend_from_slice(&d.data);
+    }
+}
+
+fn section_crc_ok(section: &[u8]) -> bool {
+    // CRC over the whole section including its CRC is zero.
+    section.len() >= SECTION_HEADER_SIZE + CRC_SIZE && crc32_mpeg2(section) == 0
+}
+
+/// CRC-32/MPEG-2 of PSI sections.
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= u32::from(byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// PID, payload_unit_start_indicator and payload offset of a TS packet, `None` if it is not
+/// one or has no payload.
+fn parse_packet(packet: &[u8]) -> Option<(u16, bool, usize)> {
+    if packet.len() != PACKET_SIZE || packet[0] != SYNC_BYTE {
+        return None;
+    }
+    let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+    let unit_start = packet[1] & 0x40 != 0;
+    let payload = match (packet[3] >> 4) & 0x03 {
+        0b01 => 4,
+        0b11 => 5 + usize::from(packet[4]),
+        _ => return None,
+    };
+    (payload < PACKET_SIZE).then_some((pid, unit_start, payload))
+}
+
+/// Range of the section starting in a packet, if the whole section is in it.
+fn section_range(packet: &[u8], payload: usize) -> Option<Range<usize>> {
+    let start = payload + 1 + usize::from(packet[payload]);
+    let header = packet.get(start..start + SECTION_HEADER_SIZE)?;
+    let len = usize::from(u16::from_be_bytes([header[1] & 0x0f, header[2]]));
+    let end = start + SECTION_HEADER_SIZE + len;
+    (end <= packet.len()).then_some(start..end)
+}
+
+/// Finds PMTs in a stream of TS packets, PMT PIDs are learned from the PAT.
+#[derive(Debug, Default)]
+pub struct PmtReader {
+    pmt_pids: Vec<u16>,
+}
+
+impl PmtReader {
+    /// PMTs in `data`, which has to consist of whole TS packets.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Pmt> {
+        data.chunks_exact(PACKET_SIZE)
+            .filter_map(|packet| Pmt::parse(&packet[self.pmt_section(packet)?]))
+            .collect()
+    }
+
+    /// Range of the PMT section in `packet`, a PAT updates the PMT PIDs.
+    fn pmt_section(&mut self, packet: &[u8]) -> Option<Range<usize>> {
+        let (pid, unit_start, payload) = parse_packet(packet)?;
+        if !unit_start {
+            return None;
+        }
+        if pid == PAT_PID {
+            let section = &packet[section_range(packet, payload)?];
+            if section[0] == TABLE_ID_PAT && section_crc_ok(section) {
+                let programs = section.get(8..section.len() - CRC_SIZE).unwrap_or_default();
+                self.pmt_pids = programs
+                    .chunks_exact(4)
+                    // Program 0 points to the network information table.
+                    .filter(|program| program[..2] != [0, 0])
+                    .map(|program| u16::from_be_bytes([program[2] & 0x1f, program[3]]))
+                    .collect();
+            }
+            return None;
+        }
+        if !self.pmt_pids.contains(&pid) {
+            return None;
+        }
+        section_range(packet, payload)
+    }
+}
+
+/// Rewrites muxed TS packets, so that the KLV stream on `klv_pid` is signaled as configured.
+#[derive(Debug)]
+pub struct KlvSignaler {
+    reader: PmtReader,
+    klv_pid: u16,
+    signaling: KlvSignaling,
+    warned: bool,
+}
+
+impl KlvSignaler {
+    pub fn new(klv_pid: u16, signaling: KlvSignaling) -> Self {
+        KlvSignaler {
+            reader: PmtReader::default(),
+            klv_pid,
+            signaling,
+            warned: false,
+        }
+    }
+
+    /// Rewrites PMTs and KLV PES headers in `data`, which has to consist of whole TS packets.
+    pub fn rewrite(&mut self, data: &mut [u8]) {
+        for packet in data.chunks_exact_mut(PACKET_SIZE) {
+            if let Some(range) = self.reader.pmt_section(packet) {
+                self.rewrite_pmt(packet, range);
+            } else if let Some((pid, true, payload)) = parse_packet(packet) {
+                if pid == self.klv_pid {
+                    self.rewrite_stream_id(&mut packet[payload..]);
+                }
+            }
+        }
+    }
+
+    fn rewrite_pmt(&mut self, packet: &mut [u8], range: Range<usize>) {
+        let Some(mut pmt) = Pmt::parse(&packet[range.clone()]) else {
+            return;
+        };
+        let Some(stream) = pmt.streams.iter_mut().find(|s| s.pid == self.klv_pid) else {
+            return;
+        };
+        if stream.klv_signaling() == Some(self.signaling) {
+            return;
+        }
+        stream.set_klv_signaling(self.signaling);
+        let section = pmt.encode();
+        if range.start + section.len() > packet.len() {
+            if !self.warned {
+                warn!("PMT does not fit in one packet, KLV signaling is left as it is");
+                self.warned = true;
+            }
+            return;
+        }
+        packet[range.start..range.start + section.len()].copy_from_slice(&section);
+        // Stuffing after the last section.
+        packet[range.start + section.len()..].fill(0xff);
+    }
+
+    /// Sync KLV goes in metadata PES packets, async KLV in private stream 1.
+    fn rewrite_stream_id(&self, pes: &mut [u8]) {
+        let (from, to) = match self.signaling {
+            KlvSignaling::Async => (STREAM_ID_METADATA, STREAM_ID_PRIVATE_1),
+            KlvSignaling::Sync => (STREAM_ID_PRIVATE_1, STREAM_ID_METADATA),
+        };
+        if pes.len() > 3 && pes[..3] == [0, 0, 1] && pes[3] == from {
+            pes[3] = to;
+        }
+    }
+}
+
+/// Wraps KLV of one access unit into metadata AU cells, as sync KLV is carried in PES.
+pub fn wrap_metadata_au(klv: &[u8], sequence_number: u8) -> Vec<u8> {
+    let chunks: Vec<&[u8]> = klv.chunks(usize::from(u16::MAX)).collect();
+    let mut out = Vec::with_capacity(klv.len() + chunks.len() * CELL_HEADER_SIZE);
+    for (i, chunk) in chunks.iter().enumerate() {
+        let fragment = match (i, chunks.len()) {
+            (_, 1) => CELL_COMPLETE,
+            (0, _) => CELL_FIRST,
+            (i, n) if i + 1 == n => CELL_LAST,
+            _ => CELL_MIDDLE,
+        };
+        // Every access unit of KLV stands on its own, decoding can start at any of them.
+        let random_access = if i == 0 { CELL_RANDOM_ACCESS } else { 0 };
+        let flags = fragment << 6 | random_access | CELL_RESERVED;
+        out.extend_from_slice(&[0, sequence_number, flags]);
+        out.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+/// KLV from metadata AU cells, `data` as it is if it does not consist of cells holding KLV,
+/// e.g. because the demuxer took the cells apart already.
+pub fn unwrap_metadata_au(data: &[u8]) -> Cow<'_, [u8]> {
+    let starts_with_key = |data: &[u8]| {
+        data.get(..16)
+            .and_then(|key| KlvKey::from_slice(key).ok())
+            .is_some_and(|key| key.is_universal_label())
+    };
+    if starts_with_key(data) {
+        return Cow::Borrowed(data);
+    }
+
+    let mut klv = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some(header) = take(&mut rest, CELL_HEADER_SIZE) else {
+            return Cow::Borrowed(data);
+        };
+        let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        let Some(cell) = take(&mut rest, len) else {
+            return Cow::Borrowed(data);
+        };
+        klv.extend_from_slice(cell);
+    }
+    if starts_with_key(&klv) {
+        Cow::Owned(klv)
+    } else {
+        Cow::Borrowed(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::klv::st0601::UAS_DATALINK_LS_KEY;
+
+    /// Section with CRC appended, `data` starts at `section_length`.
+    fn section(table_id: u8, data: &[u8]) -> Vec<u8> {
+        let mut section = vec![table_id];
+        section.extend_from_slice(data);
+        let crc = crc32_mpeg2(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+        section
+    }
+
+    /// Program 1 with H.264 video on PID 0x100, which carries the PCR, and KLV on 0x101.
+    fn pmt(klv: &[u8]) -> Vec<u8> {
+        let mut data = vec![
+            0xb0, 0x00, 0x00, 0x01, 0xc1, 0x00, 0x00, 0xe1, 0x00, 0xf0, 0x00, // header
+            0x1b, 0xe1, 0x00, 0xf0, 0x00, // video without descriptors
+        ];
+        data.extend_from_slice(klv);
+        data[1] = (data.len() - 2 + CRC_SIZE) as u8;
+        section(TABLE_ID_PMT, &data)
+    }
+
+    /// Stream type 0x06 with a KLVA registration descriptor.
+    const ASYNC_KLV: [u8; 11] = [
+        0x06, 0xe1, 0x01, 0xf0, 0x06, // private data, 6 bytes of descriptors
+        0x05, 0x04, b'K', b'L', b'V', b'A',
+    ];
+
+    /// Stream type 0x15 with metadata and metadata STD descriptors.
+    const SYNC_KLV: [u8; 31] = [
+        0x15, 0xe1, 0x01, 0xf0, 0x1a, // metadata, 26 bytes of descriptors
+        0x26, 0x0d, 0xff, 0xff, b'K', b'L', b'V', b'A', 0xff, b'K', b'L', b'V', b'A', 0x00, 0x0f,
+        0x27, 0x09, 0xc0, 0x09, 0xc4, 0xc0, 0x00, 0x40, 0xc0, 0x09, 0xc4,
+    ];
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_e6e7);
+    }
+
+    #[test]
+    fn parse_async_pmt() {
+        let section = pmt(&ASYNC_KLV);
+        let pmt = Pmt::parse(&section).unwrap();
+        assert_eq!(
+            (pmt.program_number, pmt.version, pmt.pcr_pid),
+            (1, 0, 0x100)
+        );
+        assert_eq!(pmt.streams.len(), 2);
+        assert_eq!(pmt.streams[0].klv_signaling(), None);
+        let klv = &pmt.streams[1];
+        assert_eq!(
+            (klv.stream_type, klv.pid),
+            (STREAM_TYPE_PRIVATE_DATA, 0x101)
+        );
+        assert_eq!(pmt.klv_stream(), Some((0x101, KlvSignaling::Async)));
+        assert_eq!(pmt.encode(), section);
+    }
+
+    #[test]
+    fn parse_sync_pmt() {
+        let section = pmt(&SYNC_KLV);
+        let pmt = Pmt::parse(&section).unwrap();
+        let klv = &pmt.streams[1];
+        assert_eq!((klv.stream_type, klv.pid), (STREAM_TYPE_METADATA, 0x101));
+        assert_eq!(klv.descriptors.len(), 2);
+        assert_eq!(pmt.klv_stream(), Some((0x101, KlvSignaling::Sync)));
+        assert_eq!(pmt.encode(), section);
+    }
+
+    #[test]
+    fn metadata_format_without_application_format_identifier() {
+        let mut klv = SYNC_KLV.to_vec();
+        // metadata_application_format 0x0100, straight followed by the format.
+        klv.splice(7..13, [0x01, 0x00]);
+        klv[4] -= 4;
+        klv[6] -= 4;
+        let pmt = Pmt::parse(&pmt(&klv)).unwrap();
+        assert_eq!(pmt.klv_stream(), Some((0x101, KlvSignaling::Sync)));
+    }
+
+    #[test]
+    fn not_klv() {
+        let mut klv = ASYNC_KLV;
+        klv[10] = b'X';
+        assert_eq!(Pmt::parse(&pmt(&klv)).unwrap().klv_stream(), None);
+        let mut klv = SYNC_KLV;
+        klv[0] = STREAM_TYPE_PRIVATE_DATA;
+        assert_eq!(Pmt::parse(&pmt(&klv)).unwrap().klv_stream(), None);
+    }
+
+    #[test]
+    fn invalid_section() {
+        let mut pmt = pmt(&ASYNC_KLV);
+        assert!(Pmt::parse(&pmt[..pmt.len() - 1]).is_none());
+        pmt[14] ^= 1;
+        assert!(Pmt::parse(&pmt).is_none());
+        assert!(Pmt::parse(&section(TABLE_ID_PAT, &[0xb0, 0x05, 0, 1, 0xc1, 0, 0])).is_none());
+    }
+
+    #[test]
+    fn set_klv_signaling() {
+        let mut pmt = Pmt::parse(&pmt(&ASYNC_KLV)).unwrap();
+        pmt.streams[1].set_klv_signaling(KlvSignaling::Sync);
+        assert_eq!(pmt.encode(), self::pmt(&SYNC_KLV));
+        pmt.streams[1].set_klv_signaling(KlvSignaling::Async);
+        assert_eq!(pmt.encode(), self::pmt(&ASYNC_KLV));
+    }
+
+    /// KLV packet of `len` bytes in total.
+    fn klv_packet(len: usize) -> Vec<u8> {
+        let mut packet = UAS_DATALINK_LS_KEY.as_bytes().to_vec();
+        let value_len = len - packet.len() - 4;
+        packet.push(0x83);
+        packet.extend_from_slice(&(value_len as u32).to_be_bytes()[1..]);
+        packet.resize(len, 0x2a);
+        packet
+    }
+
+    #[test]
+    fn unwrap_single_cell() {
+        let klv = klv_packet(100);
+        let cells = wrap_metadata_au(&klv, 7);
+        assert_eq!(cells[..CELL_HEADER_SIZE], [0, 7, 0xdf, 0, 100]);
+        assert_eq!(unwrap_metadata_au(&cells), klv);
+        // Demuxer took the cells apart already.
+        assert!(matches!(unwrap_metadata_au(&klv), Cow::Borrowed(data) if data == klv));
+    }
+
+    #[test]
+    fn unwrap_multiple_cells() {
+        let klv = klv_packet(2 * usize::from(u16::MAX) + 10);
+        let cells = wrap_metadata_au(&klv, 0);
+        let flags: Vec<u8> = [0, 1, 2]
+            .map(|i| cells[i * (CELL_HEADER_SIZE + usize::from(u16::MAX)) + 2])
+            .to_vec();
+        assert_eq!(flags, [0x5f, 0x0f, 0x8f]);
+        assert_eq!(cells.len(), klv.len() + 3 * CELL_HEADER_SIZE);
+        assert_eq!(unwrap_metadata_au(&cells), klv);
+
+        // Two packets, each a complete cell of its own.
+        let (a, b) = (klv_packet(40), klv_packet(50));
+        let mut cells = wrap_metadata_au(&a, 1);
+        cells.extend_from_slice(&wrap_metadata_au(&b, 2));
+        assert_eq!(unwrap_metadata_au(&cells), [a, b].concat());
+    }
+
+    #[test]
+    fn unwrap_truncated_cells() {
+        let cells = wrap_metadata_au(&klv_packet(100), 0);
+        for len in [3, CELL_HEADER_SIZE + 50, cells.len() - 1] {
+            let data = &cells[..len];
+            assert!(matches!(unwrap_metadata_au(data), Cow::Borrowed(d) if d == data));
+        }
+        // Cells which don't hold KLV.
+        let cells = wrap_metadata_au(&[1, 2, 3], 0);
+        assert_eq!(unwrap_metadata_au(&cells), cells);
+    }
+}
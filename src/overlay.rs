@@ -0,0 +1,300 @@
+//! Overlay which draws the KLV attached to every frame over the video.
+
+use crate::klv;
+use gst::{glib, prelude::*};
+use gstreamer as gst;
+use gstreamer_video as gst_video;
+use pango::prelude::{FontMapExt, ObjectExt as _};
+use std::{
+    ops,
+    sync::{Arc, Mutex},
+};
+
+struct DrawingContext {
+    layout: LayoutWrapper,
+    info: Option<gst_video::VideoInfo>,
+}
+
+#[derive(Debug)]
+struct LayoutWrapper(pango::Layout);
+
+impl ops::Deref for LayoutWrapper {
+    type Target = pango::Layout;
+
+    fn deref(&self) -> &pango::Layout {
+        assert_eq!(self.0.ref_count(), 1);
+        &self.0
+    }
+}
+
+// SAFETY: We ensure that there are never multiple references to the layout.
+unsafe impl Send for LayoutWrapper {}
+
+/// Background and text color of the banner, following the usual security marking colors.
+fn banner_colors(
+    classification: klv::st0102::Classification,
+) -> ((f64, f64, f64), (f64, f64, f64)) {
+    use klv::st0102::Classification;
+
+    const WHITE: (f64, f64, f64) = (1.0, 1.0, 1.0);
+    const BLACK: (f64, f64, f64) = (0.0, 0.0, 0.0);
+    match classification {
+        Classification::Unclassified => ((0.0, 0.48, 0.2), WHITE),
+        Classification::Restricted => ((1.0, 0.85, 0.0), BLACK),
+        Classification::Confidential => ((0.0, 0.2, 0.63), WHITE),
+        Classification::Secret => ((0.78, 0.06, 0.18), WHITE),
+        Classification::TopSecret => ((1.0, 0.4, 0.12), BLACK),
+    }
+}
+
+/// Draws ST 0102 classification banner over the top of the frame.
+fn draw_security_banner(
+    cr: &cairo::Context,
+    layout: &pango::Layout,
+    width: u32,
+    security: &klv::st0102::SecurityLocalSet,
+) {
+    cr.save().expect("Failed to save state");
+    // Banner is positioned relative to the frame, not to whatever transformation is active.
+    cr.identity_matrix();
+
+    layout.set_text(&security.banner());
+    pangocairo::functions::update_layout(cr, layout);
+    let (text_width, text_height) = layout.pixel_size();
+    let ((br, bg, bb), (tr, tg, tb)) = banner_colors(security.classification);
+
+    cr.set_source_rgb(br, bg, bb);
+    cr.rectangle(0.0, 0.0, f64::from(width), f64::from(text_height) + 8.0);
+    cr.fill().expect("Failed to fill banner");
+
+    cr.set_source_rgb(tr, tg, tb);
+    cr.move_to((f64::from(width) - f64::from(text_width)) / 2.0, 4.0);
+    pangocairo::functions::show_layout(cr, layout);
+    cr.restore().expect("Failed to restore state");
+}
+
+/// Draws ST 0903 target bounding boxes with their id and confidence.
+/// Target pixels are scaled from the VMTI frame size to the size of the video.
+fn draw_targets(
+    cr: &cairo::Context,
+    layout: &pango::Layout,
+    width: u32,
+    height: u32,
+    vmti: &klv::st0903::VmtiLocalSet,
+) {
+    let frame_width = vmti.frame_width.unwrap_or(width);
+    let frame_height = vmti.frame_height.unwrap_or(height);
+    if frame_width == 0 || frame_height == 0 {
+        return;
+    }
+    let sx = f64::from(width) / f64::from(frame_width);
+    let sy = f64::from(height) / f64::from(frame_height);
+
+    cr.save().expect("Failed to save state");
+    cr.identity_matrix();
+    cr.set_source_rgb(1.0, 0.2, 0.2);
+    cr.set_line_width(3.0);
+    for target in &vmti.targets {
+        let Some((x, y, w, h)) = target.bounding_box(frame_width) else {
+            continue;
+        };
+        let (x, y) = (f64::from(x) * sx, f64::from(y) * sy);
+        cr.rectangle(x, y, f64::from(w) * sx, f64::from(h) * sy);
+        cr.stroke().expect("Failed to draw target");
+
+        let label = match target.confidence {
+            Some(confidence) => format!("{} {confidence}%", target.id),
+            None => target.id.to_string(),
+        };
+        layout.set_text(&label);
+        pangocairo::functions::update_layout(cr, layout);
+        let (_, text_height) = layout.pixel_size();
+        cr.move_to(x, y - f64::from(text_height));
+        pangocairo::functions::show_layout(cr, layout);
+    }
+    cr.restore().expect("Failed to restore state");
+}
+
+/// Connects "draw" handler which draws frame and KLV timestamps, security banner and target
+/// boxes from the `KlvMeta` of every frame.
+pub fn connect_overlay(overlay: &gst::Element, font: &str) {
+    // The PangoFontMap represents the set of fonts available for a particular rendering system.
+    let fontmap = pangocairo::FontMap::new();
+    // Create a new pango layouting context for the fontmap.
+    let context = fontmap.create_context();
+    // Create a pango layout object. This object is a string of text we want to layout.
+    // It is wrapped in a LayoutWrapper (defined above) to be able to send it across threads.
+    let layout = LayoutWrapper(pango::Layout::new(&context));
+
+    // Select the text content and the font we want to use for the piece of text.
+    let font_desc = pango::FontDescription::from_string(font);
+    layout.set_font_description(Some(&font_desc));
+    layout.set_text("GStreamer");
+
+    // The following is a context struct (containing the pango layout and the configured video info).
+    // We have to wrap it in an Arc (or Rc) to get reference counting, that is: to be able to have
+    // shared ownership of it in multiple different places (the two signal handlers here).
+    // We have to wrap it in a Mutex because Rust's type-system can't know that both signals are
+    // only ever called from a single thread (the streaming thread). It would be enough to have
+    // something that is Send in theory but that's not how signal handlers are generated unfortunately.
+    // The Mutex (or otherwise if we didn't need the Sync bound we could use a RefCell) is to implement
+    // interior mutability (see Rust docs). Via this we can get a mutable reference to the contained
+    // data which is checked at runtime for uniqueness (blocking in case of mutex, panic in case
+    // of refcell) instead of compile-time (like with normal references).
+    let drawer = Arc::new(Mutex::new(DrawingContext { layout, info: None }));
+    // Connect to the overlaycomposition element's "draw" signal, which is emitted for
+    // each videoframe piped through the element. The signal handler needs to
+    // return a gst_video::VideoOverlayComposition to be drawn on the frame
+    //
+    // Signals connected with the connect(<name>, ...) API get their arguments
+    // passed as array of glib::Value. For a documentation about the actual arguments
+    // it is always a good idea to check the element's signals using either
+    // gst-inspect, or the online documentation.
+    //
+    // In this case, the signal passes the gst::Element and a gst::Sample with
+    // the current buffer
+    overlay.connect_closure(
+        "draw",
+        false,
+        glib::closure!(@strong drawer => move |_overlay: &gst::Element,
+                                               sample: &gst::Sample| {
+            let drawer = drawer.lock().unwrap();
+
+            let buffer = sample.buffer().unwrap();
+            let timestamp = buffer.pts().unwrap();
+
+            let info = drawer.info.as_ref().unwrap();
+            let layout = &drawer.layout;
+
+            // Create a Cairo image surface to draw into and the context around it.
+            let surface = cairo::ImageSurface::create(
+                cairo::Format::ARgb32,
+                info.width() as i32,
+                info.height() as i32,
+            )
+            .unwrap();
+            let cr = cairo::Context::new(&surface).expect("Failed to create cairo context");
+
+            cr.save().expect("Failed to save state");
+            cr.set_operator(cairo::Operator::Clear);
+            cr.paint().expect("Failed to clear background");
+            cr.restore().expect("Failed to restore state");
+
+            // The image we draw (the text) will be static, but we will change the
+            // transformation on the drawing context, which rotates and shifts everything
+            // that we draw afterwards. Like this, we have no complicated calculations
+            // in the actual drawing below.
+            // Calling multiple transformation methods after each other will apply the
+            // new transformation on top. If you repeat the cr.rotate(angle) line below
+            // this a second time, everything in the canvas will rotate twice as fast.
+            cr.translate(
+                f64::from(info.width()) / 2.0,
+                f64::from(info.height()) / 2.0,
+            );
+
+            // Cairo, like most rendering frameworks, is using a stack for transformations
+            // with this, we push our current transformation onto this stack - allowing us
+            // to make temporary changes / render something / and then returning to the
+            // previous transformations.
+            cr.save().expect("Failed to save state");
+            cr.set_source_rgb(0.90, 0.65, 0.36);
+
+            // Update the text layout. This function is only updating pango's internal state.
+            // So e.g. that after a 90 degree rotation it knows that what was previously going
+            // to end up as a 200x100 rectangle would now be 100x200.
+            pangocairo::functions::update_layout(&cr, layout);
+            let (width, _height) = layout.size();
+            // Using width and height of the text, we can properly position it within
+            // our canvas.
+            cr.move_to(
+                -(f64::from(width) / f64::from(pango::SCALE)) / 2.0,
+                -(f64::from(info.height())) / 8.0,
+            );
+
+            // KLV of this exact frame was attached to the buffer after the decoder.
+            let meta = buffer.meta::<klv::meta::KlvMeta>();
+            let klv_ts = if let Some(meta) = meta.as_ref() {
+                format!("{}", meta.klv_pts())
+            } else {
+                String::from("not available")
+            };
+            let packets = meta.as_ref().map(|meta| meta.packets()).unwrap_or_default();
+            let uas_set = klv::find_uas_local_set(packets);
+            let vmti = klv::find_vmti_local_set(packets);
+            layout.set_text(&format!("frame time: {timestamp}\n  klv time: {klv_ts}"));
+            // After telling the layout object where to draw itself, we actually tell
+            // it to draw itself into our cairo context.
+            pangocairo::functions::show_layout(&cr, layout);
+
+            // Here we go one step up in our stack of transformations, removing any
+            // changes we did to them since the last call to cr.save();
+            cr.restore().expect("Failed to restore state");
+
+            if let Some(vmti) = vmti {
+                draw_targets(&cr, layout, info.width(), info.height(), &vmti);
+            }
+            if let Some(security) = uas_set.and_then(|set| set.security) {
+                draw_security_banner(&cr, layout, info.width(), &security);
+            }
+
+
+            /* Drop the Cairo context to release the additional reference to the data and
+             * then take ownership of the data. This only works if we have the one and only
+             * reference to the image surface */
+            drop(cr);
+            let stride = surface.stride();
+            let data = surface.take_data().unwrap();
+
+            /* Create an RGBA buffer, and add a video meta that the videooverlaycomposition expects */
+            let mut buffer = gst::Buffer::from_mut_slice(data);
+
+            gst_video::VideoMeta::add_full(
+                buffer.get_mut().unwrap(),
+                gst_video::VideoFrameFlags::empty(),
+                gst_video::VideoFormat::Bgra,
+                info.width(),
+                info.height(),
+                &[0],
+                &[stride],
+            )
+            .unwrap();
+
+            /* Turn the buffer into a VideoOverlayRectangle, then place
+             * that into a VideoOverlayComposition and return it.
+             *
+             * A VideoOverlayComposition can take a Vec of such rectangles
+             * spaced around the video frame, but we're just outputting 1
+             * here */
+            let rect = gst_video::VideoOverlayRectangle::new_raw(
+                &buffer,
+                0,
+                0,
+                info.width(),
+                info.height(),
+                gst_video::VideoOverlayFormatFlags::PREMULTIPLIED_ALPHA,
+            );
+
+            gst_video::VideoOverlayComposition::new(Some(&rect))
+                .unwrap()
+        }),
+    );
+
+    // Add a signal handler to the overlay's "caps-changed" signal. This could e.g.
+    // be called when the sink that we render to does not support resizing the image
+    // itself - but the user just changed the window-size. The element after the overlay
+    // will then change its caps and we use the notification about this change to
+    // resize our canvas's size.
+    // Another possibility for when this might happen is, when our video is a network
+    // stream that dynamically changes resolution when enough bandwidth is available.
+    overlay.connect_closure(
+        "caps-changed",
+        false,
+        glib::closure!(move |_overlay: &gst::Element,
+                             caps: &gst::Caps,
+                             _width: u32,
+                             _height: u32| {
+            let mut drawer = drawer.lock().unwrap();
+            drawer.info = Some(gst_video::VideoInfo::from_caps(caps).unwrap());
+        }),
+    );
+}
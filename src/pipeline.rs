@@ -0,0 +1,143 @@
+//! Pipelines run by the binaries and the bus loop which drives them.
+
+use crate::{
+    builder::{KlvPipelineBuilder, PipelineParts},
+    check::{SyncCheck, SyncReport},
+    config, klv, media, receiver, recorder, sender,
+};
+use anyhow::Error;
+use derive_more::{Display, Error};
+use gst::{glib, prelude::*};
+use gstreamer as gst;
+use log::*;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "Received error from {src}: {error} (debug: {debug:?})")]
+struct ErrorMessage {
+    src: glib::GString,
+    error: glib::Error,
+    debug: Option<glib::GString>,
+}
+
+/// Sender and receiver in one pipeline, `mpegtsmux` linked straight to `tsdemux`.
+/// Muxing and demuxing simulates that there is some transport step in between.
+pub fn loopback(
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+    check: Option<Arc<SyncCheck>>,
+) -> Result<gst::Pipeline, Error> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let mpegtsmux = sender::build_sender(&pipeline, config, parts, check.clone())?;
+    let tsdemux = receiver::build_receiver(&pipeline, config, parts, check)?;
+    let muxed = recorder::tee(&pipeline, &mpegtsmux, config.record.as_ref())?;
+    muxed.link(&tsdemux)?;
+    Ok(pipeline)
+}
+
+/// Runs `frames` generated frames through the loopback pipeline to EOS and reports whether every
+/// frame after `tsdemux` carried its KLV, see `check`.
+pub fn sync_check(config: &config::PipelineConfig, frames: u32) -> Result<SyncReport, Error> {
+    // KLV in SEI has no stream of its own to signal.
+    let klv_signaling =
+        (config.klv_carriage == config::KlvCarriage::Stream).then_some(config.klv_signaling);
+    let check = Arc::new(
+        SyncCheck::new(frames, config.framerate)
+            .max_frame_gap(config.max_frame_gap)
+            .klv_signaling(klv_signaling),
+    );
+    let config = config::PipelineConfig {
+        source: media::VideoSource::Test,
+        sink: media::VideoSink::Fake,
+        ..config.clone()
+    };
+    let pipeline = KlvPipelineBuilder::new(config)
+        .sync_check(Arc::clone(&check))
+        .build()?;
+    main_loop(pipeline)?;
+    Ok(check.report())
+}
+
+pub fn main_loop(pipeline: gst::Pipeline) -> Result<(), Error> {
+    pipeline.set_state(gst::State::Playing)?;
+
+    // Ctrl-C ends the stream with EOS, so that muxers and recordings are finalized. Second
+    // Ctrl-C gives up waiting for it.
+    let pipeline_weak = pipeline.downgrade();
+    let interrupted = AtomicBool::new(false);
+    let handler = ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        if let Some(pipeline) = pipeline_weak.upgrade() {
+            info!("interrupted, waiting for EOS");
+            pipeline.send_event(gst::event::Eos::new());
+        }
+    });
+    if let Err(err) = handler {
+        warn!("Ctrl-C won't finalize the stream: {err}");
+    }
+
+    let bus = pipeline
+        .bus()
+        .expect("Pipeline without bus. Shouldn't happen!");
+
+    for msg in bus.iter_timed(gst::ClockTime::NONE) {
+        use gst::MessageView;
+
+        match msg.view() {
+            MessageView::Eos(..) => break,
+            MessageView::Error(err) => {
+                pipeline.set_state(gst::State::Null)?;
+                return Err(ErrorMessage {
+                    src: msg
+                        .src()
+                        .map(|s| s.path_string())
+                        .unwrap_or_else(|| glib::GString::from("UNKNOWN")),
+                    error: err.error(),
+                    debug: err.debug(),
+                }
+                .into());
+            }
+
+            MessageView::Element(msg) => match msg.structure() {
+                Some(s) if s.name() == klv::CORRUPTED_MESSAGE => {
+                    warn!(
+                        "Corrupted KLV packet #{} at {:?}: {}",
+                        s.get::<u64>("count").unwrap_or_default(),
+                        s.get::<gst::ClockTime>("pts").ok(),
+                        s.get::<&str>("error").unwrap_or_default()
+                    );
+                }
+                Some(s) if s.name() == klv::UNMATCHED_MESSAGE => {
+                    debug!(
+                        "Frame #{} at {:?} without KLV",
+                        s.get::<u64>("count").unwrap_or_default(),
+                        s.get::<gst::ClockTime>("pts").ok()
+                    );
+                }
+                _ => (),
+            },
+
+            MessageView::StateChanged(s) => {
+                info!(
+                    "State changed from {:?}: {:?} -> {:?} ({:?})",
+                    s.src().map(|s| s.path_string()),
+                    s.old(),
+                    s.current(),
+                    s.pending()
+                );
+            }
+
+            _ => (),
+        }
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+
+    Ok(())
+}
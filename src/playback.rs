@@ -0,0 +1,386 @@
+//! Playback of recorded MPEG-TS files with seeking by stream time and by KLV Precision Time
+//! Stamp.
+//!
+//! The camera and the transport are replaced with `filesrc`, everything after `tsdemux` is the
+//! same as for live video. Seeks are flushing, which clears KLV waiting for its frame, so the
+//! overlay stays in sync after a jump in either direction.
+
+use crate::{
+    builder::PipelineParts,
+    config,
+    klv::{self, sei::SeiUuid, st0601, KlvPacket, KlvStreamParser},
+    mpegts, receiver,
+};
+use anyhow::{anyhow, bail, Error};
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use log::*;
+use std::{
+    io, mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Commands read from stdin during playback.
+pub const COMMANDS: &str = "\
+seek <[[h:]m:]s>   jump to stream time, e.g. seek 1:30
+seek +<s>, -<s>    jump forward or back, e.g. seek -10
+klv <time stamp>   jump to KLV Precision Time Stamp, microseconds or 2024-05-01T12:00:00Z
+pause, play        pause or continue
+quit               stop playback";
+
+/// Pipeline which plays `path` like it was received live.
+pub fn pipeline(config: &config::PipelineConfig, path: &Path) -> Result<gst::Pipeline, Error> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    // File is read as fast as possible, the video sink has to keep the pace.
+    let config = config::PipelineConfig {
+        sink_sync: true,
+        ..config.clone()
+    };
+    let tsdemux = receiver::build_receiver(&pipeline, &config, &PipelineParts::default(), None)?;
+
+    info!("playing {}", path.display());
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property("location", path.to_string_lossy().as_ref())
+        .build()?;
+    pipeline.add(&filesrc)?;
+    filesrc.link(&tsdemux)?;
+
+    Ok(pipeline)
+}
+
+/// Reads commands from stdin and applies them to `pipeline` until stdin is closed or the
+/// pipeline is gone. KLV in H.264 SEI is searched for in messages with `sei_uuid`.
+pub fn control(pipeline: &gst::Pipeline, path: PathBuf, sei_uuid: SeiUuid) {
+    let pipeline_weak = pipeline.downgrade();
+    thread::spawn(move || {
+        // Indexing reads the whole file, it is only done once KLV time is asked for.
+        let mut index = None;
+        for line in io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let Some(pipeline) = pipeline_weak.upgrade() else {
+                break;
+            };
+            if let Err(err) = command(&pipeline, &path, sei_uuid, &mut index, line.trim()) {
+                eprintln!("{err}");
+            }
+        }
+    });
+}
+
+fn command(
+    pipeline: &gst::Pipeline,
+    path: &Path,
+    sei_uuid: SeiUuid,
+    index: &mut Option<KlvIndex>,
+    line: &str,
+) -> Result<(), Error> {
+    let (command, arg) = line
+        .split_once(char::is_whitespace)
+        .map_or((line, ""), |(command, arg)| (command, arg.trim()));
+    match command {
+        "" => (),
+        "pause" => {
+            pipeline.set_state(gst::State::Paused)?;
+        }
+        "play" => {
+            pipeline.set_state(gst::State::Playing)?;
+        }
+        "seek" => {
+            let position = seek_position(arg, pipeline.query_position::<gst::ClockTime>())?;
+            seek(pipeline, position)?;
+        }
+        "klv" => {
+            let time_stamp = parse_time_stamp(arg)
+                .ok_or_else(|| anyhow!("\"{arg}\" is not a KLV time stamp"))?;
+            if index.is_none() {
+                println!("indexing KLV of {}", path.display());
+                *index = Some(KlvIndex::scan(path, sei_uuid)?);
+            }
+            let position = index
+                .as_ref()
+                .and_then(|index| index.position(time_stamp))
+                .ok_or_else(|| anyhow!("no KLV at or before {arg}"))?;
+            seek(pipeline, position)?;
+        }
+        "quit" => {
+            pipeline.send_event(gst::event::Eos::new());
+        }
+        _ => bail!("unknown command \"{command}\", one of:\n{COMMANDS}"),
+    }
+    Ok(())
+}
+
+/// Position `seek <arg>` jumps to, `+` and `-` offsets are from `current`.
+fn seek_position(arg: &str, current: Option<gst::ClockTime>) -> Result<gst::ClockTime, Error> {
+    let Some(offset) = arg.strip_prefix(['+', '-']) else {
+        return parse_position(arg).ok_or_else(|| anyhow!("\"{arg}\" is not a time like 1:30"));
+    };
+    let offset = parse_position(offset).ok_or_else(|| anyhow!("\"{arg}\" is not a time offset"))?;
+    let current = current.ok_or_else(|| anyhow!("current position is not known"))?;
+    if arg.starts_with('+') {
+        Ok(current.saturating_add(offset))
+    } else {
+        Ok(current.saturating_sub(offset))
+    }
+}
+
+fn seek(pipeline: &gst::Pipeline, position: gst::ClockTime) -> Result<(), Error> {
+    info!("seek to {position}");
+    // Accurate, so that the first frame shown is the one asked for and not the key frame
+    // before it.
+    pipeline.seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::ACCURATE, position)?;
+    Ok(())
+}
+
+/// ST 0601 Precision Time Stamps of a file with the stream time they are at.
+#[derive(Debug, Default)]
+pub struct KlvIndex {
+    /// Sorted by time stamp.
+    entries: Vec<(u64, gst::ClockTime)>,
+}
+
+impl KlvIndex {
+    /// Demuxes the whole file, KLV is taken from its own stream and from H.264 SEI messages
+    /// with `sei_uuid`.
+    pub fn scan(path: &Path, sei_uuid: SeiUuid) -> Result<Self, Error> {
+        gst::init()?;
+        let pipeline = gst::parse_launch(
+            "filesrc name=file ! tsdemux name=demux \
+             demux. ! meta/x-klv ! queue ! appsink name=klv sync=false \
+             demux. ! video/x-h264 ! queue ! h264parse \
+             ! video/x-h264,stream-format=byte-stream,alignment=au \
+             ! appsink name=video sync=false",
+        )?
+        .downcast::<gst::Pipeline>()
+        .expect("parse_launch with several elements gives a pipeline");
+        pipeline
+            .by_name("file")
+            .expect("filesrc is in the pipeline")
+            .set_property("location", path.to_string_lossy().as_ref());
+
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let sink = |name: &str| {
+            pipeline
+                .by_name(name)
+                .expect("appsink is in the pipeline")
+                .downcast::<gst_app::AppSink>()
+                .expect("element is an appsink")
+        };
+
+        let klv_entries = Arc::clone(&entries);
+        let mut parser = KlvStreamParser::new();
+        sink("klv").set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    if let Some(buffer) = sample.buffer() {
+                        let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                        let data = mpegts::unwrap_metadata_au(map.as_slice());
+                        let mut entries = klv_entries.lock().unwrap();
+                        for (pts, packet) in parser.push(&data, buffer.pts()) {
+                            if let (Some(pts), Ok(packet)) = (pts, packet) {
+                                entries.extend(time_stamp(&packet).map(|ts| (ts, pts)));
+                            }
+                        }
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        let video_entries = Arc::clone(&entries);
+        sink("video").set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    let Some((buffer, pts)) = sample
+                        .buffer()
+                        .and_then(|buffer| Some((buffer, buffer.pts()?)))
+                    else {
+                        return Ok(gst::FlowSuccess::Ok);
+                    };
+                    let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+                    let data = klv::sei::extract_klv(map.as_slice(), sei_uuid);
+                    let mut entries = video_entries.lock().unwrap();
+                    for packet in klv::decode_buffer(&data).into_iter().flatten() {
+                        entries.extend(time_stamp(&packet).map(|ts| (ts, pts)));
+                    }
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+
+        pipeline.set_state(gst::State::Playing)?;
+        let bus = pipeline
+            .bus()
+            .expect("Pipeline without bus. Shouldn't happen!");
+        let msg = bus.timed_pop_filtered(
+            gst::ClockTime::NONE,
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        );
+        pipeline.set_state(gst::State::Null)?;
+        if let Some(gst::MessageView::Error(err)) = msg.as_ref().map(|msg| msg.view()) {
+            bail!("failed to index {}: {}", path.display(), err.error());
+        }
+
+        let mut entries = mem::take(&mut *entries.lock().unwrap());
+        entries.sort();
+        info!("indexed {} KLV time stamps", entries.len());
+        Ok(KlvIndex { entries })
+    }
+
+    /// Stream time of the last KLV with time stamp at or before `time_stamp`.
+    pub fn position(&self, time_stamp: u64) -> Option<gst::ClockTime> {
+        let after = self.entries.partition_point(|(ts, _)| *ts <= time_stamp);
+        after.checked_sub(1).map(|i| self.entries[i].1)
+    }
+}
+
+/// Precision Time Stamp of a valid ST 0601 local set.
+fn time_stamp(packet: &KlvPacket) -> Option<u64> {
+    if packet.key != st0601::UAS_DATALINK_LS_KEY {
+        return None;
+    }
+    st0601::UasDatalinkLocalSet::decode(packet)
+        .ok()?
+        .precision_time_stamp
+}
+
+/// Parses `s`, `m:s` or `h:m:s`, seconds can have a fraction.
+pub fn parse_position(s: &str) -> Option<gst::ClockTime> {
+    let mut parts = s.rsplit(':');
+    let secs: f64 = parts.next()?.parse().ok()?;
+    let mins: u64 = parts.next().map_or(Some(0), |m| m.parse().ok())?;
+    let hours: u64 = parts.next().map_or(Some(0), |h| h.parse().ok())?;
+    if parts.next().is_some() || !secs.is_finite() || secs < 0.0 {
+        return None;
+    }
+    let nanos = (hours * 3600 + mins * 60) * 1_000_000_000 + (secs * 1e9).round() as u64;
+    Some(gst::ClockTime::from_nseconds(nanos))
+}
+
+/// Parses microseconds since the Unix epoch or UTC time like `2024-05-01T12:00:00.5Z`.
+pub fn parse_time_stamp(s: &str) -> Option<u64> {
+    if let Ok(micros) = s.parse() {
+        return Some(micros);
+    }
+    let (date, time) = s.strip_suffix('Z')?.split_once('T')?;
+
+    let mut date = date.splitn(3, '-').map(|v| v.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut time = time.splitn(3, ':');
+    let hours: u64 = time.next()?.parse().ok()?;
+    let mins: u64 = time.next()?.parse().ok()?;
+    let secs: f64 = time.next()?.parse().ok()?;
+    if hours > 23 || mins > 59 || !(0.0..61.0).contains(&secs) {
+        return None;
+    }
+
+    // Days since 1970-01-01 from civil date, proleptic Gregorian calendar.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = u64::try_from(era * 146097 + doe - 719468).ok()?;
+
+    let secs_of_day = hours * 3600 + mins * 60;
+    Some((days * 86400 + secs_of_day) * 1_000_000 + (secs * 1e6).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> gst::ClockTime {
+        gst::ClockTime::from_seconds(secs)
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(parse_position("90"), Some(secs(90)));
+        assert_eq!(parse_position("1:30"), Some(secs(90)));
+        assert_eq!(parse_position("1:01:30"), Some(secs(3690)));
+        assert_eq!(
+            parse_position("0:1.25"),
+            Some(gst::ClockTime::from_mseconds(1250))
+        );
+        assert_eq!(parse_position("1:2:3:4"), None);
+        assert_eq!(parse_position("-1"), None);
+        assert_eq!(parse_position("1:x"), None);
+        assert_eq!(parse_position(""), None);
+    }
+
+    #[test]
+    fn seek_positions() {
+        let current = Some(secs(60));
+        assert_eq!(seek_position("1:30", current).unwrap(), secs(90));
+        assert_eq!(seek_position("+10", current).unwrap(), secs(70));
+        assert_eq!(seek_position("-1:00", current).unwrap(), secs(0));
+        assert_eq!(seek_position("-90", current).unwrap(), secs(0));
+        assert_eq!(seek_position("5", None).unwrap(), secs(5));
+        assert!(seek_position("+5", None).is_err());
+        assert!(seek_position("+x", current).is_err());
+        assert!(seek_position("later", current).is_err());
+    }
+
+    #[test]
+    fn time_stamps() {
+        assert_eq!(parse_time_stamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_time_stamp("1224807209913000"),
+            Some(1_224_807_209_913_000)
+        );
+        // Time stamp of the ST 0601 example.
+        assert_eq!(
+            parse_time_stamp("2008-10-24T00:13:29.913Z"),
+            Some(1_224_807_209_913_000)
+        );
+        assert_eq!(
+            parse_time_stamp("2024-02-29T12:00:00Z"),
+            Some(1_709_208_000_000_000)
+        );
+        assert_eq!(
+            parse_time_stamp("2024-03-01T00:00:00.5Z"),
+            Some(1_709_251_200_500_000)
+        );
+        assert_eq!(
+            parse_time_stamp("2100-03-01T00:00:00Z"),
+            Some(4_107_542_400_000_000)
+        );
+        assert_eq!(parse_time_stamp("2024-13-01T00:00:00Z"), None);
+        assert_eq!(parse_time_stamp("2024-00-01T00:00:00Z"), None);
+        assert_eq!(parse_time_stamp("2024-05-01T24:00:00Z"), None);
+        assert_eq!(parse_time_stamp("2024-05-01T12:00:00"), None);
+        assert_eq!(parse_time_stamp("1969-12-31T23:59:59Z"), None);
+    }
+
+    #[test]
+    fn index_position() {
+        let index = KlvIndex {
+            entries: vec![
+                (100, secs(1)),
+                (200, secs(2)),
+                (200, secs(3)),
+                (300, secs(4)),
+            ],
+        };
+        assert_eq!(index.position(99), None);
+        assert_eq!(index.position(100), Some(secs(1)));
+        assert_eq!(index.position(150), Some(secs(1)));
+        // Of equal time stamps the last one.
+        assert_eq!(index.position(200), Some(secs(3)));
+        assert_eq!(index.position(1000), Some(secs(4)));
+        assert_eq!(KlvIndex::default().position(100), None);
+    }
+}
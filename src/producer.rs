@@ -0,0 +1,368 @@
+//! KLV sent along with every frame of the sender.
+//!
+//! The sender calls its `KlvProducer` for every frame leaving the source and sends whatever
+//! packets it returns. Own telemetry is plugged in by implementing the trait, or with a
+//! closure, and giving it to `KlvPipelineBuilder::klv_producer`.
+
+use crate::{
+    config::{self, KlvProducerKind, KlvProfile},
+    klv::{self, st0102, st0601, st0903, KlvPacket},
+};
+use anyhow::{anyhow, Context, Error};
+use gstreamer as gst;
+use log::*;
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+pub mod flight;
+pub mod telemetry;
+
+/// Frame size the synthetic VMTI targets are given in.
+const VMTI_FRAME_WIDTH: u32 = 1920;
+const VMTI_FRAME_HEIGHT: u32 = 1080;
+
+/// Frame KLV is produced for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Counts from 0 at the first frame after the source.
+    pub number: u32,
+    pub pts: Option<gst::ClockTime>,
+    /// Wall clock when the frame left the source, microseconds since 1970-01-01 UTC.
+    pub time_stamp: u64,
+}
+
+/// Gives the KLV of every frame, called from the streaming thread of the source.
+pub trait KlvProducer: Send {
+    /// KLV packets of `frame`, none if the frame has no KLV.
+    fn produce(&mut self, frame: &Frame) -> Vec<KlvPacket>;
+}
+
+impl<F> KlvProducer for F
+where
+    F: FnMut(&Frame) -> Vec<KlvPacket> + Send,
+{
+    fn produce(&mut self, frame: &Frame) -> Vec<KlvPacket> {
+        self(frame)
+    }
+}
+
+/// Producer chosen by `config.klv_producer`.
+pub fn from_config(config: &config::PipelineConfig) -> Result<Arc<Mutex<dyn KlvProducer>>, Error> {
+    info!("KLV producer {:?}", config.klv_producer);
+    let profile = config.klv_profile;
+    Ok(match config.klv_producer {
+        KlvProducerKind::Counter => Arc::new(Mutex::new(Counter::new(profile))),
+        KlvProducerKind::Static => {
+            Arc::new(Mutex::new(Static::new(Static::default_set(), profile)))
+        }
+        KlvProducerKind::Flight => Arc::new(Mutex::new(flight::Flight::new(
+            config.flight.clone(),
+            config.framerate,
+            (config.width, config.height),
+            profile,
+        ))),
+        KlvProducerKind::Replay => {
+            let path = config
+                .klv_replay
+                .as_deref()
+                .ok_or_else(|| anyhow!("KLV replay needs a file"))?;
+            Arc::new(Mutex::new(Replay::open(path)?))
+        }
+        KlvProducerKind::Telemetry => {
+            let path = config
+                .telemetry
+                .file
+                .as_deref()
+                .ok_or_else(|| anyhow!("telemetry needs a log file"))?;
+            Arc::new(Mutex::new(telemetry::Telemetry::open(
+                path,
+                config.telemetry.offset,
+                config.telemetry.interpolation,
+                profile,
+            )?))
+        }
+    })
+}
+
+/// ST 0601 local set whose platform heading is the frame number modulo 360, so it turns one
+/// degree with every frame and it is easy to see that values change from frame to frame. There
+/// is no frame counter item of its own, the number is only in the heading.
+pub struct Counter {
+    profile: KlvProfile,
+}
+
+impl Counter {
+    pub fn new(profile: KlvProfile) -> Self {
+        Counter { profile }
+    }
+}
+
+impl KlvProducer for Counter {
+    fn produce(&mut self, frame: &Frame) -> Vec<KlvPacket> {
+        let set = st0601::UasDatalinkLocalSet {
+            platform_heading: Some(f64::from(frame.number % 360)),
+            ..Static::default_set()
+        };
+        vec![with_profile(set, self.profile, frame).encode()]
+    }
+}
+
+/// Same ST 0601 local set with every frame, only its time stamp changes.
+pub struct Static {
+    set: st0601::UasDatalinkLocalSet,
+    profile: KlvProfile,
+}
+
+impl Static {
+    pub fn new(set: st0601::UasDatalinkLocalSet, profile: KlvProfile) -> Self {
+        Static { set, profile }
+    }
+
+    /// Identification of this test program, without any position.
+    pub fn default_set() -> st0601::UasDatalinkLocalSet {
+        st0601::UasDatalinkLocalSet {
+            mission_id: Some(String::from("KLV TEST")),
+            platform_designation: Some(String::from("gstreamer-klv-test")),
+            image_source_sensor: Some(String::from("camera")),
+            ls_version: Some(st0601::LS_VERSION),
+            ..Default::default()
+        }
+    }
+}
+
+impl KlvProducer for Static {
+    fn produce(&mut self, frame: &Frame) -> Vec<KlvPacket> {
+        vec![with_profile(self.set.clone(), self.profile, frame).encode()]
+    }
+}
+
+/// KLV packets of a file, e.g. a KLV stream extracted from a recording, one per frame. After
+/// the last packet it starts from the first again.
+pub struct Replay {
+    packets: Vec<KlvPacket>,
+    next: usize,
+}
+
+impl Replay {
+    /// Reads all packets of `path`, the file is not used after that.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let mut packets = Vec::new();
+        for packet in klv::decode_buffer(&data) {
+            match packet {
+                Ok(packet) => packets.push(packet),
+                Err(err) => warn!("skip corrupted KLV in {}: {err}", path.display()),
+            }
+        }
+        if packets.is_empty() {
+            return Err(anyhow!("no KLV packets in {}", path.display()));
+        }
+        info!("replay {} KLV packets of {}", packets.len(), path.display());
+        Ok(Replay { packets, next: 0 })
+    }
+}
+
+impl KlvProducer for Replay {
+    fn produce(&mut self, _frame: &Frame) -> Vec<KlvPacket> {
+        let packet = self.packets[self.next].clone();
+        self.next += 1;
+        if self.next == self.packets.len() {
+            info!("replayed all KLV packets, starting again");
+            self.next = 0;
+        }
+        vec![packet]
+    }
+}
+
+/// `set` with the time stamp of `frame` and the local sets `profile` adds to it.
+pub(crate) fn with_profile(
+    set: st0601::UasDatalinkLocalSet,
+    profile: KlvProfile,
+    frame: &Frame,
+) -> st0601::UasDatalinkLocalSet {
+    let security = profile != KlvProfile::Minimal;
+    let vmti = profile == KlvProfile::Full;
+    st0601::UasDatalinkLocalSet {
+        precision_time_stamp: Some(frame.time_stamp),
+        security: security.then(|| st0102::SecurityLocalSet {
+            classification: st0102::Classification::Unclassified,
+            classifying_country_coding_method: Some(st0102::ISO_3166_THREE_LETTER),
+            classifying_country: Some(String::from("//USA")),
+            object_country_coding_method: Some(st0102::ISO_3166_THREE_LETTER),
+            object_country_codes: Some(String::from("USA")),
+            version: Some(st0102::LS_VERSION),
+            ..Default::default()
+        }),
+        vmti: vmti.then(|| frame_targets(frame.number, frame.time_stamp)),
+        ..set
+    }
+}
+
+/// ST 0903 local set with one synthetic target moving across the frame.
+fn frame_targets(frame_nr: u32, time_stamp: u64) -> st0903::VmtiLocalSet {
+    use st0903::{pixel_number, VTarget, VmtiLocalSet};
+
+    let (w, h) = (200, 120);
+    let x = (frame_nr * 8) % (VMTI_FRAME_WIDTH - w);
+    let y = VMTI_FRAME_HEIGHT / 2 - h / 2;
+    let target = VTarget {
+        id: 1,
+        centroid: Some(pixel_number(x + w / 2, y + h / 2, VMTI_FRAME_WIDTH)),
+        boundary_top_left: Some(pixel_number(x, y, VMTI_FRAME_WIDTH)),
+        boundary_bottom_right: Some(pixel_number(x + w - 1, y + h - 1, VMTI_FRAME_WIDTH)),
+        priority: Some(1),
+        confidence: Some(50 + (frame_nr % 50) as u8),
+        ..Default::default()
+    };
+    VmtiLocalSet {
+        precision_time_stamp: Some(time_stamp),
+        system_name: Some(String::from("gstreamer-klv-test")),
+        version: Some(st0903::LS_VERSION),
+        total_targets_detected: Some(1),
+        reported_targets: Some(1),
+        frame_number: Some(u64::from(frame_nr)),
+        frame_width: Some(VMTI_FRAME_WIDTH),
+        frame_height: Some(VMTI_FRAME_HEIGHT),
+        targets: vec![target],
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(number: u32) -> Frame {
+        Frame {
+            number,
+            pts: Some(gst::ClockTime::from_mseconds(u64::from(number) * 40)),
+            time_stamp: 1_224_807_209_913_000 + u64::from(number) * 40_000,
+        }
+    }
+
+    fn decode(packets: &[KlvPacket]) -> st0601::UasDatalinkLocalSet {
+        assert_eq!(packets.len(), 1);
+        st0601::UasDatalinkLocalSet::decode(&packets[0]).unwrap()
+    }
+
+    fn heading(producer: &mut dyn KlvProducer, number: u32) -> f64 {
+        decode(&producer.produce(&frame(number)))
+            .platform_heading
+            .unwrap()
+    }
+
+    /// File of its own for a test, removed when dropped.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, data: &[u8]) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("klv-producer-{}-{name}", std::process::id()));
+            fs::write(&path, data).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn producers() {
+        let mut producers: Vec<Box<dyn KlvProducer>> = vec![
+            Box::new(Counter::new(KlvProfile::Minimal)),
+            Box::new(Static::new(Static::default_set(), KlvProfile::Minimal)),
+            Box::new(Replay {
+                packets: vec![Static::default_set().encode()],
+                next: 0,
+            }),
+            Box::new(|_: &Frame| vec![Static::default_set().encode()]),
+        ];
+        for producer in &mut producers {
+            let set = decode(&producer.produce(&frame(0)));
+            assert_eq!(set.mission_id.as_deref(), Some("KLV TEST"));
+        }
+    }
+
+    #[test]
+    fn counter_heading() {
+        let mut counter = Counter::new(KlvProfile::Minimal);
+        assert!(heading(&mut counter, 0).abs() < 0.01);
+        assert!((heading(&mut counter, 90) - 90.0).abs() < 0.01);
+        assert!((heading(&mut counter, 361) - 1.0).abs() < 0.01);
+        let set = decode(&counter.produce(&frame(5)));
+        assert_eq!(set.precision_time_stamp, Some(frame(5).time_stamp));
+    }
+
+    #[test]
+    fn static_time_stamp() {
+        let mut producer = Static::new(Static::default_set(), KlvProfile::Minimal);
+        let first = decode(&producer.produce(&frame(0)));
+        let second = decode(&producer.produce(&frame(1)));
+        assert_ne!(first.precision_time_stamp, second.precision_time_stamp);
+        assert_eq!(
+            st0601::UasDatalinkLocalSet {
+                precision_time_stamp: None,
+                ..first
+            },
+            st0601::UasDatalinkLocalSet {
+                precision_time_stamp: None,
+                ..second
+            }
+        );
+    }
+
+    #[test]
+    fn replay_wraps() {
+        let packets: Vec<KlvPacket> = [10.0, 20.0]
+            .into_iter()
+            .map(|heading| {
+                st0601::UasDatalinkLocalSet {
+                    platform_heading: Some(heading),
+                    ..Static::default_set()
+                }
+                .encode()
+            })
+            .collect();
+        let data: Vec<u8> = packets.iter().flat_map(KlvPacket::encode).collect();
+        let file = TempFile::new("replay.klv", &data);
+
+        let mut replay = Replay::open(&file.0).unwrap();
+        for expected in [&packets[0], &packets[1], &packets[0], &packets[1]] {
+            assert_eq!(replay.produce(&frame(0)), std::slice::from_ref(expected));
+        }
+    }
+
+    #[test]
+    fn replay_without_packets() {
+        let empty = TempFile::new("empty.klv", &[]);
+        assert!(Replay::open(&empty.0).is_err());
+        let garbage = TempFile::new("garbage.klv", b"not KLV at all");
+        assert!(Replay::open(&garbage.0).is_err());
+        assert!(Replay::open(Path::new("/nonexistent/replay.klv")).is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        let sets = |profile| with_profile(Static::default_set(), profile, &frame(3));
+
+        let minimal = sets(KlvProfile::Minimal);
+        assert_eq!(minimal.precision_time_stamp, Some(frame(3).time_stamp));
+        assert!(minimal.security.is_none() && minimal.vmti.is_none());
+
+        let security = sets(KlvProfile::Security);
+        assert!(security.security.is_some() && security.vmti.is_none());
+
+        let full = sets(KlvProfile::Full);
+        assert!(full.security.is_some());
+        let vmti = full.vmti.unwrap();
+        assert_eq!(vmti.frame_number, Some(3));
+        assert_eq!(vmti.precision_time_stamp, Some(frame(3).time_stamp));
+        assert_eq!(vmti.targets.len(), 1);
+    }
+}
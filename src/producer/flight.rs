@@ -0,0 +1,555 @@
+//! Simulated flight: position, attitude, sensor pointing and field of view of an aircraft
+//! flying an orbit, waypoints or a seeded random walk.
+//!
+//! The aircraft is a function of the frame number, frame rate and config only, so the same
+//! frames always get the same metadata. Frame center, corners, slant range and target width
+//! are where the rays of the sensor hit flat ground, so they agree with everything else.
+
+use super::{with_profile, Frame, KlvProducer, Static};
+use crate::{
+    config::{FlightConfig, FlightRoute, Framerate, KlvProfile},
+    klv::{st0601, KlvPacket},
+};
+use std::f64::consts::{PI, TAU};
+
+/// Meters per degree of latitude, and of longitude at the equator.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+/// Gravity for the bank angle of turns, m/s².
+const GRAVITY: f64 = 9.81;
+/// Largest corner offset ST 0601 can carry, degrees.
+const MAX_CORNER_OFFSET: f64 = 0.075;
+/// Largest target width ST 0601 can carry, meters.
+const MAX_TARGET_WIDTH: f64 = 10_000.0;
+
+/// Turn rate and its change of the random walk, rad/s and rad/s².
+const RANDOM_MAX_TURN_RATE: f64 = 0.05;
+const RANDOM_TURN_ACCELERATION: f64 = 0.02;
+/// Climb rate and its change of the random walk, m/s and m/s².
+const RANDOM_MAX_CLIMB_RATE: f64 = 2.0;
+const RANDOM_CLIMB_ACCELERATION: f64 = 0.5;
+
+/// Position in meters north and east of the config center and above the ground.
+type Position = [f64; 3];
+type Rotation = [[f64; 3]; 3];
+
+/// Aircraft at one frame, angles in radians.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Aircraft {
+    position: Position,
+    heading: f64,
+    pitch: f64,
+    roll: f64,
+    speed: f64,
+}
+
+/// State of the random walk, stepped once per frame.
+#[derive(Debug, Clone)]
+struct Walk {
+    aircraft: Aircraft,
+    turn_rate: f64,
+    climb_rate: f64,
+    rng: SplitMix64,
+    /// Frame number `aircraft` is at.
+    frame: u32,
+}
+
+impl Walk {
+    /// At the center, heading in a direction given by the seed.
+    fn new(config: &FlightConfig) -> Self {
+        let mut rng = SplitMix64(config.seed);
+        Walk {
+            aircraft: Aircraft {
+                position: [0.0, 0.0, config.altitude],
+                heading: rng.uniform() * PI,
+                pitch: 0.0,
+                roll: 0.0,
+                speed: config.speed,
+            },
+            turn_rate: 0.0,
+            climb_rate: 0.0,
+            rng,
+            frame: 0,
+        }
+    }
+}
+
+pub struct Flight {
+    config: FlightConfig,
+    framerate: Framerate,
+    /// Frame height divided by width.
+    aspect: f64,
+    profile: KlvProfile,
+    /// Waypoints in meters from the center.
+    waypoints: Vec<Position>,
+    walk: Walk,
+}
+
+impl Flight {
+    /// Flight of `config` for frames of `width` by `height`.
+    pub fn new(
+        config: FlightConfig,
+        framerate: Framerate,
+        (width, height): (u32, u32),
+        profile: KlvProfile,
+    ) -> Self {
+        let (lat, lon) = config.center;
+        let waypoints = config
+            .waypoints
+            .iter()
+            .map(|&(wp_lat, wp_lon, altitude)| {
+                [
+                    (wp_lat - lat) * METERS_PER_DEGREE,
+                    (wp_lon - lon) * METERS_PER_DEGREE * lat.to_radians().cos(),
+                    altitude,
+                ]
+            })
+            .collect();
+        Flight {
+            walk: Walk::new(&config),
+            framerate,
+            aspect: f64::from(height) / f64::from(width),
+            profile,
+            waypoints,
+            config,
+        }
+    }
+
+    /// Local set of frame `frame_nr`.
+    pub fn at(&mut self, frame_nr: u32) -> st0601::UasDatalinkLocalSet {
+        let Framerate {
+            numerator,
+            denominator,
+        } = self.framerate;
+        let dt = f64::from(denominator) / f64::from(numerator);
+        let (aircraft, look_at) = match self.config.route {
+            FlightRoute::Orbit => (self.orbit(f64::from(frame_nr) * dt), [0.0, 0.0]),
+            FlightRoute::Waypoints => self.waypoints(f64::from(frame_nr) * dt),
+            FlightRoute::Random => (self.random_walk(frame_nr, dt), [0.0, 0.0]),
+        };
+        self.local_set(&aircraft, look_at)
+    }
+
+    /// Clockwise circle around the center, seen from above.
+    fn orbit(&self, secs: f64) -> Aircraft {
+        let FlightConfig {
+            radius,
+            altitude,
+            speed,
+            ..
+        } = self.config;
+        let bearing = speed * secs / radius;
+        Aircraft {
+            position: [radius * bearing.cos(), radius * bearing.sin(), altitude],
+            // Center is on the right.
+            heading: bearing + PI / 2.0,
+            pitch: 0.0,
+            roll: bank(speed, speed / radius),
+            speed,
+        }
+    }
+
+    /// Straight lines from waypoint to waypoint, after the last one back to the first. The
+    /// sensor looks at the ground under the waypoint flown to.
+    fn waypoints(&self, secs: f64) -> (Aircraft, [f64; 2]) {
+        let legs = || {
+            let next = self.waypoints.iter().cycle().skip(1);
+            self.waypoints.iter().zip(next)
+        };
+        let total: f64 = legs().map(|(a, b)| horizontal_distance(a, b)).sum();
+        let mut along = if total > 0.0 {
+            (self.config.speed * secs).rem_euclid(total)
+        } else {
+            0.0
+        };
+        for (a, b) in legs() {
+            let length = horizontal_distance(a, b);
+            if along > length {
+                along -= length;
+                continue;
+            }
+            let f = if length > 0.0 { along / length } else { 0.0 };
+            let aircraft = Aircraft {
+                position: [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * f),
+                heading: (b[1] - a[1]).atan2(b[0] - a[0]),
+                pitch: (b[2] - a[2]).atan2(length),
+                roll: 0.0,
+                speed: self.config.speed,
+            };
+            return (aircraft, [b[0], b[1]]);
+        }
+        let a = self.waypoints[0];
+        let aircraft = Aircraft {
+            position: a,
+            heading: 0.0,
+            pitch: 0.0,
+            roll: 0.0,
+            speed: self.config.speed,
+        };
+        (aircraft, [a[0], a[1]])
+    }
+
+    /// Random turns, climbs and descents, turning back towards the center when farther than
+    /// the radius and leveling off when more than half the altitude away from it.
+    fn random_walk(&mut self, frame_nr: u32, dt: f64) -> Aircraft {
+        if frame_nr < self.walk.frame {
+            // Frame numbers started again, so does the flight.
+            self.walk = Walk::new(&self.config);
+        }
+        let FlightConfig {
+            radius, altitude, ..
+        } = self.config;
+        let walk = &mut self.walk;
+        while walk.frame < frame_nr {
+            let aircraft = &mut walk.aircraft;
+            let [north, east, up] = aircraft.position;
+
+            walk.turn_rate += walk.rng.uniform() * RANDOM_TURN_ACCELERATION * dt;
+            if north.hypot(east) > radius {
+                let home = wrap_angle((-east).atan2(-north) - aircraft.heading);
+                walk.turn_rate = home.signum() * RANDOM_MAX_TURN_RATE;
+            }
+            walk.turn_rate = walk
+                .turn_rate
+                .clamp(-RANDOM_MAX_TURN_RATE, RANDOM_MAX_TURN_RATE);
+
+            walk.climb_rate += walk.rng.uniform() * RANDOM_CLIMB_ACCELERATION * dt;
+            if (up - altitude).abs() > altitude / 2.0 {
+                walk.climb_rate = (altitude - up).signum() * RANDOM_MAX_CLIMB_RATE;
+            }
+            walk.climb_rate = walk
+                .climb_rate
+                .clamp(-RANDOM_MAX_CLIMB_RATE, RANDOM_MAX_CLIMB_RATE);
+
+            aircraft.heading = wrap_angle(aircraft.heading + walk.turn_rate * dt);
+            aircraft.position = [
+                north + aircraft.speed * aircraft.heading.cos() * dt,
+                east + aircraft.speed * aircraft.heading.sin() * dt,
+                up + walk.climb_rate * dt,
+            ];
+            aircraft.pitch = walk.climb_rate.atan2(aircraft.speed);
+            aircraft.roll = bank(aircraft.speed, walk.turn_rate);
+            walk.frame += 1;
+        }
+        walk.aircraft
+    }
+
+    /// Local set of `aircraft` with its sensor pointed at `look_at`, north and east on the
+    /// ground.
+    fn local_set(&self, aircraft: &Aircraft, look_at: [f64; 2]) -> st0601::UasDatalinkLocalSet {
+        let platform = rotation(aircraft.heading, aircraft.pitch, aircraft.roll);
+        let position = aircraft.position;
+
+        // Line of sight in aircraft axes gives the sensor angles relative to the aircraft.
+        let los = [
+            look_at[0] - position[0],
+            look_at[1] - position[1],
+            position[2],
+        ];
+        let los = apply(&transpose(&platform), los);
+        let azimuth = los[1].atan2(los[0]);
+        let elevation = (-los[2]).atan2(los[0].hypot(los[1]));
+        let sensor = mul(&platform, &rotation(azimuth, elevation, 0.0));
+
+        let hfov = self.config.horizontal_fov.to_radians();
+        let half_width = (hfov / 2.0).tan();
+        let half_height = half_width * self.aspect;
+        let vfov = 2.0 * half_height.atan();
+        // Rays in sensor axes: forward, right, down.
+        let ground =
+            |right: f64, down: f64| hit_ground(position, apply(&sensor, [1.0, right, down]));
+
+        let center = ground(0.0, 0.0);
+        let target_width = match (ground(-half_width, 0.0), ground(half_width, 0.0)) {
+            (Some((left, _)), Some((right, _))) => {
+                Some(horizontal_distance(&left, &right)).filter(|&w| w <= MAX_TARGET_WIDTH)
+            }
+            _ => None,
+        };
+        // Upper left, upper right, lower right, lower left.
+        let corners = [
+            (-half_width, -half_height),
+            (half_width, -half_height),
+            (half_width, half_height),
+            (-half_width, half_height),
+        ]
+        .map(|(right, down)| ground(right, down));
+
+        let (lat, lon) = self.to_lat_lon(&position);
+        let mut set = st0601::UasDatalinkLocalSet {
+            platform_heading: Some(full_circle(aircraft.heading)),
+            platform_pitch: Some(aircraft.pitch.to_degrees()),
+            platform_roll: Some(aircraft.roll.to_degrees()),
+            platform_true_airspeed: Some(aircraft.speed.round().min(255.0) as u8),
+            platform_ground_speed: Some(aircraft.speed.round().min(255.0) as u8),
+            sensor_latitude: Some(lat),
+            sensor_longitude: Some(lon),
+            sensor_true_altitude: Some(self.config.ground_elevation + position[2]),
+            sensor_horizontal_fov: Some(hfov.to_degrees()),
+            sensor_vertical_fov: Some(vfov.to_degrees()),
+            sensor_relative_azimuth: Some(full_circle(azimuth)),
+            sensor_relative_elevation: Some(elevation.to_degrees()),
+            sensor_relative_roll: Some(0.0),
+            target_width,
+            ..Static::default_set()
+        };
+        if let Some((center, range)) = center {
+            let (center_lat, center_lon) = self.to_lat_lon(&center);
+            set.slant_range = Some(range);
+            set.frame_center_latitude = Some(center_lat);
+            set.frame_center_longitude = Some(center_lon);
+            set.frame_center_elevation = Some(self.config.ground_elevation);
+
+            let offsets = corners.map(|corner| {
+                let (corner_lat, corner_lon) = self.to_lat_lon(&corner?.0);
+                Some((corner_lat - center_lat, corner_lon - center_lon))
+                    .filter(|(lat, lon)| lat.abs().max(lon.abs()) <= MAX_CORNER_OFFSET)
+            });
+            // Corners are sent only as a whole.
+            if offsets.iter().all(Option::is_some) {
+                set.offset_corner_latitude = offsets.map(|o| o.map(|(lat, _)| lat));
+                set.offset_corner_longitude = offsets.map(|o| o.map(|(_, lon)| lon));
+            }
+        }
+        set
+    }
+
+    fn to_lat_lon(&self, position: &Position) -> (f64, f64) {
+        let (lat, lon) = self.config.center;
+        (
+            lat + position[0] / METERS_PER_DEGREE,
+            lon + position[1] / (METERS_PER_DEGREE * lat.to_radians().cos()),
+        )
+    }
+}
+
+impl KlvProducer for Flight {
+    fn produce(&mut self, frame: &Frame) -> Vec<KlvPacket> {
+        let set = self.at(frame.number);
+        vec![with_profile(set, self.profile, frame).encode()]
+    }
+}
+
+/// Bank angle of a coordinated turn at `speed` and `turn_rate`.
+fn bank(speed: f64, turn_rate: f64) -> f64 {
+    (speed * turn_rate / GRAVITY).atan()
+}
+
+/// `angle` in degrees `0.0..360.0`.
+fn full_circle(angle: f64) -> f64 {
+    let degrees = angle.to_degrees().rem_euclid(360.0);
+    // Rounds up to 360 for tiny negative angles.
+    if degrees < 360.0 {
+        degrees
+    } else {
+        0.0
+    }
+}
+
+/// `angle` in `-PI..=PI`.
+fn wrap_angle(angle: f64) -> f64 {
+    let angle = angle.rem_euclid(TAU);
+    if angle > PI {
+        angle - TAU
+    } else {
+        angle
+    }
+}
+
+fn horizontal_distance(a: &Position, b: &Position) -> f64 {
+    (b[0] - a[0]).hypot(b[1] - a[1])
+}
+
+/// Where `ray` from `position` hits the ground and how far that is, `None` if it doesn't point
+/// down. `ray` is north, east, down.
+fn hit_ground(position: Position, ray: [f64; 3]) -> Option<(Position, f64)> {
+    if ray[2] <= 1e-9 {
+        return None;
+    }
+    let t = position[2] / ray[2];
+    let point = [position[0] + t * ray[0], position[1] + t * ray[1], 0.0];
+    let range = t * (ray[0] * ray[0] + ray[1] * ray[1] + ray[2] * ray[2]).sqrt();
+    Some((point, range))
+}
+
+/// Rotation from body axes (forward, right, down) to north, east, down by yaw, then pitch,
+/// then roll.
+fn rotation(yaw: f64, pitch: f64, roll: f64) -> Rotation {
+    let (sy, cy) = yaw.sin_cos();
+    let (sp, cp) = pitch.sin_cos();
+    let (sr, cr) = roll.sin_cos();
+    [
+        [cy * cp, cy * sp * sr - sy * cr, cy * sp * cr + sy * sr],
+        [sy * cp, sy * sp * sr + cy * cr, sy * sp * cr - cy * sr],
+        [-sp, cp * sr, cp * cr],
+    ]
+}
+
+fn mul(a: &Rotation, b: &Rotation) -> Rotation {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+fn transpose(m: &Rotation) -> Rotation {
+    [0, 1, 2].map(|i| [0, 1, 2].map(|j| m[j][i]))
+}
+
+fn apply(m: &Rotation, v: [f64; 3]) -> [f64; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+/// Small seeded generator, the same seed always gives the same flight.
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `-1.0..1.0`.
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PipelineConfig;
+
+    const FRAMERATE: Framerate = Framerate {
+        numerator: 30,
+        denominator: 1,
+    };
+
+    fn flight(config: FlightConfig) -> Flight {
+        Flight::new(config, FRAMERATE, (1920, 1080), KlvProfile::Minimal)
+    }
+
+    fn config(route: FlightRoute) -> FlightConfig {
+        FlightConfig {
+            route,
+            ..PipelineConfig::default().flight
+        }
+    }
+
+    /// Level aircraft at `altitude` above the center, heading north.
+    fn level(altitude: f64) -> Aircraft {
+        Aircraft {
+            position: [0.0, 0.0, altitude],
+            heading: 0.0,
+            pitch: 0.0,
+            roll: 0.0,
+            speed: 40.0,
+        }
+    }
+
+    #[test]
+    fn same_seed_same_flight() {
+        for route in [FlightRoute::Orbit, FlightRoute::Random] {
+            let mut a = flight(config(route));
+            let mut b = flight(config(route));
+            assert_eq!(a.at(100), b.at(100), "{route:?}");
+            assert_eq!(a.at(101), b.at(101), "{route:?}");
+        }
+
+        let mut walk = flight(config(FlightRoute::Random));
+        let at_50 = walk.at(50);
+        let at_300 = walk.at(300);
+        // Frame numbers start again, e.g. after a restart of the source.
+        assert_eq!(walk.at(50), at_50);
+        assert_eq!(walk.at(300), at_300);
+        assert_eq!(flight(config(FlightRoute::Random)).at(300), at_300);
+
+        let other_seed = FlightConfig {
+            seed: 2,
+            ..config(FlightRoute::Random)
+        };
+        assert_ne!(flight(other_seed).at(300), at_300);
+    }
+
+    #[test]
+    fn orbit() {
+        let config = config(FlightRoute::Orbit);
+        let flight = flight(config.clone());
+        for secs in [0.0, 10.0, 61.3, 200.0, 1000.0] {
+            let aircraft = flight.orbit(secs);
+            let [north, east, up] = aircraft.position;
+            assert!((north.hypot(east) - config.radius).abs() < 1e-6, "{secs}");
+            assert_eq!(up, config.altitude);
+
+            // Heading is along the circle, center on the right.
+            let (sin, cos) = aircraft.heading.sin_cos();
+            assert!((cos * north + sin * east).abs() < 1e-6, "{secs}");
+            let right = [-sin, cos];
+            assert!(right[0] * -north + right[1] * -east > 0.0, "{secs}");
+            assert!(aircraft.roll > 0.0);
+        }
+    }
+
+    #[test]
+    fn nadir_frame() {
+        let altitude = 1000.0;
+        let flight = flight(config(FlightRoute::Orbit));
+        let set = flight.local_set(&level(altitude), [0.0, 0.0]);
+
+        assert_eq!(set.sensor_relative_elevation, Some(-90.0));
+        assert!((set.slant_range.unwrap() - altitude).abs() < 1e-6);
+        let (lat, lon) = flight.config.center;
+        assert!((set.frame_center_latitude.unwrap() - lat).abs() < 1e-9);
+        assert!((set.frame_center_longitude.unwrap() - lon).abs() < 1e-9);
+
+        // Looking straight down with the top of the frame to the north.
+        let half_width = (flight.config.horizontal_fov.to_radians() / 2.0).tan();
+        let half_height = half_width * 1080.0 / 1920.0;
+        let rays = [
+            [half_height, -half_width, 1.0],
+            [half_height, half_width, 1.0],
+            [-half_height, half_width, 1.0],
+            [-half_height, -half_width, 1.0],
+        ];
+        for (i, ray) in rays.into_iter().enumerate() {
+            let (corner, _) = hit_ground([0.0, 0.0, altitude], ray).unwrap();
+            let (corner_lat, corner_lon) = flight.to_lat_lon(&corner);
+            let offset_lat = set.offset_corner_latitude[i].unwrap();
+            let offset_lon = set.offset_corner_longitude[i].unwrap();
+            assert!((offset_lat - (corner_lat - lat)).abs() < 1e-9, "corner {i}");
+            assert!((offset_lon - (corner_lon - lon)).abs() < 1e-9, "corner {i}");
+        }
+        let width = set.target_width.unwrap();
+        assert!((width - 2.0 * altitude * half_width).abs() < 1e-6);
+    }
+
+    #[test]
+    fn corners_out_of_range() {
+        let flight = flight(config(FlightRoute::Orbit));
+        // Frame is over 16 km wide, more than the corner offsets can carry.
+        let set = flight.local_set(&level(30_000.0), [0.0, 0.0]);
+        assert!(set.frame_center_latitude.is_some());
+        assert_eq!(set.offset_corner_latitude, [None; 4]);
+        assert_eq!(set.offset_corner_longitude, [None; 4]);
+        assert_eq!(set.target_width, None);
+
+        // Upper corners are above the horizon.
+        let set = flight.local_set(&level(1000.0), [20_000.0, 0.0]);
+        assert!(set.frame_center_latitude.is_some());
+        assert_eq!(set.offset_corner_latitude, [None; 4]);
+    }
+
+    #[test]
+    fn angles() {
+        assert_eq!(full_circle(-1e-17), 0.0);
+        assert!((full_circle(-PI / 2.0) - 270.0).abs() < 1e-9);
+        assert!((wrap_angle(3.0 * PI / 2.0) + PI / 2.0).abs() < 1e-9);
+        assert!((wrap_angle(-3.0 * PI / 2.0) - PI / 2.0).abs() < 1e-9);
+    }
+}
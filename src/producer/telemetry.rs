@@ -0,0 +1,536 @@
+//! Recorded telemetry, e.g. an autopilot log, sent with the frames it was recorded for.
+//!
+//! The log is CSV with a header line or JSON Lines, with the field names of
+//! `UasDatalinkLocalSet` like the input of `klvenc`. Every record needs `precision_time_stamp`,
+//! the clock of the log. A frame belongs to the log time of the first record plus the offset
+//! plus its PTS. Before the first and after the last record those records are sent.
+
+use super::{with_profile, Frame, KlvProducer};
+use crate::{
+    config::{KlvProfile, TelemetryInterpolation},
+    klv::{st0601::UasDatalinkLocalSet, KlvPacket},
+};
+use anyhow::{anyhow, Context, Error};
+use log::*;
+use serde_json::{Map, Value};
+use std::{fs, path::Path};
+
+/// Fields which stay text in CSV even if they look like numbers.
+const TEXT_FIELDS: &[&str] = &[
+    "mission_id",
+    "platform_tail_number",
+    "platform_designation",
+    "image_source_sensor",
+    "image_coordinate_system",
+];
+
+pub struct Telemetry {
+    /// Sorted by time stamp, all have one.
+    records: Vec<UasDatalinkLocalSet>,
+    /// Microseconds from the first record to PTS 0.
+    offset: i64,
+    interpolation: TelemetryInterpolation,
+    profile: KlvProfile,
+}
+
+impl Telemetry {
+    /// Reads all records of `path`, the file is not used after that. `offset` is seconds from
+    /// the first record to PTS 0.
+    pub fn open(
+        path: &Path,
+        offset: f64,
+        interpolation: TelemetryInterpolation,
+        profile: KlvProfile,
+    ) -> Result<Self, Error> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let records = if data.trim_start().starts_with('{') {
+            parse_json_lines(&data)
+        } else {
+            parse_csv(&data)
+        };
+        let mut records: Vec<_> = records
+            .into_iter()
+            .filter_map(|(line, record)| match record {
+                Ok(record) if record.precision_time_stamp.is_some() => Some(record),
+                Ok(_) => {
+                    warn!(
+                        "skip {}:{line} without precision_time_stamp",
+                        path.display()
+                    );
+                    None
+                }
+                Err(err) => {
+                    warn!("skip invalid {}:{line}: {err}", path.display());
+                    None
+                }
+            })
+            .collect();
+        if records.is_empty() {
+            return Err(anyhow!("no telemetry records in {}", path.display()));
+        }
+        records.sort_by_key(|record| record.precision_time_stamp);
+        info!(
+            "telemetry of {} records and {:.1} s in {}, {:?} interpolation",
+            records.len(),
+            (time(&records[records.len() - 1]) - time(&records[0])) as f64 / 1e6,
+            path.display(),
+            interpolation
+        );
+        Ok(Telemetry {
+            records,
+            offset: (offset * 1e6).round() as i64,
+            interpolation,
+            profile,
+        })
+    }
+
+    /// Local set at `time_stamp`, microseconds of the log clock.
+    pub fn at(&self, time_stamp: i64) -> UasDatalinkLocalSet {
+        let records = &self.records;
+        let after = records.partition_point(|record| time(record) <= time_stamp);
+        if after == 0 {
+            return records[0].clone();
+        }
+        if after == records.len() {
+            return records[after - 1].clone();
+        }
+        let (a, b) = (&records[after - 1], &records[after]);
+        // `a` is at or before `time_stamp` and `b` after it, so they differ.
+        let f = (time_stamp - time(a)) as f64 / (time(b) - time(a)) as f64;
+        let mut set = match self.interpolation {
+            TelemetryInterpolation::Nearest if f < 0.5 => a.clone(),
+            TelemetryInterpolation::Nearest => b.clone(),
+            TelemetryInterpolation::Linear => interpolate(a, b, f, false),
+            TelemetryInterpolation::Slerp => interpolate(a, b, f, true),
+        };
+        if self.interpolation != TelemetryInterpolation::Nearest {
+            set.precision_time_stamp = Some(time_stamp as u64);
+        }
+        set
+    }
+}
+
+impl KlvProducer for Telemetry {
+    fn produce(&mut self, frame: &Frame) -> Vec<KlvPacket> {
+        let Some(pts) = frame.pts else {
+            warn!("no telemetry for frame {} without PTS", frame.number);
+            return Vec::new();
+        };
+        let time_stamp = time(&self.records[0]) + self.offset + pts.useconds() as i64;
+        let set = self.at(time_stamp);
+        // Time stamps of the log, not of the sender, so the KLV is as it was recorded.
+        let frame = Frame {
+            time_stamp: set.precision_time_stamp.unwrap_or_default(),
+            ..*frame
+        };
+        vec![with_profile(set, self.profile, &frame).encode()]
+    }
+}
+
+fn time(record: &UasDatalinkLocalSet) -> i64 {
+    record.precision_time_stamp.unwrap_or_default() as i64
+}
+
+/// Records of every non-empty line with its line number.
+fn parse_json_lines(data: &str) -> Vec<(usize, Result<UasDatalinkLocalSet, serde_json::Error>)> {
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| (i + 1, serde_json::from_str(line)))
+        .collect()
+}
+
+/// Records of every non-empty line after the header with its line number. Empty cells are
+/// missing values, quoting is not supported.
+fn parse_csv(data: &str) -> Vec<(usize, Result<UasDatalinkLocalSet, serde_json::Error>)> {
+    let mut lines = data
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let Some((_, header)) = lines.next() else {
+        return Vec::new();
+    };
+    let fields: Vec<&str> = header.split(',').map(str::trim).collect();
+    lines
+        .map(|(i, line)| {
+            let mut record = Map::new();
+            for (&field, cell) in fields.iter().zip(line.split(',').map(str::trim)) {
+                if cell.is_empty() {
+                    continue;
+                }
+                let value = if TEXT_FIELDS.contains(&field) {
+                    Value::from(cell)
+                } else {
+                    serde_json::from_str(cell).unwrap_or_else(|_| Value::from(cell))
+                };
+                record.insert(field.to_owned(), value);
+            }
+            (i + 1, serde_json::from_value(Value::Object(record)))
+        })
+        .collect()
+}
+
+/// `a` to `b` at `f` between them, from 0 at `a` to 1 at `b`. What can't be interpolated comes
+/// from the nearer one.
+fn interpolate(
+    a: &UasDatalinkLocalSet,
+    b: &UasDatalinkLocalSet,
+    f: f64,
+    slerp: bool,
+) -> UasDatalinkLocalSet {
+    let mut set = if f < 0.5 { a.clone() } else { b.clone() };
+    macro_rules! interpolate {
+        ($interpolate:expr; $($field:ident),+) => {
+            $(if let (Some(a), Some(b)) = (a.$field, b.$field) {
+                set.$field = Some($interpolate(a, b));
+            })+
+        };
+    }
+    let linear = |a: f64, b: f64| a + (b - a) * f;
+    // Angles of 0..360 the shorter way around.
+    let circular =
+        |a: f64, b: f64| full_circle(a + ((b - a + 180.0).rem_euclid(360.0) - 180.0) * f);
+    let speed = |a: u8, b: u8| linear(f64::from(a), f64::from(b)).round() as u8;
+
+    interpolate!(linear;
+        sensor_latitude,
+        sensor_longitude,
+        sensor_true_altitude,
+        sensor_horizontal_fov,
+        sensor_vertical_fov,
+        slant_range,
+        target_width,
+        frame_center_latitude,
+        frame_center_longitude,
+        frame_center_elevation,
+        target_width_extended
+    );
+    interpolate!(speed;
+        platform_true_airspeed,
+        platform_indicated_airspeed,
+        platform_ground_speed
+    );
+    for i in 0..4 {
+        if let (Some(a), Some(b)) = (a.offset_corner_latitude[i], b.offset_corner_latitude[i]) {
+            set.offset_corner_latitude[i] = Some(linear(a, b));
+        }
+        if let (Some(a), Some(b)) = (a.offset_corner_longitude[i], b.offset_corner_longitude[i]) {
+            set.offset_corner_longitude[i] = Some(linear(a, b));
+        }
+    }
+
+    let platform = |set: &UasDatalinkLocalSet| {
+        Some([
+            set.platform_heading?,
+            set.platform_pitch?,
+            set.platform_roll?,
+        ])
+    };
+    let sensor = |set: &UasDatalinkLocalSet| {
+        Some([
+            set.sensor_relative_azimuth?,
+            set.sensor_relative_elevation?,
+            set.sensor_relative_roll?,
+        ])
+    };
+    match (slerp, platform(a), platform(b)) {
+        (true, Some(a), Some(b)) => {
+            let [heading, pitch, roll] = slerp_angles(a, b, f);
+            set.platform_heading = Some(full_circle(heading));
+            set.platform_pitch = Some(pitch);
+            set.platform_roll = Some(roll);
+        }
+        _ => {
+            interpolate!(circular; platform_heading);
+            interpolate!(linear; platform_pitch, platform_roll);
+        }
+    }
+    match (slerp, sensor(a), sensor(b)) {
+        (true, Some(a), Some(b)) => {
+            let [azimuth, elevation, roll] = slerp_angles(a, b, f);
+            set.sensor_relative_azimuth = Some(full_circle(azimuth));
+            set.sensor_relative_elevation = Some(elevation);
+            set.sensor_relative_roll = Some(full_circle(roll));
+        }
+        _ => {
+            interpolate!(circular; sensor_relative_azimuth, sensor_relative_roll);
+            interpolate!(linear; sensor_relative_elevation);
+        }
+    }
+    set
+}
+
+/// `degrees` in `0.0..360.0`.
+fn full_circle(degrees: f64) -> f64 {
+    let degrees = degrees.rem_euclid(360.0);
+    // Rounds up to 360 for tiny negative angles.
+    if degrees < 360.0 {
+        degrees
+    } else {
+        0.0
+    }
+}
+
+/// Yaw, pitch and roll in degrees of the rotation `f` of the way from `a` to `b`.
+fn slerp_angles(a: [f64; 3], b: [f64; 3], f: f64) -> [f64; 3] {
+    let a = Quaternion::from_angles(a);
+    let mut b = Quaternion::from_angles(b);
+    let mut dot = a.dot(&b);
+    // Same rotation, the shorter way around.
+    if dot < 0.0 {
+        b = b.scale(-1.0);
+        dot = -dot;
+    }
+    let (wa, wb) = if dot > 0.9995 {
+        (1.0 - f, f)
+    } else {
+        let theta = dot.acos();
+        let sin = theta.sin();
+        (((1.0 - f) * theta).sin() / sin, (f * theta).sin() / sin)
+    };
+    a.scale(wa).plus(&b.scale(wb)).normalize().to_angles()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Quaternion([f64; 4]);
+
+impl Quaternion {
+    /// Rotation by yaw, then pitch, then roll, in degrees.
+    fn from_angles([yaw, pitch, roll]: [f64; 3]) -> Self {
+        let (sy, cy) = (yaw.to_radians() / 2.0).sin_cos();
+        let (sp, cp) = (pitch.to_radians() / 2.0).sin_cos();
+        let (sr, cr) = (roll.to_radians() / 2.0).sin_cos();
+        Quaternion([
+            cr * cp * cy + sr * sp * sy,
+            sr * cp * cy - cr * sp * sy,
+            cr * sp * cy + sr * cp * sy,
+            cr * cp * sy - sr * sp * cy,
+        ])
+    }
+
+    fn to_angles(self) -> [f64; 3] {
+        let [w, x, y, z] = self.0;
+        let yaw = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+        let pitch = (2.0 * (w * y - z * x)).clamp(-1.0, 1.0).asin();
+        let roll = (2.0 * (w * x + y * z)).atan2(1.0 - 2.0 * (x * x + y * y));
+        [yaw, pitch, roll].map(f64::to_degrees)
+    }
+
+    fn dot(&self, other: &Self) -> f64 {
+        (0..4).map(|i| self.0[i] * other.0[i]).sum()
+    }
+
+    fn scale(self, s: f64) -> Self {
+        Quaternion(self.0.map(|v| v * s))
+    }
+
+    fn plus(self, other: &Self) -> Self {
+        Quaternion([0, 1, 2, 3].map(|i| self.0[i] + other.0[i]))
+    }
+
+    fn normalize(self) -> Self {
+        self.scale(1.0 / self.dot(&self).sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gstreamer as gst;
+
+    const START: u64 = 1_224_807_209_913_000;
+
+    fn record(secs: u64, heading: f64, latitude: f64) -> UasDatalinkLocalSet {
+        UasDatalinkLocalSet {
+            precision_time_stamp: Some(START + secs * 1_000_000),
+            platform_heading: Some(heading),
+            platform_pitch: Some(0.0),
+            platform_roll: Some(0.0),
+            sensor_latitude: Some(latitude),
+            platform_true_airspeed: Some(40),
+            ..Default::default()
+        }
+    }
+
+    fn telemetry(interpolation: TelemetryInterpolation) -> Telemetry {
+        Telemetry {
+            records: vec![record(0, 359.0, 59.0), record(1, 1.0, 60.0)],
+            offset: 0,
+            interpolation,
+            profile: KlvProfile::Minimal,
+        }
+    }
+
+    /// `secs` after the first record.
+    fn at(telemetry: &Telemetry, secs: f64) -> UasDatalinkLocalSet {
+        telemetry.at(START as i64 + (secs * 1e6).round() as i64)
+    }
+
+    fn angle_diff(a: f64, b: f64) -> f64 {
+        ((a - b + 180.0).rem_euclid(360.0) - 180.0).abs()
+    }
+
+    /// File of its own for a test, removed when dropped.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, data: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("klv-telemetry-{}-{name}", std::process::id()));
+            fs::write(&path, data).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn clamped_to_log() {
+        for interpolation in [
+            TelemetryInterpolation::Nearest,
+            TelemetryInterpolation::Linear,
+            TelemetryInterpolation::Slerp,
+        ] {
+            let telemetry = telemetry(interpolation);
+            assert_eq!(at(&telemetry, -10.0), record(0, 359.0, 59.0));
+            assert_eq!(at(&telemetry, 0.0), record(0, 359.0, 59.0));
+            assert_eq!(at(&telemetry, 1.0), record(1, 1.0, 60.0));
+            assert_eq!(at(&telemetry, 100.0), record(1, 1.0, 60.0));
+        }
+    }
+
+    #[test]
+    fn nearest() {
+        let telemetry = telemetry(TelemetryInterpolation::Nearest);
+        assert_eq!(at(&telemetry, 0.499), record(0, 359.0, 59.0));
+        assert_eq!(at(&telemetry, 0.5), record(1, 1.0, 60.0));
+        assert_eq!(at(&telemetry, 0.8), record(1, 1.0, 60.0));
+    }
+
+    #[test]
+    fn linear() {
+        let telemetry = telemetry(TelemetryInterpolation::Linear);
+        let set = at(&telemetry, 0.25);
+        assert_eq!(set.precision_time_stamp, Some(START + 250_000));
+        assert!((set.sensor_latitude.unwrap() - 59.25).abs() < 1e-9);
+        // 359° to 1° goes through north, not back around.
+        assert!(angle_diff(set.platform_heading.unwrap(), 359.5) < 1e-9);
+        let heading = at(&telemetry, 0.5).platform_heading.unwrap();
+        assert!((0.0..360.0).contains(&heading));
+        assert!(angle_diff(heading, 0.0) < 1e-9);
+        assert!(angle_diff(at(&telemetry, 0.75).platform_heading.unwrap(), 0.5) < 1e-9);
+    }
+
+    #[test]
+    fn slerp() {
+        let telemetry = telemetry(TelemetryInterpolation::Slerp);
+        for (secs, expected) in [(0.25, 359.5), (0.5, 0.0), (0.75, 0.5)] {
+            let set = at(&telemetry, secs);
+            let heading = set.platform_heading.unwrap();
+            assert!((0.0..360.0).contains(&heading), "{heading}");
+            // Rotations this close are interpolated linearly and normalized, not exactly evenly.
+            assert!(angle_diff(heading, expected) < 1e-3, "{secs}: {heading}");
+            assert!(set.platform_pitch.unwrap().abs() < 1e-6);
+            assert!(set.platform_roll.unwrap().abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn quaternion_round_trip() {
+        for angles in [
+            [0.0, 0.0, 0.0],
+            [90.0, 0.0, 0.0],
+            [-170.0, 30.0, -45.0],
+            [10.0, -80.0, 120.0],
+            [179.0, 5.0, 179.0],
+        ] {
+            let back = Quaternion::from_angles(angles).to_angles();
+            for (a, b) in angles.into_iter().zip(back) {
+                assert!(angle_diff(a, b) < 1e-9, "{angles:?} gave {back:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn csv() {
+        let data = "\
+mission_id, precision_time_stamp, platform_heading, sensor_latitude
+
+0123, 1224807209913000, 12.5, 59.4
+ABC, 1224807210913000, , 59.5
+";
+        let records: Vec<_> = parse_csv(data)
+            .into_iter()
+            .map(|(line, record)| (line, record.unwrap()))
+            .collect();
+        assert_eq!(records.len(), 2);
+
+        let (line, first) = &records[0];
+        assert_eq!(*line, 3);
+        // Looks like a number, stays text.
+        assert_eq!(first.mission_id.as_deref(), Some("0123"));
+        assert_eq!(first.precision_time_stamp, Some(START));
+        assert_eq!(first.platform_heading, Some(12.5));
+
+        let (line, second) = &records[1];
+        assert_eq!(*line, 4);
+        assert_eq!(second.mission_id.as_deref(), Some("ABC"));
+        assert_eq!(second.platform_heading, None);
+        assert_eq!(second.sensor_latitude, Some(59.5));
+    }
+
+    #[test]
+    fn json_lines() {
+        let file = TempFile::new(
+            "log.jsonl",
+            r#"{"precision_time_stamp": 1224807210913000, "platform_heading": 20.0}
+{"platform_heading": 99.0}
+
+{"precision_time_stamp": 1224807209913000, "platform_heading": 10.0}
+{"unknown_field": 1}
+"#,
+        );
+        let mut telemetry = Telemetry::open(
+            &file.0,
+            0.5,
+            TelemetryInterpolation::Nearest,
+            KlvProfile::Minimal,
+        )
+        .unwrap();
+        // Lines without time stamp and invalid ones are skipped, the rest sorted.
+        let headings: Vec<_> = telemetry
+            .records
+            .iter()
+            .map(|record| record.platform_heading)
+            .collect();
+        assert_eq!(headings, [Some(10.0), Some(20.0)]);
+
+        // Offset of half a second and PTS of 0.6 s are past the second record.
+        let frame = Frame {
+            number: 15,
+            pts: Some(gst::ClockTime::from_mseconds(600)),
+            time_stamp: 0,
+        };
+        let packets = telemetry.produce(&frame);
+        let set = UasDatalinkLocalSet::decode(&packets[0]).unwrap();
+        assert_eq!(set.precision_time_stamp, Some(START + 1_000_000));
+        assert!(telemetry.produce(&Frame { pts: None, ..frame }).is_empty());
+    }
+
+    #[test]
+    fn no_records() {
+        let file = TempFile::new("empty.jsonl", "{\"platform_heading\": 99.0}\n");
+        let telemetry = Telemetry::open(
+            &file.0,
+            0.0,
+            TelemetryInterpolation::Nearest,
+            KlvProfile::Minimal,
+        );
+        assert!(telemetry.is_err());
+    }
+}
@@ -0,0 +1,478 @@
+//! Receiving side: MPEG-TS is demuxed, video is decoded and shown with the KLV matched to
+//! each frame drawn over it. KLV comes either in its own stream or in H.264 SEI.
+
+use crate::{
+    builder::PipelineParts,
+    check::SyncCheck,
+    config::{self, KlvCarriage},
+    klv::{self, matcher::KlvMatch, sei::SeiUuid},
+    mpegts, overlay, recorder,
+};
+use anyhow::{anyhow, Error};
+use gst::prelude::*;
+use gstreamer as gst;
+use log::*;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Codec of a video stream the receiver can decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    H265,
+}
+
+impl VideoCodec {
+    /// `None` for anything but H.264 and H.265.
+    pub fn from_caps(caps: &gst::CapsRef) -> Option<Self> {
+        match caps.structure(0)?.name().as_str() {
+            "video/x-h264" => Some(VideoCodec::H264),
+            "video/x-h265" => Some(VideoCodec::H265),
+            _ => None,
+        }
+    }
+}
+
+/// Overlay and video sink which decoded video is shown on and the matcher KLV streams feed,
+/// branches for the streams of the transport are added with `add_video` and `add_klv`.
+pub struct ReceiverStreams {
+    /// Sink pad of the overlay, takes decoded video of one stream at a time.
+    pub video: gst::Pad,
+    matcher: Arc<Mutex<klv::KlvMatcher>>,
+    klv_carriage: KlvCarriage,
+    sei_uuid: SeiUuid,
+}
+
+impl ReceiverStreams {
+    /// Adds `elements`, a parser and a decoder for `codec` to the running `pipeline` and shows
+    /// `src_pad` with them. Returns the elements of the branch.
+    ///
+    /// `elements` come first, e.g. a depayloader. KLV in SEI is only read from H.264.
+    pub fn add_video(
+        &self,
+        pipeline: &gst::Pipeline,
+        src_pad: &gst::Pad,
+        codec: VideoCodec,
+        mut elements: Vec<gst::Element>,
+    ) -> Result<Vec<gst::Element>, Error> {
+        match codec {
+            VideoCodec::H264 => {
+                let h264parse = gst::ElementFactory::make("h264parse").build()?;
+                // Access units with start codes, that is what SEI is read from.
+                let parsed_capsfilter = gst::ElementFactory::make("capsfilter")
+                    .property(
+                        "caps",
+                        gst::Caps::builder("video/x-h264")
+                            .field("stream-format", "byte-stream")
+                            .field("alignment", "au")
+                            .build(),
+                    )
+                    .build()?;
+                if self.klv_carriage == KlvCarriage::Sei {
+                    extract_sei(&parsed_capsfilter, self.sei_uuid);
+                }
+                let decoder = gst::ElementFactory::make("avdec_h264").build()?;
+                elements.extend([h264parse, parsed_capsfilter, decoder]);
+            }
+            VideoCodec::H265 => {
+                let h265parse = gst::ElementFactory::make("h265parse").build()?;
+                let decoder = gst::ElementFactory::make("avdec_h265").build()?;
+                elements.extend([h265parse, decoder]);
+            }
+        }
+        add_branch(pipeline, src_pad, &elements)?;
+        let decoded = elements.last().unwrap().static_pad("src").unwrap();
+        decoded.link(&self.video)?;
+        Ok(elements)
+    }
+
+    /// Adds `elements`, a queue and a KLV sink feeding the matcher to the running `pipeline`
+    /// and links `src_pad` to them. Returns the elements of the branch.
+    pub fn add_klv(
+        &self,
+        pipeline: &gst::Pipeline,
+        src_pad: &gst::Pad,
+        mut elements: Vec<gst::Element>,
+    ) -> Result<Vec<gst::Element>, Error> {
+        let queue = gst::ElementFactory::make("queue").build()?;
+        let appsink = klv::klv_sink(Arc::clone(&self.matcher))?;
+        appsink.set_property("sync", false);
+
+        // Probe when KLV data reaches appsink element.
+        let klv_sink_pad = appsink.static_pad("sink").unwrap();
+        klv_sink_pad.add_probe(gst::PadProbeType::DATA_DOWNSTREAM, {
+            move |_, probe_info| {
+                match probe_info.data {
+                    Some(gst::PadProbeData::Event(ref event)) => {
+                        info!("Event {:?}", event);
+                    }
+                    // Raw bytes of every packet, only worth mapping the buffer when asked for.
+                    Some(gst::PadProbeData::Buffer(ref buf)) if log_enabled!(Level::Trace) => {
+                        let mr = buf.map_readable().unwrap();
+                        trace!("klvprobe klv {:?} {:?}", buf.pts(), mr.as_slice());
+                    }
+                    _ => (),
+                }
+                gst::PadProbeReturn::Ok
+            }
+        });
+
+        elements.extend([queue, appsink]);
+        add_branch(pipeline, src_pad, &elements)?;
+        Ok(elements)
+    }
+}
+
+/// Adds demuxer, decoder, overlay, video sink and KLV sink to `pipeline` and returns
+/// `tsdemux`. Its `sink` pad is left for the caller to link to the transport.
+///
+/// Demuxer pads are routed by their caps: the first H.264 or H.265 stream is shown, every
+/// KLV stream gets its own branch into the matcher and everything else goes to `fakesink`.
+/// When the demuxer switches to another program it removes its pads, their branches are
+/// removed too and the streams of the new program take their place.
+pub fn build_receiver(
+    pipeline: &gst::Pipeline,
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+    check: Option<Arc<SyncCheck>>,
+) -> Result<gst::Element, Error> {
+    let tsdemux = gst::ElementFactory::make("tsdemux").build()?;
+    pipeline.add(&tsdemux)?;
+    if let Some(check) = check.as_ref().filter(|check| check.checks_pmt()) {
+        let check = Arc::clone(check);
+        let reader = Mutex::new(mpegts::PmtReader::default());
+        let read = move |buf: &gst::BufferRef| {
+            if let Ok(map) = buf.map_readable() {
+                for pmt in reader.lock().unwrap().push(map.as_slice()) {
+                    check.pmt(pmt);
+                }
+            }
+        };
+        let muxed_pad = tsdemux.static_pad("sink").unwrap();
+        muxed_pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+            move |_, probe_info| {
+                match probe_info.data {
+                    Some(gst::PadProbeData::Buffer(ref buf)) => read(buf),
+                    Some(gst::PadProbeData::BufferList(ref list)) => list.iter().for_each(&read),
+                    _ => (),
+                }
+                gst::PadProbeReturn::Ok
+            },
+        );
+    }
+    let streams = build_streams(pipeline, config, parts, check, klv::KlvMatcher::default())?;
+
+    // Elements of the branch of every demuxer pad, by pad name.
+    let branches = Arc::new(Mutex::new(HashMap::new()));
+
+    // Pipeline can be disposed of at any point (), so convert to a weak ref that will force us to check if there is any strong reference
+    // using `pipeline_weak.upgrade()` below
+    let pipeline_weak = pipeline.downgrade();
+    let added_branches = Arc::clone(&branches);
+    // Demuxer needs to connect after playing (detect source).
+    tsdemux.connect_pad_added(move |src, src_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        match route_pad(&pipeline, &streams, src_pad) {
+            Ok(elements) => {
+                added_branches
+                    .lock()
+                    .unwrap()
+                    .insert(src_pad.name(), elements);
+            }
+            Err(err) => error!(
+                "failed to connect pad {} from {}: {err}",
+                src_pad.name(),
+                src.name()
+            ),
+        }
+    });
+
+    let pipeline_weak = pipeline.downgrade();
+    tsdemux.connect_pad_removed(move |src, src_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        let Some(elements) = branches.lock().unwrap().remove(&src_pad.name()) else {
+            return;
+        };
+        info!(
+            "remove branch of pad {} from {}",
+            src_pad.name(),
+            src.name()
+        );
+        for element in &elements {
+            let _ = element.set_state(gst::State::Null);
+        }
+        let elements: Vec<&gst::Element> = elements.iter().collect();
+        if let Err(err) = pipeline.remove_many(&elements) {
+            warn!("failed to remove branch of pad {}: {err}", src_pad.name());
+        }
+    });
+
+    Ok(tsdemux)
+}
+
+/// Links a demuxer pad to a branch chosen by its caps and returns the elements of the branch.
+fn route_pad(
+    pipeline: &gst::Pipeline,
+    streams: &ReceiverStreams,
+    src_pad: &gst::Pad,
+) -> Result<Vec<gst::Element>, Error> {
+    let caps = src_pad
+        .current_caps()
+        .unwrap_or_else(|| src_pad.query_caps(None));
+    let media_type = caps
+        .structure(0)
+        .map_or_else(String::new, |s| s.name().to_string());
+    let name = src_pad.name();
+
+    let codec = VideoCodec::from_caps(&caps);
+    if let Some(codec) = codec.filter(|_| !streams.video.is_linked()) {
+        info!("connect {codec:?} video pad {name}");
+        let queue = gst::ElementFactory::make("queue").build()?;
+        return streams.add_video(pipeline, src_pad, codec, vec![queue]);
+    }
+    if media_type == "meta/x-klv" {
+        info!("connect KLV pad {name}");
+        return streams.add_klv(pipeline, src_pad, Vec::new());
+    }
+
+    if codec.is_some() {
+        info!("video pad {name} is not shown, only the first video stream is");
+    } else if media_type.starts_with("audio/") {
+        info!("audio pad {name} is not played");
+    } else {
+        warn!("unsupported pad {name} with caps {caps}");
+    }
+    // Unlinked pads would make the demuxer stop once all of them are unlinked.
+    let queue = gst::ElementFactory::make("queue").build()?;
+    let fakesink = gst::ElementFactory::make("fakesink")
+        .property("sync", false)
+        .property("async", false)
+        .build()?;
+    let elements = vec![queue, fakesink];
+    add_branch(pipeline, src_pad, &elements)?;
+    Ok(elements)
+}
+
+/// Adds overlay and video sink to `pipeline`, KLV is matched to decoded frames with
+/// `matcher`. Sink and KLV consumer given in `parts` replace the configured sink.
+pub fn build_streams(
+    pipeline: &gst::Pipeline,
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+    check: Option<Arc<SyncCheck>>,
+    matcher: klv::KlvMatcher,
+) -> Result<ReceiverStreams, Error> {
+    let videoconvert = gst::ElementFactory::make("videoconvert").build()?;
+    let overlay = if config.overlay {
+        let overlay = gst::ElementFactory::make("overlaycomposition").build()?;
+        overlay::connect_overlay(&overlay, &config.font);
+        overlay
+    } else {
+        gst::ElementFactory::make("identity").build()?
+    };
+    let videosink = match &parts.sink {
+        Some(sink) => {
+            info!("video sink {}", sink.name());
+            sink.clone()
+        }
+        None => {
+            info!("video sink {}", config.sink);
+            config.sink.build(config.sink_sync)?
+        }
+    };
+    let klv_consumer = parts.klv_consumer.clone();
+
+    let matcher = Arc::new(Mutex::new(matcher));
+
+    pipeline.add_many(&[&overlay, &videoconvert, &videosink])?;
+
+    // Link display pipe. Overlay draws on frames of whatever size the sender chose.
+    gst::Element::link_many(&[&overlay, &videoconvert, &videosink])?;
+
+    let decoded_pad = overlay.static_pad("sink").unwrap();
+    let video_sink_pad = videosink.static_pad("sink").unwrap();
+
+    // Flushing seek can take PTS back, KLV of the old position must not match new frames.
+    let flushed_matcher = Arc::clone(&matcher);
+    decoded_pad.add_probe(gst::PadProbeType::EVENT_FLUSH, move |_, probe_info| {
+        if let Some(gst::PadProbeData::Event(ref event)) = probe_info.data {
+            if event.type_() == gst::EventType::FlushStop {
+                flushed_matcher.lock().unwrap().clear();
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    // Attach KLV to decoded frames, from here on every element downstream can read it from
+    // the buffer. Only KLV with the same PTS as the frame belongs to it.
+    let decoded_matcher = Arc::clone(&matcher);
+    decoded_pad.add_probe(gst::PadProbeType::BUFFER, move |pad, probe_info| {
+        let Some(gst::PadProbeData::Buffer(ref mut buf)) = probe_info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Some(pts) = buf.pts() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        // KLV from SEI came through the decoder together with its frame.
+        if buf.meta::<klv::meta::KlvMeta>().is_some() {
+            return gst::PadProbeReturn::Ok;
+        }
+        let mut matcher = decoded_matcher.lock().unwrap();
+        if let Some(klv_match) = matcher.find(pts) {
+            klv::meta::KlvMeta::add(buf.make_mut(), klv_match);
+        } else {
+            let count = matcher.unmatched_frames();
+            warn!("No KLV for frame {pts}, {count} unmatched frames so far");
+            if let Some(overlay) = pad.parent_element() {
+                let s = gst::Structure::builder(klv::UNMATCHED_MESSAGE)
+                    .field("pts", pts)
+                    .field("count", count)
+                    .build();
+                let msg = gst::message::Element::builder(s).src(&overlay).build();
+                let _ = overlay.post_message(msg);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    // Probe when new frame reaches videosink element.
+    video_sink_pad.add_probe(gst::PadProbeType::DATA_DOWNSTREAM, move |_, probe_info| {
+        match probe_info.data {
+            Some(gst::PadProbeData::Event(ref event)) => {
+                info!("Event {:?}", event);
+            }
+            Some(gst::PadProbeData::Buffer(ref buf)) => {
+                log::info!("video sink {:?} ", buf.pts());
+                if let Some(check) = check.as_ref() {
+                    check.received(buf);
+                }
+                if let (Some(consumer), Some(pts)) = (klv_consumer.as_ref(), buf.pts()) {
+                    let meta = buf.meta::<klv::meta::KlvMeta>();
+                    consumer(pts, meta.as_ref().map_or(&[][..], |meta| meta.packets()));
+                }
+            }
+            _ => (),
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    Ok(ReceiverStreams {
+        video: decoded_pad,
+        matcher,
+        klv_carriage: config.klv_carriage,
+        sei_uuid: config.klv_sei_uuid,
+    })
+}
+
+/// Attaches KLV from SEI of every access unit leaving `parsed` to the access unit itself,
+/// the decoder copies it to the decoded frame.
+fn extract_sei(parsed: &gst::Element, uuid: SeiUuid) {
+    let parsed_pad = parsed.static_pad("src").unwrap();
+    let corrupted = AtomicU64::new(0);
+    parsed_pad.add_probe(gst::PadProbeType::BUFFER, move |pad, probe_info| {
+        let Some(gst::PadProbeData::Buffer(ref mut buf)) = probe_info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Some(pts) = buf.pts() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let data = {
+            let map = buf.map_readable().unwrap();
+            klv::sei::extract_klv(map.as_slice(), uuid)
+        };
+        if data.is_empty() {
+            return gst::PadProbeReturn::Ok;
+        }
+
+        let mut packets = Vec::new();
+        for packet in klv::decode_buffer(&data) {
+            match packet {
+                Ok(packet) => packets.push(packet),
+                Err(err) => {
+                    let count = corrupted.fetch_add(1, Ordering::SeqCst) + 1;
+                    warn!("receive corrupted klv in SEI {pts}: {err}");
+                    if let Some(parent) = pad.parent_element() {
+                        klv::post_corrupted(&parent, Some(pts), &err, count);
+                    }
+                }
+            }
+        }
+        if !packets.is_empty() {
+            klv::meta::KlvMeta::add(buf.make_mut(), KlvMatch { pts, packets });
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Adds `elements` to the running `pipeline`, links them one after another and `src_pad` to
+/// the first of them.
+pub(crate) fn add_branch(
+    pipeline: &gst::Pipeline,
+    src_pad: &gst::Pad,
+    elements: &[gst::Element],
+) -> Result<(), Error> {
+    let elements: Vec<&gst::Element> = elements.iter().collect();
+    pipeline.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+    let sink_pad = elements[0]
+        .static_pad("sink")
+        .ok_or_else(|| anyhow!("{} has no sink pad", elements[0].name()))?;
+    src_pad.link(&sink_pad)?;
+    for e in elements {
+        e.sync_state_with_parent()?;
+    }
+    Ok(())
+}
+
+/// Receiver which takes MPEG-TS from `config.network` over UDP, unicast or multicast.
+pub fn udp_pipeline(
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+) -> Result<gst::Pipeline, Error> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let tsdemux = build_receiver(&pipeline, config, parts, None)?;
+
+    let network = &config.network;
+    info!("receiving from udp://{}:{}", network.host, network.port);
+    let caps = gst::Caps::builder("video/mpegts")
+        .field("systemstream", true)
+        .field("packetsize", 188i32)
+        .build();
+    let udpsrc = udp_src(network, network.port, &caps)?;
+    pipeline.add(&udpsrc)?;
+    let received = recorder::tee(&pipeline, &udpsrc, config.record.as_ref())?;
+    received.link(&tsdemux)?;
+
+    Ok(pipeline)
+}
+
+/// `udpsrc` listening on `port` of the configured host, joins the group if it is multicast.
+pub(crate) fn udp_src(
+    network: &config::NetworkConfig,
+    port: u16,
+    caps: &gst::Caps,
+) -> Result<gst::Element, Error> {
+    let udpsrc = gst::ElementFactory::make("udpsrc")
+        .property("address", network.host.as_str())
+        .property("port", i32::from(port))
+        .property("caps", caps)
+        .build()?;
+    if network.is_multicast() {
+        udpsrc.set_property("auto-multicast", true);
+        if let Some(iface) = &network.multicast_iface {
+            udpsrc.set_property("multicast-iface", iface.as_str());
+        }
+    }
+    Ok(udpsrc)
+}
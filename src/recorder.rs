@@ -0,0 +1,268 @@
+//! Recording of the muxed MPEG-TS, video together with KLV, into segment files.
+//!
+//! Segments are named `klv-<UTC start time>-<index>.ts`, so that they sort by time within the
+//! recording directory. A segment is closed at the first key frame after the configured
+//! duration or size, so every segment can be played on its own. Received MPEG-TS has no key
+//! frame flags, there segments are cut at any TS packet. The oldest segments in the directory
+//! are deleted when the configured total size is exceeded.
+
+use crate::config::{RecordConfig, Segment};
+use anyhow::Error;
+use gst::prelude::*;
+use gstreamer as gst;
+use log::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Every recorded segment starts with it.
+const SEGMENT_PREFIX: &str = "klv-";
+const SEGMENT_EXTENSION: &str = "ts";
+
+/// How often the total size of recordings is checked.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tees `muxed` into a recorder if recording is configured and returns the element the
+/// transport has to be linked to, either a branch of the tee or `muxed` itself.
+pub fn tee(
+    pipeline: &gst::Pipeline,
+    muxed: &gst::Element,
+    config: Option<&RecordConfig>,
+) -> Result<gst::Element, Error> {
+    let Some(config) = config else {
+        return Ok(muxed.clone());
+    };
+
+    let tee = gst::ElementFactory::make("tee").build()?;
+    // Each branch needs its own thread, otherwise slow disk would stall the transport.
+    let transport_queue = gst::ElementFactory::make("queue").build()?;
+    let record_queue = gst::ElementFactory::make("queue").build()?;
+    let recorder = build(config)?;
+    pipeline.add_many(&[&tee, &transport_queue, &record_queue, &recorder])?;
+    muxed.link(&tee)?;
+    gst::Element::link_many(&[&tee, &record_queue, &recorder])?;
+    tee.link(&transport_queue)?;
+
+    if let Some(max_total_bytes) = config.max_total_bytes {
+        let dir = config.dir.clone();
+        let pruned_at = Mutex::new(None::<Instant>);
+        let record_pad = record_queue.static_pad("src").unwrap();
+        record_pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+            let mut pruned_at = pruned_at.lock().unwrap();
+            if !pruned_at.is_some_and(|at| at.elapsed() < PRUNE_INTERVAL) {
+                *pruned_at = Some(Instant::now());
+                if let Err(err) = prune(&dir, max_total_bytes) {
+                    warn!("failed to prune recordings in {}: {err}", dir.display());
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    Ok(transport_queue)
+}
+
+/// `multifilesink` which writes MPEG-TS segments into `config.dir`.
+fn build(config: &RecordConfig) -> Result<gst::Element, Error> {
+    fs::create_dir_all(&config.dir)?;
+    let location = config.dir.join(format!(
+        "{SEGMENT_PREFIX}{}-%05d.{SEGMENT_EXTENSION}",
+        utc_stamp(SystemTime::now())
+    ));
+    info!("recording to {}", location.display());
+
+    let sink = gst::ElementFactory::make("multifilesink")
+        .property("location", location.to_string_lossy().as_ref())
+        // Keep a GOP in one segment, so that a segment starts with a key frame.
+        .property("aggregate-gops", true)
+        .property("sync", false)
+        .property("async", false)
+        .build()?;
+    match config.segment {
+        Segment::Duration(duration) => {
+            sink.set_property_from_str("next-file", "max-duration");
+            sink.set_property("max-file-duration", duration.as_nanos() as u64);
+        }
+        Segment::Size(bytes) => {
+            sink.set_property_from_str("next-file", "max-size");
+            sink.set_property("max-file-size", bytes);
+        }
+    }
+    Ok(sink)
+}
+
+/// Deletes the oldest segments in `dir` until all of them together take at most
+/// `max_total_bytes`. The newest segment is being written and is never deleted.
+pub fn prune(dir: &Path, max_total_bytes: u64) -> io::Result<()> {
+    let mut segments = segments(dir)?;
+    let mut total: u64 = segments.iter().map(|(_, size)| size).sum();
+    segments.pop();
+    for (path, size) in segments {
+        if total <= max_total_bytes {
+            break;
+        }
+        info!("delete old recording {}", path.display());
+        fs::remove_file(&path)?;
+        total -= size;
+    }
+    Ok(())
+}
+
+/// Recorded segments in `dir` with their sizes, oldest first.
+pub fn segments(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_segment = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(SEGMENT_PREFIX))
+            && path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION);
+        if is_segment && entry.file_type()?.is_file() {
+            segments.push((path, entry.metadata()?.len()));
+        }
+    }
+    // Names start with UTC time and end with the index, so they sort by age.
+    segments.sort();
+    Ok(segments)
+}
+
+/// `YYYYMMDDTHHMMSSZ` in UTC.
+fn utc_stamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01, proleptic Gregorian calendar.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playback;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn utc_stamps() {
+        assert_eq!(utc_stamp(at(0)), "19700101T000000Z");
+        assert_eq!(utc_stamp(at(951_782_400)), "20000229T000000Z");
+        assert_eq!(utc_stamp(at(1_709_251_199)), "20240229T235959Z");
+        assert_eq!(utc_stamp(at(1_709_251_200)), "20240301T000000Z");
+        assert_eq!(utc_stamp(at(4_107_542_399)), "21000228T235959Z");
+        assert_eq!(utc_stamp(at(4_107_542_400)), "21000301T000000Z");
+        assert_eq!(
+            utc_stamp(UNIX_EPOCH - Duration::from_secs(1)),
+            "19700101T000000Z"
+        );
+    }
+
+    #[test]
+    fn utc_stamp_agrees_with_playback() {
+        // Every day of 1999 to 2002 and then some, at a different time of day each.
+        for day in (10_592..12_000).chain((0..100_000).step_by(997)) {
+            let secs = day * 86400 + day * 7919 % 86400;
+            let stamp = utc_stamp(at(secs));
+            let iso = format!(
+                "{}-{}-{}T{}:{}:{}Z",
+                &stamp[0..4],
+                &stamp[4..6],
+                &stamp[6..8],
+                &stamp[9..11],
+                &stamp[11..13],
+                &stamp[13..15]
+            );
+            assert_eq!(
+                playback::parse_time_stamp(&iso),
+                Some(secs * 1_000_000),
+                "{iso}"
+            );
+        }
+    }
+
+    /// Empty directory of its own for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("klv-recorder-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn prune_oldest_first() {
+        let dir = temp_dir("prune");
+        let recorded = [
+            "klv-20240101T000000Z-00001.ts",
+            "klv-20240101T000000Z-00000.ts",
+            "klv-20240102T000000Z-00000.ts",
+            "klv-20240103T000000Z-00000.ts",
+        ];
+        for name in recorded {
+            fs::write(dir.join(name), [0; 100]).unwrap();
+        }
+        // Not segments, left alone whatever their size.
+        let others = ["klv-notes.txt", "other-20240101T000000Z-00000.ts"];
+        for name in others {
+            fs::write(dir.join(name), [0; 1000]).unwrap();
+        }
+        fs::create_dir(dir.join("klv-dir.ts")).unwrap();
+
+        prune(&dir, 400).unwrap();
+        assert_eq!(segments(&dir).unwrap().len(), 4);
+
+        prune(&dir, 250).unwrap();
+        assert_eq!(
+            names(&dir),
+            [
+                "klv-20240102T000000Z-00000.ts",
+                "klv-20240103T000000Z-00000.ts",
+                "klv-dir.ts",
+                "klv-notes.txt",
+                "other-20240101T000000Z-00000.ts",
+            ]
+        );
+
+        // The newest is being written, it stays even if it alone is too big.
+        prune(&dir, 0).unwrap();
+        assert_eq!(
+            segments(&dir).unwrap(),
+            [(dir.join("klv-20240103T000000Z-00000.ts"), 100)]
+        );
+        assert_eq!(names(&dir).len(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+//! RTP carriage: H.264 (RFC 6184) and KLV (RFC 6597) in two sessions of one `rtpbin`.
+//!
+//! Every session has its RTCP on the next port, video RTP is on `port`, its RTCP on
+//! `port + 1`, KLV RTP on `port + 2` and its RTCP on `port + 3`. Sender reports of both
+//! sessions map RTP timestamps to the same NTP clock, with them `rtpbin` puts video and KLV
+//! on one PTS timeline, after which KLV is matched to frames by PTS like with MPEG-TS.
+
+use crate::{builder::PipelineParts, config, klv, receiver, sender};
+use anyhow::Error;
+use gst::prelude::*;
+use gstreamer as gst;
+use log::*;
+
+const VIDEO_SESSION: u16 = 0;
+const KLV_SESSION: u16 = 1;
+
+const VIDEO_PAYLOAD: u32 = 96;
+const KLV_PAYLOAD: u32 = 97;
+
+/// RTP and RTCP port of a session.
+fn ports(network: &config::NetworkConfig, session: u16) -> (u16, u16) {
+    let rtp = network.port + 2 * session;
+    (rtp, rtp + 1)
+}
+
+/// Sender which streams RTP to `config.network`, unicast or multicast.
+pub fn sender_pipeline(
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+) -> Result<gst::Pipeline, Error> {
+    gst::init()?;
+    if config.record.is_some() {
+        warn!("recording is only supported with MPEG-TS transport");
+    }
+    let pipeline = gst::Pipeline::new();
+    let streams = sender::build_streams(&pipeline, config, parts, None)?;
+
+    let rtpbin = gst::ElementFactory::make("rtpbin").build()?;
+    let h264pay = gst::ElementFactory::make("rtph264pay")
+        .property("pt", VIDEO_PAYLOAD)
+        // SPS/PPS with every key frame, so that a receiver can join at any time.
+        .property("config-interval", -1i32)
+        .build()?;
+    pipeline.add_many(&[&rtpbin, &h264pay])?;
+    streams.video.link(&h264pay)?;
+    let mut sessions = vec![(VIDEO_SESSION, h264pay)];
+    // KLV in SEI goes along with the video, there is no KLV session then.
+    if let Some(klv) = streams.klv {
+        let klvpay = gst::ElementFactory::make("rtpklvpay")
+            .property("pt", KLV_PAYLOAD)
+            .build()?;
+        pipeline.add(&klvpay)?;
+        klv.link(&klvpay)?;
+        sessions.push((KLV_SESSION, klvpay));
+    }
+
+    let network = &config.network;
+    info!("sending RTP to {}:{}", network.host, network.port);
+    for (session, payloader) in sessions {
+        let (rtp_port, rtcp_port) = ports(network, session);
+        let rtp_sink = sender::udp_sink(network, rtp_port)?;
+        let rtcp_sink = sender::udp_sink(network, rtcp_port)?;
+        // RTCP is sent on its own schedule and must not hold back preroll.
+        rtcp_sink.set_property("async", false);
+        pipeline.add_many(&[&rtp_sink, &rtcp_sink])?;
+
+        payloader.link_pads(
+            Some("src"),
+            &rtpbin,
+            Some(&format!("send_rtp_sink_{session}")),
+        )?;
+        rtpbin.link_pads(
+            Some(&format!("send_rtp_src_{session}")),
+            &rtp_sink,
+            Some("sink"),
+        )?;
+        rtpbin.link_pads(
+            Some(&format!("send_rtcp_src_{session}")),
+            &rtcp_sink,
+            Some("sink"),
+        )?;
+    }
+
+    Ok(pipeline)
+}
+
+/// Receiver which takes RTP from `config.network`, unicast or multicast.
+///
+/// Receiver reports are not sent back, sender reports alone are enough to keep video and KLV
+/// in sync.
+pub fn receiver_pipeline(
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+) -> Result<gst::Pipeline, Error> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+
+    // RTP timestamps go through the jitter buffer clock skew estimation, so PTS of a frame
+    // and its KLV are not exactly equal. Anything within half a frame is the same frame.
+    let framerate = config.framerate;
+    let tolerance = gst::ClockTime::from_nseconds(
+        gst::ClockTime::SECOND.nseconds() * framerate.denominator as u64
+            / (2 * framerate.numerator as u64),
+    );
+    let matcher = klv::KlvMatcher::new(tolerance, klv::matcher::DEFAULT_MAX_AGE);
+    let streams = receiver::build_streams(&pipeline, config, parts, None, matcher)?;
+
+    let rtpbin = gst::ElementFactory::make("rtpbin").build()?;
+    pipeline.add(&rtpbin)?;
+
+    let network = &config.network;
+    info!("receiving RTP from {}:{}", network.host, network.port);
+    let video_caps = gst::Caps::builder("application/x-rtp")
+        .field("media", "video")
+        .field("clock-rate", 90000i32)
+        .field("encoding-name", "H264")
+        .field("payload", VIDEO_PAYLOAD as i32)
+        .build();
+    let klv_caps = gst::Caps::builder("application/x-rtp")
+        .field("media", "application")
+        .field("clock-rate", 90000i32)
+        .field("encoding-name", "SMPTE336M")
+        .field("payload", KLV_PAYLOAD as i32)
+        .build();
+    let rtcp_caps = gst::Caps::new_empty_simple("application/x-rtcp");
+    for (session, caps) in [(VIDEO_SESSION, &video_caps), (KLV_SESSION, &klv_caps)] {
+        let (rtp_port, rtcp_port) = ports(network, session);
+        let rtp_src = receiver::udp_src(network, rtp_port, caps)?;
+        let rtcp_src = receiver::udp_src(network, rtcp_port, &rtcp_caps)?;
+        pipeline.add_many(&[&rtp_src, &rtcp_src])?;
+
+        rtp_src.link_pads(
+            Some("src"),
+            &rtpbin,
+            Some(&format!("recv_rtp_sink_{session}")),
+        )?;
+        rtcp_src.link_pads(
+            Some("src"),
+            &rtpbin,
+            Some(&format!("recv_rtcp_sink_{session}")),
+        )?;
+    }
+
+    let pipeline_weak = pipeline.downgrade();
+    // `rtpbin` creates `recv_rtp_src_<session>_<ssrc>_<pt>` when a stream starts.
+    rtpbin.connect_pad_added(move |src, src_pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        let name = src_pad.name();
+        let connected = if name.starts_with(&format!("recv_rtp_src_{VIDEO_SESSION}_")) {
+            info!("connect new video pad {} from {}", name, src.name());
+            gst::ElementFactory::make("rtph264depay")
+                .build()
+                .map_err(Error::from)
+                .and_then(|depay| {
+                    streams.add_video(&pipeline, src_pad, receiver::VideoCodec::H264, vec![depay])
+                })
+        } else if name.starts_with(&format!("recv_rtp_src_{KLV_SESSION}_")) {
+            info!("connect new metadata pad {} from {}", name, src.name());
+            gst::ElementFactory::make("rtpklvdepay")
+                .build()
+                .map_err(Error::from)
+                .and_then(|depay| streams.add_klv(&pipeline, src_pad, vec![depay]))
+        } else {
+            warn!("Received unsupported new pad {} from {}", name, src.name());
+            return;
+        };
+        if let Err(err) = connected {
+            error!("failed to connect pad {name} from {}: {err}", src.name());
+        }
+    });
+
+    Ok(pipeline)
+}
@@ -0,0 +1,527 @@
+//! Sending side: camera frames are encoded and muxed into MPEG-TS together with KLV
+//! generated for each of them.
+
+use crate::{
+    builder::PipelineParts,
+    check::SyncCheck,
+    config::{self, KlvCarriage, KlvSignaling},
+    klv::{self, sei, KlvPacket},
+    mpegts,
+    producer::{self, Frame},
+    recorder,
+};
+use anyhow::{anyhow, Error};
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use gstreamer_video as gst_video;
+use log::*;
+use std::{
+    collections::BTreeMap,
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicU32, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Encoded video and the KLV generated for its frames, `src` pads are not linked yet.
+pub struct SenderStreams {
+    /// `h264parse` giving H.264 in byte-stream format.
+    pub video: gst::Element,
+    /// `appsrc` giving `meta/x-klv` with the PTS of the frame each packet belongs to,
+    /// `None` if KLV is carried in the video itself.
+    pub klv: Option<gst::Element>,
+}
+
+/// Adds camera, encoder and KLV source to `pipeline` and returns `mpegtsmux` which muxes
+/// them together. Its `src` pad is left for the caller to link to the transport.
+pub fn build_sender(
+    pipeline: &gst::Pipeline,
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+    check: Option<Arc<SyncCheck>>,
+) -> Result<gst::Element, Error> {
+    let streams = build_streams(pipeline, config, parts, check)?;
+    let mpegtsmux = gst::ElementFactory::make("mpegtsmux").build()?;
+    pipeline.add(&mpegtsmux)?;
+    // h264 video and KLV stream are both linked to mpegtsmux which muxes them together.
+    streams.video.link(&mpegtsmux)?;
+    if let Some(klv) = streams.klv {
+        mux_klv(&klv, &mpegtsmux, config.klv_signaling)?;
+    }
+    Ok(mpegtsmux)
+}
+
+/// Links `klv` to a new pad of `mpegtsmux`, signals its stream as `signaling` and returns the
+/// PID of the stream.
+fn mux_klv(
+    klv: &gst::Element,
+    mpegtsmux: &gst::Element,
+    signaling: KlvSignaling,
+) -> Result<u16, Error> {
+    // Pads are named after the PID of their stream.
+    let klv_pad = mpegtsmux
+        .request_pad_simple("sink_%d")
+        .ok_or_else(|| anyhow!("mpegtsmux gave no pad for KLV"))?;
+    let klv_pid = klv_pad
+        .name()
+        .strip_prefix("sink_")
+        .and_then(|pid| pid.parse().ok())
+        .ok_or_else(|| anyhow!("no PID in mpegtsmux pad name {}", klv_pad.name()))?;
+    klv.link_pads_filtered(
+        Some("src"),
+        mpegtsmux,
+        Some(klv_pad.name().as_str()),
+        &gst::Caps::builder("meta/x-klv")
+            .field("parsed", true)
+            .build(),
+    )?;
+    signal_klv(klv, mpegtsmux, klv_pid, signaling);
+    Ok(klv_pid)
+}
+
+/// Makes `mpegtsmux` output signal the KLV stream on `klv_pid` as `signaling`, sync KLV is
+/// also wrapped in metadata AU cells before it is muxed.
+fn signal_klv(klv: &gst::Element, mpegtsmux: &gst::Element, klv_pid: u16, signaling: KlvSignaling) {
+    info!("signal KLV on PID {klv_pid} as {signaling:?}");
+    if signaling == KlvSignaling::Sync {
+        let sequence_number = AtomicU8::new(0);
+        let klv_pad = klv.static_pad("src").unwrap();
+        klv_pad.add_probe(gst::PadProbeType::BUFFER, move |_, probe_info| {
+            let Some(gst::PadProbeData::Buffer(ref mut buf)) = probe_info.data else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let cells = {
+                let map = buf.map_readable().unwrap();
+                let n = sequence_number.fetch_add(1, Ordering::Relaxed);
+                mpegts::wrap_metadata_au(map.as_slice(), n)
+            };
+            let mut buffer = gst::Buffer::from_mut_slice(cells);
+            {
+                let bufref = buffer.get_mut().unwrap();
+                let _ = buf.copy_into(
+                    bufref,
+                    gst::BufferCopyFlags::FLAGS
+                        | gst::BufferCopyFlags::TIMESTAMPS
+                        | gst::BufferCopyFlags::META,
+                    ..,
+                );
+            }
+            *buf = buffer;
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    let signaler = Mutex::new(mpegts::KlvSignaler::new(klv_pid, signaling));
+    let rewrite = move |buf: &mut gst::BufferRef| {
+        if let Ok(mut map) = buf.map_writable() {
+            signaler.lock().unwrap().rewrite(map.as_mut_slice());
+        }
+    };
+    let muxed_pad = mpegtsmux.static_pad("src").unwrap();
+    muxed_pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+        move |_, probe_info| {
+            match probe_info.data {
+                Some(gst::PadProbeData::Buffer(ref mut buf)) => rewrite(buf.make_mut()),
+                Some(gst::PadProbeData::BufferList(ref mut list)) => {
+                    list.make_mut().foreach_mut(|mut buf, _| {
+                        rewrite(buf.make_mut());
+                        ControlFlow::Continue(Some(buf))
+                    });
+                }
+                _ => (),
+            }
+            gst::PadProbeReturn::Ok
+        },
+    );
+}
+
+/// Adds camera, encoder and KLV source to `pipeline`, so that they can be carried by any
+/// transport. Source, encoder and KLV given in `parts` replace the configured ones.
+pub fn build_streams(
+    pipeline: &gst::Pipeline,
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+    check: Option<Arc<SyncCheck>>,
+) -> Result<SenderStreams, Error> {
+    let videosrc = match &parts.source {
+        Some(source) => {
+            info!("video source {}", source.name());
+            source.clone()
+        }
+        None => {
+            info!("video source {}", config.source);
+            config.source.build()?
+        }
+    };
+    if let Some(check) = check.as_ref() {
+        // Source sends EOS after the frames, which ends the check.
+        if videosrc.find_property("num-buffers").is_some() {
+            videosrc.set_property("num-buffers", check.frames() as i32);
+        } else {
+            warn!(
+                "video source {} can't be limited to the checked frames",
+                videosrc.name()
+            );
+        }
+    }
+    let encoder = match &parts.encoder {
+        Some(encoder) => encoder.clone(),
+        None => {
+            let x264enc = gst::ElementFactory::make("x264enc").build()?;
+            x264enc.set_property_from_str("tune", &config.encoder.tune);
+            if let Some(preset) = &config.encoder.speed_preset {
+                x264enc.set_property_from_str("speed-preset", preset);
+            }
+            if let Some(bitrate) = config.encoder.bitrate {
+                x264enc.set_property("bitrate", bitrate);
+            }
+            x264enc
+        }
+    };
+
+    // Access units with start codes, that is what SEI is inserted into.
+    let enc_capsfilter = gst::ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-h264")
+                .field("stream-format", "byte-stream")
+                .field("alignment", "au")
+                .build(),
+        )
+        .build()?;
+    let h264parse = gst::ElementFactory::make("h264parse").build()?;
+
+    // Sources differ in size, frame rate and format, bring them all to the configured caps.
+    let framerate = config.framerate;
+    let caps = gst_video::VideoCapsBuilder::new()
+        .width(config.width as i32)
+        .height(config.height as i32)
+        .framerate((framerate.numerator, framerate.denominator).into())
+        .build();
+    let src_convert = gst::ElementFactory::make("videoconvert").build()?;
+    let src_scale = gst::ElementFactory::make("videoscale").build()?;
+    let src_rate = gst::ElementFactory::make("videorate").build()?;
+    let src_capsfilter = gst::ElementFactory::make("capsfilter")
+        .property("caps", &caps)
+        .build()?;
+
+    let appsrc = match config.klv_carriage {
+        KlvCarriage::Stream => {
+            let appsrc = klv::klv_test_src()?;
+            pipeline.add(&appsrc)?;
+            Some(appsrc)
+        }
+        KlvCarriage::Sei => None,
+    };
+    // KLV waiting for its frame to come out of the encoder, for SEI carriage.
+    let pending = Arc::new(Mutex::new(BTreeMap::<gst::ClockTime, Vec<u8>>::new()));
+
+    pipeline.add_many(&[
+        &videosrc,
+        &src_convert,
+        &src_scale,
+        &src_rate,
+        &src_capsfilter,
+        &encoder,
+        &enc_capsfilter,
+        &h264parse,
+    ])?;
+
+    gst::Element::link_many(&[
+        &videosrc,
+        &src_convert,
+        &src_scale,
+        &src_rate,
+        &src_capsfilter,
+        &encoder,
+        &enc_capsfilter,
+        &h264parse,
+    ])?;
+
+    // Frames are taken after `videorate`, which may drop or duplicate source frames.
+    let video_src_pad = src_capsfilter.static_pad("src").unwrap();
+    let klv_src = appsrc.clone();
+    let ts = Arc::new(Mutex::new(Instant::now()));
+    let frame_nr = AtomicU32::new(0);
+    let klv_producer = match &parts.klv_producer {
+        Some(klv_producer) => Arc::clone(klv_producer),
+        None => producer::from_config(config)?,
+    };
+
+    let sei_pending = Arc::clone(&pending);
+
+    // This is called evertime when new video frame is produced by videosrc.
+    // Here KLV data is pushed to appsrc buffer.
+    video_src_pad.add_probe(gst::PadProbeType::DATA_DOWNSTREAM, move |_, probe_info| {
+        match probe_info.data {
+            Some(gst::PadProbeData::Event(ref event)) => {
+                info!("Event {:?}", event);
+                // Muxer waits for EOS on all of its inputs, KLV ends together with video.
+                if event.type_() == gst::EventType::Eos {
+                    if let Some(appsrc) = appsrc
+                        .as_ref()
+                        .and_then(|appsrc| appsrc.downcast_ref::<gst_app::AppSrc>())
+                    {
+                        let _ = appsrc.end_of_stream();
+                    }
+                }
+            }
+            Some(gst::PadProbeData::Buffer(ref buf)) => {
+                let now = Instant::now();
+                let mut ts = ts.lock().unwrap();
+                let frame_time_ms = (now - *ts).as_micros() as f32 / 1000.;
+                *ts = now;
+                let frame_time = buf.pts();
+
+                let nr = frame_nr.fetch_add(1, Ordering::SeqCst);
+                let time_stamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_micros() as u64;
+                let frame = Frame {
+                    number: nr,
+                    pts: frame_time,
+                    time_stamp,
+                };
+                let data: Vec<u8> = klv_producer
+                    .lock()
+                    .unwrap()
+                    .produce(&frame)
+                    .iter()
+                    .flat_map(KlvPacket::encode)
+                    .collect();
+                if let Some(check) = check.as_ref() {
+                    check.sent(frame_time, &data);
+                }
+
+                if frame_time_ms > 35. {
+                    error!(
+                        "src frame {:?} {} nr {} klv {} bytes",
+                        frame_time,
+                        frame_time_ms,
+                        nr,
+                        data.len()
+                    );
+                } else {
+                    warn!(
+                        "src frame {:?} {} nr {} klv {} bytes",
+                        frame_time,
+                        frame_time_ms,
+                        nr,
+                        data.len()
+                    );
+                }
+                if data.is_empty() {
+                    return gst::PadProbeReturn::Ok;
+                }
+
+                let Some(appsrc) = appsrc.as_ref() else {
+                    // Encoder keeps PTS, the probe after it puts KLV to the same frame.
+                    if let Some(pts) = frame_time {
+                        pending.lock().unwrap().insert(pts, data);
+                    }
+                    return gst::PadProbeReturn::Ok;
+                };
+                if let Some(appsrc) = appsrc.downcast_ref::<gst_app::AppSrc>() {
+                    let mut buffer = gst::Buffer::with_size(data.len()).unwrap();
+                    {
+                        let bufref = buffer.make_mut();
+                        bufref.set_pts(frame_time);
+                        //bufref.set_dts(buf.dts());
+
+                        let mut mw = bufref.map_writable().unwrap();
+                        mw.as_mut_slice().copy_from_slice(&data)
+                    }
+
+                    appsrc.push_buffer(buffer).unwrap();
+                } else {
+                    error!("Failed to downcast appsrc to gst_app::AppSrc");
+                }
+            }
+            _ => (),
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    if config.klv_carriage == KlvCarriage::Sei {
+        insert_sei(&enc_capsfilter, sei_pending, config.klv_sei_uuid);
+    }
+
+    Ok(SenderStreams {
+        video: h264parse,
+        klv: klv_src,
+    })
+}
+
+/// Puts KLV from `pending` into the access unit of its frame as it leaves `encoded`.
+fn insert_sei(
+    encoded: &gst::Element,
+    pending: Arc<Mutex<BTreeMap<gst::ClockTime, Vec<u8>>>>,
+    uuid: sei::SeiUuid,
+) {
+    let encoded_pad = encoded.static_pad("src").unwrap();
+    encoded_pad.add_probe(gst::PadProbeType::BUFFER, move |_, probe_info| {
+        let Some(gst::PadProbeData::Buffer(ref mut buf)) = probe_info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Some(pts) = buf.pts() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let data = {
+            let mut pending = pending.lock().unwrap();
+            let data = pending.remove(&pts);
+            // Frames the encoder dropped never come, don't keep their KLV forever.
+            *pending = pending.split_off(&pts.saturating_sub(klv::matcher::DEFAULT_MAX_AGE));
+            data
+        };
+        let Some(data) = data else {
+            warn!("No KLV for encoded frame {pts}");
+            return gst::PadProbeReturn::Ok;
+        };
+
+        // ST 0604 time stamp of the frame is the one its ST 0601 set carries.
+        let packets: Vec<_> = klv::decode_buffer(&data).into_iter().flatten().collect();
+        let time_stamp = klv::find_uas_local_set(&packets).and_then(|set| set.precision_time_stamp);
+        let access_unit = {
+            let map = buf.map_readable().unwrap();
+            sei::insert_sei(map.as_slice(), &sei::encode_sei(&data, uuid, time_stamp))
+        };
+        let mut buffer = gst::Buffer::from_mut_slice(access_unit);
+        {
+            let bufref = buffer.get_mut().unwrap();
+            let _ = buf.copy_into(
+                bufref,
+                gst::BufferCopyFlags::FLAGS
+                    | gst::BufferCopyFlags::TIMESTAMPS
+                    | gst::BufferCopyFlags::META,
+                ..,
+            );
+        }
+        *buf = buffer;
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Sender which streams MPEG-TS to `config.network` over UDP, unicast or multicast.
+pub fn udp_pipeline(
+    config: &config::PipelineConfig,
+    parts: &PipelineParts,
+) -> Result<gst::Pipeline, Error> {
+    gst::init()?;
+    let pipeline = gst::Pipeline::new();
+    let mpegtsmux = build_sender(&pipeline, config, parts, None)?;
+    // 7 TS packets of 188 bytes fit into one Ethernet frame.
+    mpegtsmux.set_property("alignment", 7i32);
+
+    let muxed = recorder::tee(&pipeline, &mpegtsmux, config.record.as_ref())?;
+
+    let network = &config.network;
+    info!("sending to udp://{}:{}", network.host, network.port);
+    let udpsink = udp_sink(network, network.port)?;
+    pipeline.add(&udpsink)?;
+    muxed.link(&udpsink)?;
+
+    Ok(pipeline)
+}
+
+/// `udpsink` sending to `port` of the configured host, joins the group if it is multicast.
+pub(crate) fn udp_sink(network: &config::NetworkConfig, port: u16) -> Result<gst::Element, Error> {
+    let udpsink = gst::ElementFactory::make("udpsink")
+        .property("host", network.host.as_str())
+        .property("port", i32::from(port))
+        // Source is live, there is no need to wait for the clock again.
+        .property("sync", false)
+        .build()?;
+    if network.is_multicast() {
+        udpsink.set_property("auto-multicast", true);
+        if let Some(ttl) = network.ttl {
+            udpsink.set_property("ttl-mc", i32::from(ttl));
+        }
+        if let Some(iface) = &network.multicast_iface {
+            udpsink.set_property("multicast-iface", iface.as_str());
+        }
+    } else if let Some(ttl) = network.ttl {
+        udpsink.set_property("ttl", i32::from(ttl));
+    }
+    Ok(udpsink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PMTs `mpegtsmux` sends for a few KLV packets signaled as `signaling`, with the PID of the
+    /// KLV stream. `None` if GStreamer or its elements are missing.
+    fn muxed_pmts(signaling: KlvSignaling) -> Option<(u16, Vec<mpegts::Pmt>)> {
+        if let Err(err) = gst::init() {
+            eprintln!("skipped, GStreamer not available: {err}");
+            return None;
+        }
+        if let Some(name) = ["appsrc", "mpegtsmux", "appsink"]
+            .into_iter()
+            .find(|name| gst::ElementFactory::find(name).is_none())
+        {
+            eprintln!("skipped, {name} is missing");
+            return None;
+        }
+
+        let pipeline = gst::Pipeline::new();
+        let klv_src = klv::klv_test_src().unwrap();
+        let mpegtsmux = gst::ElementFactory::make("mpegtsmux").build().unwrap();
+        let appsink = gst_app::AppSink::builder().sync(false).build();
+        pipeline
+            .add_many([&klv_src, &mpegtsmux, appsink.upcast_ref()])
+            .unwrap();
+        let klv_pid = mux_klv(&klv_src, &mpegtsmux, signaling).unwrap();
+        mpegtsmux.link(&appsink).unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        let appsrc = klv_src.downcast::<gst_app::AppSrc>().unwrap();
+        for frame in 0..5 {
+            let set = klv::st0601::UasDatalinkLocalSet {
+                precision_time_stamp: Some(1_224_807_209_913_000 + frame * 40_000),
+                ..Default::default()
+            };
+            let mut buffer = gst::Buffer::from_slice(set.encode().encode());
+            buffer
+                .get_mut()
+                .unwrap()
+                .set_pts(gst::ClockTime::from_mseconds(40 * frame));
+            appsrc.push_buffer(buffer).unwrap();
+        }
+        appsrc.end_of_stream().unwrap();
+
+        let mut reader = mpegts::PmtReader::default();
+        let mut pmts = Vec::new();
+        while let Ok(sample) = appsink.pull_sample() {
+            let map = sample.buffer().unwrap().map_readable().unwrap();
+            pmts.extend(reader.push(map.as_slice()));
+        }
+        pipeline.set_state(gst::State::Null).unwrap();
+        Some((klv_pid, pmts))
+    }
+
+    fn check_signaling(signaling: KlvSignaling) {
+        let Some((klv_pid, pmts)) = muxed_pmts(signaling) else {
+            return;
+        };
+        assert!(!pmts.is_empty());
+        for pmt in pmts {
+            assert_eq!(pmt.klv_stream(), Some((klv_pid, signaling)), "{pmt:?}");
+        }
+    }
+
+    #[test]
+    fn signal_async_klv() {
+        check_signaling(KlvSignaling::Async);
+    }
+
+    #[test]
+    fn signal_sync_klv() {
+        check_signaling(KlvSignaling::Sync);
+    }
+}
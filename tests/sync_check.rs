@@ -0,0 +1,67 @@
+//! Runs the `--sync-check` loopback pipeline, skipped where GStreamer plugins are missing.
+
+use gstreamer as gst;
+use gstreamer_klv_test::{config, pipeline};
+use std::time::Duration;
+
+/// Elements of the loopback pipeline with a generated source and `fakesink`.
+const ELEMENTS: &[&str] = &[
+    "videotestsrc",
+    "videoconvert",
+    "videoscale",
+    "videorate",
+    "capsfilter",
+    "x264enc",
+    "h264parse",
+    "mpegtsmux",
+    "tsdemux",
+    "queue",
+    "avdec_h264",
+    "overlaycomposition",
+    "identity",
+    "fakesink",
+    "appsrc",
+    "appsink",
+];
+
+fn plugins_available() -> bool {
+    if let Err(err) = gst::init() {
+        eprintln!("skipped, GStreamer not available: {err}");
+        return false;
+    }
+    let missing: Vec<_> = ELEMENTS
+        .iter()
+        .filter(|name| gst::ElementFactory::find(name).is_none())
+        .collect();
+    if !missing.is_empty() {
+        eprintln!("skipped, missing elements: {missing:?}");
+    }
+    missing.is_empty()
+}
+
+fn sync_check(klv_carriage: config::KlvCarriage) {
+    if !plugins_available() {
+        return;
+    }
+    let config = config::PipelineConfig {
+        width: 320,
+        height: 240,
+        klv_carriage,
+        // Debug build on a shared runner, KLV and PTS are what's under test here.
+        max_frame_gap: Some(Duration::from_secs(1)),
+        ..Default::default()
+    };
+    let report = pipeline::sync_check(&config, 60).expect("sync check pipeline failed");
+    assert!(report.is_ok(), "{report}");
+    assert_eq!(report.frames_received, 60);
+}
+
+#[test]
+fn klv_stream() {
+    sync_check(config::KlvCarriage::Stream);
+}
+
+#[test]
+fn klv_sei() {
+    sync_check(config::KlvCarriage::Sei);
+}